@@ -0,0 +1,68 @@
+use clap::Args;
+use profiler_model::{sign_request, KEY_ID_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How a collector authenticates to the assembler, shared by every collector's command line.
+#[derive(Args, Debug, Clone)]
+#[command(about = None, long_about = None)]
+pub struct AuthArgs {
+    /// File holding the secret of this collector's ingest key. Requests are sent without a
+    /// credential when unset.
+    #[clap(long, value_parser)]
+    pub assembler_key_file: Option<PathBuf>,
+    /// Name of the key in the assembler's keys file. When given, each batch is signed with
+    /// HMAC instead of sending the secret itself as a bearer token.
+    #[clap(long, value_parser)]
+    pub assembler_key_id: Option<String>,
+}
+
+/// The credential attached to every batch.
+pub enum Credential {
+    Bearer(String),
+    Hmac { key_id: String, secret: Vec<u8> },
+}
+
+impl Credential {
+    /// Reads the key file, if any. Surrounding whitespace, such as a trailing newline, is
+    /// not part of the secret.
+    pub fn load(args: &AuthArgs) -> io::Result<Option<Self>> {
+        let Some(path) = &args.assembler_key_file else {
+            return Ok(None);
+        };
+        let secret = fs::read_to_string(path)?.trim().to_string();
+        if secret.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is empty", path.display()),
+            ));
+        }
+        Ok(Some(match &args.assembler_key_id {
+            Some(key_id) => Credential::Hmac {
+                key_id: key_id.clone(),
+                secret: secret.into_bytes(),
+            },
+            None => Credential::Bearer(secret),
+        }))
+    }
+
+    /// Adds the credential to a request carrying `body`. Signatures are made fresh on every
+    /// attempt, so a retried batch is not refused as a replay.
+    pub fn apply(&self, request: reqwest::RequestBuilder, body: &[u8]) -> reqwest::RequestBuilder {
+        match self {
+            Credential::Bearer(secret) => request.bearer_auth(secret),
+            Credential::Hmac { key_id, secret } => {
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                request
+                    .header(KEY_ID_HEADER, key_id)
+                    .header(TIMESTAMP_HEADER, timestamp.to_string())
+                    .header(SIGNATURE_HEADER, sign_request(secret, timestamp, body))
+            }
+        }
+    }
+}
@@ -0,0 +1,499 @@
+use clap::Args;
+use metrics::{counter, gauge, histogram};
+use profiler_model::IngestEvent;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{self, MissedTickBehavior};
+use tracing::{debug, error, info, warn};
+
+use crate::auth::{AuthArgs, Credential};
+use crate::health::{AssemblerState, Health};
+use crate::metrics::{
+    DROPPED_EVENTS, FINGERPRINTS, SEND_DURATION, SEND_FAILURES, SENT_EVENTS, SPOOLED_EVENTS,
+    SPOOL_BYTES,
+};
+use crate::retry::{RetryArgs, RetryPolicy};
+use crate::spool::{Spool, SpoolArgs};
+use crate::tls::{AssemblerClient, TlsArgs};
+
+/// Batching options shared by every collector's command line.
+#[derive(Args, Debug, Clone)]
+#[command(about = None, long_about = None)]
+pub struct BatchArgs {
+    /// Flush to the assembler once this many events are buffered.
+    #[clap(long, value_parser, default_value_t = 100)]
+    pub batch_size: usize,
+    /// Flush whatever is buffered at least this often, in milliseconds.
+    #[clap(long, value_parser, default_value_t = 1000)]
+    pub batch_interval_ms: u64,
+    #[command(flatten)]
+    pub retry: RetryArgs,
+    #[command(flatten)]
+    pub spool: SpoolArgs,
+    #[command(flatten)]
+    pub auth: AuthArgs,
+    #[command(flatten)]
+    pub tls: TlsArgs,
+}
+
+/// Buffers [`IngestEvent`]s and posts them to `/api/ingest/batch` as NDJSON.
+///
+/// A batch is flushed when it reaches `batch_size` events or when `batch_interval_ms`
+/// elapses, whichever comes first. Failed batches are retried with backoff and then
+/// spooled to disk, if a spool is configured, until the assembler is reachable again.
+/// Dropping the sender without calling [`BatchSender::close`] loses whatever is still
+/// buffered in memory.
+pub struct BatchSender {
+    tx: mpsc::Sender<IngestEvent>,
+    task: JoinHandle<()>,
+}
+
+impl BatchSender {
+    /// Spawns the flushing task on the current tokio runtime.
+    ///
+    /// `endpoint` is the ingest base URL, e.g. `http://localhost:8000/api/ingest`.
+    ///
+    /// Every request goes through `client` and carries `credential`, if any. The outcome of every post is reported to
+    /// `health` for the readiness check.
+    pub fn spawn(
+        client: AssemblerClient,
+        endpoint: &str,
+        args: &BatchArgs,
+        credential: Option<Credential>,
+        health: Arc<Health>,
+    ) -> Self {
+        let batch_size = args.batch_size.max(1);
+        let interval = Duration::from_millis(args.batch_interval_ms.max(1));
+
+        let spool = args.spool.spool_path.as_deref().and_then(|path| {
+            match Spool::open(path, args.spool.spool_max_bytes) {
+                Ok(spool) => {
+                    gauge!(SPOOL_BYTES).set(spool.pending_bytes() as f64);
+                    if !spool.is_empty() {
+                        info!(
+                            "Found {} bytes of spooled events in {}, replaying once the assembler is reachable",
+                            spool.pending_bytes(),
+                            path.display()
+                        );
+                    }
+                    Some(spool)
+                }
+                Err(e) => {
+                    error!("Failed to open spool {}: {e}", path.display());
+                    None
+                }
+            }
+        });
+
+        let delivery = Delivery {
+            client,
+            url: format!("{}/batch", endpoint.trim_end_matches('/')),
+            credential,
+            retry: RetryPolicy::from(&args.retry),
+            spool,
+            batch_size,
+            replay_failures: 0,
+            replay_after: None,
+            dropped: 0,
+            health,
+        };
+        let (tx, rx) = mpsc::channel(batch_size.saturating_mul(2));
+        let task = tokio::spawn(run(delivery, rx, interval));
+        BatchSender { tx, task }
+    }
+
+    pub async fn send(&self, event: IngestEvent) {
+        counter!(FINGERPRINTS, "kind" => event.kind()).increment(1);
+        if self.tx.send(event).await.is_err() {
+            error!("Batch sender task is gone, dropping event");
+            counter!(DROPPED_EVENTS).increment(1);
+        }
+    }
+
+    /// Flushes the remaining events and waits for the last request to finish.
+    pub async fn close(self) {
+        drop(self.tx);
+        if let Err(e) = self.task.await {
+            error!("Batch sender task failed: {e}");
+        }
+    }
+}
+
+async fn run(mut delivery: Delivery, mut rx: mpsc::Receiver<IngestEvent>, interval: Duration) {
+    let mut buffer: Vec<IngestEvent> = Vec::with_capacity(delivery.batch_size);
+    let mut ticker = time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            received = rx.recv() => match received {
+                Some(event) => {
+                    buffer.push(event);
+                    if buffer.len() >= delivery.batch_size {
+                        delivery.deliver(std::mem::take(&mut buffer)).await;
+                        ticker.reset();
+                    }
+                }
+                None => break,
+            },
+            _ = ticker.tick() => {
+                if !buffer.is_empty() || delivery.has_spooled() {
+                    delivery.deliver(std::mem::take(&mut buffer)).await;
+                }
+            }
+        }
+    }
+
+    if !buffer.is_empty() || delivery.has_spooled() {
+        delivery.deliver(buffer).await;
+    }
+}
+
+enum PostError {
+    /// The assembler could not be reached or is temporarily failing; worth retrying.
+    Transient(String),
+    /// The assembler refused the batch; sending it again will not help.
+    Rejected(String),
+    /// The assembler refused the collector's credential. The batch is kept for when the
+    /// credential is fixed.
+    Unauthorized(String),
+    /// The assembler is rate limiting this collector and said when to come back.
+    Throttled(String, Duration),
+}
+
+struct Delivery {
+    client: AssemblerClient,
+    url: String,
+    credential: Option<Credential>,
+    retry: RetryPolicy,
+    spool: Option<Spool>,
+    batch_size: usize,
+    /// Failed attempts at replaying the spool since the last success, for backoff.
+    replay_failures: u32,
+    /// No replay is attempted before this.
+    replay_after: Option<Instant>,
+    /// Events lost for good: retries exhausted without a spool, spool full, or rejected
+    /// by the assembler as malformed.
+    dropped: u64,
+    health: Arc<Health>,
+}
+
+impl Delivery {
+    fn has_spooled(&self) -> bool {
+        self.spool.as_ref().is_some_and(|spool| !spool.is_empty())
+    }
+
+    /// Sends `events`, keeping them behind anything already spooled so that the assembler
+    /// always sees events in capture order.
+    async fn deliver(&mut self, events: Vec<IngestEvent>) {
+        self.client.reload_if_changed();
+        let lines = serialize(&events);
+
+        if self.has_spooled() {
+            self.spool_lines(&lines);
+            if self
+                .replay_after
+                .is_none_or(|replay_after| Instant::now() >= replay_after)
+            {
+                self.replay().await;
+            }
+            return;
+        }
+        if lines.is_empty() {
+            return;
+        }
+
+        let result = self.post_with_retry(&lines).await;
+        self.report(&result);
+        match result {
+            Ok(()) => {}
+            Err(PostError::Rejected(e)) => {
+                error!("Assembler rejected batch of {} events: {e}", lines.len());
+                self.count_dropped(lines.len());
+            }
+            Err(PostError::Transient(e)) => {
+                warn!(
+                    "Giving up on batch of {} events after {} attempts: {e}",
+                    lines.len(),
+                    self.retry.max_attempts
+                );
+                self.spool_lines(&lines);
+                self.defer_replay(None);
+            }
+            Err(PostError::Throttled(e, wait)) => {
+                warn!(
+                    "Giving up on batch of {} events after {} attempts: {e}",
+                    lines.len(),
+                    self.retry.max_attempts
+                );
+                self.spool_lines(&lines);
+                self.defer_replay(Some(wait));
+            }
+            Err(PostError::Unauthorized(e)) => {
+                error!(
+                    "Assembler refused the credential for a batch of {} events: {e}",
+                    lines.len()
+                );
+                self.spool_lines(&lines);
+                self.defer_replay(None);
+            }
+        }
+    }
+
+    /// Posts spooled events oldest first, one batch at a time, until the spool is empty or
+    /// the assembler fails again. After a failure the next replay waits for the retry
+    /// backoff, or for as long as the assembler asked.
+    async fn replay(&mut self) {
+        let mut replayed = 0usize;
+        while let Some(spool) = self.spool.as_ref() {
+            let batch = match spool.peek(self.batch_size) {
+                Ok(batch) => batch,
+                Err(e) => {
+                    error!("Failed to read spool: {e}");
+                    self.defer_replay(None);
+                    break;
+                }
+            };
+            if batch.len_bytes == 0 {
+                break;
+            }
+
+            if !batch.lines.is_empty() {
+                let result = post(
+                    self.client.get(),
+                    &self.url,
+                    self.credential.as_ref(),
+                    &batch.lines,
+                )
+                .await;
+                self.report(&result);
+                match result {
+                    Ok(()) => replayed = replayed.saturating_add(batch.lines.len()),
+                    Err(PostError::Rejected(e)) => {
+                        error!(
+                            "Assembler rejected {} spooled events: {e}",
+                            batch.lines.len()
+                        );
+                        self.count_dropped(batch.lines.len());
+                    }
+                    Err(PostError::Transient(e)) => {
+                        debug!("Assembler still unreachable, keeping spool: {e}");
+                        self.defer_replay(None);
+                        break;
+                    }
+                    Err(PostError::Throttled(e, wait)) => {
+                        debug!("Assembler is throttling, keeping spool: {e}");
+                        self.defer_replay(Some(wait));
+                        break;
+                    }
+                    Err(PostError::Unauthorized(e)) => {
+                        debug!("Assembler still refuses the credential, keeping spool: {e}");
+                        self.defer_replay(None);
+                        break;
+                    }
+                }
+            }
+            self.replay_failures = 0;
+            self.replay_after = None;
+            if let Some(spool) = self.spool.as_mut() {
+                spool.consume(&batch);
+            }
+        }
+
+        let Some(spool) = self.spool.as_mut() else {
+            return;
+        };
+        if let Err(e) = spool.compact() {
+            error!("Failed to rewrite spool after replay: {e}");
+        }
+        gauge!(SPOOL_BYTES).set(spool.pending_bytes() as f64);
+        if replayed > 0 {
+            info!(
+                "Replayed {replayed} spooled events, {} bytes still pending",
+                spool.pending_bytes()
+            );
+        }
+    }
+
+    /// Holds off the next replay for the retry backoff, which grows with every failure, or
+    /// for `at_least` if the assembler asked for longer.
+    fn defer_replay(&mut self, at_least: Option<Duration>) {
+        let delay = self
+            .retry
+            .backoff(self.replay_failures)
+            .max(at_least.unwrap_or_default());
+        self.replay_failures = self.replay_failures.saturating_add(1);
+        self.replay_after = Instant::now().checked_add(delay);
+    }
+
+    fn spool_lines(&mut self, lines: &[String]) {
+        let Some(spool) = self.spool.as_mut() else {
+            self.count_dropped(lines.len());
+            return;
+        };
+        let appended = spool.append(lines);
+        gauge!(SPOOL_BYTES).set(spool.pending_bytes() as f64);
+        match appended {
+            Ok(written) => {
+                counter!(SPOOLED_EVENTS).increment(written as u64);
+                let overflow = lines.len().saturating_sub(written);
+                if overflow > 0 {
+                    warn!("Spool is full, dropping {overflow} events");
+                    self.count_dropped(overflow);
+                }
+            }
+            Err(e) => {
+                error!("Failed to write {} events to spool: {e}", lines.len());
+                self.count_dropped(lines.len());
+            }
+        }
+    }
+
+    fn report(&self, result: &Result<(), PostError>) {
+        self.health.set_assembler(match result {
+            Ok(()) => AssemblerState::Reachable,
+            Err(PostError::Transient(_)) => AssemblerState::Unreachable,
+            // Busy rather than down: it answers, and takes batches again shortly.
+            Err(PostError::Throttled(..)) => AssemblerState::Reachable,
+            Err(PostError::Rejected(_) | PostError::Unauthorized(_)) => AssemblerState::Rejecting,
+        });
+    }
+
+    fn count_dropped(&mut self, count: usize) {
+        counter!(DROPPED_EVENTS).increment(count as u64);
+        self.dropped = self.dropped.saturating_add(count as u64);
+        warn!("Dropped {count} events ({} in total)", self.dropped);
+    }
+
+    async fn post_with_retry(&self, lines: &[String]) -> Result<(), PostError> {
+        let mut attempt = 0u32;
+        loop {
+            let result = post(
+                self.client.get(),
+                &self.url,
+                self.credential.as_ref(),
+                lines,
+            )
+            .await;
+            let (e, asked) = match &result {
+                Err(PostError::Transient(e)) => (e, Duration::ZERO),
+                Err(PostError::Throttled(e, wait)) => (e, *wait),
+                _ => return result,
+            };
+            if attempt.saturating_add(1) >= self.retry.max_attempts {
+                return result;
+            }
+            let delay = self.retry.backoff(attempt).max(asked);
+            warn!(
+                "Failed to send batch of {} events (attempt {}), retrying in {delay:?}: {e}",
+                lines.len(),
+                attempt.saturating_add(1)
+            );
+            time::sleep(delay).await;
+            attempt = attempt.saturating_add(1);
+        }
+    }
+}
+
+fn serialize(events: &[IngestEvent]) -> Vec<String> {
+    events
+        .iter()
+        .filter_map(|event| match serde_json::to_string(event) {
+            Ok(line) => Some(line),
+            Err(e) => {
+                error!("Failed to serialize {} event: {e}", event.kind());
+                None
+            }
+        })
+        .collect()
+}
+
+async fn post(
+    client: &reqwest::Client,
+    url: &str,
+    credential: Option<&Credential>,
+    lines: &[String],
+) -> Result<(), PostError> {
+    let mut body = lines.join("\n");
+    body.push('\n');
+
+    debug!("Flushing batch of {} events to {url}", lines.len());
+    let started = Instant::now();
+    let result = send_batch(client, url, credential, body).await;
+    histogram!(SEND_DURATION).record(started.elapsed().as_secs_f64());
+
+    match &result {
+        Ok(body) => {
+            counter!(SENT_EVENTS).increment(lines.len() as u64);
+            info!("Sent batch of {} events: {body}", lines.len());
+        }
+        Err(PostError::Transient(_)) => {
+            counter!(SEND_FAILURES, "reason" => "transient").increment(1);
+        }
+        Err(PostError::Rejected(_)) => {
+            counter!(SEND_FAILURES, "reason" => "rejected").increment(1);
+        }
+        Err(PostError::Unauthorized(_)) => {
+            counter!(SEND_FAILURES, "reason" => "unauthorized").increment(1);
+        }
+        Err(PostError::Throttled(..)) => {
+            counter!(SEND_FAILURES, "reason" => "throttled").increment(1);
+        }
+    }
+    result.map(drop)
+}
+
+async fn send_batch(
+    client: &reqwest::Client,
+    url: &str,
+    credential: Option<&Credential>,
+    body: String,
+) -> Result<String, PostError> {
+    let mut request = client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/x-ndjson");
+    if let Some(credential) = credential {
+        request = credential.apply(request, body.as_bytes());
+    }
+    let response = request
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| PostError::Transient(e.to_string()))?;
+
+    let status = response.status();
+    // Only the delta-seconds form; the assembler never sends a date.
+    let retry_after = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs);
+    let body = response.text().await.unwrap_or_default();
+    if status.is_success() {
+        Ok(body)
+    } else if status == reqwest::StatusCode::UNAUTHORIZED
+        || status == reqwest::StatusCode::FORBIDDEN
+    {
+        Err(PostError::Unauthorized(format!(
+            "status: {status} body: {body}"
+        )))
+    } else if let (reqwest::StatusCode::TOO_MANY_REQUESTS, Some(retry_after)) =
+        (status, retry_after)
+    {
+        Err(PostError::Throttled(
+            format!("status: {status} body: {body}"),
+            retry_after,
+        ))
+    } else if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        Err(PostError::Transient(format!(
+            "status: {status} body: {body}"
+        )))
+    } else {
+        Err(PostError::Rejected(format!(
+            "status: {status} body: {body}"
+        )))
+    }
+}
@@ -0,0 +1,339 @@
+use pcap_file::pcap::{PcapHeader, PcapPacket, PcapReader, PcapWriter};
+use pcap_file::pcapng::{Block, PcapNgReader};
+use pcap_file::{DataLink, PcapError, TsResolution};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{PoisonError, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Extensions of the capture files picked up from a directory, compared case-insensitively.
+const CAPTURE_EXTENSIONS: [&str; 3] = ["pcap", "pcapng", "cap"];
+
+/// Block type of the section header every pcapng file starts with.
+const PCAPNG_MAGIC: [u8; 4] = [0x0a, 0x0d, 0x0d, 0x0a];
+
+/// Where a collector reads packets from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CaptureSource {
+    /// Live capture on a network interface. Runs until shutdown.
+    Interface(String),
+    /// Offline replay of capture files, in order. Finishes after the last file.
+    Pcap(Vec<PathBuf>),
+}
+
+#[derive(Debug)]
+pub enum CaptureError {
+    InvalidPattern(String),
+    ReadDir(PathBuf, std::io::Error),
+    NoFiles(String),
+    Replay(PathBuf, PcapError),
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureError::InvalidPattern(e) => write!(f, "invalid pcap pattern: {e}"),
+            CaptureError::ReadDir(path, e) => {
+                write!(f, "failed to read pcap directory {}: {e}", path.display())
+            }
+            CaptureError::NoFiles(input) => write!(f, "no pcap files found for {input}"),
+            CaptureError::Replay(path, e) => {
+                write!(f, "failed to read capture file {}: {e}", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for CaptureError {}
+
+impl CaptureSource {
+    /// Resolves `--pcap` into a list of capture files, falling back to live capture on
+    /// `interface` when no pcap input was given.
+    ///
+    /// `pcap` may be a single file, a directory (every `*.pcap`, `*.pcapng` and `*.cap` file
+    /// in it, sorted by name) or a glob pattern such as `captures/2024-*.pcap`.
+    pub fn resolve(interface: String, pcap: Option<&str>) -> Result<Self, CaptureError> {
+        match pcap {
+            Some(input) => resolve_pcap_files(input).map(CaptureSource::Pcap),
+            None => Ok(CaptureSource::Interface(interface)),
+        }
+    }
+}
+
+impl fmt::Display for CaptureSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureSource::Interface(name) => write!(f, "interface {name}"),
+            CaptureSource::Pcap(files) => write!(f, "{} pcap file(s)", files.len()),
+        }
+    }
+}
+
+fn resolve_pcap_files(input: &str) -> Result<Vec<PathBuf>, CaptureError> {
+    let path = Path::new(input);
+    let mut files = if path.is_dir() {
+        fs::read_dir(path)
+            .map_err(|e| CaptureError::ReadDir(path.to_path_buf(), e))?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|p| p.is_file() && is_capture_file(p))
+            .collect::<Vec<_>>()
+    } else if path.is_file() {
+        vec![path.to_path_buf()]
+    } else {
+        glob::glob(input)
+            .map_err(|e| CaptureError::InvalidPattern(e.to_string()))?
+            .filter_map(Result::ok)
+            .filter(|p| p.is_file())
+            .collect::<Vec<_>>()
+    };
+
+    if files.is_empty() {
+        return Err(CaptureError::NoFiles(input.to_string()));
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn is_capture_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            CAPTURE_EXTENSIONS
+                .iter()
+                .any(|known| ext.eq_ignore_ascii_case(known))
+        })
+}
+
+/// When the flows of replayed capture files were captured, so that their events carry the
+/// time of the traffic rather than the time of the replay. Live capture leaves it empty.
+#[derive(Default)]
+pub struct ReplayClock {
+    flows: RwLock<HashMap<(SocketAddr, SocketAddr), u64>>,
+}
+
+impl ReplayClock {
+    /// Reads `path`, recording when each TCP flow in it was first seen, and returns the file
+    /// to hand to the analyzer: `path` itself, or a pcap copy of a pcapng file, which the
+    /// analyzers cannot read.
+    pub fn open(&self, path: &Path) -> Result<ReplayFile, CaptureError> {
+        let replay_error = |e| CaptureError::Replay(path.to_path_buf(), e);
+        let mut reader = File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| replay_error(PcapError::IoError(e)))?;
+        let is_pcapng = reader
+            .fill_buf()
+            .map_err(|e| replay_error(PcapError::IoError(e)))?
+            .starts_with(&PCAPNG_MAGIC);
+
+        let mut flows = HashMap::new();
+        let file = if is_pcapng {
+            convert_pcapng(reader, &mut flows).map_err(replay_error)?
+        } else {
+            let mut pcap = PcapReader::new(reader).map_err(replay_error)?;
+            let datalink = pcap.header().datalink;
+            while let Some(packet) = pcap.next_packet() {
+                let packet = packet.map_err(replay_error)?;
+                record(&mut flows, datalink, &packet.data, packet.timestamp);
+            }
+            ReplayFile {
+                path: path.to_path_buf(),
+                temporary: false,
+            }
+        };
+
+        self.flows
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .extend(flows);
+        Ok(file)
+    }
+
+    /// Seconds since the epoch at which the flow between `source` and `destination` was
+    /// captured, in either direction, or the current time for traffic not replayed.
+    pub fn timestamp(&self, source: SocketAddr, destination: SocketAddr) -> u64 {
+        let flows = self.flows.read().unwrap_or_else(PoisonError::into_inner);
+        flows
+            .get(&(source, destination))
+            .or_else(|| flows.get(&(destination, source)))
+            .copied()
+            .unwrap_or_else(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs()
+            })
+    }
+}
+
+/// A capture file ready for the analyzer. Temporary copies are removed when dropped.
+pub struct ReplayFile {
+    path: PathBuf,
+    temporary: bool,
+}
+
+impl ReplayFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ReplayFile {
+    fn drop(&mut self) {
+        if !self.temporary {
+            return;
+        }
+        match fs::remove_file(&self.path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Failed to remove {}: {e}", self.path.display()),
+        }
+    }
+}
+
+/// Copies the packets of a pcapng file into a temporary pcap file, recording their flows.
+/// The copy takes the link type of the first interface; the analyzers detect the link
+/// layer of each packet anyway.
+fn convert_pcapng(
+    reader: BufReader<File>,
+    flows: &mut HashMap<(SocketAddr, SocketAddr), u64>,
+) -> Result<ReplayFile, PcapError> {
+    static COPIES: AtomicU64 = AtomicU64::new(0);
+    let copy = ReplayFile {
+        path: std::env::temp_dir().join(format!(
+            "huginn-replay-{}-{}.pcap",
+            process::id(),
+            COPIES.fetch_add(1, Ordering::Relaxed)
+        )),
+        temporary: true,
+    };
+
+    let mut pcapng = PcapNgReader::new(reader)?;
+    let mut interfaces: Vec<DataLink> = Vec::new();
+    let mut writer: Option<PcapWriter<BufWriter<File>>> = None;
+    let mut last_timestamp = Duration::ZERO;
+    while let Some(block) = pcapng.next_block() {
+        let (datalink, timestamp, orig_len, data) = match block? {
+            Block::SectionHeader(_) => {
+                interfaces.clear();
+                continue;
+            }
+            Block::InterfaceDescription(interface) => {
+                interfaces.push(interface.linktype);
+                continue;
+            }
+            Block::EnhancedPacket(packet) => {
+                let datalink = usize::try_from(packet.interface_id)
+                    .ok()
+                    .and_then(|id| interfaces.get(id).copied());
+                (datalink, packet.timestamp, packet.original_len, packet.data)
+            }
+            // Simple packets carry no timestamp; they belong with the packet before them.
+            Block::SimplePacket(packet) => (
+                interfaces.first().copied(),
+                last_timestamp,
+                packet.original_len,
+                packet.data,
+            ),
+            _ => continue,
+        };
+        let Some(datalink) = datalink else {
+            continue;
+        };
+        last_timestamp = timestamp;
+        record(flows, datalink, &data, timestamp);
+
+        let writer = match &mut writer {
+            Some(writer) => writer,
+            None => writer.insert(PcapWriter::with_header(
+                BufWriter::new(File::create(&copy.path).map_err(PcapError::IoError)?),
+                PcapHeader {
+                    datalink,
+                    snaplen: u32::MAX,
+                    ts_resolution: TsResolution::NanoSecond,
+                    ..PcapHeader::default()
+                },
+            )?),
+        };
+        let orig_len = orig_len.max(u32::try_from(data.len()).unwrap_or(u32::MAX));
+        writer.write_packet(&PcapPacket::new(timestamp, orig_len, &data))?;
+    }
+
+    match writer {
+        Some(mut writer) => writer.flush()?,
+        None => {
+            return Err(PcapError::IoError(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no packets",
+            )))
+        }
+    }
+    Ok(copy)
+}
+
+fn record(
+    flows: &mut HashMap<(SocketAddr, SocketAddr), u64>,
+    datalink: DataLink,
+    frame: &[u8],
+    timestamp: Duration,
+) {
+    if let Some(endpoints) = tcp_endpoints(datalink, frame) {
+        flows.entry(endpoints).or_insert(timestamp.as_secs());
+    }
+}
+
+/// The source and destination of a TCP segment, for the link layers capture tools write.
+fn tcp_endpoints(datalink: DataLink, frame: &[u8]) -> Option<(SocketAddr, SocketAddr)> {
+    let packet = match datalink {
+        DataLink::ETHERNET => {
+            let mut rest = frame.get(12..)?;
+            // 802.1Q and 802.1ad tags come before the EtherType.
+            while let [0x81, 0x00, _, _, tail @ ..] | [0x88, 0xa8, _, _, tail @ ..] = rest {
+                rest = tail;
+            }
+            rest.get(2..)?
+        }
+        DataLink::LINUX_SLL => frame.get(16..)?,
+        DataLink::LINUX_SLL2 => frame.get(20..)?,
+        DataLink::NULL | DataLink::LOOP => frame.get(4..)?,
+        _ => frame,
+    };
+
+    const TCP: u8 = 6;
+    let (source, destination, segment): (IpAddr, IpAddr, &[u8]) = match packet.first()? >> 4 {
+        4 => {
+            if *packet.get(9)? != TCP {
+                return None;
+            }
+            let header_len = usize::from(packet.first()? & 0x0f).saturating_mul(4);
+            let source: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+            let destination: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+            (source.into(), destination.into(), packet.get(header_len..)?)
+        }
+        6 => {
+            if *packet.get(6)? != TCP {
+                return None;
+            }
+            let source: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+            let destination: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+            (source.into(), destination.into(), packet.get(40..)?)
+        }
+        _ => return None,
+    };
+    let &[source_hi, source_lo, destination_hi, destination_lo] = segment.get(..4)? else {
+        return None;
+    };
+    Some((
+        SocketAddr::new(source, u16::from_be_bytes([source_hi, source_lo])),
+        SocketAddr::new(
+            destination,
+            u16::from_be_bytes([destination_hi, destination_lo]),
+        ),
+    ))
+}
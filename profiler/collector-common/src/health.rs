@@ -0,0 +1,255 @@
+use clap::Args;
+use metrics_exporter_prometheus::PrometheusHandle;
+use serde_json::json;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{error, info};
+
+use crate::capture::CaptureSource;
+
+/// Health options shared by every collector's command line.
+#[derive(Args, Debug, Clone)]
+#[command(about = None, long_about = None)]
+pub struct HealthArgs {
+    /// Report the collector as not live once no packet has been analyzed for this many
+    /// seconds. Off by default, since a quiet network is not a fault.
+    #[clap(long, value_parser)]
+    pub health_max_idle_secs: Option<u64>,
+}
+
+/// Lifecycle of the huginn-net analysis thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AnalyzerState {
+    Starting = 0,
+    Running = 1,
+    /// Analysis returned without an error. Expected for pcap input, a fault for live capture.
+    Stopped = 2,
+    Failed = 3,
+}
+
+impl AnalyzerState {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => AnalyzerState::Starting,
+            1 => AnalyzerState::Running,
+            2 => AnalyzerState::Stopped,
+            _ => AnalyzerState::Failed,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            AnalyzerState::Starting => "starting",
+            AnalyzerState::Running => "running",
+            AnalyzerState::Stopped => "stopped",
+            AnalyzerState::Failed => "failed",
+        }
+    }
+}
+
+/// Outcome of the last batch posted to the assembler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AssemblerState {
+    /// Nothing has been sent yet.
+    Unknown = 0,
+    Reachable = 1,
+    /// Retries were exhausted without an answer, or the assembler kept failing.
+    Unreachable = 2,
+    /// The assembler answered but refused the batch.
+    Rejecting = 3,
+}
+
+impl AssemblerState {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => AssemblerState::Reachable,
+            2 => AssemblerState::Unreachable,
+            3 => AssemblerState::Rejecting,
+            _ => AssemblerState::Unknown,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            AssemblerState::Unknown => "unknown",
+            AssemblerState::Reachable => "reachable",
+            AssemblerState::Unreachable => "unreachable",
+            AssemblerState::Rejecting => "rejecting",
+        }
+    }
+}
+
+/// How long a health client may take to send its request or to read the response.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Sentinel for "no packet analyzed yet" in [`Health::last_packet_ms`].
+const NEVER: u64 = u64::MAX;
+
+/// State shared between the analysis thread, the sender and the health endpoint.
+///
+/// A collector is live while its analyzer can still produce results, and ready when it is
+/// live, capturing, and the assembler takes what it sends.
+pub struct Health {
+    started: Instant,
+    continuous: bool,
+    max_idle: Option<Duration>,
+    analyzer: AtomicU8,
+    assembler: AtomicU8,
+    /// Milliseconds after `started` at which the last analysis result arrived.
+    last_packet_ms: AtomicU64,
+}
+
+impl Health {
+    pub fn new(source: &CaptureSource, args: &HealthArgs) -> Arc<Self> {
+        Arc::new(Health {
+            started: Instant::now(),
+            continuous: matches!(source, CaptureSource::Interface(_)),
+            max_idle: args.health_max_idle_secs.map(Duration::from_secs),
+            analyzer: AtomicU8::new(AnalyzerState::Starting as u8),
+            assembler: AtomicU8::new(AssemblerState::Unknown as u8),
+            last_packet_ms: AtomicU64::new(NEVER),
+        })
+    }
+
+    pub fn set_analyzer(&self, state: AnalyzerState) {
+        self.analyzer.store(state as u8, Ordering::Relaxed);
+    }
+
+    pub fn analyzer(&self) -> AnalyzerState {
+        AnalyzerState::from_u8(self.analyzer.load(Ordering::Relaxed))
+    }
+
+    pub fn set_assembler(&self, state: AssemblerState) {
+        self.assembler.store(state as u8, Ordering::Relaxed);
+    }
+
+    pub fn assembler(&self) -> AssemblerState {
+        AssemblerState::from_u8(self.assembler.load(Ordering::Relaxed))
+    }
+
+    /// Records that the analyzer produced a result, i.e. packets are still flowing.
+    pub fn record_packet(&self) {
+        let elapsed = u64::try_from(self.started.elapsed().as_millis()).unwrap_or(NEVER);
+        self.last_packet_ms.store(elapsed, Ordering::Relaxed);
+    }
+
+    /// Time since the last analysis result, or `None` if there has not been one yet.
+    pub fn since_last_packet(&self) -> Option<Duration> {
+        match self.last_packet_ms.load(Ordering::Relaxed) {
+            NEVER => None,
+            ms => Some(
+                self.started
+                    .elapsed()
+                    .saturating_sub(Duration::from_millis(ms)),
+            ),
+        }
+    }
+
+    pub fn is_live(&self) -> bool {
+        let analyzer_ok = match self.analyzer() {
+            AnalyzerState::Starting | AnalyzerState::Running => true,
+            AnalyzerState::Stopped => !self.continuous,
+            AnalyzerState::Failed => false,
+        };
+        let idle = self
+            .since_last_packet()
+            .unwrap_or_else(|| self.started.elapsed());
+        analyzer_ok && self.max_idle.is_none_or(|max_idle| idle <= max_idle)
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.is_live()
+            && self.analyzer() != AnalyzerState::Starting
+            && matches!(
+                self.assembler(),
+                AssemblerState::Unknown | AssemblerState::Reachable
+            )
+    }
+
+    fn report(&self) -> String {
+        json!({
+            "live": self.is_live(),
+            "ready": self.is_ready(),
+            "analyzer": self.analyzer().as_str(),
+            "assembler": self.assembler().as_str(),
+            "seconds_since_last_packet": self.since_last_packet().map(|d| d.as_secs()),
+        })
+        .to_string()
+    }
+}
+
+/// Serves health checks and Prometheus metrics on a background thread.
+///
+/// - `GET /live` (and `/health`, for existing checks): 200 while the analyzer can still
+///   produce results, 503 otherwise.
+/// - `GET /ready`: 200 once the collector is capturing and the assembler accepts batches.
+/// - `GET /metrics`: the text exposition rendered by `metrics`.
+///
+/// Each connection is served on its own thread with I/O timeouts, so a client that connects
+/// and sends nothing cannot hold up the probes.
+pub fn spawn(addr: SocketAddr, health: Arc<Health>, metrics: PrometheusHandle) {
+    thread::spawn(move || {
+        let listener = match TcpListener::bind(addr) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Failed to bind health endpoint on {addr}: {e}");
+                return;
+            }
+        };
+        info!("Serving health and metrics on {addr}");
+        for stream in listener.incoming().flatten() {
+            let health = health.clone();
+            let metrics = metrics.clone();
+            thread::spawn(move || handle_request(stream, &health, &metrics));
+        }
+    });
+}
+
+fn handle_request(mut stream: TcpStream, health: &Health, metrics: &PrometheusHandle) {
+    if let Err(e) = stream
+        .set_read_timeout(Some(CLIENT_TIMEOUT))
+        .and_then(|()| stream.set_write_timeout(Some(CLIENT_TIMEOUT)))
+    {
+        error!("Failed to set health connection timeouts: {e}");
+        return;
+    }
+    let mut request_line = String::new();
+    if let Ok(reader) = stream.try_clone() {
+        let _ = BufReader::new(reader).read_line(&mut request_line);
+    }
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+
+    let (status, content_type, body) = match path {
+        "/metrics" => ("200 OK", "text/plain; version=0.0.4", metrics.render()),
+        "/live" | "/health" => (
+            status_line(health.is_live()),
+            "application/json",
+            health.report(),
+        ),
+        "/ready" => (
+            status_line(health.is_ready()),
+            "application/json",
+            health.report(),
+        ),
+        _ => ("404 Not Found", "text/plain", "Not Found".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let _ = stream.write_all(response.as_bytes());
+}
+
+fn status_line(ok: bool) -> &'static str {
+    if ok {
+        "200 OK"
+    } else {
+        "503 Service Unavailable"
+    }
+}
@@ -0,0 +1,11 @@
+//! Plumbing shared by the tcp, http and tls collectors.
+
+pub mod auth;
+pub mod batch;
+pub mod capture;
+pub mod health;
+pub mod metrics;
+pub mod queue;
+pub mod retry;
+pub mod spool;
+pub mod tls;
@@ -0,0 +1,64 @@
+use metrics::{describe_counter, describe_gauge, describe_histogram, Unit};
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder, PrometheusHandle};
+
+pub const ANALYSIS_RESULTS: &str = "collector_analysis_results_total";
+pub const FINGERPRINTS: &str = "collector_fingerprints_total";
+pub const QUEUE_DEPTH: &str = "collector_queue_depth";
+pub const QUEUE_DROPPED: &str = "collector_queue_dropped_total";
+pub const SEND_DURATION: &str = "collector_send_duration_seconds";
+pub const SEND_FAILURES: &str = "collector_send_failures_total";
+pub const SENT_EVENTS: &str = "collector_sent_events_total";
+pub const DROPPED_EVENTS: &str = "collector_dropped_events_total";
+pub const SPOOLED_EVENTS: &str = "collector_spooled_events_total";
+pub const SPOOL_BYTES: &str = "collector_spool_bytes";
+
+const SEND_DURATION_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Installs the global Prometheus recorder, labelling every series with the collector name.
+///
+/// The returned handle renders the text exposition served on `/metrics`.
+pub fn install(collector: &str) -> Result<PrometheusHandle, BuildError> {
+    let handle = PrometheusBuilder::new()
+        .add_global_label("collector", collector)
+        .set_buckets_for_metric(
+            Matcher::Full(SEND_DURATION.to_string()),
+            SEND_DURATION_BUCKETS,
+        )?
+        .install_recorder()?;
+
+    describe_counter!(
+        ANALYSIS_RESULTS,
+        "Analysis results produced by huginn-net from captured packets"
+    );
+    describe_counter!(FINGERPRINTS, "Fingerprints handed to the sender, by kind");
+    describe_gauge!(QUEUE_DEPTH, "Analysis results waiting to be sent");
+    describe_counter!(
+        QUEUE_DROPPED,
+        "Analysis results discarded by the queue policy"
+    );
+    describe_histogram!(
+        SEND_DURATION,
+        Unit::Seconds,
+        "Latency of batch requests to the assembler"
+    );
+    describe_counter!(
+        SEND_FAILURES,
+        "Failed batch requests to the assembler, by reason"
+    );
+    describe_counter!(SENT_EVENTS, "Events accepted by the assembler");
+    describe_counter!(
+        DROPPED_EVENTS,
+        "Events lost after retries, spool overflow or rejection"
+    );
+    describe_counter!(
+        SPOOLED_EVENTS,
+        "Events written to the spool while the assembler was unreachable"
+    );
+    describe_gauge!(
+        SPOOL_BYTES,
+        Unit::Bytes,
+        "Size of the spooled events waiting for replay"
+    );
+
+    Ok(handle)
+}
@@ -0,0 +1,226 @@
+use clap::{Args, ValueEnum};
+use metrics::{counter, gauge};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use tokio::sync::Notify;
+use tracing::warn;
+
+use crate::capture::CaptureSource;
+use crate::metrics::{ANALYSIS_RESULTS, QUEUE_DEPTH, QUEUE_DROPPED};
+
+/// How often a drop is logged, to keep a saturated collector from flooding its own logs.
+const DROP_LOG_EVERY: u64 = 1000;
+
+/// What the capture side does when the queue towards the sender is full.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Wait for room. Lossless, but a slow assembler eventually stalls capture.
+    Block,
+    /// Evict the oldest queued result to make room for the new one.
+    DropOldest,
+    /// Discard the new result.
+    DropNewest,
+    /// Once the queue is half full, admit only one result in `--queue-sample-rate`;
+    /// discard the new result when it is completely full.
+    Sample,
+}
+
+impl fmt::Display for QueuePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            QueuePolicy::Block => "block",
+            QueuePolicy::DropOldest => "drop-oldest",
+            QueuePolicy::DropNewest => "drop-newest",
+            QueuePolicy::Sample => "sample",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Queue options shared by every collector's command line.
+#[derive(Args, Debug, Clone)]
+#[command(about = None, long_about = None)]
+pub struct QueueArgs {
+    /// Analysis results buffered between capture and the sender.
+    #[clap(long, value_parser, default_value_t = 1000)]
+    pub queue_capacity: usize,
+    /// Policy applied when the queue is full. Defaults to `drop-oldest` for live capture
+    /// and `block` for pcap input, where nothing is lost by waiting.
+    #[clap(long, value_enum)]
+    pub queue_policy: Option<QueuePolicy>,
+    /// With `--queue-policy sample`, keep one result out of this many under pressure.
+    #[clap(long, value_parser, default_value_t = 10)]
+    pub queue_sample_rate: u64,
+}
+
+impl QueueArgs {
+    pub fn policy_for(&self, source: &CaptureSource) -> QueuePolicy {
+        self.queue_policy.unwrap_or(match source {
+            CaptureSource::Interface(_) => QueuePolicy::DropOldest,
+            CaptureSource::Pcap(_) => QueuePolicy::Block,
+        })
+    }
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    producer_closed: bool,
+    consumer_closed: bool,
+    sampled: u64,
+    dropped: u64,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    not_full: Condvar,
+    not_empty: Notify,
+    capacity: usize,
+    policy: QueuePolicy,
+    sample_rate: u64,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn record_drop(&self, state: &mut State<T>) {
+        counter!(QUEUE_DROPPED).increment(1);
+        state.dropped = state.dropped.saturating_add(1);
+        if state.dropped % DROP_LOG_EVERY == 1 {
+            warn!(
+                "Queue full, {} results dropped so far (policy: {})",
+                state.dropped, self.policy
+            );
+        }
+    }
+}
+
+/// Returned by [`QueueProducer::push`] once the consumer is gone.
+#[derive(Debug)]
+pub struct Closed;
+
+/// Creates a bounded queue applying `policy` when full.
+///
+/// The producer side is synchronous, for the thread draining huginn-net's channel; the
+/// consumer side is awaited from the tokio runtime that posts to the assembler.
+pub fn bounded<T>(
+    capacity: usize,
+    policy: QueuePolicy,
+    sample_rate: u64,
+) -> (QueueProducer<T>, QueueConsumer<T>) {
+    let capacity = capacity.max(1);
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            items: VecDeque::with_capacity(capacity),
+            producer_closed: false,
+            consumer_closed: false,
+            sampled: 0,
+            dropped: 0,
+        }),
+        not_full: Condvar::new(),
+        not_empty: Notify::new(),
+        capacity,
+        policy,
+        sample_rate: sample_rate.max(1),
+    });
+    (
+        QueueProducer {
+            shared: shared.clone(),
+        },
+        QueueConsumer { shared },
+    )
+}
+
+pub struct QueueProducer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> QueueProducer<T> {
+    /// Enqueues `item` according to the queue policy. Only [`QueuePolicy::Block`] waits.
+    pub fn push(&self, item: T) -> Result<(), Closed> {
+        counter!(ANALYSIS_RESULTS).increment(1);
+        let shared = &self.shared;
+        let mut state = shared.lock();
+        if state.consumer_closed {
+            return Err(Closed);
+        }
+
+        if shared.policy == QueuePolicy::Sample && state.items.len() >= shared.capacity.div_ceil(2)
+        {
+            state.sampled = state.sampled.wrapping_add(1);
+            if state.sampled.checked_rem(shared.sample_rate).unwrap_or(0) != 0 {
+                shared.record_drop(&mut state);
+                return Ok(());
+            }
+        }
+
+        while state.items.len() >= shared.capacity {
+            match shared.policy {
+                QueuePolicy::Block => {
+                    state = shared
+                        .not_full
+                        .wait(state)
+                        .unwrap_or_else(PoisonError::into_inner);
+                    if state.consumer_closed {
+                        return Err(Closed);
+                    }
+                }
+                QueuePolicy::DropOldest => {
+                    state.items.pop_front();
+                    shared.record_drop(&mut state);
+                }
+                QueuePolicy::DropNewest | QueuePolicy::Sample => {
+                    shared.record_drop(&mut state);
+                    return Ok(());
+                }
+            }
+        }
+
+        state.items.push_back(item);
+        gauge!(QUEUE_DEPTH).set(state.items.len() as f64);
+        drop(state);
+        shared.not_empty.notify_one();
+        Ok(())
+    }
+}
+
+impl<T> Drop for QueueProducer<T> {
+    fn drop(&mut self) {
+        self.shared.lock().producer_closed = true;
+        self.shared.not_empty.notify_one();
+    }
+}
+
+pub struct QueueConsumer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> QueueConsumer<T> {
+    /// Waits for the next item. Returns `None` once the producer is gone and the queue drained.
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            {
+                let mut state = self.shared.lock();
+                if let Some(item) = state.items.pop_front() {
+                    gauge!(QUEUE_DEPTH).set(state.items.len() as f64);
+                    drop(state);
+                    self.shared.not_full.notify_one();
+                    return Some(item);
+                }
+                if state.producer_closed {
+                    return None;
+                }
+            }
+            self.shared.not_empty.notified().await;
+        }
+    }
+}
+
+impl<T> Drop for QueueConsumer<T> {
+    fn drop(&mut self) {
+        self.shared.lock().consumer_closed = true;
+        self.shared.not_full.notify_all();
+    }
+}
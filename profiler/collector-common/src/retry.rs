@@ -0,0 +1,54 @@
+use clap::Args;
+use rand::Rng;
+use std::time::Duration;
+
+/// Retry options shared by every collector's command line.
+#[derive(Args, Debug, Clone)]
+#[command(about = None, long_about = None)]
+pub struct RetryArgs {
+    /// Attempts per batch before it is spooled to disk (or dropped without a spool).
+    #[clap(long, value_parser, default_value_t = 5)]
+    pub retry_max_attempts: u32,
+    /// Delay before the first retry, in milliseconds. Doubles on every further attempt.
+    #[clap(long, value_parser, default_value_t = 200)]
+    pub retry_initial_backoff_ms: u64,
+    /// Upper bound for the delay between two attempts, in milliseconds.
+    #[clap(long, value_parser, default_value_t = 10_000)]
+    pub retry_max_backoff_ms: u64,
+}
+
+/// Bounded exponential backoff with jitter.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl From<&RetryArgs> for RetryPolicy {
+    fn from(args: &RetryArgs) -> Self {
+        RetryPolicy {
+            max_attempts: args.retry_max_attempts.max(1),
+            initial_backoff: Duration::from_millis(args.retry_initial_backoff_ms),
+            max_backoff: Duration::from_millis(args.retry_max_backoff_ms),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay to wait after the given failed attempt (0-based).
+    ///
+    /// The nominal delay doubles per attempt up to `max_backoff`; the actual delay is drawn
+    /// uniformly from the upper half of it so that collectors restarted together do not retry
+    /// in lockstep.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt).unwrap_or(u32::MAX);
+        let nominal = self
+            .initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff);
+        let nominal_ms = u64::try_from(nominal.as_millis()).unwrap_or(u64::MAX);
+        let floor_ms = nominal_ms / 2;
+        Duration::from_millis(rand::rng().random_range(floor_ms..=nominal_ms))
+    }
+}
@@ -0,0 +1,141 @@
+use clap::Args;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Spool options shared by every collector's command line.
+#[derive(Args, Debug, Clone)]
+#[command(about = None, long_about = None)]
+pub struct SpoolArgs {
+    /// Append-only file that keeps batches the assembler could not take, for replay once it
+    /// is reachable again. Without it, such batches are dropped.
+    #[clap(long, value_parser)]
+    pub spool_path: Option<PathBuf>,
+    /// Size cap of the spool file in bytes. Events that do not fit are dropped.
+    #[clap(long, value_parser, default_value_t = 64 * 1024 * 1024)]
+    pub spool_max_bytes: u64,
+}
+
+/// Append-only NDJSON file of undelivered events, replayed oldest first.
+///
+/// Lines are stored exactly as they are posted to `/api/ingest/batch`, so replaying them
+/// needs no re-serialization and survives collector restarts. Replay reads one batch at a
+/// time from a head offset; the replayed lines are only cut from the file by
+/// [`Spool::compact`], so a crash in between replays them again rather than losing them.
+pub struct Spool {
+    path: PathBuf,
+    max_bytes: u64,
+    len_bytes: u64,
+    /// Offset of the first line not replayed yet.
+    head: u64,
+}
+
+/// Lines read from the head of a [`Spool`], and the bytes they take in the file.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SpoolBatch {
+    pub lines: Vec<String>,
+    pub len_bytes: u64,
+}
+
+impl Spool {
+    pub fn open(path: &Path, max_bytes: u64) -> io::Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let len_bytes = file.metadata()?.len();
+        Ok(Spool {
+            path: path.to_path_buf(),
+            max_bytes,
+            len_bytes,
+            head: 0,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending_bytes() == 0
+    }
+
+    /// Bytes of the lines not replayed yet.
+    pub fn pending_bytes(&self) -> u64 {
+        self.len_bytes.saturating_sub(self.head)
+    }
+
+    /// Appends serialized events in order, stopping at the size cap.
+    ///
+    /// Returns how many lines were written; the caller counts the rest as dropped.
+    pub fn append(&mut self, lines: &[String]) -> io::Result<usize> {
+        let mut file = OpenOptions::new().append(true).open(&self.path)?;
+        let mut written = 0usize;
+        for line in lines {
+            let size = u64::try_from(line.len())
+                .unwrap_or(u64::MAX)
+                .saturating_add(1);
+            if self.len_bytes.saturating_add(size) > self.max_bytes {
+                break;
+            }
+            file.write_all(line.as_bytes())?;
+            file.write_all(b"\n")?;
+            self.len_bytes = self.len_bytes.saturating_add(size);
+            written = written.saturating_add(1);
+        }
+        file.flush()?;
+        Ok(written)
+    }
+
+    /// Reads up to `max_lines` lines from the head, oldest first, without consuming them.
+    pub fn peek(&self, max_lines: usize) -> io::Result<SpoolBatch> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.head))?;
+        let mut reader = BufReader::new(file);
+        let mut batch = SpoolBatch::default();
+        let mut line = String::new();
+        while batch.lines.len() < max_lines {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+            batch.len_bytes = batch
+                .len_bytes
+                .saturating_add(u64::try_from(read).unwrap_or(u64::MAX));
+            let trimmed = line.trim_end_matches(['\n', '\r']);
+            if !trimmed.trim().is_empty() {
+                batch.lines.push(trimmed.to_string());
+            }
+        }
+        Ok(batch)
+    }
+
+    /// Marks a batch returned by [`Spool::peek`] as replayed.
+    pub fn consume(&mut self, batch: &SpoolBatch) {
+        self.head = self
+            .head
+            .saturating_add(batch.len_bytes)
+            .min(self.len_bytes);
+    }
+
+    /// Cuts the replayed lines from the file, copying the pending ones to a new file
+    /// without holding them in memory.
+    pub fn compact(&mut self) -> io::Result<()> {
+        if self.head == 0 {
+            return Ok(());
+        }
+        if self.is_empty() {
+            File::create(&self.path)?.sync_all()?;
+        } else {
+            let tmp = self.path.with_extension("tmp");
+            {
+                let mut source = File::open(&self.path)?;
+                source.seek(SeekFrom::Start(self.head))?;
+                let mut file = File::create(&tmp)?;
+                io::copy(&mut source, &mut file)?;
+                file.sync_all()?;
+            }
+            fs::rename(&tmp, &self.path)?;
+        }
+        self.len_bytes = self.pending_bytes();
+        self.head = 0;
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+use clap::Args;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{error, info};
+
+/// TLS options for the connection to the assembler, shared by every collector's command line.
+#[derive(Args, Debug, Clone)]
+#[command(about = None, long_about = None)]
+pub struct TlsArgs {
+    /// PEM bundle of the CAs trusted to sign the assembler's certificate, on top of the
+    /// system roots.
+    #[clap(long, value_parser)]
+    pub tls_ca_file: Option<PathBuf>,
+    /// PEM certificate chain presented to an assembler that requires client certificates.
+    #[clap(long, value_parser, requires = "tls_key_file")]
+    pub tls_cert_file: Option<PathBuf>,
+    /// PEM private key of `--tls-cert-file`.
+    #[clap(long, value_parser, requires = "tls_cert_file")]
+    pub tls_key_file: Option<PathBuf>,
+    /// How often the files above are checked for changes, in seconds.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 30)]
+    pub tls_reload_secs: u64,
+}
+
+impl TlsArgs {
+    fn files(&self) -> impl Iterator<Item = &PathBuf> {
+        [&self.tls_ca_file, &self.tls_cert_file, &self.tls_key_file]
+            .into_iter()
+            .flatten()
+    }
+}
+
+/// The HTTP client posting to the assembler, rebuilt when its certificate files change so
+/// that renewed certificates are picked up without a restart.
+pub struct AssemblerClient {
+    args: TlsArgs,
+    client: reqwest::Client,
+    modified: Vec<Option<SystemTime>>,
+    checked: Instant,
+}
+
+impl AssemblerClient {
+    pub fn open(args: &TlsArgs) -> Result<Self, TlsError> {
+        let modified = args.files().map(|path| modified(path)).collect();
+        let client = build(args)?;
+        if let Some(path) = &args.tls_cert_file {
+            info!("Presenting client certificate {}", path.display());
+        }
+        Ok(AssemblerClient {
+            args: args.clone(),
+            client,
+            modified,
+            checked: Instant::now(),
+        })
+    }
+
+    pub fn get(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    /// Rebuilds the client if a file changed since it was last read, checking at most once
+    /// per `tls_reload_secs`. Files that fail to load leave the current client in place.
+    pub fn reload_if_changed(&mut self) {
+        if self.checked.elapsed() < Duration::from_secs(self.args.tls_reload_secs) {
+            return;
+        }
+        self.checked = Instant::now();
+        let current: Vec<_> = self.args.files().map(|path| modified(path)).collect();
+        if current == self.modified {
+            return;
+        }
+        self.modified = current;
+
+        match build(&self.args) {
+            Ok(client) => {
+                info!("Reloaded TLS certificates for the assembler connection");
+                self.client = client;
+            }
+            Err(e) => error!("Keeping the previous TLS certificates: {e}"),
+        }
+    }
+}
+
+fn build(args: &TlsArgs) -> Result<reqwest::Client, TlsError> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(path) = &args.tls_ca_file {
+        let certificates = reqwest::Certificate::from_pem_bundle(&read(path)?)
+            .map_err(|e| TlsError::Invalid(path.clone(), e))?;
+        if certificates.is_empty() {
+            return Err(TlsError::Empty(path.clone()));
+        }
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    if let (Some(cert), Some(key)) = (&args.tls_cert_file, &args.tls_key_file) {
+        let mut pem = read(cert)?;
+        pem.push(b'\n');
+        pem.extend(read(key)?);
+        let identity =
+            reqwest::Identity::from_pem(&pem).map_err(|e| TlsError::Invalid(cert.clone(), e))?;
+        builder = builder.identity(identity);
+    }
+    builder.build().map_err(TlsError::Client)
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, TlsError> {
+    fs::read(path).map_err(|e| TlsError::Read(path.to_path_buf(), e))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+#[derive(Debug)]
+pub enum TlsError {
+    Read(PathBuf, io::Error),
+    Empty(PathBuf),
+    Invalid(PathBuf, reqwest::Error),
+    Client(reqwest::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::Read(path, e) => write!(f, "cannot read {}: {e}", path.display()),
+            TlsError::Empty(path) => write!(f, "no certificate in {}", path.display()),
+            TlsError::Invalid(path, e) => write!(f, "invalid PEM in {}: {e}", path.display()),
+            TlsError::Client(e) => write!(f, "cannot build HTTP client: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
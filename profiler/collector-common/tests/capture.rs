@@ -0,0 +1,224 @@
+use collector_common::capture::{CaptureError, CaptureSource, ReplayClock};
+use pcap_file::pcap::{PcapPacket, PcapReader, PcapWriter};
+use std::fs::{self, File};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+/// 2024-03-01T12:00:00Z, well before any test runs.
+const CAPTURED_AT: u64 = 1_709_294_400;
+
+/// An empty directory of its own for each test.
+fn scratch(name: &str) -> Result<PathBuf, std::io::Error> {
+    let dir = std::env::temp_dir().join(format!("collector-common-{name}-{}", std::process::id()));
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+fn touch(path: &Path) -> Result<(), std::io::Error> {
+    fs::write(path, b"")
+}
+
+fn client() -> SocketAddr {
+    SocketAddr::from(([192, 168, 1, 10], 51234))
+}
+
+fn server() -> SocketAddr {
+    SocketAddr::from(([10, 0, 0, 1], 443))
+}
+
+/// An Ethernet frame carrying an IPv4 SYN from `client()` to `server()`.
+fn syn_frame() -> Vec<u8> {
+    let mut frame = vec![0; 12];
+    frame.extend([0x08, 0x00]);
+    frame.extend([0x45, 0, 0, 40, 0, 0, 0x40, 0, 64, 6, 0, 0]);
+    frame.extend([192, 168, 1, 10, 10, 0, 0, 1]);
+    frame.extend(51234u16.to_be_bytes());
+    frame.extend(443u16.to_be_bytes());
+    frame.extend([0, 0, 0, 1, 0, 0, 0, 0, 0x50, 0x02, 0xff, 0xff, 0, 0, 0, 0]);
+    frame
+}
+
+fn write_pcap(path: &Path) -> TestResult {
+    let mut writer = PcapWriter::new(File::create(path)?)?;
+    let frame = syn_frame();
+    writer.write_packet(&PcapPacket::new(
+        Duration::from_secs(CAPTURED_AT),
+        u32::try_from(frame.len())?,
+        &frame,
+    ))?;
+    Ok(())
+}
+
+/// A little-endian pcapng file: section header, one Ethernet interface, one enhanced
+/// packet with a microsecond timestamp.
+fn write_pcapng(path: &Path) -> TestResult {
+    fn block(bytes: &mut Vec<u8>, kind: u32, body: &[u8]) -> TestResult {
+        let len = u32::try_from(body.len().saturating_add(12))?;
+        bytes.extend(kind.to_le_bytes());
+        bytes.extend(len.to_le_bytes());
+        bytes.extend(body);
+        bytes.extend(len.to_le_bytes());
+        Ok(())
+    }
+
+    let mut bytes = Vec::new();
+    let mut section = Vec::new();
+    section.extend(0x1a2b_3c4du32.to_le_bytes());
+    section.extend(1u16.to_le_bytes());
+    section.extend(0u16.to_le_bytes());
+    section.extend((-1i64).to_le_bytes());
+    block(&mut bytes, 0x0a0d_0d0a, &section)?;
+
+    let mut interface = Vec::new();
+    interface.extend(1u16.to_le_bytes());
+    interface.extend(0u16.to_le_bytes());
+    interface.extend(0u32.to_le_bytes());
+    block(&mut bytes, 1, &interface)?;
+
+    let frame = syn_frame();
+    let micros = CAPTURED_AT.saturating_mul(1_000_000);
+    let mut packet = Vec::new();
+    packet.extend(0u32.to_le_bytes());
+    packet.extend(u32::try_from(micros >> 32)?.to_le_bytes());
+    packet.extend(u32::try_from(micros & u64::from(u32::MAX))?.to_le_bytes());
+    packet.extend(u32::try_from(frame.len())?.to_le_bytes());
+    packet.extend(u32::try_from(frame.len())?.to_le_bytes());
+    packet.extend(&frame);
+    while packet.len() % 4 != 0 {
+        packet.push(0);
+    }
+    block(&mut bytes, 6, &packet)?;
+
+    fs::write(path, bytes)?;
+    Ok(())
+}
+
+#[test]
+fn no_pcap_input_captures_live() -> TestResult {
+    let source = CaptureSource::resolve("eth0".to_string(), None)?;
+    assert_eq!(source, CaptureSource::Interface("eth0".to_string()));
+    Ok(())
+}
+
+#[test]
+fn single_file_is_replayed_whatever_its_name() -> TestResult {
+    let dir = scratch("single")?;
+    let file = dir.join("incident.dump");
+    touch(&file)?;
+
+    let source = CaptureSource::resolve("eth0".to_string(), file.to_str())?;
+    assert_eq!(source, CaptureSource::Pcap(vec![file]));
+    fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[test]
+fn directory_replays_every_capture_format_in_name_order() -> TestResult {
+    let dir = scratch("directory")?;
+    for name in ["c.pcapng", "a.pcap", "b.cap", "d.PCAP", "notes.txt", "e"] {
+        touch(&dir.join(name))?;
+    }
+    fs::create_dir(dir.join("nested.pcap"))?;
+
+    let source = CaptureSource::resolve("eth0".to_string(), dir.to_str())?;
+    let expected = ["a.pcap", "b.cap", "c.pcapng", "d.PCAP"]
+        .iter()
+        .map(|name| dir.join(name))
+        .collect();
+    assert_eq!(source, CaptureSource::Pcap(expected));
+    fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[test]
+fn glob_pattern_selects_matching_files() -> TestResult {
+    let dir = scratch("glob")?;
+    for name in ["2024-01.pcap", "2024-02.pcap", "2023-12.pcap"] {
+        touch(&dir.join(name))?;
+    }
+
+    let pattern = dir.join("2024-*.pcap");
+    let source = CaptureSource::resolve("eth0".to_string(), pattern.to_str())?;
+    assert_eq!(
+        source,
+        CaptureSource::Pcap(vec![dir.join("2024-01.pcap"), dir.join("2024-02.pcap")])
+    );
+    fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[test]
+fn empty_directory_is_an_error() -> TestResult {
+    let dir = scratch("empty")?;
+    touch(&dir.join("notes.txt"))?;
+
+    let result = CaptureSource::resolve("eth0".to_string(), dir.to_str());
+    assert!(matches!(result, Err(CaptureError::NoFiles(_))));
+    fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[test]
+fn replayed_pcap_flows_carry_their_capture_time() -> TestResult {
+    let dir = scratch("clock-pcap")?;
+    let file = dir.join("syn.pcap");
+    write_pcap(&file)?;
+
+    let clock = ReplayClock::default();
+    let replay = clock.open(&file)?;
+    assert_eq!(replay.path(), file);
+    assert_eq!(clock.timestamp(client(), server()), CAPTURED_AT);
+    // Responses travel the other way on the same flow.
+    assert_eq!(clock.timestamp(server(), client()), CAPTURED_AT);
+
+    // Traffic the file does not contain is stamped with the current time.
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let other = SocketAddr::from(([192, 168, 1, 11], 51234));
+    assert!(clock.timestamp(other, server()) >= now);
+    drop(replay);
+    assert!(file.exists());
+    fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[test]
+fn pcapng_is_replayed_through_a_temporary_pcap_copy() -> TestResult {
+    let dir = scratch("clock-pcapng")?;
+    let file = dir.join("syn.pcapng");
+    write_pcapng(&file)?;
+
+    let clock = ReplayClock::default();
+    let replay = clock.open(&file)?;
+    assert_ne!(replay.path(), file);
+    assert_eq!(clock.timestamp(client(), server()), CAPTURED_AT);
+
+    let mut copy = PcapReader::new(File::open(replay.path())?)?;
+    let packet = copy.next_packet().ok_or("the copy has no packet")??;
+    assert_eq!(packet.timestamp, Duration::from_secs(CAPTURED_AT));
+    assert_eq!(packet.data.as_ref(), syn_frame().as_slice());
+    assert!(copy.next_packet().is_none());
+
+    let copy_path = replay.path().to_path_buf();
+    drop(replay);
+    assert!(!copy_path.exists());
+    fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[test]
+fn file_that_is_not_a_capture_is_an_error() -> TestResult {
+    let dir = scratch("clock-invalid")?;
+    let file = dir.join("notes.pcap");
+    fs::write(&file, b"not a capture file")?;
+
+    let result = ReplayClock::default().open(&file);
+    assert!(matches!(result, Err(CaptureError::Replay(..))));
+    fs::remove_dir_all(dir)?;
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use collector_common::capture::CaptureSource;
+use collector_common::health::{self, Health, HealthArgs};
+use metrics_exporter_prometheus::PrometheusBuilder;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+/// Serves the health endpoint on a free local port.
+fn serve() -> Result<SocketAddr, std::io::Error> {
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let health = Health::new(
+        &CaptureSource::Pcap(Vec::new()),
+        &HealthArgs {
+            health_max_idle_secs: None,
+        },
+    );
+    let metrics = PrometheusBuilder::new().build_recorder().handle();
+    health::spawn(addr, health, metrics);
+    Ok(addr)
+}
+
+/// Connects once the endpoint's thread has bound its listener.
+fn connect(addr: SocketAddr) -> Result<TcpStream, std::io::Error> {
+    let mut attempts = 0u32;
+    loop {
+        match TcpStream::connect(addr) {
+            Ok(stream) => return Ok(stream),
+            Err(e) if attempts >= 50 => return Err(e),
+            Err(_) => {
+                attempts = attempts.saturating_add(1);
+                thread::sleep(Duration::from_millis(20));
+            }
+        }
+    }
+}
+
+fn get(addr: SocketAddr, path: &str) -> Result<String, std::io::Error> {
+    let mut stream = connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+    write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+#[test]
+fn idle_client_does_not_hold_up_the_probes() -> TestResult {
+    let addr = serve()?;
+    // Connects and never sends its request line.
+    let _idle = connect(addr)?;
+
+    let response = get(addr, "/live")?;
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    Ok(())
+}
+
+#[test]
+fn unknown_path_is_not_found() -> TestResult {
+    let addr = serve()?;
+    let response = get(addr, "/nope")?;
+    assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+    Ok(())
+}
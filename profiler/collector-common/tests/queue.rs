@@ -0,0 +1,117 @@
+use collector_common::queue::{bounded, Closed, QueueConsumer, QueuePolicy};
+use std::thread;
+use std::time::Duration;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+/// Long enough for a blocked producer to have returned if it was not blocked.
+const SETTLE: Duration = Duration::from_millis(100);
+
+/// Everything queued so far, once the producer is gone.
+async fn drain(mut consumer: QueueConsumer<u32>) -> Vec<u32> {
+    let mut items = Vec::new();
+    while let Some(item) = consumer.recv().await {
+        items.push(item);
+    }
+    items
+}
+
+#[tokio::test]
+async fn drop_oldest_evicts_the_head() -> TestResult {
+    let (producer, consumer) = bounded(2, QueuePolicy::DropOldest, 1);
+    for item in 1..=4 {
+        producer.push(item).map_err(|_| "consumer gone")?;
+    }
+    drop(producer);
+    assert_eq!(drain(consumer).await, [3, 4]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn drop_newest_discards_the_new_item() -> TestResult {
+    let (producer, consumer) = bounded(2, QueuePolicy::DropNewest, 1);
+    for item in 1..=4 {
+        producer.push(item).map_err(|_| "consumer gone")?;
+    }
+    drop(producer);
+    assert_eq!(drain(consumer).await, [1, 2]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn sample_admits_one_in_n_above_half_capacity_and_drops_when_full() -> TestResult {
+    let (producer, consumer) = bounded(4, QueuePolicy::Sample, 3);
+    for item in 1..=12 {
+        producer.push(item).map_err(|_| "consumer gone")?;
+    }
+    drop(producer);
+    // 1 and 2 fill half the queue; from then on every third result is admitted, until 5 and
+    // 8 fill it and 11, though sampled, finds no room.
+    assert_eq!(drain(consumer).await, [1, 2, 5, 8]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn block_waits_for_room() -> TestResult {
+    let (producer, mut consumer) = bounded(1, QueuePolicy::Block, 1);
+    producer.push(1).map_err(|_| "consumer gone")?;
+    let blocked = thread::spawn(move || producer.push(2));
+    thread::sleep(SETTLE);
+    assert!(!blocked.is_finished());
+
+    assert_eq!(consumer.recv().await, Some(1));
+    blocked
+        .join()
+        .map_err(|_| "producer panicked")?
+        .map_err(|_| "consumer gone")?;
+    assert_eq!(drain(consumer).await, [2]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn block_gives_up_once_the_consumer_is_dropped() -> TestResult {
+    let (producer, consumer) = bounded(1, QueuePolicy::Block, 1);
+    producer.push(1).map_err(|_| "consumer gone")?;
+    let blocked = thread::spawn(move || {
+        let waited = producer.push(2);
+        (waited, producer.push(3))
+    });
+    thread::sleep(SETTLE);
+    assert!(!blocked.is_finished());
+
+    drop(consumer);
+    let (waited, after) = blocked.join().map_err(|_| "producer panicked")?;
+    assert!(matches!(waited, Err(Closed)));
+    assert!(matches!(after, Err(Closed)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn recv_ends_once_the_producer_is_gone_and_the_queue_drained() -> TestResult {
+    let (producer, mut consumer) = bounded(4, QueuePolicy::Block, 1);
+    producer.push(1).map_err(|_| "consumer gone")?;
+    producer.push(2).map_err(|_| "consumer gone")?;
+    drop(producer);
+
+    assert_eq!(consumer.recv().await, Some(1));
+    assert_eq!(consumer.recv().await, Some(2));
+    assert_eq!(consumer.recv().await, None);
+    assert_eq!(consumer.recv().await, None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn recv_wakes_up_for_items_pushed_while_waiting() -> TestResult {
+    let (producer, mut consumer) = bounded(4, QueuePolicy::Block, 1);
+    let pushing = thread::spawn(move || {
+        thread::sleep(SETTLE);
+        producer.push(1)
+    });
+    assert_eq!(consumer.recv().await, Some(1));
+    pushing
+        .join()
+        .map_err(|_| "producer panicked")?
+        .map_err(|_| "consumer gone")?;
+    assert_eq!(consumer.recv().await, None);
+    Ok(())
+}
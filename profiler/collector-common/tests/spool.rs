@@ -0,0 +1,147 @@
+use collector_common::retry::RetryPolicy;
+use collector_common::spool::Spool;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+/// A spool path of its own for each test, in a fresh directory.
+fn spool_path(name: &str) -> Result<PathBuf, std::io::Error> {
+    let dir = std::env::temp_dir().join(format!("collector-spool-{name}-{}", std::process::id()));
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    fs::create_dir_all(&dir)?;
+    Ok(dir.join("events.ndjson"))
+}
+
+fn lines(range: std::ops::Range<u32>) -> Vec<String> {
+    range.map(|i| format!("{{\"n\":{i}}}")).collect()
+}
+
+#[test]
+fn appended_lines_are_replayed_in_order_across_restarts() -> TestResult {
+    let path = spool_path("order")?;
+    let mut spool = Spool::open(&path, 1024)?;
+    assert!(spool.is_empty());
+    assert_eq!(spool.append(&lines(0..2))?, 2);
+
+    let mut spool = Spool::open(&path, 1024)?;
+    assert_eq!(spool.append(&lines(2..3))?, 1);
+    assert_eq!(spool.peek(10)?.lines, lines(0..3));
+    assert_eq!(spool.pending_bytes(), fs::metadata(&path)?.len());
+    Ok(())
+}
+
+#[test]
+fn append_stops_at_the_size_cap() -> TestResult {
+    let path = spool_path("cap")?;
+    // Each line is 8 bytes with its newline.
+    let mut spool = Spool::open(&path, 20)?;
+    assert_eq!(spool.append(&lines(0..3))?, 2);
+    assert_eq!(spool.pending_bytes(), 16);
+    assert_eq!(spool.append(&lines(3..4))?, 0);
+    assert_eq!(spool.peek(10)?.lines, lines(0..2));
+    Ok(())
+}
+
+#[test]
+fn peek_reads_only_one_batch_from_the_head() -> TestResult {
+    let path = spool_path("peek")?;
+    let mut spool = Spool::open(&path, 1024)?;
+    spool.append(&lines(0..5))?;
+
+    let first = spool.peek(2)?;
+    assert_eq!(first.lines, lines(0..2));
+    // Peeking does not consume.
+    assert_eq!(spool.peek(2)?, first);
+
+    spool.consume(&first);
+    assert_eq!(spool.peek(2)?.lines, lines(2..4));
+    assert_eq!(spool.pending_bytes(), 24);
+    Ok(())
+}
+
+#[test]
+fn compact_keeps_only_pending_lines() -> TestResult {
+    let path = spool_path("compact")?;
+    let mut spool = Spool::open(&path, 1024)?;
+    spool.append(&lines(0..5))?;
+    let batch = spool.peek(3)?;
+    spool.consume(&batch);
+
+    spool.compact()?;
+    assert_eq!(fs::read_to_string(&path)?, lines(3..5).join("\n") + "\n");
+    assert_eq!(spool.pending_bytes(), 16);
+    assert_eq!(spool.peek(10)?.lines, lines(3..5));
+
+    let rest = spool.peek(10)?;
+    spool.consume(&rest);
+    assert!(spool.is_empty());
+    spool.compact()?;
+    assert_eq!(fs::metadata(&path)?.len(), 0);
+    Ok(())
+}
+
+#[test]
+fn lines_consumed_but_not_compacted_are_replayed_again() -> TestResult {
+    let path = spool_path("crash")?;
+    let mut spool = Spool::open(&path, 1024)?;
+    spool.append(&lines(0..3))?;
+    let batch = spool.peek(2)?;
+    spool.consume(&batch);
+    drop(spool);
+
+    let spool = Spool::open(&path, 1024)?;
+    assert_eq!(spool.peek(10)?.lines, lines(0..3));
+    Ok(())
+}
+
+#[test]
+fn blank_lines_are_skipped_but_consumed() -> TestResult {
+    let path = spool_path("blank")?;
+    fs::write(&path, "a\n\n  \nb\n")?;
+    let mut spool = Spool::open(&path, 1024)?;
+
+    let batch = spool.peek(10)?;
+    assert_eq!(batch.lines, ["a", "b"]);
+    spool.consume(&batch);
+    assert!(spool.is_empty());
+    Ok(())
+}
+
+fn policy() -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: 5,
+        initial_backoff: Duration::from_millis(200),
+        max_backoff: Duration::from_millis(1000),
+    }
+}
+
+#[test]
+fn backoff_doubles_within_the_upper_half() {
+    let policy = policy();
+    for (attempt, nominal) in [(0, 200), (1, 400), (2, 800)] {
+        for _ in 0..50 {
+            let delay = policy.backoff(attempt);
+            assert!(
+                delay >= Duration::from_millis(nominal / 2)
+                    && delay <= Duration::from_millis(nominal),
+                "attempt {attempt}: {delay:?}"
+            );
+        }
+    }
+}
+
+#[test]
+fn backoff_is_capped_for_any_attempt() {
+    let policy = policy();
+    for attempt in [3, 10, 31, 32, u32::MAX] {
+        let delay = policy.backoff(attempt);
+        assert!(
+            delay >= Duration::from_millis(500) && delay <= Duration::from_millis(1000),
+            "attempt {attempt}: {delay:?}"
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use collector_common::tls::{AssemblerClient, TlsArgs};
+use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::{server::WebPkiClientVerifier, RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+/// An empty directory of its own for each test.
+fn scratch(name: &str) -> Result<PathBuf, std::io::Error> {
+    let dir = std::env::temp_dir().join(format!("collector-common-{name}-{}", std::process::id()));
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+fn ca() -> Result<CertifiedIssuer<'static, KeyPair>, rcgen::Error> {
+    let mut params = CertificateParams::new(Vec::new())?;
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    CertifiedIssuer::self_signed(params, KeyPair::generate()?)
+}
+
+/// A certificate for 127.0.0.1 signed by `ca`, and its key, in PEM.
+fn issue(ca: &CertifiedIssuer<'_, KeyPair>) -> Result<(String, String), rcgen::Error> {
+    let key = KeyPair::generate()?;
+    let cert = CertificateParams::new(vec!["127.0.0.1".to_string()])?.signed_by(&key, ca)?;
+    Ok((cert.pem(), key.serialize_pem()))
+}
+
+/// An assembler stand-in answering `ok` to clients presenting a certificate signed by `ca`.
+async fn serve(
+    ca: &CertifiedIssuer<'_, KeyPair>,
+) -> Result<SocketAddr, Box<dyn std::error::Error>> {
+    let (cert, key) = issue(ca)?;
+    let mut roots = RootCertStore::empty();
+    roots.add(ca.der().clone())?;
+    let config = ServerConfig::builder()
+        .with_client_cert_verifier(WebPkiClientVerifier::builder(Arc::new(roots)).build()?)
+        .with_single_cert(
+            vec![CertificateDer::from_pem_slice(cert.as_bytes())?],
+            PrivateKeyDer::from_pem_slice(key.as_bytes())?,
+        )?;
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let Ok(mut stream) = acceptor.accept(stream).await else {
+                    return;
+                };
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 1024];
+                while !request.windows(4).any(|end| end == b"\r\n\r\n") {
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(read) => {
+                            request.extend_from_slice(buffer.get(..read).unwrap_or_default())
+                        }
+                    }
+                }
+                let response =
+                    "HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok";
+                stream.write_all(response.as_bytes()).await.ok();
+                stream.shutdown().await.ok();
+            });
+        }
+    });
+    Ok(addr)
+}
+
+/// Writes the collector's certificate and key into `dir`.
+fn install(dir: &Path, (cert, key): &(String, String)) -> Result<(), std::io::Error> {
+    fs::write(dir.join("cert.pem"), cert)?;
+    fs::write(dir.join("key.pem"), key)
+}
+
+async fn reaches(client: &AssemblerClient, addr: SocketAddr) -> bool {
+    match client.get().get(format!("https://{addr}/")).send().await {
+        Ok(response) => response.text().await.is_ok_and(|text| text == "ok"),
+        Err(_) => false,
+    }
+}
+
+#[tokio::test]
+async fn broken_files_leave_the_previous_client_in_place() -> TestResult {
+    let dir = scratch("tls-reload")?;
+    let ca = ca()?;
+    let addr = serve(&ca).await?;
+    fs::write(dir.join("ca.pem"), ca.pem())?;
+    let first = issue(&ca)?;
+    install(&dir, &first)?;
+    let args = TlsArgs {
+        tls_ca_file: Some(dir.join("ca.pem")),
+        tls_cert_file: Some(dir.join("cert.pem")),
+        tls_key_file: Some(dir.join("key.pem")),
+        tls_reload_secs: 0,
+    };
+    let mut client = AssemblerClient::open(&args)?;
+    assert!(reaches(&client, addr).await);
+
+    // A certificate with the key of another, then a key that is not PEM at all.
+    let second = issue(&ca)?;
+    install(&dir, &(second.0.clone(), first.1))?;
+    client.reload_if_changed();
+    assert!(reaches(&client, addr).await);
+    install(&dir, &(second.0, "not a key".to_string()))?;
+    client.reload_if_changed();
+    assert!(reaches(&client, addr).await);
+
+    // A whole pair is picked up, here one the assembler does not accept.
+    install(&dir, &issue(&self::ca()?)?)?;
+    client.reload_if_changed();
+    assert!(!reaches(&client, addr).await);
+
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
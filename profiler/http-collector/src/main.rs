@@ -1,25 +1,41 @@
+use clap::builder::RangedU64ValueParser;
 use clap::Parser;
+use collector_common::auth::Credential;
+use collector_common::batch::{BatchArgs, BatchSender};
+use collector_common::capture::{CaptureSource, ReplayClock};
+use collector_common::health::{AnalyzerState, Health, HealthArgs};
+use collector_common::queue::{self, QueueArgs};
+use collector_common::tls::AssemblerClient;
 use huginn_net_db::{Database, MatchQualityType};
 use huginn_net_http::http_common::HttpHeader;
 use huginn_net_http::{HttpAnalysisResult, HuginnNetHttp};
-use serde::{Deserialize, Serialize};
+use profiler_config::ConfigArgs;
+use profiler_model::{
+    BrowserDetection, HttpRequestIngest, HttpRequestObserved, HttpResponseIngest,
+    HttpResponseObserved, IngestEvent, NetworkEndpoint, RedactionRules, WebServerDetection,
+    DEFAULT_COOKIE_REDACTION, DEFAULT_HEADER_REDACTION, SCHEMA_VERSION,
+};
 use std::collections::HashMap;
-use std::env;
+use std::net::SocketAddr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc as std_mpsc;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::runtime::Runtime;
-use tokio::sync::mpsc as tokio_mpsc;
 use tracing::{debug, error, info, Level};
 use tracing_subscriber::FmtSubscriber;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[clap(short, long, value_parser)]
-    interface: Option<String>,
+    /// Network interface to capture on.
+    #[clap(short, long, value_parser, default_value = "wlp0s20f3")]
+    interface: String,
+    /// Read packets from a pcap file, a directory of pcap files or a glob pattern instead of
+    /// capturing live; takes precedence over `--interface`. The collector exits once every
+    /// file has been processed.
+    #[clap(long, value_parser)]
+    pcap: Option<String>,
     #[clap(
         short,
         long,
@@ -27,70 +43,36 @@ struct Args {
         default_value = "http://localhost:8000/api/ingest"
     )]
     assembler_endpoint: String,
+    /// Flows tracked by the huginn-net analyzer.
+    #[clap(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..), default_value_t = 1000)]
+    analyzer_cache_size: usize,
+    /// Client connections remembered to attribute responses to the client behind a proxy.
+    #[clap(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..), default_value_t = 100)]
+    max_connections: usize,
+    /// Header redaction rules, `name=action` separated by commas with `*=action` for the rest;
+    /// actions are keep, name (value replaced), hash or drop. Names and order are always kept
+    /// unless dropped, as fingerprinting relies on them.
+    #[clap(long, value_parser = str::parse::<RedactionRules>, default_value = DEFAULT_HEADER_REDACTION)]
+    redact_headers: RedactionRules,
+    /// Cookie redaction rules, in the same form as `--redact-headers`.
+    #[clap(long, value_parser = str::parse::<RedactionRules>, default_value = DEFAULT_COOKIE_REDACTION)]
+    redact_cookies: RedactionRules,
+    /// Address serving `/live`, `/ready` and `/metrics`.
+    #[clap(long, value_parser, default_value = "0.0.0.0:9001")]
+    health_listen: SocketAddr,
+    /// Log verbosity: error, warn, info, debug or trace.
+    #[clap(long, value_parser, default_value = "info")]
+    log_level: Level,
+    #[command(flatten)]
+    batch: BatchArgs,
+    #[command(flatten)]
+    queue: QueueArgs,
+    #[command(flatten)]
+    health: HealthArgs,
+    #[command(flatten)]
+    config: ConfigArgs,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct NetworkEndpoint {
-    pub ip: String,
-    pub port: u16,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct BrowserDetection {
-    pub browser: String,
-    pub quality: f32,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct HttpRequestObserved {
-    pub lang: Option<String>,
-    pub user_agent: Option<String>,
-    pub diagnostic: String,
-    pub method: Option<String>,
-    pub version: String,
-    pub headers: String,
-    pub cookies: String,
-    pub referer: Option<String>,
-    pub uri: Option<String>,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct HttpRequestData {
-    pub source: NetworkEndpoint,
-    pub destination: NetworkEndpoint,
-    pub observed: HttpRequestObserved,
-    pub signature: String,
-    pub browser: BrowserDetection,
-    pub timestamp: u64,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct WebServerDetection {
-    pub web_server: String,
-    pub quality: f32,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct HttpResponseObserved {
-    pub server: Option<String>,
-    pub version: String,
-    pub headers: String,
-    pub status_code: Option<u16>,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct HttpResponseData {
-    pub source: NetworkEndpoint,
-    pub destination: NetworkEndpoint,
-    pub observed: HttpResponseObserved,
-    pub signature: String,
-    pub web_server: WebServerDetection,
-    pub timestamp: u64,
-}
-
-type HttpRequestIngest = HttpRequestData;
-type HttpResponseIngest = HttpResponseData;
-
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
 struct ConnectionKey {
     source_ip: String,
@@ -107,8 +89,6 @@ struct ConnectionInfo {
 
 type ConnectionMap = Arc<Mutex<HashMap<ConnectionKey, ConnectionInfo>>>;
 
-const MAX_CONNECTIONS: usize = 100;
-
 fn extract_client_ip_from_headers(headers: &[HttpHeader], fallback_ip: &str) -> String {
     headers
         .iter()
@@ -123,12 +103,12 @@ fn extract_client_ip_from_headers(headers: &[HttpHeader], fallback_ip: &str) ->
         .unwrap_or_else(|| fallback_ip.to_string())
 }
 
-fn enforce_connection_limit(connection_map: &ConnectionMap) {
+fn enforce_connection_limit(connection_map: &ConnectionMap, max_connections: usize) {
     let Ok(mut map) = connection_map.lock() else {
         error!("Failed to acquire lock on connection map");
         return;
     };
-    if map.len() <= MAX_CONNECTIONS {
+    if map.len() <= max_connections {
         return;
     }
 
@@ -137,17 +117,25 @@ fn enforce_connection_limit(connection_map: &ConnectionMap) {
         .map(|(key, info)| (key.clone(), info.timestamp))
         .collect();
 
-    connections.sort_by(|a, b| a.1.cmp(&b.1));
+    connections.sort_by_key(|a| a.1);
 
-    let to_remove = map.len().saturating_sub(MAX_CONNECTIONS);
+    let to_remove = map.len().saturating_sub(max_connections);
     for (key, _) in connections.iter().take(to_remove) {
         map.remove(key);
     }
 }
 
 fn main() {
+    let args: Args = match profiler_config::load(&["collector", "http-collector"]) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e}");
+            return;
+        }
+    };
+
     let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::INFO)
+        .with_max_level(args.log_level)
         .finish();
 
     if let Err(e) = tracing::subscriber::set_global_default(subscriber) {
@@ -155,13 +143,43 @@ fn main() {
         return;
     }
 
-    let args = Args::parse();
-    let interface = args
-        .interface
-        .unwrap_or_else(|| env::var("PROFILER_INTERFACE").unwrap_or("wlp0s20f3".to_string()));
+    let metrics_handle = match collector_common::metrics::install("http") {
+        Ok(handle) => handle,
+        Err(e) => {
+            error!("Failed to install metrics recorder: {e}");
+            return;
+        }
+    };
     let assembler_endpoint = args.assembler_endpoint;
+    let credential = match Credential::load(&args.batch.auth) {
+        Ok(credential) => credential,
+        Err(e) => {
+            error!("Failed to read the assembler key: {e}");
+            return;
+        }
+    };
+    let client = match AssemblerClient::open(&args.batch.tls) {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Failed to load the assembler TLS settings: {e}");
+            return;
+        }
+    };
+    let source = match CaptureSource::resolve(args.interface, args.pcap.as_deref()) {
+        Ok(source) => source,
+        Err(e) => {
+            error!("Invalid capture input: {e}");
+            return;
+        }
+    };
 
-    info!("Booting http-collector on interface {interface} pointed to {assembler_endpoint}");
+    info!("Booting http-collector on {source} pointed to {assembler_endpoint}");
+    let queue_policy = args.queue.policy_for(&source);
+    info!(
+        "Queueing up to {} results with policy {queue_policy}",
+        args.queue.queue_capacity
+    );
+    let health = Health::new(&source, &args.health);
 
     let (sender, receiver) = std_mpsc::channel::<HttpAnalysisResult>();
 
@@ -177,58 +195,91 @@ fn main() {
         return;
     }
 
+    let clock = Arc::new(ReplayClock::default());
+    let analysis_clock = clock.clone();
+    let analysis_health = health.clone();
+    let analyzer_cache_size = args.analyzer_cache_size;
     thread::spawn(move || {
         let db = match Database::load_default() {
             Ok(db) => db,
             Err(e) => {
                 error!("Failed to load default database: {}", e);
+                analysis_health.set_analyzer(AnalyzerState::Failed);
                 return;
             }
         };
         debug!("Loaded database: {:?}", db);
 
-        let mut analyzer = match HuginnNetHttp::new(Some(Arc::new(db)), 1000) {
+        let mut analyzer = match HuginnNetHttp::new(Some(Arc::new(db)), analyzer_cache_size) {
             Ok(analyzer) => analyzer,
             Err(e) => {
                 error!("Failed to create HuginnNetHttp analyzer: {}", e);
+                analysis_health.set_analyzer(AnalyzerState::Failed);
                 return;
             }
         };
 
-        info!("Starting HTTP live capture on interface: {}", interface);
-        if let Err(e) = analyzer.analyze_network(&interface, sender, Some(thread_cancel_signal)) {
-            error!("HTTP analysis failed: {e}");
+        match source {
+            CaptureSource::Interface(interface) => {
+                info!("Starting HTTP live capture on interface: {}", interface);
+                analysis_health.set_analyzer(AnalyzerState::Running);
+                if let Err(e) =
+                    analyzer.analyze_network(&interface, sender, Some(thread_cancel_signal))
+                {
+                    error!("HTTP analysis failed: {e}");
+                    analysis_health.set_analyzer(AnalyzerState::Failed);
+                } else {
+                    analysis_health.set_analyzer(AnalyzerState::Stopped);
+                }
+            }
+            CaptureSource::Pcap(files) => {
+                analysis_health.set_analyzer(AnalyzerState::Running);
+                for file in files {
+                    if thread_cancel_signal.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    info!("Reading pcap file {}", file.display());
+                    let replay = match analysis_clock.open(&file) {
+                        Ok(replay) => replay,
+                        Err(e) => {
+                            error!("Skipping {}: {e}", file.display());
+                            continue;
+                        }
+                    };
+                    if let Err(e) = analyzer.analyze_pcap(
+                        &replay.path().to_string_lossy(),
+                        sender.clone(),
+                        Some(thread_cancel_signal.clone()),
+                    ) {
+                        error!("HTTP analysis of {} failed: {e}", file.display());
+                    }
+                }
+                info!("HTTP analysis of pcap input finished.");
+                analysis_health.set_analyzer(AnalyzerState::Stopped);
+            }
         }
     });
 
     // Health check endpoint
-    thread::spawn(|| {
-        use std::io::Write;
-        use std::net::{TcpListener, TcpStream};
-
-        fn handle_health_request(mut stream: TcpStream) {
-            let response = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK";
-            let _ = stream.write_all(response.as_bytes());
-        }
+    collector_common::health::spawn(args.health_listen, health.clone(), metrics_handle);
 
-        if let Ok(listener) = TcpListener::bind("0.0.0.0:9001") {
-            for stream in listener.incoming().flatten() {
-                handle_health_request(stream);
-            }
-        }
-    });
-
-    let (async_tx, mut async_rx) = tokio_mpsc::channel(1000);
+    let (queue_tx, mut queue_rx) = queue::bounded(
+        args.queue.queue_capacity,
+        queue_policy,
+        args.queue.queue_sample_rate,
+    );
     let connection_map: ConnectionMap = Arc::new(Mutex::new(HashMap::new()));
 
     // Bridge thread to move from sync to async
+    let bridge_health = health.clone();
     thread::spawn(move || {
         while let Ok(item) = receiver.recv() {
+            bridge_health.record_packet();
             if cancel_signal.load(Ordering::Relaxed) {
                 info!("Shutdown signal received in bridge thread");
                 break;
             }
-            if async_tx.blocking_send(item).is_err() {
+            if queue_tx.push(item).is_err() {
                 error!("Failed to send data to async processor. Channel closed.");
                 break;
             }
@@ -243,15 +294,11 @@ fn main() {
         }
     };
     rt.block_on(async {
-        let client = reqwest::Client::new();
+        let batch_sender =
+            BatchSender::spawn(client, &assembler_endpoint, &args.batch, credential, health);
         info!("Starting HTTP result processor...");
 
-        while let Some(result) = async_rx.recv().await {
-            let now = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs();
-
+        while let Some(result) = queue_rx.recv().await {
             if let Some(http_request) = result.http_request {
                 let source_ip = http_request.source.ip.to_string();
                 let real_client_ip =
@@ -282,9 +329,10 @@ fn main() {
                         },
                     );
                 }
-                enforce_connection_limit(&connection_map);
+                enforce_connection_limit(&connection_map, args.max_connections);
 
                 let ingest = HttpRequestIngest {
+                    schema_version: SCHEMA_VERSION,
                     source: NetworkEndpoint {
                         ip: real_client_ip,
                         port: http_request.source.port,
@@ -301,32 +349,20 @@ fn main() {
                         method: http_request.sig.method,
                         uri: http_request.sig.uri,
                         version: http_request.sig.matching.version.to_string(),
-                        headers: http_request
-                            .sig
-                            .headers
-                            .iter()
-                            .map(|header| {
-                                format!(
-                                    "{}: {}",
-                                    header.name,
-                                    header.value.as_deref().unwrap_or("")
-                                )
-                            })
-                            .collect::<Vec<String>>()
-                            .join(", "),
-                        cookies: http_request
-                            .sig
-                            .cookies
-                            .iter()
-                            .map(|cookie| {
-                                format!(
-                                    "{}: {}",
-                                    cookie.name,
-                                    cookie.value.as_deref().unwrap_or("")
-                                )
-                            })
-                            .collect::<Vec<String>>()
-                            .join(", "),
+                        headers: args.redact_headers.join(
+                            http_request
+                                .sig
+                                .headers
+                                .iter()
+                                .map(|header| (header.name.as_str(), header.value.as_deref())),
+                        ),
+                        cookies: args.redact_cookies.join(
+                            http_request
+                                .sig
+                                .cookies
+                                .iter()
+                                .map(|cookie| (cookie.name.as_str(), cookie.value.as_deref())),
+                        ),
                         referer: http_request.sig.referer,
                     },
                     browser: http_request
@@ -350,9 +386,9 @@ fn main() {
                             browser: "unknown".to_string(),
                             quality: 0.0,
                         }),
-                    timestamp: now,
+                    timestamp: captured_at(&clock, &http_request.source, &http_request.destination),
                 };
-                send_http_request_to_assembler(ingest, &client, &assembler_endpoint).await;
+                batch_sender.send(IngestEvent::HttpRequest(ingest)).await;
             }
 
             if let Some(http_response) = result.http_response {
@@ -372,6 +408,7 @@ fn main() {
                 };
 
                 let ingest = HttpResponseIngest {
+                    schema_version: SCHEMA_VERSION,
                     source: NetworkEndpoint {
                         ip: http_response.source.ip.to_string(),
                         port: http_response.source.port,
@@ -388,19 +425,13 @@ fn main() {
                             .find(|h| h.name.to_lowercase() == "server")
                             .and_then(|h| h.value.as_ref().cloned()),
                         version: http_response.sig.matching.version.to_string(),
-                        headers: http_response
-                            .sig
-                            .headers
-                            .iter()
-                            .map(|header| {
-                                format!(
-                                    "{}: {}",
-                                    header.name,
-                                    header.value.as_deref().unwrap_or("")
-                                )
-                            })
-                            .collect::<Vec<String>>()
-                            .join(", "),
+                        headers: args.redact_headers.join(
+                            http_response
+                                .sig
+                                .headers
+                                .iter()
+                                .map(|header| (header.name.as_str(), header.value.as_deref())),
+                        ),
                         status_code: http_response.sig.status_code,
                     },
                     signature: http_response.sig.to_string(),
@@ -425,58 +456,30 @@ fn main() {
                             web_server: "unknown".to_string(),
                             quality: 0.0,
                         }),
-                    timestamp: now,
+                    timestamp: captured_at(
+                        &clock,
+                        &http_response.source,
+                        &http_response.destination,
+                    ),
                 };
-                send_http_response_to_assembler(ingest, &client, &assembler_endpoint).await;
+                batch_sender.send(IngestEvent::HttpResponse(ingest)).await;
             }
         }
+
+        batch_sender.close().await;
     });
 
     info!("Analysis shutdown completed");
 }
 
-async fn send_http_request_to_assembler(
-    data: HttpRequestIngest,
-    client: &reqwest::Client,
-    endpoint: &str,
-) {
-    info!(
-        "Sending HTTP request data for {}:{}",
-        data.source.ip, data.source.port
-    );
-    let url = format!("{endpoint}/http_request");
-    match client.post(&url).json(&data).send().await {
-        Ok(response) => {
-            if !response.status().is_success() {
-                error!(
-                    "Failed to send HTTP request data, status: {}",
-                    response.status()
-                );
-            }
-        }
-        Err(e) => error!("Failed to send HTTP request data: {e}"),
-    }
-}
-
-async fn send_http_response_to_assembler(
-    data: HttpResponseIngest,
-    client: &reqwest::Client,
-    endpoint: &str,
-) {
-    info!(
-        "Sending HTTP response data for {}:{} -> {}:{}",
-        data.source.ip, data.source.port, data.destination.ip, data.destination.port
-    );
-    let url = format!("{endpoint}/http_response");
-    match client.post(&url).json(&data).send().await {
-        Ok(response) => {
-            if !response.status().is_success() {
-                error!(
-                    "Failed to send HTTP response data, status: {}",
-                    response.status()
-                );
-            }
-        }
-        Err(e) => error!("Failed to send HTTP response data: {e}"),
-    }
+/// When the traffic of a result was captured.
+fn captured_at(
+    clock: &ReplayClock,
+    source: &huginn_net_http::IpPort,
+    destination: &huginn_net_http::IpPort,
+) -> u64 {
+    clock.timestamp(
+        SocketAddr::new(source.ip, source.port),
+        SocketAddr::new(destination.ip, destination.port),
+    )
 }
@@ -0,0 +1,631 @@
+//! API credentials: collectors authenticate to ingest, API clients to read profiles and
+//! operators to administer the assembler.
+//!
+//! Keys are listed per role in a TOML file, one named secret each:
+//!
+//! ```toml
+//! [ingest]
+//! tcp-collector = "..."
+//! tls-collector = "..."
+//!
+//! [read]
+//! dashboard = "..."
+//!
+//! [admin]
+//! operator = "..."
+//! ```
+//!
+//! An ingest or read role without keys is open. The admin role is only open without a keys
+//! file at all, so that listing collector keys never leaves the admin routes unguarded.
+//! Ingest requests present either `Authorization: Bearer <secret>` or an HMAC signature of
+//! the body (see [`profiler_model::sign_request`]) whose timestamp must be recent and which
+//! is refused if seen before. Read and admin requests present a bearer secret.
+
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::{
+    body::{self, Body},
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use metrics::counter;
+use profiler_model::{verify_request, KEY_ID_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+use crate::metrics::AUTH_REJECTIONS;
+use crate::pseudonym::Pseudonymizer;
+use crate::BATCH_BODY_LIMIT;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeysFile {
+    #[serde(default)]
+    ingest: BTreeMap<String, String>,
+    #[serde(default)]
+    read: BTreeMap<String, String>,
+    #[serde(default)]
+    admin: BTreeMap<String, String>,
+}
+
+struct Key {
+    name: String,
+    secret: Vec<u8>,
+}
+
+fn keys(entries: BTreeMap<String, String>) -> Vec<Key> {
+    entries
+        .into_iter()
+        .map(|(name, secret)| Key {
+            name,
+            secret: secret.into_bytes(),
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Role {
+    Ingest,
+    Read,
+    Admin,
+}
+
+impl Role {
+    fn as_str(self) -> &'static str {
+        match self {
+            Role::Ingest => "ingest",
+            Role::Read => "read",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rejection {
+    /// No credential at all.
+    Missing,
+    /// A bearer secret matching no key of the role.
+    InvalidKey,
+    /// A signature naming a key the role does not have.
+    UnknownKey,
+    /// A signature that does not match the body, or is not written the way
+    /// [`profiler_model::sign_request`] writes it.
+    BadSignature,
+    /// A signature whose timestamp is missing or too far from now.
+    StaleTimestamp,
+    /// A signature already used by an earlier request.
+    Replayed,
+    /// A signed body over the size limit.
+    TooLarge,
+}
+
+impl Rejection {
+    fn as_str(self) -> &'static str {
+        match self {
+            Rejection::Missing => "missing",
+            Rejection::InvalidKey => "invalid_key",
+            Rejection::UnknownKey => "unknown_key",
+            Rejection::BadSignature => "bad_signature",
+            Rejection::StaleTimestamp => "stale_timestamp",
+            Rejection::Replayed => "replayed",
+            Rejection::TooLarge => "too_large",
+        }
+    }
+}
+
+/// Signatures seen recently, kept long enough to outlive their timestamp's validity.
+#[derive(Default)]
+struct Replays {
+    seen: HashSet<String>,
+    /// When to forget each signature, oldest first.
+    expiry: VecDeque<(u64, String)>,
+}
+
+impl Replays {
+    /// Remembers `signature` until `forget_at`; returns `false` if it is already known.
+    fn insert(&mut self, signature: &str, now: u64, forget_at: u64) -> bool {
+        while self.expiry.front().is_some_and(|(at, _)| *at <= now) {
+            if let Some((_, expired)) = self.expiry.pop_front() {
+                self.seen.remove(&expired);
+            }
+        }
+        if !self.seen.insert(signature.to_string()) {
+            return false;
+        }
+        self.expiry.push_back((forget_at, signature.to_string()));
+        true
+    }
+}
+
+pub struct Auth {
+    ingest: Vec<Key>,
+    read: Vec<Key>,
+    /// `None` without a keys file, when the admin routes are open.
+    admin: Option<Vec<Key>>,
+    /// How far a signature's timestamp may be from the assembler's clock, in seconds.
+    max_skew_secs: u64,
+    replays: Mutex<Replays>,
+    /// Refused peers are logged by pseudonym.
+    pseudonyms: Arc<Pseudonymizer>,
+}
+
+impl Auth {
+    /// Leaves every route open, used when no keys file is configured.
+    pub fn disabled() -> Self {
+        Auth {
+            ingest: Vec::new(),
+            read: Vec::new(),
+            admin: None,
+            max_skew_secs: 0,
+            replays: Mutex::new(Replays::default()),
+            pseudonyms: Arc::new(Pseudonymizer::off()),
+        }
+    }
+
+    pub fn open(
+        path: &Path,
+        max_skew_secs: u64,
+        pseudonyms: Arc<Pseudonymizer>,
+    ) -> Result<Self, AuthError> {
+        let content = fs::read_to_string(path).map_err(AuthError::Read)?;
+        let file: KeysFile = toml::from_str(&content).map_err(AuthError::Parse)?;
+        if let Some(name) = file
+            .ingest
+            .iter()
+            .chain(&file.read)
+            .chain(&file.admin)
+            .find(|(_, secret)| secret.is_empty())
+            .map(|(name, _)| name)
+        {
+            return Err(AuthError::EmptySecret(name.clone()));
+        }
+        Ok(Auth {
+            ingest: keys(file.ingest),
+            read: keys(file.read),
+            admin: Some(keys(file.admin)),
+            max_skew_secs,
+            replays: Mutex::new(Replays::default()),
+            pseudonyms,
+        })
+    }
+
+    pub fn ingest_keys(&self) -> usize {
+        self.ingest.len()
+    }
+
+    pub fn read_keys(&self) -> usize {
+        self.read.len()
+    }
+
+    pub fn admin_keys(&self) -> usize {
+        self.admin.as_ref().map_or(0, Vec::len)
+    }
+
+    /// Whether requests of `role` need no credential.
+    fn is_open(&self, role: Role) -> bool {
+        match role {
+            Role::Ingest => self.ingest.is_empty(),
+            Role::Read => self.read.is_empty(),
+            Role::Admin => self.admin.is_none(),
+        }
+    }
+
+    fn keys(&self, role: Role) -> &[Key] {
+        match role {
+            Role::Ingest => &self.ingest,
+            Role::Read => &self.read,
+            Role::Admin => self.admin.as_deref().unwrap_or_default(),
+        }
+    }
+
+    fn bearer(&self, role: Role, headers: &HeaderMap) -> Option<Result<&str, Rejection>> {
+        let secret = headers
+            .get(header::AUTHORIZATION)?
+            .to_str()
+            .ok()
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::trim);
+        Some(
+            secret
+                .and_then(|secret| {
+                    self.keys(role)
+                        .iter()
+                        .find(|key| same_secret(&key.secret, secret.as_bytes()))
+                })
+                .map(|key| key.name.as_str())
+                .ok_or(Rejection::InvalidKey),
+        )
+    }
+
+    /// Checks an HMAC-signed ingest request, buffering its body to do so.
+    async fn signed(&self, request: Request) -> Result<(String, Request), Rejection> {
+        let (parts, body) = request.into_parts();
+        let header = |name: &str| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string()
+        };
+        let (key_id, timestamp, signature) = (
+            header(KEY_ID_HEADER),
+            header(TIMESTAMP_HEADER),
+            header(SIGNATURE_HEADER),
+        );
+        let key = self
+            .ingest
+            .iter()
+            .find(|key| key.name == key_id)
+            .ok_or(Rejection::UnknownKey)?;
+
+        let now = unix_now();
+        let timestamp = timestamp
+            .parse::<u64>()
+            .ok()
+            .filter(|timestamp| timestamp.abs_diff(now) <= self.max_skew_secs)
+            .ok_or(Rejection::StaleTimestamp)?;
+
+        let bytes = body::to_bytes(body, BATCH_BODY_LIMIT)
+            .await
+            .map_err(|_| Rejection::TooLarge)?;
+        // The replay check compares signatures as written, so each tag must have exactly one
+        // spelling: hex digits in another case would otherwise pass as a new signature.
+        if !is_canonical_signature(&signature)
+            || !verify_request(&key.secret, timestamp, &bytes, &signature)
+        {
+            return Err(Rejection::BadSignature);
+        }
+
+        let forget_at = now.saturating_add(self.max_skew_secs.saturating_mul(2));
+        let fresh = self
+            .replays
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(&signature, now, forget_at);
+        if !fresh {
+            return Err(Rejection::Replayed);
+        }
+        Ok((
+            key.name.clone(),
+            Request::from_parts(parts, Body::from(bytes)),
+        ))
+    }
+}
+
+/// Whether `signature` is 64 lowercase hex digits, as [`profiler_model::sign_request`]
+/// writes an HMAC-SHA256 tag.
+fn is_canonical_signature(signature: &str) -> bool {
+    signature.len() == 64
+        && signature
+            .bytes()
+            .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Compares secrets without exiting at the first differing byte.
+fn same_secret(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn reject(role: Role, request_path: &str, peer: &str, reason: Rejection) -> Response {
+    warn!(
+        "Rejected {} request to {request_path} from {peer}: {}",
+        role.as_str(),
+        reason.as_str()
+    );
+    counter!(AUTH_REJECTIONS, "role" => role.as_str(), "reason" => reason.as_str()).increment(1);
+    let status = match reason {
+        Rejection::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+        _ => StatusCode::UNAUTHORIZED,
+    };
+    (
+        status,
+        [(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"))],
+        reason.as_str(),
+    )
+        .into_response()
+}
+
+/// The pseudonym of the peer that sent `request`, for logging.
+fn peer(auth: &Auth, request: &Request) -> String {
+    request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map_or_else(
+            || "unknown peer".to_string(),
+            |ConnectInfo(peer)| auth.pseudonyms.ip(&peer.ip().to_string()),
+        )
+}
+
+/// Guards the ingest routes: a bearer secret or a signature from an ingest key.
+pub async fn require_ingest(
+    State(auth): State<Arc<Auth>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if auth.is_open(Role::Ingest) {
+        return next.run(request).await;
+    }
+    let path = request.uri().path().to_string();
+    let peer = peer(&auth, &request);
+
+    let authenticated = match auth.bearer(Role::Ingest, request.headers()) {
+        Some(result) => result.map(|name| (name.to_string(), request)),
+        None if request.headers().contains_key(KEY_ID_HEADER) => auth.signed(request).await,
+        None => Err(Rejection::Missing),
+    };
+    match authenticated {
+        Ok((name, request)) => {
+            debug!("Ingest request to {path} authenticated as {name}");
+            next.run(request).await
+        }
+        Err(reason) => reject(Role::Ingest, &path, &peer, reason),
+    }
+}
+
+/// Lets through requests with a bearer secret from a key of `role`.
+async fn require_bearer(auth: &Auth, role: Role, request: Request, next: Next) -> Response {
+    match auth.bearer(role, request.headers()) {
+        Some(Ok(name)) => {
+            debug!(
+                "{} request to {} authenticated as {name}",
+                role.as_str(),
+                request.uri().path()
+            );
+            next.run(request).await
+        }
+        Some(Err(reason)) => reject(role, request.uri().path(), &peer(auth, &request), reason),
+        None => reject(
+            role,
+            request.uri().path(),
+            &peer(auth, &request),
+            Rejection::Missing,
+        ),
+    }
+}
+
+/// Guards the read routes: a bearer secret from a read key.
+pub async fn require_read(State(auth): State<Arc<Auth>>, request: Request, next: Next) -> Response {
+    if auth.is_open(Role::Read) {
+        return next.run(request).await;
+    }
+    require_bearer(&auth, Role::Read, request, next).await
+}
+
+/// Guards the admin routes: a bearer secret from an admin key.
+pub async fn require_admin(
+    State(auth): State<Arc<Auth>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if auth.is_open(Role::Admin) {
+        return next.run(request).await;
+    }
+    require_bearer(&auth, Role::Admin, request, next).await
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    Read(std::io::Error),
+    Parse(toml::de::Error),
+    EmptySecret(String),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Read(e) => write!(f, "cannot read keys file: {e}"),
+            AuthError::Parse(e) => write!(f, "invalid keys file: {e}"),
+            AuthError::EmptySecret(name) => write!(f, "key `{name}` has an empty secret"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use profiler_model::sign_request;
+    use std::path::PathBuf;
+
+    type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+    const KEYS: &str = r#"
+        [ingest]
+        collector = "ingest-secret"
+
+        [read]
+        dashboard = "read-secret"
+
+        [admin]
+        operator = "admin-secret"
+    "#;
+
+    /// A keys file of its own for each test.
+    fn keys_file(name: &str, content: &str) -> Result<PathBuf, std::io::Error> {
+        let path =
+            std::env::temp_dir().join(format!("assembler-keys-{name}-{}.toml", std::process::id()));
+        fs::write(&path, content)?;
+        Ok(path)
+    }
+
+    fn auth(name: &str, content: &str) -> Result<Auth, Box<dyn std::error::Error>> {
+        Ok(Auth::open(
+            &keys_file(name, content)?,
+            300,
+            Arc::new(Pseudonymizer::off()),
+        )?)
+    }
+
+    fn bearer(secret: &str) -> Result<HeaderMap, header::InvalidHeaderValue> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, format!("Bearer {secret}").parse()?);
+        Ok(headers)
+    }
+
+    fn signed_request(
+        key_id: &str,
+        timestamp: u64,
+        body: &'static str,
+        signature: &str,
+    ) -> Result<Request, axum::http::Error> {
+        Request::builder()
+            .header(KEY_ID_HEADER, key_id)
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .header(SIGNATURE_HEADER, signature)
+            .body(Body::from(body))
+    }
+
+    async fn check_signed(
+        auth: &Auth,
+        key_id: &str,
+        timestamp: u64,
+        body: &'static str,
+        signature: &str,
+    ) -> Result<Result<String, Rejection>, axum::http::Error> {
+        let request = signed_request(key_id, timestamp, body, signature)?;
+        Ok(auth.signed(request).await.map(|(name, _)| name))
+    }
+
+    #[test]
+    fn bearer_keys_only_open_their_own_role() -> TestResult {
+        let auth = auth("bearer", KEYS)?;
+        let ingest = bearer("ingest-secret")?;
+        assert_eq!(auth.bearer(Role::Ingest, &ingest), Some(Ok("collector")));
+        assert_eq!(
+            auth.bearer(Role::Read, &ingest),
+            Some(Err(Rejection::InvalidKey))
+        );
+        assert_eq!(
+            auth.bearer(Role::Admin, &ingest),
+            Some(Err(Rejection::InvalidKey))
+        );
+        assert_eq!(
+            auth.bearer(Role::Admin, &bearer("admin-secret")?),
+            Some(Ok("operator"))
+        );
+
+        assert_eq!(
+            auth.bearer(Role::Read, &bearer("read-secreT")?),
+            Some(Err(Rejection::InvalidKey))
+        );
+        assert_eq!(auth.bearer(Role::Read, &HeaderMap::new()), None);
+        Ok(())
+    }
+
+    #[test]
+    fn roles_without_keys_are_open_except_admin() -> TestResult {
+        let disabled = Auth::disabled();
+        assert!(disabled.is_open(Role::Ingest));
+        assert!(disabled.is_open(Role::Read));
+        assert!(disabled.is_open(Role::Admin));
+
+        let auth = auth("open", "[ingest]\ncollector = \"ingest-secret\"\n")?;
+        assert!(!auth.is_open(Role::Ingest));
+        assert!(auth.is_open(Role::Read));
+        assert!(!auth.is_open(Role::Admin));
+        // Nothing opens the closed admin role.
+        assert_eq!(
+            auth.bearer(Role::Admin, &bearer("ingest-secret")?),
+            Some(Err(Rejection::InvalidKey))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn empty_secrets_are_refused() -> TestResult {
+        let path = keys_file("empty", "[admin]\noperator = \"\"\n")?;
+        assert!(matches!(
+            Auth::open(&path, 300, Arc::new(Pseudonymizer::off())),
+            Err(AuthError::EmptySecret(name)) if name == "operator"
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signed_requests_are_checked() -> TestResult {
+        let auth = auth("signed", KEYS)?;
+        let now = unix_now();
+        let body = "{\"type\":\"syn\"}\n";
+        let signature = sign_request(b"ingest-secret", now, body.as_bytes());
+
+        assert_eq!(
+            check_signed(&auth, "collector", now, body, &signature).await?,
+            Ok("collector".to_string())
+        );
+        assert_eq!(
+            check_signed(&auth, "someone", now, body, &signature).await?,
+            Err(Rejection::UnknownKey)
+        );
+        assert_eq!(
+            check_signed(&auth, "collector", now, "{}\n", &signature).await?,
+            Err(Rejection::BadSignature)
+        );
+
+        let stale = now.saturating_sub(301);
+        let stale_signature = sign_request(b"ingest-secret", stale, body.as_bytes());
+        assert_eq!(
+            check_signed(&auth, "collector", stale, body, &stale_signature).await?,
+            Err(Rejection::StaleTimestamp)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signatures_are_refused_when_replayed_in_any_spelling() -> TestResult {
+        let auth = auth("replay", KEYS)?;
+        let now = unix_now();
+        let body = "{\"type\":\"tls\"}\n";
+        let signature = sign_request(b"ingest-secret", now, body.as_bytes());
+
+        assert!(check_signed(&auth, "collector", now, body, &signature)
+            .await?
+            .is_ok());
+        assert_eq!(
+            check_signed(&auth, "collector", now, body, &signature).await?,
+            Err(Rejection::Replayed)
+        );
+        // The same tag in uppercase decodes to the same bytes.
+        assert_eq!(
+            check_signed(&auth, "collector", now, body, &signature.to_uppercase()).await?,
+            Err(Rejection::BadSignature)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn replays_are_forgotten_after_their_window() {
+        let mut replays = Replays::default();
+        assert!(replays.insert("a", 100, 700));
+        assert!(replays.insert("b", 200, 800));
+        assert!(!replays.insert("a", 699, 1_299));
+        assert!(replays.insert("a", 700, 1_300));
+        assert!(!replays.insert("b", 700, 1_300));
+        assert!(replays.insert("b", 800, 1_400));
+    }
+
+    #[test]
+    fn only_lowercase_hex_tags_are_canonical() {
+        let signature = sign_request(b"secret", 1_700_000_000, b"{}\n");
+        assert!(is_canonical_signature(&signature));
+        assert!(!is_canonical_signature(&signature.to_uppercase()));
+        assert!(!is_canonical_signature(&format!("+{}", &signature[1..])));
+        assert!(!is_canonical_signature(&signature[2..]));
+    }
+}
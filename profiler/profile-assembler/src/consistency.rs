@@ -0,0 +1,466 @@
+//! Cross-layer consistency checks: what the user agent claims against what the TCP stack,
+//! the HTTP header layout, the TLS client hello and its JA4 database match give away.
+
+use profiler_model::{Consistency, Finding, Profile, Severity, TlsClient};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OsFamily {
+    Windows,
+    /// Linux, Android and ChromeOS share a TCP stack.
+    Linux,
+    /// macOS and iOS share a TCP stack.
+    Apple,
+}
+
+impl OsFamily {
+    fn name(self) -> &'static str {
+        match self {
+            OsFamily::Windows => "Windows",
+            OsFamily::Linux => "Linux",
+            OsFamily::Apple => "macOS/iOS",
+        }
+    }
+
+    fn initial_ttl(self) -> u16 {
+        match self {
+            OsFamily::Windows => 128,
+            OsFamily::Linux | OsFamily::Apple => 64,
+        }
+    }
+
+    /// The family claimed by a user agent.
+    fn from_user_agent(user_agent: &str) -> Option<Self> {
+        if user_agent.contains("Windows") {
+            Some(OsFamily::Windows)
+        } else if ["iPhone", "iPad", "Macintosh", "Mac OS X"]
+            .iter()
+            .any(|token| user_agent.contains(token))
+        {
+            Some(OsFamily::Apple)
+        } else if ["Android", "Linux", "CrOS", "X11"]
+            .iter()
+            .any(|token| user_agent.contains(token))
+        {
+            Some(OsFamily::Linux)
+        } else {
+            None
+        }
+    }
+
+    /// The family of an OS matched from a SYN, e.g. `Linux / unix / 3.11 and newer`.
+    fn from_tcp(os: &str) -> Option<Self> {
+        let os = os.to_lowercase();
+        if os.starts_with("windows") {
+            Some(OsFamily::Windows)
+        } else if os.starts_with("linux") || os.starts_with("android") {
+            Some(OsFamily::Linux)
+        } else if os.starts_with("mac os") || os.starts_with("ios") {
+            Some(OsFamily::Apple)
+        } else {
+            None
+        }
+    }
+}
+
+/// Browser names as they appear in huginn's HTTP signature labels.
+const BROWSERS: &[&str] = &["Chrome", "Firefox", "Safari", "MSIE"];
+
+/// Application names a JA4 database may use for each browser.
+const BROWSER_APPLICATIONS: &[(&str, &[&str])] = &[
+    (
+        "Chrome",
+        &[
+            "chrome", "chromium", "edge", "opera", "brave", "vivaldi", "electron",
+        ],
+    ),
+    ("Firefox", &["firefox", "mozilla", "gecko"]),
+    ("Safari", &["safari", "webkit"]),
+    ("MSIE", &["internet explorer", "msie"]),
+];
+
+/// Non-browser clients that commonly fake a browser user agent.
+const TOOLS: &[(&str, &str)] = &[
+    ("curl/", "curl"),
+    ("Wget/", "wget"),
+    ("python-requests/", "Python"),
+    ("Python-urllib/", "Python"),
+    ("aiohttp/", "Python"),
+    ("Go-http-client/", "Go"),
+    ("okhttp/", "OkHttp"),
+];
+
+/// The browser a user agent claims to be, named like huginn's labels, or the tool it
+/// admits to being.
+fn claimed_client(user_agent: &str) -> Option<&'static str> {
+    if let Some((_, tool)) = TOOLS.iter().find(|(token, _)| user_agent.contains(token)) {
+        return Some(tool);
+    }
+    if user_agent.contains("Firefox/") {
+        Some("Firefox")
+    } else if user_agent.contains("Trident/") || user_agent.contains("MSIE ") {
+        Some("MSIE")
+    } else if user_agent.contains("Chrome/") || user_agent.contains("Chromium/") {
+        Some("Chrome")
+    } else if user_agent.contains("Safari/") {
+        Some("Safari")
+    } else {
+        None
+    }
+}
+
+/// The leading `t13d1516h2` section of a JA4 fingerprint.
+struct Ja4Hello<'a> {
+    version: &'a str,
+    sni: bool,
+    alpn: &'a str,
+}
+
+impl<'a> Ja4Hello<'a> {
+    fn parse(ja4: &'a str) -> Option<Self> {
+        let hello = ja4.split('_').next()?;
+        if hello.len() != 10 || !hello.is_ascii() {
+            return None;
+        }
+        Some(Ja4Hello {
+            version: hello.get(1..3)?,
+            sni: hello.get(3..4)? == "d",
+            alpn: hello.get(8..10)?,
+        })
+    }
+}
+
+/// Runs every check whose inputs are present in `profile`.
+pub fn assess(profile: &Profile) -> Consistency {
+    let mut report = Report::default();
+    let user_agent = profile
+        .http_request
+        .as_ref()
+        .and_then(|request| request.observed.user_agent.as_deref());
+
+    if let Some(user_agent) = user_agent {
+        check_os(&mut report, profile, user_agent);
+        check_browser(&mut report, profile, user_agent);
+        check_tls(&mut report, profile, user_agent);
+    }
+    report.finish()
+}
+
+fn check_os(report: &mut Report, profile: &Profile, user_agent: &str) {
+    let (Some(claimed), Some(syn)) = (OsFamily::from_user_agent(user_agent), &profile.syn) else {
+        return;
+    };
+
+    match OsFamily::from_tcp(&syn.os_detected.os).filter(|_| syn.os_detected.quality > 0.0) {
+        Some(detected) => {
+            report.checked("user_agent_os_vs_tcp");
+            if detected != claimed {
+                report.found(
+                    "user_agent_os_vs_tcp",
+                    Severity::High,
+                    format!(
+                        "user agent claims {} but the TCP SYN matches {}",
+                        claimed.name(),
+                        syn.os_detected.os
+                    ),
+                );
+            }
+        }
+        // Without an OS match, the initial TTL still tells Windows from the rest.
+        None => {
+            let ttl = &syn.observed.initial_ttl;
+            if ttl.ends_with('-') {
+                return;
+            }
+            let digits: String = ttl.chars().take_while(char::is_ascii_digit).collect();
+            let Ok(ttl) = digits.parse::<u16>() else {
+                return;
+            };
+            report.checked("user_agent_os_vs_ttl");
+            if ttl != claimed.initial_ttl() && matches!(ttl, 64 | 128) {
+                report.found(
+                    "user_agent_os_vs_ttl",
+                    Severity::Medium,
+                    format!(
+                        "user agent claims {} but the initial TTL is {ttl}, not {}",
+                        claimed.name(),
+                        claimed.initial_ttl()
+                    ),
+                );
+            }
+        }
+    }
+}
+
+fn check_browser(report: &mut Report, profile: &Profile, user_agent: &str) {
+    let (Some(claimed), Some(request)) = (claimed_client(user_agent), &profile.http_request) else {
+        return;
+    };
+    let detected = &request.browser.browser;
+    let detected_name = detected.split('/').next().unwrap_or_default();
+    if request.browser.quality <= 0.0 || !BROWSERS.contains(&detected_name) {
+        return;
+    }
+
+    report.checked("user_agent_browser_vs_http");
+    // Some labels name two candidates, e.g. `Firefox/10.x or Safari 5.x/???`.
+    if !detected.contains(claimed) {
+        report.found(
+            "user_agent_browser_vs_http",
+            Severity::High,
+            format!("user agent claims {claimed} but the HTTP headers match {detected}"),
+        );
+    }
+}
+
+fn check_tls(report: &mut Report, profile: &Profile, user_agent: &str) {
+    let Some(claimed) = claimed_client(user_agent).filter(|client| BROWSERS.contains(client))
+    else {
+        return;
+    };
+    let Some(tls) = &profile.tls_client else {
+        return;
+    };
+    let Some(hello) = Ja4Hello::parse(&tls.ja4) else {
+        return;
+    };
+
+    check_ja4_match(report, claimed, tls);
+
+    report.checked("user_agent_browser_vs_tls");
+    if hello.alpn == "00" {
+        report.found(
+            "user_agent_browser_vs_tls",
+            Severity::High,
+            format!(
+                "user agent claims {claimed} but the TLS client offers no ALPN, as scripts and \
+                 command-line tools do ({})",
+                tls.ja4
+            ),
+        );
+    }
+    match hello.version {
+        "13" => {}
+        "12" => report.found(
+            "user_agent_browser_vs_tls",
+            Severity::Medium,
+            format!(
+                "user agent claims {claimed} but the TLS client does not offer TLS 1.3 ({})",
+                tls.ja4
+            ),
+        ),
+        version => report.found(
+            "user_agent_browser_vs_tls",
+            Severity::High,
+            format!(
+                "user agent claims {claimed} but the TLS client tops out at version {version} ({})",
+                tls.ja4
+            ),
+        ),
+    }
+    if !hello.sni {
+        report.found(
+            "user_agent_browser_vs_tls",
+            Severity::Low,
+            format!("user agent claims {claimed} but the TLS client sent no server name"),
+        );
+    }
+}
+
+/// Compares a claimed browser with the application the JA4 database matched.
+fn check_ja4_match(report: &mut Report, claimed: &str, tls: &TlsClient) {
+    let Some(application) = tls
+        .application_detected
+        .as_ref()
+        .filter(|detection| detection.quality > 0.0)
+        .and_then(|detection| detection.application.as_deref())
+    else {
+        return;
+    };
+    let Some((_, names)) = BROWSER_APPLICATIONS
+        .iter()
+        .find(|(browser, _)| *browser == claimed)
+    else {
+        return;
+    };
+
+    report.checked("user_agent_browser_vs_ja4");
+    let application_lower = application.to_lowercase();
+    if !names.iter().any(|name| application_lower.contains(name)) {
+        report.found(
+            "user_agent_browser_vs_ja4",
+            Severity::High,
+            format!("user agent claims {claimed} but the JA4 fingerprint matches {application}"),
+        );
+    }
+}
+
+#[derive(Default)]
+struct Report {
+    checked: Vec<String>,
+    findings: Vec<Finding>,
+}
+
+impl Report {
+    fn checked(&mut self, check: &str) {
+        if !self.checked.iter().any(|known| known == check) {
+            self.checked.push(check.to_string());
+        }
+    }
+
+    fn found(&mut self, check: &str, severity: Severity, message: String) {
+        self.findings.push(Finding {
+            check: check.to_string(),
+            severity,
+            message,
+        });
+    }
+
+    fn finish(self) -> Consistency {
+        let penalty = self
+            .findings
+            .iter()
+            .map(|finding| match finding.severity {
+                Severity::Low => 10u8,
+                Severity::Medium => 25,
+                Severity::High => 50,
+            })
+            .fold(0u8, u8::saturating_add);
+        Consistency {
+            score: 100u8.saturating_sub(penalty),
+            checked: self.checked,
+            findings: self.findings,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, CHROME_JA4, CHROME_ON_LINUX, CHROME_ON_WINDOWS, CURL_JA4};
+
+    const LINUX: &str = "Linux / unix / 3.11 and newer";
+
+    fn chrome(user_agent: &str, os: &str, ja4: &str, application: Option<&str>) -> Profile {
+        Profile {
+            syn: Some(fixtures::syn(os, "64")),
+            http_request: Some(fixtures::http_request(user_agent, "Chrome/???/???")),
+            tls_client: Some(fixtures::tls(ja4, application)),
+            ..fixtures::profile("192.168.1.10", "2024-03-01T12:00:00+00:00")
+        }
+    }
+
+    fn findings(consistency: &Consistency) -> Vec<(&str, Severity)> {
+        consistency
+            .findings
+            .iter()
+            .map(|finding| (finding.check.as_str(), finding.severity))
+            .collect()
+    }
+
+    #[test]
+    fn consistent_profile_scores_full_marks() {
+        let consistency = assess(&chrome(
+            CHROME_ON_LINUX,
+            LINUX,
+            CHROME_JA4,
+            Some("Chromium"),
+        ));
+        assert_eq!(consistency.score, 100);
+        assert!(consistency.findings.is_empty());
+        assert_eq!(
+            consistency.checked,
+            [
+                "user_agent_os_vs_tcp",
+                "user_agent_browser_vs_http",
+                "user_agent_browser_vs_ja4",
+                "user_agent_browser_vs_tls",
+            ]
+        );
+    }
+
+    #[test]
+    fn windows_user_agent_over_a_linux_syn() {
+        let consistency = assess(&chrome(CHROME_ON_WINDOWS, LINUX, CHROME_JA4, None));
+        assert_eq!(
+            findings(&consistency),
+            [("user_agent_os_vs_tcp", Severity::High)]
+        );
+        assert_eq!(consistency.score, 50);
+    }
+
+    #[test]
+    fn windows_user_agent_over_an_unmatched_ttl_64_syn() {
+        let mut profile = chrome(CHROME_ON_WINDOWS, "???", CHROME_JA4, None);
+        if let Some(syn) = &mut profile.syn {
+            syn.os_detected.quality = 0.0;
+        }
+        let consistency = assess(&profile);
+        assert_eq!(
+            findings(&consistency),
+            [("user_agent_os_vs_ttl", Severity::Medium)]
+        );
+        assert_eq!(consistency.score, 75);
+    }
+
+    #[test]
+    fn chrome_user_agent_with_a_curl_ja4() {
+        let consistency = assess(&chrome(CHROME_ON_LINUX, LINUX, CURL_JA4, Some("curl")));
+        assert_eq!(
+            findings(&consistency),
+            [
+                ("user_agent_browser_vs_ja4", Severity::High),
+                ("user_agent_browser_vs_tls", Severity::High),
+            ]
+        );
+        assert_eq!(consistency.score, 0);
+    }
+
+    #[test]
+    fn chrome_user_agent_with_a_python_ja4() {
+        let consistency = assess(&chrome(
+            CHROME_ON_LINUX,
+            LINUX,
+            CHROME_JA4,
+            Some("Python requests"),
+        ));
+        assert_eq!(
+            findings(&consistency),
+            [("user_agent_browser_vs_ja4", Severity::High)]
+        );
+    }
+
+    #[test]
+    fn browser_without_alpn_is_flagged() {
+        let consistency = assess(&chrome(
+            CHROME_ON_LINUX,
+            LINUX,
+            "t13d151600_8daaf6152771_02713d6af862",
+            None,
+        ));
+        assert_eq!(
+            findings(&consistency),
+            [("user_agent_browser_vs_tls", Severity::High)]
+        );
+        assert_eq!(consistency.score, 50);
+    }
+
+    #[test]
+    fn tool_user_agent_is_not_held_to_browser_tls() {
+        let mut profile = chrome("curl/8.5.0", LINUX, CURL_JA4, Some("curl"));
+        if let Some(request) = &mut profile.http_request {
+            request.browser.browser = "unknown".to_string();
+        }
+        let consistency = assess(&profile);
+        assert!(consistency.findings.is_empty());
+        assert_eq!(consistency.score, 100);
+    }
+
+    #[test]
+    fn profile_without_a_user_agent_is_not_checked() {
+        let mut profile = chrome(CHROME_ON_WINDOWS, LINUX, CURL_JA4, Some("curl"));
+        profile.http_request = None;
+        let consistency = assess(&profile);
+        assert!(consistency.checked.is_empty());
+        assert_eq!(consistency.score, 100);
+    }
+}
@@ -0,0 +1,517 @@
+//! `/api/profiles/diff`: field-by-field comparison of two profiles or connection records.
+//!
+//! Comparing two connection records of the same IP shows what changed between two points in
+//! time, e.g. a new TLS stack after a browser update. Only the last
+//! `--connections-per-profile` connections of a profile are kept, so the earlier point can be
+//! no older than those; a profile itself only holds its latest layers.
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::Json,
+};
+use profiler_model::{
+    ConnectionRecord, HttpRequestData, HttpResponseData, MtuData, Profile, SynAckPacketData,
+    SynPacketData, TcpObserved, TlsClient,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::info;
+
+use crate::store::{self, StoreError};
+use crate::{storage_error, AppState};
+
+#[derive(Debug, Deserialize)]
+pub struct DiffQuery {
+    /// Profile id or connection id.
+    a: String,
+    b: String,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum SubjectKind {
+    Profile,
+    Connection,
+}
+
+#[derive(Serialize)]
+struct SubjectRef {
+    id: String,
+    kind: SubjectKind,
+}
+
+/// The layers of a profile or connection record, whichever was asked for.
+struct Subject {
+    reference: SubjectRef,
+    syn: Option<SynPacketData>,
+    syn_ack: Option<SynAckPacketData>,
+    mtu: Option<MtuData>,
+    http_request: Option<HttpRequestData>,
+    http_response: Option<HttpResponseData>,
+    tls_client: Option<TlsClient>,
+}
+
+impl From<Profile> for Subject {
+    fn from(profile: Profile) -> Self {
+        Subject {
+            reference: SubjectRef {
+                id: profile.id,
+                kind: SubjectKind::Profile,
+            },
+            syn: profile.syn,
+            syn_ack: profile.syn_ack,
+            mtu: profile.mtu,
+            http_request: profile.http_request,
+            http_response: profile.http_response,
+            tls_client: profile.tls_client,
+        }
+    }
+}
+
+impl From<ConnectionRecord> for Subject {
+    fn from(record: ConnectionRecord) -> Self {
+        Subject {
+            reference: SubjectRef {
+                id: record.id,
+                kind: SubjectKind::Connection,
+            },
+            syn: record.syn,
+            syn_ack: record.syn_ack,
+            mtu: record.mtu,
+            http_request: record.http_request,
+            http_response: record.http_response,
+            tls_client: record.tls_client,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum LayerStatus {
+    Same,
+    Changed,
+    OnlyA,
+    OnlyB,
+}
+
+/// One field whose value differs. List fields also say what was added, removed or reordered.
+#[derive(Serialize)]
+struct FieldChange {
+    field: &'static str,
+    a: Value,
+    b: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    added: Option<Vec<Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    removed: Option<Vec<Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reordered: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct LayerDiff {
+    layer: &'static str,
+    status: LayerStatus,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    changes: Vec<FieldChange>,
+}
+
+#[derive(Serialize)]
+pub struct ProfileDiff {
+    a: SubjectRef,
+    b: SubjectRef,
+    identical: bool,
+    /// Layers present on at least one side.
+    layers: Vec<LayerDiff>,
+}
+
+type Fields = Vec<(&'static str, Value)>;
+
+fn tcp_fields(signature: &str, os: &str, observed: &TcpObserved) -> Fields {
+    vec![
+        ("signature", json!(signature)),
+        ("os", json!(os)),
+        ("version", json!(observed.version)),
+        ("initial_ttl", json!(observed.initial_ttl)),
+        ("options_length", json!(observed.options_length)),
+        ("mss", json!(observed.mss)),
+        ("window_size", json!(observed.window_size)),
+        ("window_scale", json!(observed.window_scale)),
+        (
+            "options_layout",
+            json!(split_list(&observed.options_layout)),
+        ),
+        ("quirks", json!(split_list(&observed.quirks))),
+        ("payload_class", json!(observed.payload_class)),
+    ]
+}
+
+fn syn_fields(syn: &SynPacketData) -> Fields {
+    tcp_fields(&syn.signature, &syn.os_detected.os, &syn.observed)
+}
+
+fn syn_ack_fields(syn_ack: &SynAckPacketData) -> Fields {
+    tcp_fields(
+        &syn_ack.signature,
+        &syn_ack.os_detected.os,
+        &syn_ack.observed,
+    )
+}
+
+fn mtu_fields(mtu: &MtuData) -> Fields {
+    vec![("link", json!(mtu.link)), ("mtu", json!(mtu.mtu_value))]
+}
+
+fn tls_fields(tls: &TlsClient) -> Fields {
+    vec![
+        ("ja4", json!(tls.ja4)),
+        ("ja4_original", json!(tls.ja4_original)),
+        (
+            "application",
+            json!(tls
+                .application_detected
+                .as_ref()
+                .and_then(|detection| detection.application.as_deref())),
+        ),
+        ("version", json!(tls.observed.version)),
+        ("sni", json!(tls.observed.sni)),
+        ("alpn", json!(tls.observed.alpn)),
+        ("cipher_suites", json!(tls.observed.cipher_suites)),
+        ("extensions", json!(tls.observed.extensions)),
+        (
+            "signature_algorithms",
+            json!(tls.observed.signature_algorithms),
+        ),
+        ("elliptic_curves", json!(tls.observed.elliptic_curves)),
+    ]
+}
+
+fn http_request_fields(request: &HttpRequestData) -> Fields {
+    vec![
+        ("signature", json!(request.signature)),
+        ("browser", json!(request.browser.browser)),
+        ("user_agent", json!(request.observed.user_agent)),
+        ("lang", json!(request.observed.lang)),
+        ("version", json!(request.observed.version)),
+        (
+            "header_order",
+            json!(header_names(&request.observed.headers)),
+        ),
+        (
+            "cookie_names",
+            json!(header_names(&request.observed.cookies)),
+        ),
+    ]
+}
+
+fn http_response_fields(response: &HttpResponseData) -> Fields {
+    vec![
+        ("signature", json!(response.signature)),
+        ("web_server", json!(response.web_server.web_server)),
+        ("server", json!(response.observed.server)),
+        ("version", json!(response.observed.version)),
+        (
+            "header_order",
+            json!(header_names(&response.observed.headers)),
+        ),
+    ]
+}
+
+/// Splits a comma-separated list such as TCP options or quirks.
+fn split_list(list: &str) -> Vec<&str> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Names, in order, from a `Name: value, Name: value` list as sent by the HTTP collector.
+/// Pieces of values that themselves contain `, ` are skipped.
+fn header_names(headers: &str) -> Vec<&str> {
+    headers
+        .split(", ")
+        .filter_map(|piece| piece.split_once(':').map(|(name, _)| name))
+        .filter(|name| {
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+        .collect()
+}
+
+fn diff_layer<T>(
+    layer: &'static str,
+    a: Option<&T>,
+    b: Option<&T>,
+    fields: fn(&T) -> Fields,
+) -> Option<LayerDiff> {
+    let (a, b) = match (a, b) {
+        (None, None) => return None,
+        (Some(_), None) => {
+            return Some(LayerDiff {
+                layer,
+                status: LayerStatus::OnlyA,
+                changes: Vec::new(),
+            })
+        }
+        (None, Some(_)) => {
+            return Some(LayerDiff {
+                layer,
+                status: LayerStatus::OnlyB,
+                changes: Vec::new(),
+            })
+        }
+        (Some(a), Some(b)) => (fields(a), fields(b)),
+    };
+
+    let changes: Vec<FieldChange> = a
+        .into_iter()
+        .zip(b)
+        .filter(|((_, a), (_, b))| a != b)
+        .map(|((field, a), (_, b))| diff_field(field, a, b))
+        .collect();
+    Some(LayerDiff {
+        layer,
+        status: if changes.is_empty() {
+            LayerStatus::Same
+        } else {
+            LayerStatus::Changed
+        },
+        changes,
+    })
+}
+
+fn diff_field(field: &'static str, a: Value, b: Value) -> FieldChange {
+    let (mut added, mut removed, mut reordered) = (None, None, None);
+    if let (Value::Array(a_items), Value::Array(b_items)) = (&a, &b) {
+        added = Some(
+            b_items
+                .iter()
+                .filter(|item| !a_items.contains(item))
+                .cloned()
+                .collect(),
+        );
+        removed = Some(
+            a_items
+                .iter()
+                .filter(|item| !b_items.contains(item))
+                .cloned()
+                .collect(),
+        );
+        let a_common: Vec<&Value> = a_items.iter().filter(|i| b_items.contains(i)).collect();
+        let b_common: Vec<&Value> = b_items.iter().filter(|i| a_items.contains(i)).collect();
+        reordered = Some(a_common != b_common);
+    }
+    FieldChange {
+        field,
+        a,
+        b,
+        added,
+        removed,
+        reordered,
+    }
+}
+
+/// `id` as stored: a profile id or the client half of a connection id pseudonymized.
+fn pseudonymize(state: &AppState, id: &str) -> String {
+    if id.contains("->") {
+        state.pseudonyms.connection_id(id)
+    } else {
+        state.pseudonyms.ip(id)
+    }
+}
+
+fn resolve(state: &AppState, id: &str) -> Result<Option<Subject>, StoreError> {
+    if let Some(profile) = state.store.get(id)? {
+        return Ok(Some(profile.into()));
+    }
+    Ok(state.store.connection(id)?.map(Subject::from))
+}
+
+/// Compares `a` and `b`, each a profile id or a connection id.
+pub async fn diff_profiles(
+    State(state): State<AppState>,
+    Query(query): Query<DiffQuery>,
+) -> Result<Json<ProfileDiff>, StatusCode> {
+    let (a, b) = (
+        pseudonymize(&state, &query.a),
+        pseudonymize(&state, &query.b),
+    );
+    info!("Comparing {} with {}", a, b);
+    let resolved =
+        store::blocking(move || Ok::<_, StoreError>((resolve(&state, &a)?, resolve(&state, &b)?)))
+            .await
+            .map_err(storage_error)?;
+    let (Some(a), Some(b)) = resolved else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    Ok(Json(compare(a, b)))
+}
+
+fn compare(a: Subject, b: Subject) -> ProfileDiff {
+    let layers: Vec<LayerDiff> = [
+        diff_layer("syn", a.syn.as_ref(), b.syn.as_ref(), syn_fields),
+        diff_layer(
+            "syn_ack",
+            a.syn_ack.as_ref(),
+            b.syn_ack.as_ref(),
+            syn_ack_fields,
+        ),
+        diff_layer("mtu", a.mtu.as_ref(), b.mtu.as_ref(), mtu_fields),
+        diff_layer(
+            "tls_client",
+            a.tls_client.as_ref(),
+            b.tls_client.as_ref(),
+            tls_fields,
+        ),
+        diff_layer(
+            "http_request",
+            a.http_request.as_ref(),
+            b.http_request.as_ref(),
+            http_request_fields,
+        ),
+        diff_layer(
+            "http_response",
+            a.http_response.as_ref(),
+            b.http_response.as_ref(),
+            http_response_fields,
+        ),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+
+    let identical = layers
+        .iter()
+        .all(|layer| matches!(layer.status, LayerStatus::Same));
+    ProfileDiff {
+        a: a.reference,
+        b: b.reference,
+        identical,
+        layers,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, CHROME_JA4, CHROME_ON_LINUX};
+
+    type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+    const LINUX: &str = "Linux / unix / 3.11 and newer";
+
+    fn status(diff: Option<LayerDiff>) -> Option<&'static str> {
+        diff.map(|diff| match diff.status {
+            LayerStatus::Same => "same",
+            LayerStatus::Changed => "changed",
+            LayerStatus::OnlyA => "only_a",
+            LayerStatus::OnlyB => "only_b",
+        })
+    }
+
+    fn subject(id: &str, ttl: &str) -> Subject {
+        Profile {
+            syn: Some(fixtures::syn(LINUX, ttl)),
+            tls_client: Some(fixtures::tls(CHROME_JA4, None)),
+            http_request: Some(fixtures::http_request(CHROME_ON_LINUX, "Chrome")),
+            ..fixtures::profile(id, "2024-03-01T12:00:00+00:00")
+        }
+        .into()
+    }
+
+    #[test]
+    fn layers_are_told_apart_by_presence_and_content() -> TestResult {
+        let syn = fixtures::syn(LINUX, "64");
+        let other = fixtures::syn(LINUX, "128");
+        assert_eq!(status(diff_layer("syn", None, None, syn_fields)), None);
+        assert_eq!(
+            status(diff_layer("syn", Some(&syn), None, syn_fields)),
+            Some("only_a")
+        );
+        assert_eq!(
+            status(diff_layer("syn", None, Some(&syn), syn_fields)),
+            Some("only_b")
+        );
+        assert_eq!(
+            status(diff_layer(
+                "syn",
+                Some(&syn),
+                Some(&syn.clone()),
+                syn_fields
+            )),
+            Some("same")
+        );
+
+        let changed =
+            diff_layer("syn", Some(&syn), Some(&other), syn_fields).ok_or("no syn diff")?;
+        let fields: Vec<&str> = changed.changes.iter().map(|change| change.field).collect();
+        assert_eq!(fields, ["initial_ttl"]);
+        assert_eq!(status(Some(changed)), Some("changed"));
+        Ok(())
+    }
+
+    #[test]
+    fn list_fields_say_what_was_added_removed_or_reordered() {
+        let change = diff_field("extensions", json!([0, 10, 16]), json!([10, 0, 43]));
+        assert_eq!(change.added, Some(vec![json!(43)]));
+        assert_eq!(change.removed, Some(vec![json!(16)]));
+        assert_eq!(change.reordered, Some(true));
+
+        let change = diff_field("extensions", json!([0, 10]), json!([0, 10, 43]));
+        assert_eq!(change.added, Some(vec![json!(43)]));
+        assert_eq!(change.removed, Some(Vec::new()));
+        assert_eq!(change.reordered, Some(false));
+
+        let change = diff_field(
+            "header_order",
+            json!(["Host", "Accept", "User-Agent"]),
+            json!(["Host", "User-Agent", "Accept"]),
+        );
+        assert_eq!(change.added, Some(Vec::new()));
+        assert_eq!(change.removed, Some(Vec::new()));
+        assert_eq!(change.reordered, Some(true));
+
+        let change = diff_field("ja4", json!("a"), json!("b"));
+        assert_eq!(
+            (change.added, change.removed, change.reordered),
+            (None, None, None)
+        );
+    }
+
+    #[test]
+    fn header_names_skip_the_pieces_of_values_with_separators() {
+        assert_eq!(
+            header_names(
+                "Host: localhost, Accept: text/html, */*, \
+                 Date: Tue, 15 Nov 1994 08:12:31 GMT, X-Forwarded-For: 2001:db8::1, ::1, \
+                 User-Agent: curl/8.0"
+            ),
+            ["Host", "Accept", "Date", "X-Forwarded-For", "User-Agent"]
+        );
+        assert!(header_names("").is_empty());
+    }
+
+    #[test]
+    fn subjects_are_identical_only_when_every_layer_is_the_same() {
+        let diff = compare(subject("1.1.1.1", "64"), subject("2.2.2.2", "64"));
+        assert!(diff.identical);
+        assert_eq!(diff.layers.len(), 3);
+
+        assert!(!compare(subject("1.1.1.1", "64"), subject("1.1.1.1", "128")).identical);
+
+        let mut partial = subject("1.1.1.1", "64");
+        partial.tls_client = None;
+        let diff = compare(subject("1.1.1.1", "64"), partial);
+        assert!(!diff.identical);
+        assert_eq!(
+            diff.layers
+                .into_iter()
+                .find(|layer| layer.layer == "tls_client")
+                .and_then(|layer| status(Some(layer))),
+            Some("only_a")
+        );
+    }
+}
@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use metrics::{counter, gauge};
+use tokio::time::{self, MissedTickBehavior};
+use tracing::{debug, error, info};
+
+use crate::metrics::{PROFILES, PROFILE_EVICTIONS};
+use crate::store::{self, StoreError};
+use crate::AppState;
+
+/// Decides which profiles to drop: the least recently seen once there are more than
+/// `capacity`, and any not seen for `idle_timeout`.
+///
+/// Profiles are kept in order of when they were last seen, so finding the next victim is O(1)
+/// no matter how many profiles the store holds. So is recording activity, for live traffic and
+/// for replayed history older than anything held.
+pub struct Eviction {
+    lru: Mutex<Lru>,
+    capacity: usize,
+    idle_timeout: Option<Duration>,
+}
+
+impl Eviction {
+    pub fn new(capacity: usize, idle_timeout: Option<Duration>) -> Self {
+        Eviction {
+            lru: Mutex::new(Lru::default()),
+            capacity: capacity.max(1),
+            idle_timeout,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Lru> {
+        self.lru.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Records that `id` was last seen at `at_ms` (Unix milliseconds) and returns the
+    /// profiles pushed out by it.
+    fn touch(&self, id: &str, at_ms: i64) -> Vec<String> {
+        let mut lru = self.lock();
+        lru.touch(id, at_ms);
+        let mut evicted = Vec::new();
+        while lru.len() > self.capacity {
+            match lru.pop_oldest_if(|_| true) {
+                Some(id) => evicted.push(id),
+                None => break,
+            }
+        }
+        evicted
+    }
+
+    /// Removes and returns every profile idle for longer than the timeout.
+    fn expired(&self, now_ms: i64) -> Vec<String> {
+        let Some(timeout) = self.idle_timeout else {
+            return Vec::new();
+        };
+        let timeout_ms = i64::try_from(timeout.as_millis()).unwrap_or(i64::MAX);
+        let cutoff = now_ms.saturating_sub(timeout_ms);
+
+        let mut lru = self.lock();
+        let mut expired = Vec::new();
+        while let Some(id) = lru.pop_oldest_if(|touched| touched < cutoff) {
+            expired.push(id);
+        }
+        expired
+    }
+}
+
+/// Records that the profile `id` was last seen at `last_seen` (RFC 3339) and drops whatever no
+/// longer fits. Imported and replayed profiles thus age from when they were seen, not from
+/// when they were loaded.
+pub fn touch(state: &AppState, id: &str, last_seen: &str) {
+    let evicted = state.eviction.touch(id, seen_ms(last_seen));
+    remove(state, &evicted, "capacity");
+    gauge!(PROFILES).set(state.eviction.len() as f64);
+}
+
+/// Loads the profiles already in the store, oldest first, so that a persistent store picks up
+/// where it left off.
+pub fn seed(state: &AppState) {
+    let recency = match state.store.recency() {
+        Ok(recency) => recency,
+        Err(e) => {
+            error!("Failed to load stored profiles for eviction: {e}");
+            return;
+        }
+    };
+    if recency.is_empty() {
+        return;
+    }
+
+    let mut evicted = Vec::new();
+    for (id, last_seen) in &recency {
+        evicted.extend(state.eviction.touch(id, seen_ms(last_seen)));
+    }
+    remove(state, &evicted, "capacity");
+    info!(
+        "Tracking {} stored profiles ({} over capacity removed)",
+        state.eviction.len(),
+        evicted.len()
+    );
+    gauge!(PROFILES).set(state.eviction.len() as f64);
+}
+
+/// Periodically drops profiles idle for longer than the configured timeout.
+pub async fn sweep(state: AppState, interval: Duration) {
+    if state.eviction.idle_timeout.is_none() {
+        return;
+    }
+    let mut ticker = time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let expired = state.eviction.expired(Utc::now().timestamp_millis());
+        if expired.is_empty() {
+            continue;
+        }
+        let count = expired.len();
+        let sweeper = state.clone();
+        let removed = store::blocking(move || {
+            remove(&sweeper, &expired, "idle");
+            Ok::<_, StoreError>(())
+        })
+        .await;
+        if let Err(e) = removed {
+            error!("Failed to sweep idle profiles: {e}");
+        }
+        info!("Swept {} idle profiles", count);
+        gauge!(PROFILES).set(state.eviction.len() as f64);
+    }
+}
+
+/// `last_seen` in Unix milliseconds, or now if it is not a valid time.
+fn seen_ms(last_seen: &str) -> i64 {
+    DateTime::parse_from_rfc3339(last_seen)
+        .map(|t| t.timestamp_millis())
+        .unwrap_or_else(|_| Utc::now().timestamp_millis())
+}
+
+fn remove(state: &AppState, ids: &[String], reason: &'static str) {
+    for id in ids {
+        if let Err(e) = state.store.remove(id) {
+            error!("Failed to remove profile {id}: {e}");
+            continue;
+        }
+        state.latest_client.forget(id);
+        counter!(PROFILE_EVICTIONS, "reason" => reason).increment(1);
+        debug!("Evicted profile {id} ({reason})");
+    }
+}
+
+const NIL: usize = usize::MAX;
+
+struct Node {
+    id: String,
+    touched_ms: i64,
+    /// Towards the most recently seen end.
+    newer: usize,
+    /// Towards the least recently seen end.
+    older: usize,
+}
+
+/// Doubly linked list over a slab, indexed by profile id and ordered by last activity.
+struct Lru {
+    nodes: Vec<Node>,
+    free: Vec<usize>,
+    index: HashMap<String, usize>,
+    newest: usize,
+    oldest: usize,
+}
+
+impl Default for Lru {
+    fn default() -> Self {
+        Lru {
+            nodes: Vec::new(),
+            free: Vec::new(),
+            index: HashMap::new(),
+            newest: NIL,
+            oldest: NIL,
+        }
+    }
+}
+
+impl Lru {
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    fn touch(&mut self, id: &str, at_ms: i64) {
+        if let Some(&slot) = self.index.get(id) {
+            self.unlink(slot);
+            if let Some(node) = self.nodes.get_mut(slot) {
+                node.touched_ms = at_ms;
+            }
+            self.link(slot, at_ms);
+            return;
+        }
+
+        let node = Node {
+            id: id.to_string(),
+            touched_ms: at_ms,
+            newer: NIL,
+            older: NIL,
+        };
+        let slot = match self.free.pop() {
+            Some(slot) => {
+                if let Some(free) = self.nodes.get_mut(slot) {
+                    *free = node;
+                }
+                slot
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len().saturating_sub(1)
+            }
+        };
+        self.index.insert(id.to_string(), slot);
+        self.link(slot, at_ms);
+    }
+
+    /// Removes the least recently seen entry if `pred` accepts its last activity time.
+    fn pop_oldest_if(&mut self, pred: impl Fn(i64) -> bool) -> Option<String> {
+        let slot = self.oldest;
+        let node = self.nodes.get(slot)?;
+        if !pred(node.touched_ms) {
+            return None;
+        }
+        self.unlink(slot);
+        self.free.push(slot);
+        let id = self
+            .nodes
+            .get_mut(slot)
+            .map(|node| std::mem::take(&mut node.id))?;
+        self.index.remove(&id);
+        Some(id)
+    }
+
+    /// Links `slot`, last active at `at_ms`, behind every entry active later. Live traffic
+    /// goes to the newest end and history older than every entry to the oldest end, both
+    /// without walking the list.
+    fn link(&mut self, slot: usize, at_ms: i64) {
+        if self
+            .nodes
+            .get(self.oldest)
+            .is_some_and(|oldest| at_ms < oldest.touched_ms)
+        {
+            self.insert_between(slot, self.oldest, NIL);
+            return;
+        }
+        let mut newer = NIL;
+        let mut older = self.newest;
+        while let Some(node) = self.nodes.get(older) {
+            if node.touched_ms <= at_ms {
+                break;
+            }
+            newer = older;
+            older = node.older;
+        }
+        self.insert_between(slot, newer, older);
+    }
+
+    fn insert_between(&mut self, slot: usize, newer: usize, older: usize) {
+        if let Some(node) = self.nodes.get_mut(slot) {
+            node.newer = newer;
+            node.older = older;
+        }
+        match self.nodes.get_mut(newer) {
+            Some(node) => node.older = slot,
+            None => self.newest = slot,
+        }
+        match self.nodes.get_mut(older) {
+            Some(node) => node.newer = slot,
+            None => self.oldest = slot,
+        }
+    }
+
+    fn unlink(&mut self, slot: usize) {
+        let Some((newer, older)) = self.nodes.get(slot).map(|node| (node.newer, node.older)) else {
+            return;
+        };
+        match self.nodes.get_mut(newer) {
+            Some(node) => node.older = older,
+            None => self.newest = older,
+        }
+        match self.nodes.get_mut(older) {
+            Some(node) => node.newer = newer,
+            None => self.oldest = newer,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lru(ids: &[&str]) -> Lru {
+        let mut lru = Lru::default();
+        for (at_ms, id) in (0..).zip(ids) {
+            lru.touch(id, at_ms);
+        }
+        lru
+    }
+
+    /// Empties `lru`, least recently seen first.
+    fn drain(lru: &mut Lru) -> Vec<String> {
+        std::iter::from_fn(|| lru.pop_oldest_if(|_| true)).collect()
+    }
+
+    #[test]
+    fn entries_leave_least_recently_seen_first() {
+        let mut lru = lru(&["a", "b", "c"]);
+        assert_eq!(lru.len(), 3);
+        assert_eq!(drain(&mut lru), ["a", "b", "c"]);
+        assert_eq!(lru.len(), 0);
+        assert_eq!(lru.pop_oldest_if(|_| true), None);
+    }
+
+    #[test]
+    fn touching_the_oldest_moves_it_to_the_front() {
+        let mut lru = lru(&["a", "b", "c"]);
+        lru.touch("a", 10);
+        assert_eq!(drain(&mut lru), ["b", "c", "a"]);
+    }
+
+    #[test]
+    fn touching_the_newest_keeps_the_order() {
+        let mut lru = lru(&["a", "b", "c"]);
+        lru.touch("c", 10);
+        assert_eq!(drain(&mut lru), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn touching_a_middle_entry_relinks_its_neighbours() {
+        let mut lru = lru(&["a", "b", "c"]);
+        lru.touch("b", 10);
+        assert_eq!(drain(&mut lru), ["a", "c", "b"]);
+    }
+
+    #[test]
+    fn single_entry_can_be_touched_popped_and_replaced() {
+        let mut lru = lru(&["a"]);
+        lru.touch("a", 10);
+        assert_eq!(lru.len(), 1);
+        assert_eq!(drain(&mut lru), ["a"]);
+
+        // The freed slot is reused.
+        lru.touch("b", 20);
+        lru.touch("c", 30);
+        assert_eq!(lru.nodes.len(), 2);
+        assert_eq!(drain(&mut lru), ["b", "c"]);
+    }
+
+    #[test]
+    fn capacity_pushes_out_the_least_recently_seen() {
+        let eviction = Eviction::new(2, None);
+        assert!(eviction.touch("a", 0).is_empty());
+        assert!(eviction.touch("b", 1).is_empty());
+        assert!(eviction.touch("a", 2).is_empty());
+        assert_eq!(eviction.touch("c", 3), ["b"]);
+        assert_eq!(eviction.len(), 2);
+    }
+
+    #[test]
+    fn entries_are_ordered_by_when_they_were_seen() {
+        let mut lru = lru(&[]);
+        lru.touch("a", 100);
+        lru.touch("b", 300);
+        lru.touch("c", 200);
+        // Older than everything: straight to the oldest end.
+        lru.touch("d", 50);
+        // A tie goes after the entries already there.
+        lru.touch("e", 200);
+        assert_eq!(drain(&mut lru), ["d", "a", "c", "e", "b"]);
+    }
+
+    #[test]
+    fn idle_entries_expire_after_the_timeout() {
+        let eviction = Eviction::new(10, Some(Duration::from_secs(1)));
+        eviction.touch("a", 0);
+        eviction.touch("b", 1_500);
+        // Replayed history recorded after newer traffic still expires on time.
+        eviction.touch("c", 900);
+
+        assert_eq!(eviction.expired(2_000), ["a", "c"]);
+        assert_eq!(eviction.expired(2_400), Vec::<String>::new());
+        assert_eq!(eviction.expired(3_000), ["b"]);
+        assert_eq!(eviction.len(), 0);
+    }
+
+    #[test]
+    fn capacity_evicts_replayed_history_before_live_profiles() {
+        let eviction = Eviction::new(2, None);
+        eviction.touch("live", 2_000);
+        eviction.touch("recent", 1_000);
+        assert_eq!(eviction.touch("imported", 0), ["imported"]);
+    }
+
+    #[test]
+    fn without_a_timeout_nothing_expires() {
+        let eviction = Eviction::new(10, None);
+        eviction.touch("a", 0);
+        assert!(eviction.expired(i64::MAX).is_empty());
+    }
+}
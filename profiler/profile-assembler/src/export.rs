@@ -0,0 +1,390 @@
+//! `/api/export`: matching profiles streamed as NDJSON or CSV, for notebooks and spreadsheets.
+//!
+//! Takes the filters, sort order and pagination of `/api/profiles`. Only the ids of the
+//! matching profiles are sorted up front; the profiles are loaded and rendered a chunk at a
+//! time as the body is sent.
+
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{header, HeaderName, HeaderValue, StatusCode},
+    response::Response,
+};
+use futures_util::{stream, StreamExt};
+use profiler_model::{IngestEvent, Profile};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{error, info, warn};
+
+use crate::query::{Layer, ProfileQuery};
+use crate::store::{self, StoreError};
+use crate::{select_error, AppState};
+
+/// Profiles loaded from the store and rendered together while the export is sent.
+const EXPORT_CHUNK: usize = 256;
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Format {
+    /// One JSON document per line.
+    #[default]
+    Ndjson,
+    /// Comma-separated values with a header row.
+    Csv,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Rows {
+    /// One row per profile.
+    #[default]
+    Profile,
+    /// One row per layer present in a profile.
+    Layer,
+}
+
+/// `GET /api/export` parameters, on top of those of [`ProfileQuery`].
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct ExportOptions {
+    format: Format,
+    rows: Rows,
+}
+
+/// Response header carrying the cursor of the next page.
+pub const NEXT_CURSOR: HeaderName = HeaderName::from_static("x-next-cursor");
+
+/// Columns of a CSV export with one row per profile.
+const PROFILE_COLUMNS: &[&str] = &[
+    "id",
+    "last_seen",
+    "os",
+    "os_quality",
+    "tcp_signature",
+    "initial_ttl",
+    "mtu",
+    "link",
+    "uptime_seconds",
+    "browser",
+    "browser_quality",
+    "user_agent",
+    "lang",
+    "http_signature",
+    "web_server",
+    "ja4",
+    "application",
+    "tls_version",
+    "sni",
+    "alpn",
+    "consistency_score",
+    "findings",
+];
+
+/// Columns of an export with one row per layer.
+const LAYER_COLUMNS: &[&str] = &[
+    "id",
+    "layer",
+    "timestamp",
+    "client",
+    "server",
+    "signature",
+    "label",
+];
+
+/// One layer of a profile in an NDJSON export, with the layer's own fields under `data`.
+#[derive(Serialize)]
+struct LayerRow<'a> {
+    id: &'a str,
+    layer: Layer,
+    timestamp: u64,
+    client: String,
+    server: String,
+    signature: String,
+    label: Option<&'a str>,
+    data: Value,
+}
+
+impl ExportOptions {
+    fn content_type(self) -> &'static str {
+        match self.format {
+            Format::Ndjson => "application/x-ndjson",
+            Format::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    fn file_name(self) -> &'static str {
+        match self.format {
+            Format::Ndjson => "profiles.ndjson",
+            Format::Csv => "profiles.csv",
+        }
+    }
+
+    fn header(self) -> Option<String> {
+        match (self.format, self.rows) {
+            (Format::Ndjson, _) => None,
+            (Format::Csv, Rows::Profile) => Some(csv_record(PROFILE_COLUMNS)),
+            (Format::Csv, Rows::Layer) => Some(csv_record(LAYER_COLUMNS)),
+        }
+    }
+
+    /// The lines `profile` is exported as.
+    pub fn render(self, profile: &Profile) -> Vec<String> {
+        match (self.format, self.rows) {
+            (Format::Ndjson, Rows::Profile) => json_line(profile).into_iter().collect(),
+            (Format::Ndjson, Rows::Layer) => profile
+                .layers()
+                .iter()
+                .filter_map(|event| {
+                    let (client, server, signature) = layer_fields(event);
+                    json_line(&LayerRow {
+                        id: &profile.id,
+                        layer: Layer::of(event),
+                        timestamp: event.timestamp(),
+                        client,
+                        server,
+                        signature,
+                        label: event.label(),
+                        data: layer_data(event),
+                    })
+                })
+                .collect(),
+            (Format::Csv, Rows::Profile) => vec![csv_record(&profile_record(profile))],
+            (Format::Csv, Rows::Layer) => profile
+                .layers()
+                .iter()
+                .map(|event| {
+                    let (client, server, signature) = layer_fields(event);
+                    csv_record(&[
+                        profile.id.clone(),
+                        Layer::of(event).to_string(),
+                        event.timestamp().to_string(),
+                        client,
+                        server,
+                        signature,
+                        event.label().unwrap_or_default().to_string(),
+                    ])
+                })
+                .collect(),
+        }
+    }
+}
+
+fn layer_fields(event: &IngestEvent) -> (String, String, String) {
+    (
+        event.client().to_string(),
+        event.server().to_string(),
+        event.signature(),
+    )
+}
+
+/// The fields of the layer itself, without the `type` tag of the event.
+fn layer_data(event: &IngestEvent) -> Value {
+    serde_json::to_value(event)
+        .ok()
+        .and_then(|mut value| value.get_mut("data").map(Value::take))
+        .unwrap_or_default()
+}
+
+fn json_line<T: Serialize>(row: &T) -> Option<String> {
+    match serde_json::to_string(row) {
+        Ok(mut line) => {
+            line.push('\n');
+            Some(line)
+        }
+        Err(e) => {
+            warn!("Skipping export row: {e}");
+            None
+        }
+    }
+}
+
+fn profile_record(profile: &Profile) -> Vec<String> {
+    let syn = profile.syn.as_ref();
+    let request = profile.http_request.as_ref();
+    let tls = profile.tls_client.as_ref();
+    vec![
+        profile.id.clone(),
+        profile.last_seen.clone(),
+        text(syn.map(|syn| &syn.os_detected.os)),
+        text(syn.map(|syn| syn.os_detected.quality)),
+        text(syn.map(|syn| &syn.signature)),
+        text(syn.map(|syn| &syn.observed.initial_ttl)),
+        text(profile.mtu.as_ref().map(|mtu| mtu.mtu_value)),
+        text(profile.mtu.as_ref().map(|mtu| &mtu.link)),
+        text(profile.uptime.as_ref().map(|uptime| uptime.uptime_seconds)),
+        text(request.map(|request| &request.browser.browser)),
+        text(request.map(|request| request.browser.quality)),
+        text(request.and_then(|request| request.observed.user_agent.as_ref())),
+        text(request.and_then(|request| request.observed.lang.as_ref())),
+        text(request.map(|request| &request.signature)),
+        text(
+            profile
+                .http_response
+                .as_ref()
+                .map(|response| &response.web_server.web_server),
+        ),
+        text(tls.map(|tls| &tls.ja4)),
+        text(
+            tls.and_then(|tls| tls.application_detected.as_ref())
+                .and_then(|detection| detection.application.as_ref()),
+        ),
+        text(tls.map(|tls| &tls.observed.version)),
+        text(tls.and_then(|tls| tls.observed.sni.as_ref())),
+        text(tls.and_then(|tls| tls.observed.alpn.as_ref())),
+        profile.consistency.score.to_string(),
+        profile
+            .consistency
+            .findings
+            .iter()
+            .map(|finding| finding.check.as_str())
+            .collect::<Vec<_>>()
+            .join(";"),
+    ]
+}
+
+/// Missing values are exported as empty fields.
+fn text<T: ToString>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+/// One CSV line as per RFC 4180.
+fn csv_record<T: AsRef<str>>(fields: &[T]) -> String {
+    let mut line = fields
+        .iter()
+        .map(|field| csv_field(field.as_ref()))
+        .collect::<Vec<_>>()
+        .join(",");
+    line.push_str("\r\n");
+    line
+}
+
+/// Quotes a field when needed. Fields that a spreadsheet would evaluate as a formula, such as
+/// a user agent starting with `=`, are prefixed with `'` so that they are shown as text.
+fn csv_field(field: &str) -> String {
+    let field = if field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{field}")
+    } else {
+        field.to_string()
+    };
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+/// Streams the profiles matching `query`. A `next_cursor`, when there are more, is returned
+/// in the `X-Next-Cursor` header.
+///
+/// A profile removed while the export is sent is left out. A storage error ends the body
+/// early, so the client sees a truncated download rather than a complete-looking one.
+pub async fn export_profiles(
+    State(state): State<AppState>,
+    Query(query): Query<ProfileQuery>,
+    Query(options): Query<ExportOptions>,
+) -> Result<Response, StatusCode> {
+    info!("Exporting profiles ({options:?})");
+    let store = state.store.clone();
+    let page = store::blocking(move || query.select_ids(store::profiles(&*store)))
+        .await
+        .map_err(|e| select_error("/api/export", e))?;
+
+    let chunks: Vec<Vec<String>> = page
+        .profiles
+        .chunks(EXPORT_CHUNK)
+        .map(<[String]>::to_vec)
+        .collect();
+    let store = state.store.clone();
+    let rows = stream::iter(chunks).then(move |ids| {
+        let store = store.clone();
+        async move {
+            let profiles = store::blocking(move || {
+                ids.iter()
+                    .filter_map(|id| store.get(id).transpose())
+                    .collect::<Result<Vec<_>, StoreError>>()
+            })
+            .await
+            .inspect_err(|e| error!("Export ended early: {e}"))?;
+            Ok::<_, StoreError>(
+                profiles
+                    .iter()
+                    .flat_map(|profile| options.render(profile))
+                    .collect::<String>(),
+            )
+        }
+    });
+    let header = stream::iter(options.header().map(Ok));
+    let mut response = Response::new(Body::from_stream(header.chain(rows)));
+
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(options.content_type()),
+    );
+    if let Ok(disposition) =
+        HeaderValue::from_str(&format!("attachment; filename=\"{}\"", options.file_name()))
+    {
+        headers.insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    if let Some(cursor) = page
+        .next_cursor
+        .and_then(|cursor| HeaderValue::from_str(&cursor).ok())
+    {
+        headers.insert(NEXT_CURSOR, cursor);
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_fields_are_left_as_they_are() {
+        assert_eq!(csv_field("Linux / unix"), "Linux / unix");
+        assert_eq!(csv_field(""), "");
+        assert_eq!(
+            csv_field("t13d1516h2_8daaf6152771"),
+            "t13d1516h2_8daaf6152771"
+        );
+    }
+
+    #[test]
+    fn separators_and_line_breaks_are_quoted() {
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("a\nb"), "\"a\nb\"");
+        assert_eq!(csv_field("a\r\nb"), "\"a\r\nb\"");
+    }
+
+    #[test]
+    fn quotes_are_doubled() {
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("\""), "\"\"\"\"");
+    }
+
+    #[test]
+    fn formulas_are_exported_as_text() {
+        for (field, expected) in [
+            ("=1+1", "'=1+1"),
+            ("+1", "'+1"),
+            ("-1", "'-1"),
+            ("@SUM(A1)", "'@SUM(A1)"),
+            ("\tx", "'\tx"),
+        ] {
+            assert_eq!(csv_field(field), expected);
+        }
+        // The guard comes first, then the quoting covers it.
+        assert_eq!(
+            csv_field("=HYPERLINK(\"http://x\",\"y\")"),
+            "\"'=HYPERLINK(\"\"http://x\"\",\"\"y\"\")\""
+        );
+        assert_eq!(csv_field("\rx"), "\"'\rx\"");
+        // Only a leading sign is a formula.
+        assert_eq!(csv_field("a=b"), "a=b");
+    }
+
+    #[test]
+    fn records_end_with_crlf() {
+        assert_eq!(csv_record(&["a", "b,c", "=d"]), "a,\"b,c\",'=d\r\n");
+    }
+}
@@ -0,0 +1,382 @@
+//! `/api/fingerprints`: the inverse of `/api/profiles`, one entry per distinct signature.
+//!
+//! Aggregates are computed on request from the signature history of every stored profile.
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::Json,
+};
+use profiler_model::{Profile, SignatureSeen};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::store::{self, StoreError};
+use crate::{storage_error, AppState};
+
+/// Page size of the listing when `limit` is not given.
+const DEFAULT_LIMIT: usize = 100;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FingerprintKind {
+    /// JA4 of the TLS client hello.
+    Ja4,
+    /// Signature of the TCP SYN.
+    Tcp,
+    /// Signature of the HTTP request.
+    Http,
+}
+
+impl FingerprintKind {
+    fn history(self, profile: &Profile) -> &[SignatureSeen] {
+        match self {
+            FingerprintKind::Ja4 => &profile.history.tls_client,
+            FingerprintKind::Tcp => &profile.history.syn,
+            FingerprintKind::Http => &profile.history.http_request,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct LabelCount {
+    label: String,
+    ips: usize,
+}
+
+/// One IP that showed a fingerprint.
+#[derive(Serialize)]
+pub struct IpSeen {
+    ip: String,
+    first_seen: u64,
+    last_seen: u64,
+    count: u64,
+}
+
+#[derive(Serialize)]
+pub struct FingerprintSummary {
+    value: String,
+    ip_count: usize,
+    /// Times the fingerprint was observed, across all IPs.
+    observations: u64,
+    first_seen: u64,
+    last_seen: u64,
+    /// What the fingerprint itself was matched to: an OS for TCP, a browser for HTTP, an
+    /// application for JA4.
+    labels: Vec<LabelCount>,
+    /// OS matched from the SYN of the IPs that showed it.
+    os: Vec<LabelCount>,
+    /// Browser matched from the HTTP request of the IPs that showed it.
+    browsers: Vec<LabelCount>,
+}
+
+#[derive(Serialize)]
+pub struct FingerprintDetail {
+    #[serde(flatten)]
+    summary: FingerprintSummary,
+    ips: Vec<IpSeen>,
+}
+
+#[derive(Serialize)]
+pub struct FingerprintList {
+    total: usize,
+    fingerprints: Vec<FingerprintSummary>,
+}
+
+#[derive(Default)]
+struct Aggregate {
+    ips: Vec<IpSeen>,
+    labels: BTreeMap<String, usize>,
+    os: BTreeMap<String, usize>,
+    browsers: BTreeMap<String, usize>,
+}
+
+impl Aggregate {
+    fn add(&mut self, profile: &Profile, seen: &SignatureSeen) {
+        self.ips.push(IpSeen {
+            ip: profile.id.clone(),
+            first_seen: seen.first_seen,
+            last_seen: seen.last_seen,
+            count: seen.count,
+        });
+        bump(&mut self.labels, seen.label.as_deref());
+        bump(
+            &mut self.os,
+            profile.syn.as_ref().map(|syn| syn.os_detected.os.as_str()),
+        );
+        bump(
+            &mut self.browsers,
+            profile
+                .http_request
+                .as_ref()
+                .map(|request| request.browser.browser.as_str()),
+        );
+    }
+
+    fn summary(&self, value: String) -> FingerprintSummary {
+        FingerprintSummary {
+            value,
+            ip_count: self.ips.len(),
+            observations: self
+                .ips
+                .iter()
+                .fold(0, |sum, ip| sum.saturating_add(ip.count)),
+            first_seen: self.ips.iter().map(|ip| ip.first_seen).min().unwrap_or(0),
+            last_seen: self.ips.iter().map(|ip| ip.last_seen).max().unwrap_or(0),
+            labels: ranked(&self.labels),
+            os: ranked(&self.os),
+            browsers: ranked(&self.browsers),
+        }
+    }
+}
+
+/// Counts one IP towards `label`, ignoring missing and unmatched labels.
+fn bump(counts: &mut BTreeMap<String, usize>, label: Option<&str>) {
+    let Some(label) = label.filter(|label| !label.is_empty() && *label != "unknown") else {
+        return;
+    };
+    let count = counts.entry(label.to_string()).or_default();
+    *count = count.saturating_add(1);
+}
+
+fn ranked(counts: &BTreeMap<String, usize>) -> Vec<LabelCount> {
+    let mut ranked: Vec<LabelCount> = counts
+        .iter()
+        .map(|(label, ips)| LabelCount {
+            label: label.clone(),
+            ips: *ips,
+        })
+        .collect();
+    ranked.sort_by_key(|count| Reverse(count.ips));
+    ranked
+}
+
+fn aggregate(
+    profiles: impl Iterator<Item = Result<Profile, StoreError>>,
+    kind: FingerprintKind,
+) -> Result<BTreeMap<String, Aggregate>, StoreError> {
+    let mut aggregates: BTreeMap<String, Aggregate> = BTreeMap::new();
+    for profile in profiles {
+        let profile = profile?;
+        for seen in kind.history(&profile) {
+            aggregates
+                .entry(seen.signature.clone())
+                .or_default()
+                .add(&profile, seen);
+        }
+    }
+    Ok(aggregates)
+}
+
+/// Every fingerprint of `kind`, the most widespread first.
+pub async fn list_fingerprints(
+    State(state): State<AppState>,
+    Path(kind): Path<FingerprintKind>,
+    Query(query): Query<ListQuery>,
+) -> Result<Json<FingerprintList>, StatusCode> {
+    info!("Listing {kind:?} fingerprints");
+    let store = state.store.clone();
+    let aggregates = store::blocking(move || aggregate(store::profiles(&*store), kind))
+        .await
+        .map_err(storage_error)?;
+    let mut fingerprints: Vec<FingerprintSummary> = aggregates
+        .into_iter()
+        .map(|(value, aggregate)| aggregate.summary(value))
+        .collect();
+    fingerprints.sort_by(|a, b| {
+        b.ip_count
+            .cmp(&a.ip_count)
+            .then(b.observations.cmp(&a.observations))
+            .then_with(|| a.value.cmp(&b.value))
+    });
+    let total = fingerprints.len();
+    fingerprints.truncate(query.limit.unwrap_or(DEFAULT_LIMIT));
+    Ok(Json(FingerprintList {
+        total,
+        fingerprints,
+    }))
+}
+
+/// One fingerprint with every IP that showed it.
+pub async fn get_fingerprint(
+    State(state): State<AppState>,
+    Path((kind, value)): Path<(FingerprintKind, String)>,
+) -> Result<Json<FingerprintDetail>, StatusCode> {
+    info!("Fetching {kind:?} fingerprint {value}");
+    let store = state.store.clone();
+    let signature = value.clone();
+    let aggregate = store::blocking(move || {
+        let mut aggregate = Aggregate::default();
+        for profile in store::profiles(&*store) {
+            let profile = profile?;
+            if let Some(seen) = kind
+                .history(&profile)
+                .iter()
+                .find(|seen| seen.signature == signature)
+            {
+                aggregate.add(&profile, seen);
+            }
+        }
+        Ok::<_, StoreError>(aggregate)
+    })
+    .await
+    .map_err(storage_error)?;
+    if aggregate.ips.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let summary = aggregate.summary(value);
+    let mut ips = aggregate.ips;
+    ips.sort_by_key(|ip| Reverse(ip.last_seen));
+    Ok(Json(FingerprintDetail { summary, ips }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, CHROME_JA4, CHROME_ON_LINUX, CURL_JA4};
+
+    type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+    const WINDOWS: &str = "Windows / 10";
+    const LINUX: &str = "Linux / unix / 3.11 and newer";
+
+    fn seen(
+        signature: &str,
+        label: Option<&str>,
+        first_seen: u64,
+        last_seen: u64,
+        count: u64,
+    ) -> SignatureSeen {
+        SignatureSeen {
+            signature: signature.to_string(),
+            label: label.map(str::to_string),
+            first_seen,
+            last_seen,
+            count,
+        }
+    }
+
+    /// Three clients sharing Chrome's JA4, one of which also showed curl's; the database
+    /// knew Chrome's JA4 for two of them.
+    fn profiles() -> Vec<Profile> {
+        let client = |id: &str, os: Option<&str>, tls_client: Vec<SignatureSeen>| {
+            let mut profile = fixtures::profile(id, "2024-03-01T12:00:00+00:00");
+            profile.syn = os.map(|os| fixtures::syn(os, "64"));
+            profile.http_request = os.map(|_| fixtures::http_request(CHROME_ON_LINUX, "Chrome"));
+            profile.history.tls_client = tls_client;
+            profile
+        };
+        vec![
+            client(
+                "1.1.1.1",
+                Some(WINDOWS),
+                vec![
+                    seen(CHROME_JA4, Some("Chrome"), 100, 200, 3),
+                    seen(CURL_JA4, Some("curl"), 50, 60, 1),
+                ],
+            ),
+            client(
+                "2.2.2.2",
+                Some(WINDOWS),
+                vec![seen(CHROME_JA4, Some("unknown"), 80, 150, 2)],
+            ),
+            client(
+                "3.3.3.3",
+                Some(LINUX),
+                vec![seen(CHROME_JA4, Some("Chrome"), 120, 300, 5)],
+            ),
+            client("4.4.4.4", None, vec![seen(CURL_JA4, None, 70, 90, 4)]),
+        ]
+    }
+
+    fn labels(counts: &[LabelCount]) -> Vec<(&str, usize)> {
+        counts
+            .iter()
+            .map(|count| (count.label.as_str(), count.ips))
+            .collect()
+    }
+
+    #[test]
+    fn summaries_add_up_the_ips_that_showed_a_fingerprint() -> TestResult {
+        let aggregates = aggregate(profiles().into_iter().map(Ok), FingerprintKind::Ja4)?;
+        assert_eq!(
+            aggregates.keys().map(String::as_str).collect::<Vec<_>>(),
+            [CHROME_JA4, CURL_JA4]
+        );
+
+        let chrome = aggregates
+            .get(CHROME_JA4)
+            .ok_or("no Chrome aggregate")?
+            .summary(CHROME_JA4.to_string());
+        assert_eq!(chrome.ip_count, 3);
+        assert_eq!(chrome.observations, 10);
+        assert_eq!((chrome.first_seen, chrome.last_seen), (80, 300));
+        // "unknown" is not a label worth counting.
+        assert_eq!(labels(&chrome.labels), [("Chrome", 2)]);
+        assert_eq!(labels(&chrome.os), [(WINDOWS, 2), (LINUX, 1)]);
+        assert_eq!(labels(&chrome.browsers), [("Chrome", 3)]);
+
+        let curl = aggregates
+            .get(CURL_JA4)
+            .ok_or("no curl aggregate")?
+            .summary(CURL_JA4.to_string());
+        assert_eq!(curl.ip_count, 2);
+        assert_eq!(curl.observations, 5);
+        assert_eq!((curl.first_seen, curl.last_seen), (50, 90));
+        assert_eq!(labels(&curl.labels), [("curl", 1)]);
+        // The client without a SYN or a request counts towards neither.
+        assert_eq!(labels(&curl.os), [(WINDOWS, 1)]);
+
+        let tcp = aggregate(profiles().into_iter().map(Ok), FingerprintKind::Tcp)?;
+        assert!(tcp.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fingerprints_are_looked_up_by_value() -> TestResult {
+        let state = fixtures::state()?;
+        for profile in profiles() {
+            let id = profile.id.clone();
+            state
+                .store
+                .update(&id, Box::new(move |stored| *stored = profile))?;
+        }
+
+        let Json(detail) = get_fingerprint(
+            State(state.clone()),
+            Path((FingerprintKind::Ja4, CHROME_JA4.to_string())),
+        )
+        .await
+        .map_err(|status| format!("lookup failed with {status}"))?;
+        assert_eq!(detail.summary.ip_count, 3);
+        // The most recently seen IPs first.
+        assert_eq!(
+            detail
+                .ips
+                .iter()
+                .map(|ip| ip.ip.as_str())
+                .collect::<Vec<_>>(),
+            ["3.3.3.3", "1.1.1.1", "2.2.2.2"]
+        );
+
+        let missing = get_fingerprint(
+            State(state),
+            Path((
+                FingerprintKind::Ja4,
+                "t13d0000h2_000000000000_000000000000".to_string(),
+            )),
+        )
+        .await;
+        assert_eq!(missing.err(), Some(StatusCode::NOT_FOUND));
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+//! Observations to build test profiles from, and an assembler to apply them to.
+
+use std::sync::Arc;
+
+use profiler_model::{
+    ApplicationDetection, BrowserDetection, HttpRequestData, HttpRequestObserved, NetworkEndpoint,
+    OsDetection, Profile, RedactionPolicy, SynPacketData, TcpObserved, TlsClient,
+    TlsClientObserved, DEFAULT_COOKIE_REDACTION, DEFAULT_HEADER_REDACTION, SCHEMA_VERSION,
+};
+
+use crate::eviction::Eviction;
+use crate::gateway::LatestClient;
+use crate::ja4db::Ja4Db;
+use crate::pseudonym::Pseudonymizer;
+use crate::store::MemoryStore;
+use crate::stream::Updates;
+use crate::AppState;
+
+pub const CHROME_ON_WINDOWS: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) \
+    AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36";
+
+pub const CHROME_ON_LINUX: &str = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 \
+    (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36";
+
+/// JA4 of Chrome: TLS 1.3, SNI, ALPN `h2`.
+pub const CHROME_JA4: &str = "t13d1516h2_8daaf6152771_02713d6af862";
+
+/// JA4 of curl without ALPN: TLS 1.3, SNI, no ALPN.
+pub const CURL_JA4: &str = "t13d311200_e8f1e7e78f70_6bebaf5329ac";
+
+fn client() -> NetworkEndpoint {
+    NetworkEndpoint {
+        ip: "192.168.1.10".to_string(),
+        port: 51234,
+    }
+}
+
+fn server() -> NetworkEndpoint {
+    NetworkEndpoint {
+        ip: "10.0.0.1".to_string(),
+        port: 443,
+    }
+}
+
+/// An assembler with the default settings over an empty memory store.
+pub fn state() -> Result<AppState, profiler_model::InvalidRedaction> {
+    Ok(AppState {
+        store: Arc::new(MemoryStore::default()),
+        eviction: Arc::new(Eviction::new(10_000, None)),
+        history_limit: 16,
+        connections_per_profile: 32,
+        updates: Arc::new(Updates::new(16)),
+        ja4_db: Arc::new(Ja4Db::disabled()),
+        redaction: Arc::new(RedactionPolicy {
+            headers: DEFAULT_HEADER_REDACTION.parse()?,
+            cookies: DEFAULT_COOKIE_REDACTION.parse()?,
+        }),
+        pseudonyms: Arc::new(Pseudonymizer::off()),
+        latest_client: Arc::new(LatestClient::default()),
+        trusted_proxies: Arc::default(),
+    })
+}
+
+pub fn profile(id: &str, last_seen: &str) -> Profile {
+    Profile {
+        id: id.to_string(),
+        last_seen: last_seen.to_string(),
+        ..Profile::default()
+    }
+}
+
+/// A SYN matched to `os`, with the initial TTL of its stack.
+pub fn syn(os: &str, initial_ttl: &str) -> SynPacketData {
+    SynPacketData {
+        schema_version: SCHEMA_VERSION,
+        source: client(),
+        destination: server(),
+        os_detected: OsDetection {
+            os: os.to_string(),
+            quality: 1.0,
+        },
+        signature: "4:64+0:0:1460:mss*44,7:mss,sok,ts,nop,ws:df,id+:0".to_string(),
+        observed: TcpObserved {
+            version: "4".to_string(),
+            initial_ttl: initial_ttl.to_string(),
+            options_length: 0,
+            mss: Some(1460),
+            window_size: "mss*44".to_string(),
+            window_scale: Some(7),
+            options_layout: "Mss,Sok,Ts,Nop,Ws".to_string(),
+            quirks: "Df,NonZeroID".to_string(),
+            payload_class: "0".to_string(),
+        },
+        timestamp: 1_700_000_000,
+    }
+}
+
+/// A request sent with `user_agent` whose header layout matched `browser`.
+pub fn http_request(user_agent: &str, browser: &str) -> HttpRequestData {
+    HttpRequestData {
+        schema_version: SCHEMA_VERSION,
+        source: client(),
+        destination: server(),
+        observed: HttpRequestObserved {
+            lang: Some("English".to_string()),
+            user_agent: Some(user_agent.to_string()),
+            diagnostic: "none".to_string(),
+            method: Some("GET".to_string()),
+            version: "1.1".to_string(),
+            headers: "Host: localhost, Accept: */*".to_string(),
+            cookies: String::new(),
+            referer: None,
+            uri: Some("/".to_string()),
+        },
+        signature: "1:Host,Accept:Accept-Encoding:Mozilla/5.0".to_string(),
+        browser: BrowserDetection {
+            browser: browser.to_string(),
+            quality: 1.0,
+        },
+        timestamp: 1_700_000_001,
+    }
+}
+
+/// A client hello with `ja4`, attributed to `application` by the JA4 database if given.
+pub fn tls(ja4: &str, application: Option<&str>) -> TlsClient {
+    TlsClient {
+        schema_version: SCHEMA_VERSION,
+        timestamp: 1_700_000_002,
+        source: client(),
+        destination: server(),
+        ja4: ja4.to_string(),
+        ja4_raw: String::new(),
+        ja4_original: ja4.to_string(),
+        ja4_original_raw: String::new(),
+        observed: TlsClientObserved {
+            version: "1.3".to_string(),
+            sni: Some("localhost".to_string()),
+            alpn: Some("h2".to_string()),
+            cipher_suites: vec![0x1301, 0x1302],
+            extensions: vec![0x0000, 0x000a],
+            signature_algorithms: vec![0x0403],
+            elliptic_curves: vec![0x001d],
+        },
+        application_detected: application.map(|application| ApplicationDetection {
+            application: Some(application.to_string()),
+            library: None,
+            os: None,
+            quality: 1.0,
+        }),
+    }
+}
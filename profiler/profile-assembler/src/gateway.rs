@@ -0,0 +1,117 @@
+//! The client that HTTP traffic from a Docker gateway address is attributed to.
+//!
+//! In Docker Compose, HTTP traffic is captured with the gateway IP (172.x.x.x) while TLS/TCP
+//! traffic carries the real client IP. Gateway events go to the client most recently seen
+//! with TLS/TCP data, which is kept up to date as profiles change rather than searched for.
+
+use std::sync::{PoisonError, RwLock};
+
+use profiler_model::Profile;
+use tracing::error;
+
+use crate::is_docker_gateway_ip;
+use crate::store::{self, ProfileStore};
+
+/// `(id, last_seen)` of the most recently seen profile of real client traffic.
+#[derive(Default)]
+pub struct LatestClient(RwLock<Option<(String, String)>>);
+
+impl LatestClient {
+    /// Takes `profile` as the latest client if it has TLS/TCP data and was seen no earlier
+    /// than the current one.
+    pub fn record(&self, profile: &Profile) {
+        if is_docker_gateway_ip(&profile.id)
+            || (profile.tls_client.is_none() && profile.syn.is_none() && profile.syn_ack.is_none())
+        {
+            return;
+        }
+        let mut latest = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        if latest
+            .as_ref()
+            .is_none_or(|(_, last_seen)| profile.last_seen >= *last_seen)
+        {
+            *latest = Some((profile.id.clone(), profile.last_seen.clone()));
+        }
+    }
+
+    /// Forgets `id` once its profile is removed. Gateway traffic maps to itself until the
+    /// next client is seen.
+    pub fn forget(&self, id: &str) {
+        let mut latest = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        if latest.as_ref().is_some_and(|(latest, _)| latest == id) {
+            *latest = None;
+        }
+    }
+
+    pub fn get(&self) -> Option<String> {
+        self.0
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+            .map(|(id, _)| id.clone())
+    }
+
+    /// Finds the latest client among the profiles already in the store, once at startup.
+    pub fn seed(&self, store: &dyn ProfileStore) {
+        for profile in store::profiles(store) {
+            match profile {
+                Ok(profile) => self.record(&profile),
+                Err(e) => {
+                    error!("Failed to find the latest client in the store: {e}");
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{self, CHROME_JA4};
+    use crate::store::MemoryStore;
+
+    fn client(id: &str, last_seen: &str) -> Profile {
+        Profile {
+            tls_client: Some(fixtures::tls(CHROME_JA4, None)),
+            ..fixtures::profile(id, last_seen)
+        }
+    }
+
+    #[test]
+    fn latest_client_with_tcp_or_tls_data_wins() {
+        let latest = LatestClient::default();
+        latest.record(&client("1.1.1.1", "2024-03-01T12:00:00+00:00"));
+        latest.record(&client("2.2.2.2", "2024-03-01T12:00:05+00:00"));
+        // Older, gateway and HTTP-only profiles are not clients to map to.
+        latest.record(&client("3.3.3.3", "2024-03-01T11:00:00+00:00"));
+        latest.record(&client("172.18.0.1", "2024-03-01T13:00:00+00:00"));
+        latest.record(&fixtures::profile("4.4.4.4", "2024-03-01T13:00:00+00:00"));
+        assert_eq!(latest.get().as_deref(), Some("2.2.2.2"));
+
+        latest.forget("1.1.1.1");
+        assert_eq!(latest.get().as_deref(), Some("2.2.2.2"));
+        latest.forget("2.2.2.2");
+        assert_eq!(latest.get(), None);
+    }
+
+    #[test]
+    fn seed_finds_the_latest_stored_client() -> Result<(), Box<dyn std::error::Error>> {
+        let store = MemoryStore::default();
+        for profile in [
+            client("1.1.1.1", "2024-03-01T12:00:05+00:00"),
+            Profile {
+                syn: Some(fixtures::syn("Linux / unix / 3.11 and newer", "64")),
+                ..fixtures::profile("2.2.2.2", "2024-03-01T12:00:00+00:00")
+            },
+        ] {
+            let id = profile.id.clone();
+            store.update(&id, Box::new(move |stored| *stored = profile))?;
+        }
+
+        let latest = LatestClient::default();
+        latest.seed(&store);
+        assert_eq!(latest.get().as_deref(), Some("1.1.1.1"));
+        Ok(())
+    }
+}
@@ -0,0 +1,401 @@
+//! Bulk import of NDJSON datasets, through `POST /api/admin/import` or `--import` at startup.
+//!
+//! Each line is one of:
+//! - a profile, as exported by `/api/export`, restored as is;
+//! - a layer row, as exported by `/api/export?rows=layer`, replayed as the event it describes;
+//! - an ingest event, as accepted by `/api/ingest/batch`, replayed.
+//!
+//! Unlike live ingest, replayed events date their profile by their own collector timestamp.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    http::StatusCode,
+    response::Json,
+};
+use futures_util::StreamExt;
+use profiler_model::{check_schema_version, IngestEvent, Profile};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+use crate::ingest::{apply_event, BatchSummary, SeenAt};
+use crate::query::Layer;
+use crate::store::{self, StoreError};
+use crate::{consistency, eviction, storage_error, AppState};
+
+/// Request body limit for `/api/admin/import`. Lines past it are not read.
+const IMPORT_BODY_LIMIT: usize = 256 * 1024 * 1024;
+
+/// Body chunks read ahead of the import.
+const CHUNKS_IN_FLIGHT: usize = 16;
+
+enum Line {
+    Event(Box<IngestEvent>),
+    Profile(Box<Profile>),
+}
+
+impl Line {
+    fn kind(&self) -> &'static str {
+        match self {
+            Line::Event(event) => event.kind(),
+            Line::Profile(_) => "profile",
+        }
+    }
+}
+
+fn parse_line(line: &str) -> Result<Line, String> {
+    let mut value: Value = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    if value.get("type").is_some() {
+        return serde_json::from_value(value)
+            .map(|event| Line::Event(Box::new(event)))
+            .map_err(|e| format!("invalid event: {e}"));
+    }
+    if let Some(layer) = value
+        .get("layer")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+    {
+        let data = value
+            .get_mut("data")
+            .map(Value::take)
+            .ok_or_else(|| format!("{layer} row without data"))?;
+        let kind = match layer.parse::<Layer>()? {
+            Layer::TlsClient => "tls",
+            layer => layer.as_str(),
+        };
+        return serde_json::from_value(json!({ "type": kind, "data": data }))
+            .map(|event| Line::Event(Box::new(event)))
+            .map_err(|e| format!("invalid {layer} row: {e}"));
+    }
+    let profile: Profile =
+        serde_json::from_value(value).map_err(|e| format!("invalid profile: {e}"))?;
+    if profile.id.is_empty() {
+        return Err("profile without an id".to_string());
+    }
+    Ok(Line::Profile(Box::new(profile)))
+}
+
+fn check_line(line: &Line) -> Result<(), String> {
+    match line {
+        Line::Event(event) => check_schema_version(event.schema_version())
+            .map_err(|e| format!("{} event: {e}", event.kind())),
+        Line::Profile(profile) => profile.layers().iter().try_for_each(|event| {
+            check_schema_version(event.schema_version())
+                .map_err(|e| format!("{} of profile {}: {e}", event.kind(), profile.id))
+        }),
+    }
+}
+
+/// Stores `profile` in place of any profile with the same id.
+fn restore_profile(state: &AppState, mut profile: Profile) -> Result<(), StoreError> {
+    // Connection records are not exported, so there is nothing for these to point to.
+    profile.connections.clear();
+    state.pseudonyms.profile(&mut profile);
+    if let Some(request) = &mut profile.http_request {
+        state.redaction.apply_request(&mut request.observed);
+    }
+    if let Some(response) = &mut profile.http_response {
+        state.redaction.apply_response(&mut response.observed);
+    }
+    profile.consistency = consistency::assess(&profile);
+    let id = profile.id.clone();
+    let last_seen = profile.last_seen.clone();
+    state.latest_client.record(&profile);
+    // The records behind the profile being replaced would be reachable from nothing else.
+    let mut replaced = Vec::new();
+    state.store.update(
+        &id,
+        Box::new(|stored| {
+            replaced = std::mem::take(&mut stored.connections);
+            *stored = profile;
+        }),
+    )?;
+    for connection in &replaced {
+        state.store.remove_connection(connection)?;
+    }
+    eviction::touch(state, &id, &last_seen);
+    Ok(())
+}
+
+/// Applies every line of `reader`. Like a batch, a bad line only rejects itself.
+fn import(state: &AppState, reader: impl BufRead) -> BatchSummary {
+    let mut summary = BatchSummary::default();
+    for (index, line) in reader.lines().enumerate() {
+        let line_number = index.saturating_add(1);
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Import line {line_number} rejected: {e}");
+                summary.reject(line_number, "unknown", "malformed", e.to_string());
+                // Invalid UTF-8 only spoils its own line; anything else ends the input.
+                if e.kind() == io::ErrorKind::InvalidData {
+                    continue;
+                }
+                break;
+            }
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let parsed = match parse_line(line) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                warn!("Import line {line_number} rejected: {e}");
+                summary.reject(line_number, "unknown", "malformed", e);
+                continue;
+            }
+        };
+        let kind = parsed.kind();
+        if let Err(e) = check_line(&parsed) {
+            warn!("Import line {line_number} rejected: {e}");
+            summary.reject(line_number, kind, "schema_version", e);
+            continue;
+        }
+
+        let stored = match parsed {
+            Line::Event(event) => apply_event(state, *event, SeenAt::Observation),
+            Line::Profile(profile) => restore_profile(state, *profile),
+        };
+        match stored {
+            Ok(()) => summary.accept(),
+            Err(e) => {
+                error!("Failed to store imported {kind}: {e}");
+                summary.reject(line_number, kind, "storage", e.to_string());
+            }
+        }
+    }
+    summary
+}
+
+/// Imports the NDJSON file at `path`, as given with `--import`.
+pub fn import_file(state: &AppState, path: &Path) -> io::Result<BatchSummary> {
+    let file = File::open(path)?;
+    info!("Importing {}", path.display());
+    Ok(import(state, BufReader::new(file)))
+}
+
+/// A request body read on the blocking pool, fed chunk by chunk as it arrives.
+struct BodyReader {
+    chunks: mpsc::Receiver<io::Result<Bytes>>,
+    current: Bytes,
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.current.is_empty() {
+            match self.chunks.blocking_recv() {
+                Some(chunk) => self.current = chunk?,
+                None => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.current.len());
+        let chunk = self.current.split_to(len);
+        if let Some(buf) = buf.get_mut(..len) {
+            buf.copy_from_slice(&chunk);
+        }
+        Ok(len)
+    }
+}
+
+/// Sends the chunks of `body` to the import until it ends, fails or exceeds the limit.
+async fn feed(body: Body, chunks: mpsc::Sender<io::Result<Bytes>>) {
+    let mut stream = body.into_data_stream();
+    let mut total = 0usize;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(io::Error::other).and_then(|chunk| {
+            total = total.saturating_add(chunk.len());
+            if total > IMPORT_BODY_LIMIT {
+                return Err(io::Error::other(format!(
+                    "request body larger than {IMPORT_BODY_LIMIT} bytes"
+                )));
+            }
+            Ok(chunk)
+        });
+        let failed = chunk.is_err();
+        // The import stops reading at its first unreadable line.
+        if chunks.send(chunk).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Imports an NDJSON request body, line by line as it streams in.
+pub async fn import_profiles(
+    State(state): State<AppState>,
+    body: Body,
+) -> Result<Json<BatchSummary>, StatusCode> {
+    let (sender, chunks) = mpsc::channel(CHUNKS_IN_FLIGHT);
+    let reader = BodyReader {
+        chunks,
+        current: Bytes::new(),
+    };
+    let (summary, ()) = tokio::join!(
+        store::blocking(move || Ok::<_, StoreError>(import(&state, BufReader::new(reader)))),
+        feed(body, sender),
+    );
+    let summary = summary.map_err(storage_error)?;
+    info!(
+        "Imported {} lines, rejected {}",
+        summary.accepted(),
+        summary.rejected()
+    );
+    Ok(Json(summary))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::export::ExportOptions;
+    use crate::fixtures::{self, CHROME_JA4, CHROME_ON_LINUX};
+
+    type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+    const CLIENT: &str = "192.168.1.10";
+
+    fn events() -> Vec<IngestEvent> {
+        vec![
+            IngestEvent::Syn(fixtures::syn("Linux / unix / 3.11 and newer", "64")),
+            IngestEvent::HttpRequest(fixtures::http_request(CHROME_ON_LINUX, "Chrome")),
+            IngestEvent::Tls(fixtures::tls(CHROME_JA4, None)),
+        ]
+    }
+
+    fn ndjson(lines: &[String]) -> String {
+        lines.iter().map(|line| format!("{line}\n")).collect()
+    }
+
+    fn stored(state: &AppState) -> Result<Profile, Box<dyn std::error::Error>> {
+        Ok(state.store.get(CLIENT)?.ok_or("no profile stored")?)
+    }
+
+    /// The client's profile as assembled from `events()`.
+    fn assembled() -> Result<AppState, Box<dyn std::error::Error>> {
+        let state = fixtures::state()?;
+        for event in events() {
+            apply_event(&state, event, SeenAt::Observation)?;
+        }
+        Ok(state)
+    }
+
+    fn export(profile: &Profile, rows: &str) -> Result<String, serde_json::Error> {
+        let options: ExportOptions = serde_json::from_value(json!({ "rows": rows }))?;
+        Ok(options.render(profile).concat())
+    }
+
+    #[test]
+    fn every_line_shape_is_recognized() -> TestResult {
+        let profile = serde_json::to_string(&fixtures::profile(CLIENT, "2024-03-01T12:00:00Z"))?;
+        assert!(matches!(parse_line(&profile)?, Line::Profile(profile) if profile.id == CLIENT));
+
+        let syn = IngestEvent::Syn(fixtures::syn("Linux / unix / 3.11 and newer", "64"));
+        let event = serde_json::to_string(&syn)?;
+        assert!(matches!(parse_line(&event)?, Line::Event(event) if *event == syn));
+
+        let tls = fixtures::tls(CHROME_JA4, None);
+        let row = json!({ "id": CLIENT, "layer": "tls_client", "data": tls }).to_string();
+        assert!(matches!(parse_line(&row)?, Line::Event(event) if *event == IngestEvent::Tls(tls)));
+
+        for (line, error) in [
+            (r#"{"layer":"syn"}"#, "syn row without data"),
+            (r#"{"layer":"dns","data":{}}"#, "dns"),
+            (r#"{"type":"syn","data":{}}"#, "invalid event"),
+            (
+                r#"{"id":"","timestamp":0,"last_seen":""}"#,
+                "profile without an id",
+            ),
+            ("not json", "expected"),
+        ] {
+            let rejected = parse_line(line).err().ok_or(line)?;
+            assert!(rejected.contains(error), "{line}: {rejected}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn exported_profiles_import_as_they_were() -> TestResult {
+        let original = stored(&assembled()?)?;
+        let state = fixtures::state()?;
+        let summary = import(&state, export(&original, "profile")?.as_bytes());
+        assert_eq!((summary.accepted(), summary.rejected()), (1, 0));
+        // Connection records are not exported.
+        assert_eq!(
+            stored(&state)?,
+            Profile {
+                connections: Vec::new(),
+                ..original
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn exported_layers_are_replayed_into_the_same_profile() -> TestResult {
+        let original = stored(&assembled()?)?;
+        let state = fixtures::state()?;
+        let summary = import(&state, export(&original, "layer")?.as_bytes());
+        assert_eq!((summary.accepted(), summary.rejected()), (3, 0));
+
+        let replayed = stored(&state)?;
+        assert_eq!(replayed.layers(), original.layers());
+        assert_eq!(replayed.last_seen, original.last_seen);
+        assert_eq!(replayed.consistency, original.consistency);
+        Ok(())
+    }
+
+    #[test]
+    fn replayed_events_never_move_last_seen_back() -> TestResult {
+        let state = fixtures::state()?;
+        let [syn, http, tls] = <[IngestEvent; 3]>::try_from(events()).map_err(|_| "3 events")?;
+        // The TLS hello is the latest observation; the others arrive after it.
+        import(&state, ndjson(&[serde_json::to_string(&tls)?]).as_bytes());
+        let latest = stored(&state)?.last_seen;
+
+        let summary = import(
+            &state,
+            ndjson(&[serde_json::to_string(&syn)?, serde_json::to_string(&http)?]).as_bytes(),
+        );
+        assert_eq!(summary.accepted(), 2);
+        let profile = stored(&state)?;
+        assert_eq!(profile.last_seen, latest);
+        assert!(profile.syn.is_some() && profile.http_request.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_utf8_only_rejects_its_own_line() -> TestResult {
+        let state = fixtures::state()?;
+        let lines: Vec<String> = events()
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<Result<_, _>>()?;
+        let mut body = ndjson(lines.get(..1).ok_or("no events")?).into_bytes();
+        body.extend(b"{\"type\":\"syn\xff\"}\n");
+        body.extend(ndjson(lines.get(1..).ok_or("no events")?).into_bytes());
+
+        let summary = import(&state, body.as_slice());
+        assert_eq!((summary.accepted(), summary.rejected()), (3, 1));
+        assert_eq!(stored(&state)?.layers().len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn restoring_a_profile_removes_the_connections_it_replaces() -> TestResult {
+        let state = assembled()?;
+        let live = stored(&state)?;
+        let connection = live.connections.first().ok_or("no connection")?.clone();
+        assert!(state.store.connection(&connection)?.is_some());
+
+        let summary = import(&state, export(&live, "profile")?.as_bytes());
+        assert_eq!(summary.accepted(), 1);
+        assert!(stored(&state)?.connections.is_empty());
+        assert_eq!(state.store.connection(&connection)?, None);
+        Ok(())
+    }
+}
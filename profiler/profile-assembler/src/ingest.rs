@@ -0,0 +1,345 @@
+use axum::{extract::State, http::StatusCode, response::Json};
+use chrono::{DateTime, Utc};
+use metrics::counter;
+use profiler_model::{
+    check_schema_version, ConnectionRecord, HttpRequestIngest, HttpResponseIngest, IngestEvent,
+    MtuIngest, Profile, SynAckIngest, SynIngest, TlsIngest, UptimeIngest,
+};
+use serde::Serialize;
+use tracing::{error, info, warn};
+
+use crate::metrics::{INGESTED_EVENTS, REJECTED_EVENTS};
+use crate::query::Layer;
+use crate::store::{self, StoreError};
+use crate::stream::ProfileUpdate;
+use crate::{
+    consistency, eviction, is_docker_gateway_ip, map_gateway_to_real_ip, now_rfc3339,
+    storage_error, AppState,
+};
+
+/// Upper bound on the per-line errors echoed back for a single batch.
+const MAX_BATCH_ERRORS: usize = 20;
+
+type IngestResult = Result<(), (StatusCode, String)>;
+
+pub async fn ingest_syn(
+    State(state): State<AppState>,
+    Json(ingest): Json<SynIngest>,
+) -> IngestResult {
+    ingest_one(state, IngestEvent::Syn(ingest)).await
+}
+
+pub async fn ingest_syn_ack(
+    State(state): State<AppState>,
+    Json(ingest): Json<SynAckIngest>,
+) -> IngestResult {
+    ingest_one(state, IngestEvent::SynAck(ingest)).await
+}
+
+pub async fn ingest_mtu(
+    State(state): State<AppState>,
+    Json(ingest): Json<MtuIngest>,
+) -> IngestResult {
+    ingest_one(state, IngestEvent::Mtu(ingest)).await
+}
+
+pub async fn ingest_uptime(
+    State(state): State<AppState>,
+    Json(ingest): Json<UptimeIngest>,
+) -> IngestResult {
+    ingest_one(state, IngestEvent::Uptime(ingest)).await
+}
+
+pub async fn ingest_http_request(
+    State(state): State<AppState>,
+    Json(ingest): Json<HttpRequestIngest>,
+) -> IngestResult {
+    ingest_one(state, IngestEvent::HttpRequest(ingest)).await
+}
+
+pub async fn ingest_http_response(
+    State(state): State<AppState>,
+    Json(ingest): Json<HttpResponseIngest>,
+) -> IngestResult {
+    ingest_one(state, IngestEvent::HttpResponse(ingest)).await
+}
+
+pub async fn ingest_tls(
+    State(state): State<AppState>,
+    Json(ingest): Json<TlsIngest>,
+) -> IngestResult {
+    ingest_one(state, IngestEvent::Tls(ingest)).await
+}
+
+#[derive(Serialize, Default)]
+pub struct BatchSummary {
+    accepted: usize,
+    rejected: usize,
+    errors: Vec<BatchLineError>,
+}
+
+#[derive(Serialize)]
+struct BatchLineError {
+    line: usize,
+    error: String,
+}
+
+impl BatchSummary {
+    pub fn accept(&mut self) {
+        self.accepted = self.accepted.saturating_add(1);
+    }
+
+    pub fn accepted(&self) -> usize {
+        self.accepted
+    }
+
+    pub fn rejected(&self) -> usize {
+        self.rejected
+    }
+
+    pub fn reject(&mut self, line: usize, kind: &'static str, reason: &'static str, error: String) {
+        counter!(REJECTED_EVENTS, "kind" => kind, "reason" => reason).increment(1);
+        self.rejected = self.rejected.saturating_add(1);
+        if self.errors.len() < MAX_BATCH_ERRORS {
+            self.errors.push(BatchLineError { line, error });
+        }
+    }
+}
+
+/// Accepts newline-delimited [`IngestEvent`]s of mixed kinds in one request.
+///
+/// Lines are applied independently: a malformed line or a schema mismatch only rejects that
+/// line, and the summary reports which ones were dropped.
+pub async fn ingest_batch(
+    State(state): State<AppState>,
+    body: String,
+) -> Result<Json<BatchSummary>, StatusCode> {
+    let summary = store::blocking(move || Ok::<_, StoreError>(apply_batch(&state, &body)))
+        .await
+        .map_err(storage_error)?;
+    info!(
+        "Received batch: {} accepted, {} rejected",
+        summary.accepted, summary.rejected
+    );
+    if summary.rejected > 0 {
+        warn!("Rejected {} batched events", summary.rejected);
+    }
+    Ok(Json(summary))
+}
+
+fn apply_batch(state: &AppState, body: &str) -> BatchSummary {
+    let mut summary = BatchSummary::default();
+
+    for (index, line) in body.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let line_number = index.saturating_add(1);
+
+        let event = match serde_json::from_str::<IngestEvent>(line) {
+            Ok(event) => event,
+            Err(e) => {
+                summary.reject(line_number, "unknown", "malformed", e.to_string());
+                continue;
+            }
+        };
+        if let Err(e) = check_schema_version(event.schema_version()) {
+            summary.reject(
+                line_number,
+                event.kind(),
+                "schema_version",
+                format!("{} event: {e}", event.kind()),
+            );
+            continue;
+        }
+
+        let kind = event.kind();
+        if let Err(e) = apply_event(state, event, SeenAt::Arrival) {
+            error!("Failed to store {kind} event: {e}");
+            summary.reject(line_number, kind, "storage", e.to_string());
+            continue;
+        }
+        summary.accept();
+    }
+    summary
+}
+
+async fn ingest_one(state: AppState, event: IngestEvent) -> IngestResult {
+    ensure_schema_version(event.kind(), event.schema_version())?;
+    let kind = event.kind();
+    store::blocking(move || apply_event(&state, event, SeenAt::Arrival))
+        .await
+        .map_err(|e| {
+            error!("Failed to store {kind} event: {e}");
+            counter!(REJECTED_EVENTS, "kind" => kind, "reason" => "storage").increment(1);
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        })?;
+    Ok(())
+}
+
+/// Rejects payloads produced by a collector built against another schema revision.
+fn ensure_schema_version(kind: &'static str, version: u32) -> IngestResult {
+    check_schema_version(version).map_err(|e| {
+        counter!(REJECTED_EVENTS, "kind" => kind, "reason" => "schema_version").increment(1);
+        warn!("Rejected {} data: {}", kind, e);
+        (StatusCode::UNPROCESSABLE_ENTITY, e.to_string())
+    })
+}
+
+/// What an applied event sets the `last_seen` of its profile to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeenAt {
+    /// The time it was received, for live traffic.
+    Arrival,
+    /// Its collector timestamp, for replayed datasets. Out-of-order events never move
+    /// `last_seen` back.
+    Observation,
+}
+
+/// Records one event on its connection, then merges it into the profile of the client it
+/// describes.
+pub fn apply_event(
+    state: &AppState,
+    mut event: IngestEvent,
+    seen_at: SeenAt,
+) -> Result<(), StoreError> {
+    counter!(INGESTED_EVENTS, "kind" => event.kind()).increment(1);
+    match &mut event {
+        IngestEvent::Tls(ingest) => {
+            ingest.application_detected = state.ja4_db.lookup(&ingest.ja4);
+        }
+        IngestEvent::HttpRequest(ingest) => state.redaction.apply_request(&mut ingest.observed),
+        IngestEvent::HttpResponse(ingest) => state.redaction.apply_response(&mut ingest.observed),
+        _ => {}
+    }
+    // Gateway addresses are recognized before they are pseudonymized.
+    let gateway = is_docker_gateway_ip(&event.client().ip);
+    state.pseudonyms.event(&mut event);
+    let ip = match &event {
+        IngestEvent::Syn(ingest) => {
+            info!("Received SYN data for {}", ingest.source.ip);
+            ingest.source.ip.clone()
+        }
+        IngestEvent::SynAck(ingest) => {
+            info!("Received SYN-ACK data for client {}", ingest.destination.ip);
+            ingest.destination.ip.clone()
+        }
+        IngestEvent::Mtu(ingest) => {
+            info!("Received MTU data for {}", ingest.source.ip);
+            ingest.source.ip.clone()
+        }
+        IngestEvent::Uptime(ingest) => {
+            info!("Received uptime data for {}", ingest.destination.ip);
+            ingest.destination.ip.clone()
+        }
+        IngestEvent::HttpRequest(ingest) => {
+            info!("Received HTTP request data for {}", ingest.source.ip);
+            resolve_gateway_ip(state, gateway, ingest.source.ip.clone())
+        }
+        IngestEvent::HttpResponse(ingest) => {
+            info!(
+                "Received HTTP response data for client {}",
+                ingest.destination.ip
+            );
+            resolve_gateway_ip(state, gateway, ingest.destination.ip.clone())
+        }
+        IngestEvent::Tls(ingest) => {
+            info!("Received TLS data for {}", ingest.source.ip);
+            ingest.source.ip.clone()
+        }
+    };
+
+    let connection_id = ConnectionRecord::key(event.client(), event.server());
+    state.store.update_connection(
+        &connection_id,
+        Box::new(|record| {
+            record.profile = ip.clone();
+            record.apply(&event);
+        }),
+    )?;
+
+    let history_limit = state.history_limit;
+    let connection_limit = state.connections_per_profile;
+    let mut unlinked = Vec::new();
+    let layer = Layer::of(&event);
+    let observed = match seen_at {
+        SeenAt::Arrival => None,
+        SeenAt::Observation => i64::try_from(event.timestamp())
+            .ok()
+            .and_then(|seconds| DateTime::from_timestamp(seconds, 0)),
+    };
+    update_profile(state, ip, layer, observed, |profile| {
+        unlinked = profile.track_connection(&connection_id, connection_limit);
+        profile.apply(event, history_limit);
+    })?;
+    for id in &unlinked {
+        state.store.remove_connection(id)?;
+    }
+    Ok(())
+}
+
+/// Applies `update` to the profile `ip` and publishes the result to `/api/stream`. The
+/// profile counts as seen now, or at `observed` if given.
+fn update_profile(
+    state: &AppState,
+    ip: String,
+    layer: Layer,
+    observed: Option<DateTime<Utc>>,
+    update: impl FnOnce(&mut Profile),
+) -> Result<(), StoreError> {
+    let publish = state.updates.has_subscribers();
+    let mut published = None;
+    let mut last_seen = String::new();
+    state.store.update(
+        &ip,
+        Box::new(|profile| {
+            let created = profile.id.is_empty();
+            profile.id = ip.clone();
+            update(profile);
+            profile.consistency = consistency::assess(profile);
+            profile.last_seen = match observed {
+                None => now_rfc3339(),
+                Some(observed) => later_rfc3339(&profile.last_seen, observed),
+            };
+            state.latest_client.record(profile);
+            last_seen.clone_from(&profile.last_seen);
+            if publish {
+                published = Some(ProfileUpdate {
+                    id: ip.clone(),
+                    layer,
+                    created,
+                    profile: profile.clone(),
+                });
+            }
+        }),
+    )?;
+    eviction::touch(state, &ip, &last_seen);
+    if let Some(update) = published {
+        state.updates.publish(update);
+    }
+    Ok(())
+}
+
+/// The later of a stored `last_seen` and `observed`, as RFC 3339.
+fn later_rfc3339(last_seen: &str, observed: DateTime<Utc>) -> String {
+    match DateTime::parse_from_rfc3339(last_seen) {
+        Ok(known) if known >= observed => last_seen.to_string(),
+        _ => observed.to_rfc3339(),
+    }
+}
+
+/// Maps Docker gateway IPs to real client IPs for local development.
+fn resolve_gateway_ip(state: &AppState, gateway: bool, ip: String) -> String {
+    if !gateway {
+        return ip;
+    }
+    let real_ip = map_gateway_to_real_ip(state, &ip);
+    if real_ip != ip {
+        info!(
+            "Mapping Docker gateway IP {} to real client IP {}",
+            ip, real_ip
+        );
+    }
+    real_ip
+}
@@ -0,0 +1,311 @@
+//! JA4 reference database: attributes TLS fingerprints to applications, libraries and OSes.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, SystemTime};
+
+use metrics::{counter, gauge};
+use profiler_model::ApplicationDetection;
+use serde::Deserialize;
+use tokio::time::{self, MissedTickBehavior};
+use tracing::{error, info};
+
+use crate::metrics::{JA4_DB_ENTRIES, JA4_DB_RELOADS};
+
+/// One record of a database export, as published by ja4db.com. Fields other than these are
+/// ignored.
+#[derive(Deserialize)]
+struct Record {
+    ja4_fingerprint: Option<String>,
+    application: Option<String>,
+    library: Option<String>,
+    os: Option<String>,
+    #[serde(default)]
+    verified: bool,
+    #[serde(default)]
+    observation_count: Option<u64>,
+}
+
+/// Fingerprints mapped to their best match.
+#[derive(Default)]
+struct Database {
+    matches: HashMap<String, ApplicationDetection>,
+}
+
+impl Database {
+    /// Reads a JSON array of [`Record`]s.
+    ///
+    /// A fingerprint may appear in several records. The match is the application, library
+    /// and OS combination with the most observations, verified records counting double, and
+    /// its quality is that combination's share of all observations of the fingerprint. Ties go
+    /// to the combination that sorts first, so that reloads of the same file agree.
+    fn load(path: &Path) -> Result<Self, Ja4DbError> {
+        let content = fs::read_to_string(path).map_err(Ja4DbError::Read)?;
+        let records: Vec<Record> = serde_json::from_str(&content).map_err(Ja4DbError::Parse)?;
+
+        type Key = (Option<String>, Option<String>, Option<String>);
+        let mut weights: HashMap<String, HashMap<Key, u64>> = HashMap::new();
+        for record in records {
+            let Some(ja4) = record.ja4_fingerprint.filter(|ja4| !ja4.is_empty()) else {
+                continue;
+            };
+            if record.application.is_none() && record.library.is_none() && record.os.is_none() {
+                continue;
+            }
+            let mut weight = record.observation_count.unwrap_or(1).max(1);
+            if record.verified {
+                weight = weight.saturating_mul(2);
+            }
+            let entry = weights
+                .entry(ja4)
+                .or_default()
+                .entry((record.application, record.library, record.os))
+                .or_default();
+            *entry = entry.saturating_add(weight);
+        }
+
+        let matches = weights
+            .into_iter()
+            .filter_map(|(ja4, candidates)| {
+                let total: u64 = candidates.values().fold(0, |sum, w| sum.saturating_add(*w));
+                let ((application, library, os), weight) = candidates
+                    .into_iter()
+                    .max_by(|a, b| a.1.cmp(&b.1).then_with(|| b.0.cmp(&a.0)))?;
+                let detection = ApplicationDetection {
+                    application,
+                    library,
+                    os,
+                    quality: (weight as f64 / total.max(1) as f64) as f32,
+                };
+                Some((ja4, detection))
+            })
+            .collect();
+        Ok(Database { matches })
+    }
+}
+
+/// The loaded database, swapped out whole when the file changes.
+pub struct Ja4Db {
+    path: Option<PathBuf>,
+    database: RwLock<Arc<Database>>,
+    modified: RwLock<Option<SystemTime>>,
+}
+
+impl Ja4Db {
+    /// A database that matches nothing, used when none is configured.
+    pub fn disabled() -> Self {
+        Ja4Db {
+            path: None,
+            database: RwLock::new(Arc::new(Database::default())),
+            modified: RwLock::new(None),
+        }
+    }
+
+    pub fn open(path: PathBuf) -> Result<Self, Ja4DbError> {
+        let modified = modified(&path);
+        let database = Database::load(&path)?;
+        info!(
+            "Loaded {} JA4 fingerprints from {}",
+            database.matches.len(),
+            path.display()
+        );
+        gauge!(JA4_DB_ENTRIES).set(database.matches.len() as f64);
+        Ok(Ja4Db {
+            path: Some(path),
+            database: RwLock::new(Arc::new(database)),
+            modified: RwLock::new(modified),
+        })
+    }
+
+    pub fn lookup(&self, ja4: &str) -> Option<ApplicationDetection> {
+        let database = self
+            .database
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        database.matches.get(ja4).cloned()
+    }
+
+    /// Reloads the file if it changed since it was last read. A file that fails to load
+    /// leaves the current database in place.
+    fn reload_if_changed(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let current = modified(path);
+        {
+            let mut known = self
+                .modified
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
+            if current == *known {
+                return;
+            }
+            *known = current;
+        }
+
+        match Database::load(path) {
+            Ok(database) => {
+                info!(
+                    "Reloaded {} JA4 fingerprints from {}",
+                    database.matches.len(),
+                    path.display()
+                );
+                gauge!(JA4_DB_ENTRIES).set(database.matches.len() as f64);
+                counter!(JA4_DB_RELOADS, "result" => "ok").increment(1);
+                *self
+                    .database
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner) = Arc::new(database);
+            }
+            Err(e) => {
+                error!("Keeping the previous JA4 database, {}: {e}", path.display());
+                counter!(JA4_DB_RELOADS, "result" => "error").increment(1);
+            }
+        }
+    }
+}
+
+/// Periodically picks up changes to the database file.
+pub async fn watch(db: Arc<Ja4Db>, interval: Duration) {
+    if db.path.is_none() {
+        return;
+    }
+    let mut ticker = time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        db.reload_if_changed();
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+#[derive(Debug)]
+pub enum Ja4DbError {
+    Read(std::io::Error),
+    Parse(serde_json::Error),
+}
+
+impl fmt::Display for Ja4DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ja4DbError::Read(e) => write!(f, "cannot read JA4 database: {e}"),
+            Ja4DbError::Parse(e) => write!(f, "invalid JA4 database: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Ja4DbError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+    const FIXTURE: &str = r#"[
+        {"ja4_fingerprint": "t13d_weighted", "application": "Chrome", "observation_count": 3},
+        {"ja4_fingerprint": "t13d_weighted", "application": "Firefox", "observation_count": 1},
+        {"ja4_fingerprint": "t13d_weighted", "application": null, "library": null, "os": null,
+         "observation_count": 100},
+        {"ja4_fingerprint": "t13d_verified", "application": "Chrome", "observation_count": 2},
+        {"ja4_fingerprint": "t13d_verified", "library": "curl", "os": "Linux",
+         "observation_count": 3, "verified": true},
+        {"ja4_fingerprint": "t13d_tied", "application": "B", "observation_count": 2},
+        {"ja4_fingerprint": "t13d_tied", "application": "A", "observation_count": 2},
+        {"ja4_fingerprint": "t13d_uncounted", "application": "Safari"},
+        {"ja4_fingerprint": "t13d_uncounted", "application": "Edge", "observation_count": 0},
+        {"ja4_fingerprint": "t13d_nothing", "application": null, "library": null, "os": null},
+        {"ja4_fingerprint": "", "application": "Opera"},
+        {"application": "Brave", "observation_count": 50, "comment": "unknown fields are fine"}
+    ]"#;
+
+    fn load(name: &str, content: &str) -> Result<Database, Box<dyn std::error::Error>> {
+        let path = std::env::temp_dir().join(format!("ja4db-{name}-{}.json", std::process::id()));
+        fs::write(&path, content)?;
+        let database = Database::load(&path);
+        fs::remove_file(&path)?;
+        Ok(database?)
+    }
+
+    fn detection(
+        application: Option<&str>,
+        library: Option<&str>,
+        os: Option<&str>,
+        quality: f32,
+    ) -> Option<ApplicationDetection> {
+        Some(ApplicationDetection {
+            application: application.map(str::to_string),
+            library: library.map(str::to_string),
+            os: os.map(str::to_string),
+            quality,
+        })
+    }
+
+    #[test]
+    fn the_heaviest_combination_wins_with_its_share_as_quality() -> TestResult {
+        let database = load("weights", FIXTURE)?;
+        let matched = |ja4: &str| database.matches.get(ja4).cloned();
+
+        // Records with nothing to attribute weigh nothing, not even in the total.
+        assert_eq!(
+            matched("t13d_weighted"),
+            detection(Some("Chrome"), None, None, 0.75)
+        );
+        // A verified 3 counts as 6 against an unverified 2.
+        assert_eq!(
+            matched("t13d_verified"),
+            detection(None, Some("curl"), Some("Linux"), 0.75)
+        );
+        // A missing or zero count is one observation.
+        assert_eq!(
+            matched("t13d_uncounted").map(|detection| detection.quality),
+            Some(0.5)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn ties_always_go_to_the_same_combination() -> TestResult {
+        for _ in 0..16 {
+            let database = load("ties", FIXTURE)?;
+            assert_eq!(
+                database.matches.get("t13d_tied").cloned(),
+                detection(Some("A"), None, None, 0.5)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn records_without_a_fingerprint_or_a_match_are_skipped() -> TestResult {
+        let database = load("skipped", FIXTURE)?;
+        let mut fingerprints: Vec<&str> = database.matches.keys().map(String::as_str).collect();
+        fingerprints.sort_unstable();
+        assert_eq!(
+            fingerprints,
+            [
+                "t13d_tied",
+                "t13d_uncounted",
+                "t13d_verified",
+                "t13d_weighted"
+            ]
+        );
+
+        assert!(load("empty", "[]")?.matches.is_empty());
+        assert!(matches!(
+            load("invalid", "{}")
+                .err()
+                .and_then(|e| e.downcast::<Ja4DbError>().ok())
+                .as_deref(),
+            Some(Ja4DbError::Parse(_))
+        ));
+        Ok(())
+    }
+}
@@ -1,189 +1,210 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+mod auth;
+mod consistency;
+mod diff;
+mod eviction;
+mod export;
+mod fingerprints;
+#[cfg(test)]
+mod fixtures;
+mod gateway;
+mod import;
+mod ingest;
+mod ja4db;
+mod metrics;
+mod pseudonym;
+mod query;
+mod ratelimit;
+mod store;
+mod stream;
+mod tls;
+
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use axum::{
-    extract::{Path, State},
-    http::{HeaderMap, StatusCode},
+    extract::{ConnectInfo, DefaultBodyLimit, Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware,
     response::Json,
     routing::{get, post},
     Router,
 };
 use chrono::Utc;
-use dashmap::DashMap;
+use clap::builder::RangedU64ValueParser;
+use clap::Parser;
+use profiler_config::ConfigArgs;
+use profiler_model::{
+    ConnectionRecord, Profile, ProfileHistory, RedactionPolicy, RedactionRules,
+    DEFAULT_COOKIE_REDACTION, DEFAULT_HEADER_REDACTION,
+};
 use serde::{Deserialize, Serialize};
-use tower_http::cors::{Any, CorsLayer};
-use tracing::{debug, error, info, warn, Level};
+use tower_http::cors::{AllowHeaders, AllowOrigin, Any, CorsLayer};
+use tracing::{error, info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct SynPacketData {
-    pub source: NetworkEndpoint,
-    pub destination: NetworkEndpoint,
-    pub os_detected: OsDetection,
-    pub signature: String,
-    pub observed: TcpObserved,
-    pub timestamp: u64,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct NetworkEndpoint {
-    pub ip: String,
-    pub port: u16,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct OsDetection {
-    pub os: String,
-    pub quality: f32,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct TcpObserved {
-    pub version: String,
-    pub initial_ttl: String,
-    pub options_length: u8,
-    pub mss: Option<u16>,
-    pub window_size: String,
-    pub window_scale: Option<u8>,
-    pub options_layout: String,
-    pub quirks: String,
-    pub payload_class: String,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct SynAckPacketData {
-    pub source: NetworkEndpoint,
-    pub destination: NetworkEndpoint,
-    pub os_detected: OsDetection,
-    pub signature: String,
-    pub observed: TcpObserved,
-    pub timestamp: u64,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct MtuData {
-    pub source: NetworkEndpoint,
-    pub destination: NetworkEndpoint,
-    pub link: String,
-    pub mtu_value: u16,
-    pub timestamp: u64,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct UptimeData {
-    pub source: NetworkEndpoint,
-    pub destination: NetworkEndpoint,
-    pub uptime_seconds: u64,
-    pub up_mod_days: u32,
-    pub freq: f64,
-    pub timestamp: u64,
-}
-
-type SynIngest = SynPacketData;
-type SynAckIngest = SynAckPacketData;
-type MtuIngest = MtuData;
-type UptimeIngest = UptimeData;
-
-type HttpRequestIngest = HttpRequestData;
-type HttpResponseIngest = HttpResponseData;
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct BrowserDetection {
-    pub browser: String,
-    pub quality: f32,
-}
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct HttpRequestObserved {
-    pub lang: Option<String>,
-    pub user_agent: Option<String>,
-    pub diagnostic: String,
-    pub method: Option<String>,
-    pub version: String,
-    pub headers: String,
-    pub cookies: String,
-    pub referer: Option<String>,
-    pub uri: Option<String>,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct HttpRequestData {
-    pub source: NetworkEndpoint,
-    pub destination: NetworkEndpoint,
-    pub observed: HttpRequestObserved,
-    pub signature: String,
-    pub browser: BrowserDetection,
-    pub timestamp: u64,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct WebServerDetection {
-    pub web_server: String,
-    pub quality: f32,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct HttpResponseObserved {
-    pub server: Option<String>,
-    pub version: String,
-    pub headers: String,
-    pub status_code: Option<u16>,
-}
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct HttpResponseData {
-    pub source: NetworkEndpoint,
-    pub destination: NetworkEndpoint,
-    pub observed: HttpResponseObserved,
-    pub signature: String,
-    pub web_server: WebServerDetection,
-    pub timestamp: u64,
-}
-
-#[derive(Serialize, Clone, Deserialize, Debug)]
-pub struct TlsClient {
-    pub timestamp: u64,
-    pub source: NetworkEndpoint,
-    pub destination: NetworkEndpoint,
-    pub ja4: String,
-    pub ja4_raw: String,
-    pub ja4_original: String,
-    pub ja4_original_raw: String,
-    pub observed: TlsClientObserved,
-}
-
-#[derive(Serialize, Clone, Deserialize, Debug)]
-pub struct TlsClientObserved {
-    pub version: String,
-    pub sni: Option<String>,
-    pub alpn: Option<String>,
-    pub cipher_suites: Vec<u16>,
-    pub extensions: Vec<u16>,
-    pub signature_algorithms: Vec<u16>,
-    pub elliptic_curves: Vec<u16>,
-}
-
-type TlsIngest = TlsClient;
-
-#[derive(Serialize, Clone, Debug, Default)]
-struct Profile {
-    id: String,
-    timestamp: u64,
-    syn: Option<SynPacketData>,
-    syn_ack: Option<SynAckPacketData>,
-    mtu: Option<MtuData>,
-    uptime: Option<UptimeData>,
-    http_request: Option<HttpRequestData>,
-    http_response: Option<HttpResponseData>,
-    tls_client: Option<TlsClient>,
-    last_seen: String,
-}
-
-type AppState = Arc<DashMap<String, Profile>>;
-
-const MAX_PROFILES: usize = 100;
+use crate::auth::Auth;
+use crate::eviction::Eviction;
+use crate::gateway::LatestClient;
+use crate::ja4db::Ja4Db;
+use crate::pseudonym::{PseudonymMode, Pseudonymizer};
+use crate::query::{ProfileQuery, SelectError};
+use crate::ratelimit::{Limit, RateLimiter, TrustedProxies};
+use crate::store::{ProfileStore, StorageKind, StoreError};
+use crate::stream::Updates;
+use crate::tls::{Tls, TlsFiles};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// Address the HTTP API listens on.
+    #[clap(long, value_parser, default_value = "0.0.0.0:8000")]
+    listen: SocketAddr,
+    /// Where profiles are kept: `memory` loses them on restart, `sqlite` persists them.
+    #[clap(long, value_enum, default_value_t = StorageKind::Memory)]
+    storage: StorageKind,
+    /// Database file used with `--storage sqlite`.
+    #[clap(long, value_parser, default_value = "profiles.db")]
+    sqlite_path: PathBuf,
+    /// Profiles kept; the least recently seen are evicted beyond it.
+    #[clap(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..), default_value_t = 10_000)]
+    max_profiles: usize,
+    /// Evict profiles not seen for this many seconds. Off by default.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    profile_idle_timeout_secs: Option<u64>,
+    /// How often idle profiles are swept, in seconds.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 60)]
+    sweep_interval_secs: u64,
+    /// Distinct signatures remembered per layer of a profile's history; the least recently
+    /// seen is forgotten beyond it.
+    #[clap(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..), default_value_t = 16)]
+    history_limit: usize,
+    /// Connection records kept per profile; older connections are forgotten beyond it.
+    #[clap(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..), default_value_t = 32)]
+    connections_per_profile: usize,
+    /// Profile updates buffered for each `/api/stream` client; a client further behind
+    /// misses the oldest ones.
+    #[clap(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..), default_value_t = 1024)]
+    stream_buffer: usize,
+    /// JA4 database (a JSON export from ja4db.com) used to attribute TLS fingerprints to
+    /// applications. Off by default.
+    #[clap(long, value_parser)]
+    ja4_db: Option<PathBuf>,
+    /// How often the JA4 database file is checked for changes, in seconds.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 30)]
+    ja4_db_reload_secs: u64,
+    /// NDJSON file of exported profiles or ingest events to load at startup, before the API
+    /// is served. Replayed events keep their original timestamps.
+    #[clap(long, value_parser)]
+    import: Option<PathBuf>,
+    /// TOML file of the keys collectors use to ingest and API clients use to read profiles.
+    /// Every route is open without it.
+    #[clap(long, value_parser)]
+    auth_keys: Option<PathBuf>,
+    /// How far the timestamp of a signed ingest request may be from the assembler's clock,
+    /// in seconds.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 300)]
+    auth_max_skew_secs: u64,
+    /// Comma-separated origins browsers may call the API from, or `*` for any.
+    #[clap(long, value_parser, default_value = "*")]
+    cors_origins: String,
+    /// Header redaction rules applied to HTTP observations before they are stored, in the
+    /// collector's `--redact-headers` form. Catches collectors that redact less.
+    #[clap(long, value_parser = str::parse::<RedactionRules>, default_value = DEFAULT_HEADER_REDACTION)]
+    redact_headers: RedactionRules,
+    /// Cookie redaction rules applied before storage, in the same form.
+    #[clap(long, value_parser = str::parse::<RedactionRules>, default_value = DEFAULT_COOKIE_REDACTION)]
+    redact_cookies: RedactionRules,
+    /// Replaces client IPs before they are stored, served or logged: `truncate` keeps their
+    /// network prefix, `hash` a keyed hash whose key rotates. Off by default.
+    #[clap(long, value_enum, default_value_t = PseudonymMode::Off)]
+    ip_pseudonymization: PseudonymMode,
+    /// Prefix length kept of IPv4 addresses with `--ip-pseudonymization truncate`.
+    #[clap(long, value_parser = clap::value_parser!(u8).range(0..=32), default_value_t = 24)]
+    ip_truncate_v4_prefix: u8,
+    /// Prefix length kept of IPv6 addresses with `--ip-pseudonymization truncate`.
+    #[clap(long, value_parser = clap::value_parser!(u8).range(0..=128), default_value_t = 48)]
+    ip_truncate_v6_prefix: u8,
+    /// File holding the secret the keys of `--ip-pseudonymization hash` are derived from.
+    /// Without it a random secret is drawn at startup, so pseudonyms change on restart.
+    #[clap(long, value_parser)]
+    ip_hash_secret_file: Option<PathBuf>,
+    /// How long one hash key is used, in seconds. A client gets a new pseudonym, and so a
+    /// new profile, every period.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 86_400)]
+    ip_hash_rotation_secs: u64,
+    /// PEM certificate chain to serve HTTPS with. The API is served over plain HTTP without
+    /// it.
+    #[clap(long, value_parser, requires = "tls_key_file")]
+    tls_cert_file: Option<PathBuf>,
+    /// PEM private key of `--tls-cert-file`.
+    #[clap(long, value_parser, requires = "tls_cert_file")]
+    tls_key_file: Option<PathBuf>,
+    /// PEM bundle of the CAs client certificates must be signed by. Every client, collectors
+    /// and API readers alike, must then present one.
+    #[clap(long, value_parser, requires = "tls_cert_file")]
+    tls_client_ca_file: Option<PathBuf>,
+    /// How often the TLS files are checked for changes, in seconds.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = 30)]
+    tls_reload_secs: u64,
+    /// Requests per minute each client may make to the ingest routes; 0 turns the limit off.
+    #[clap(long, value_parser, default_value_t = 1200)]
+    rate_limit_ingest_per_min: u32,
+    /// Ingest requests a client may make in a burst.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..), default_value_t = 200)]
+    rate_limit_ingest_burst: u32,
+    /// Requests per minute each client may make to the read routes, `/api/my-profile`
+    /// included; 0 turns the limit off.
+    #[clap(long, value_parser, default_value_t = 120)]
+    rate_limit_read_per_min: u32,
+    /// Read requests a client may make in a burst.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..), default_value_t = 30)]
+    rate_limit_read_burst: u32,
+    /// Requests per minute each client may make to the admin routes; 0 turns the limit off.
+    #[clap(long, value_parser, default_value_t = 6)]
+    rate_limit_admin_per_min: u32,
+    /// Admin requests a client may make in a burst.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..), default_value_t = 2)]
+    rate_limit_admin_burst: u32,
+    /// Comma-separated addresses and networks of reverse proxies, such as Traefik. Requests
+    /// they pass on are rate limited, and matched to `/api/my-profile`, by `X-Real-Ip` or
+    /// `X-Forwarded-For` instead of by the proxy's own address.
+    #[clap(long, value_parser = str::parse::<TrustedProxies>, default_value = "")]
+    trusted_proxies: TrustedProxies,
+    /// Log verbosity: error, warn, info, debug or trace.
+    #[clap(long, value_parser, default_value = "info")]
+    log_level: Level,
+    #[command(flatten)]
+    config: ConfigArgs,
+}
+
+#[derive(Clone)]
+struct AppState {
+    store: Arc<dyn ProfileStore>,
+    eviction: Arc<Eviction>,
+    history_limit: usize,
+    connections_per_profile: usize,
+    updates: Arc<Updates>,
+    ja4_db: Arc<Ja4Db>,
+    redaction: Arc<RedactionPolicy>,
+    pseudonyms: Arc<Pseudonymizer>,
+    latest_client: Arc<LatestClient>,
+    trusted_proxies: Arc<TrustedProxies>,
+}
+
+/// Request body limit for `/api/ingest/batch`, well above the collectors' default batch size.
+const BATCH_BODY_LIMIT: usize = 16 * 1024 * 1024;
 
 #[tokio::main]
 async fn main() {
+    let args: Args = match profiler_config::load(&["assembler"]) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e}");
+            return;
+        }
+    };
+
     let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::INFO)
+        .with_max_level(args.log_level)
         .finish();
 
     if let Err(e) = tracing::subscriber::set_global_default(subscriber) {
@@ -193,30 +214,272 @@ async fn main() {
 
     info!("Initializing Profile Assembler");
 
-    let state = AppState::new(DashMap::new());
+    let metrics_handle = match metrics::install() {
+        Ok(handle) => handle,
+        Err(e) => {
+            error!("Failed to install metrics recorder: {e}");
+            return;
+        }
+    };
 
-    let app = Router::new()
-        .route("/api/ingest/syn", post(ingest_syn))
-        .route("/api/ingest/syn_ack", post(ingest_syn_ack))
-        .route("/api/ingest/mtu", post(ingest_mtu))
-        .route("/api/ingest/uptime", post(ingest_uptime))
-        .route("/api/ingest/http_request", post(ingest_http_request))
-        .route("/api/ingest/http_response", post(ingest_http_response))
-        .route("/api/ingest/tls", post(ingest_tls))
+    let store = match store::open(args.storage, &args.sqlite_path) {
+        Ok(store) => store,
+        Err(e) => {
+            error!("Failed to open {} storage: {e}", args.storage);
+            return;
+        }
+    };
+    match args.storage {
+        StorageKind::Memory => info!("Keeping profiles in memory"),
+        StorageKind::Sqlite => info!("Keeping profiles in {}", args.sqlite_path.display()),
+    }
+
+    let ja4_db = match args.ja4_db {
+        Some(path) => match Ja4Db::open(path) {
+            Ok(db) => db,
+            Err(e) => {
+                error!("Failed to load the JA4 database: {e}");
+                return;
+            }
+        },
+        None => Ja4Db::disabled(),
+    };
+
+    let pseudonyms = Arc::new(match args.ip_pseudonymization {
+        PseudonymMode::Off => Pseudonymizer::off(),
+        PseudonymMode::Truncate => {
+            info!(
+                "Truncating client IPs to /{} and /{}",
+                args.ip_truncate_v4_prefix, args.ip_truncate_v6_prefix
+            );
+            Pseudonymizer::truncate(args.ip_truncate_v4_prefix, args.ip_truncate_v6_prefix)
+        }
+        PseudonymMode::Hash => {
+            let secret = match &args.ip_hash_secret_file {
+                Some(path) => match pseudonym::read_secret(path) {
+                    Ok(secret) => secret,
+                    Err(e) => {
+                        error!("Failed to read {}: {e}", path.display());
+                        return;
+                    }
+                },
+                None => rand::random::<[u8; 32]>().to_vec(),
+            };
+            info!(
+                "Hashing client IPs with a key rotated every {} seconds",
+                args.ip_hash_rotation_secs
+            );
+            Pseudonymizer::hash(secret, args.ip_hash_rotation_secs)
+        }
+    });
+
+    let auth = match &args.auth_keys {
+        Some(path) => match Auth::open(path, args.auth_max_skew_secs, pseudonyms.clone()) {
+            Ok(auth) => {
+                info!(
+                    "Loaded {} ingest, {} read and {} admin keys from {}",
+                    auth.ingest_keys(),
+                    auth.read_keys(),
+                    auth.admin_keys(),
+                    path.display()
+                );
+                auth
+            }
+            Err(e) => {
+                error!("Failed to load API keys: {e}");
+                return;
+            }
+        },
+        None => {
+            warn!("No --auth-keys given, anyone who can reach the API can ingest and read");
+            Auth::disabled()
+        }
+    };
+    let auth = Arc::new(auth);
+
+    let tls = match (&args.tls_cert_file, &args.tls_key_file) {
+        (Some(cert), Some(key)) => {
+            let files = TlsFiles {
+                cert: cert.clone(),
+                key: key.clone(),
+                client_ca: args.tls_client_ca_file.clone(),
+            };
+            match Tls::open(files) {
+                Ok(tls) => Some(Arc::new(tls)),
+                Err(e) => {
+                    error!("Failed to load TLS certificates: {e}");
+                    return;
+                }
+            }
+        }
+        _ => None,
+    };
+    let cors_origins = match cors_origins(&args.cors_origins) {
+        Ok(origins) => origins,
+        Err(e) => {
+            error!("Invalid --cors-origins: {e}");
+            return;
+        }
+    };
+
+    let state = AppState {
+        store,
+        eviction: Arc::new(Eviction::new(
+            args.max_profiles,
+            args.profile_idle_timeout_secs.map(Duration::from_secs),
+        )),
+        history_limit: args.history_limit,
+        connections_per_profile: args.connections_per_profile,
+        updates: Arc::new(Updates::new(args.stream_buffer)),
+        ja4_db: Arc::new(ja4_db),
+        redaction: Arc::new(RedactionPolicy {
+            headers: args.redact_headers.clone(),
+            cookies: args.redact_cookies.clone(),
+        }),
+        pseudonyms: pseudonyms.clone(),
+        latest_client: Arc::new(LatestClient::default()),
+        trusted_proxies: Arc::new(args.trusted_proxies.clone()),
+    };
+    eviction::seed(&state);
+    state.latest_client.seed(&*state.store);
+    if let Some(path) = &args.import {
+        match import::import_file(&state, path) {
+            Ok(summary) => info!(
+                "Imported {}: {} lines accepted, {} rejected",
+                path.display(),
+                summary.accepted(),
+                summary.rejected()
+            ),
+            Err(e) => {
+                error!("Failed to import {}: {e}", path.display());
+                return;
+            }
+        }
+    }
+    tokio::spawn(eviction::sweep(
+        state.clone(),
+        Duration::from_secs(args.sweep_interval_secs),
+    ));
+    tokio::spawn(ja4db::watch(
+        state.ja4_db.clone(),
+        Duration::from_secs(args.ja4_db_reload_secs),
+    ));
+
+    let limiter = Arc::new(RateLimiter::new(
+        Limit {
+            per_minute: args.rate_limit_ingest_per_min,
+            burst: args.rate_limit_ingest_burst,
+        },
+        Limit {
+            per_minute: args.rate_limit_read_per_min,
+            burst: args.rate_limit_read_burst,
+        },
+        Limit {
+            per_minute: args.rate_limit_admin_per_min,
+            burst: args.rate_limit_admin_burst,
+        },
+        args.trusted_proxies.clone(),
+        state.pseudonyms.clone(),
+    ));
+    tokio::spawn(ratelimit::sweep(limiter.clone(), Duration::from_secs(60)));
+
+    let ingest_routes = Router::new()
+        .route("/api/ingest/syn", post(ingest::ingest_syn))
+        .route("/api/ingest/syn_ack", post(ingest::ingest_syn_ack))
+        .route("/api/ingest/mtu", post(ingest::ingest_mtu))
+        .route("/api/ingest/uptime", post(ingest::ingest_uptime))
+        .route(
+            "/api/ingest/http_request",
+            post(ingest::ingest_http_request),
+        )
+        .route(
+            "/api/ingest/http_response",
+            post(ingest::ingest_http_response),
+        )
+        .route("/api/ingest/tls", post(ingest::ingest_tls))
+        .route(
+            "/api/ingest/batch",
+            post(ingest::ingest_batch).layer(DefaultBodyLimit::max(BATCH_BODY_LIMIT)),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            auth.clone(),
+            auth::require_ingest,
+        ))
+        // Outermost, so that refused requests cost no authentication either.
+        .route_layer(middleware::from_fn_with_state(
+            limiter.clone(),
+            ratelimit::limit_ingest,
+        ));
+
+    let admin_routes = Router::new()
+        .route("/api/admin/import", post(import::import_profiles))
+        .route_layer(middleware::from_fn_with_state(
+            auth.clone(),
+            auth::require_admin,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            limiter.clone(),
+            ratelimit::limit_admin,
+        ));
+
+    let read_routes = Router::new()
         .route("/api/profiles", get(get_profiles))
+        .route("/api/profiles/diff", get(diff::diff_profiles))
         .route("/api/profiles/{id}", get(get_profile_by_id))
+        .route("/api/profiles/{id}/history", get(get_profile_history))
+        .route(
+            "/api/profiles/{id}/connections",
+            get(get_profile_connections),
+        )
+        .route("/api/connections/{id}", get(get_connection))
+        .route(
+            "/api/fingerprints/{kind}",
+            get(fingerprints::list_fingerprints),
+        )
+        // Signatures may contain `/`, so the value takes the rest of the path.
+        .route(
+            "/api/fingerprints/{kind}/{*value}",
+            get(fingerprints::get_fingerprint),
+        )
+        .route("/api/export", get(export::export_profiles))
+        .route("/api/stream", get(stream::stream_updates))
         .route("/api/stats", get(get_stats))
-        .route("/api/my-profile", get(get_my_profile))
+        .route_layer(middleware::from_fn_with_state(auth, auth::require_read))
+        .route_layer(middleware::from_fn_with_state(
+            limiter.clone(),
+            ratelimit::limit_read,
+        ));
+
+    let app = Router::new()
+        .merge(ingest_routes)
+        .merge(admin_routes)
+        .merge(read_routes)
+        // Visitors see their own profile without a key. It is found by the address they
+        // connect from, which they cannot choose the way they can choose headers.
+        .route(
+            "/api/my-profile",
+            get(get_my_profile).layer(middleware::from_fn_with_state(
+                limiter,
+                ratelimit::limit_read,
+            )),
+        )
         .route("/health", get(health_check))
+        .route(
+            "/metrics",
+            get(move || std::future::ready(metrics_handle.render())),
+        )
+        .layer(middleware::from_fn(metrics::track_requests))
         .layer(
             CorsLayer::new()
-                .allow_origin(Any)
+                .allow_origin(cors_origins)
                 .allow_methods(Any)
-                .allow_headers(Any),
+                // A wildcard would not cover `Authorization`.
+                .allow_headers(AllowHeaders::mirror_request())
+                .expose_headers([export::NEXT_CURSOR, header::RETRY_AFTER]),
         )
         .with_state(state);
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 8000));
+    let addr = args.listen;
     info!("Profile Assembler listening on {}", addr);
     let listener = match tokio::net::TcpListener::bind(addr).await {
         Ok(listener) => listener,
@@ -225,252 +488,257 @@ async fn main() {
             return;
         }
     };
-    if let Err(e) = axum::serve(listener, app).await {
+    if let Some(tls) = tls {
+        tokio::spawn(tls::watch(
+            tls.clone(),
+            Duration::from_secs(args.tls_reload_secs),
+        ));
+        tls::serve(listener, app, tls, pseudonyms).await;
+        return;
+    }
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+    if let Err(e) = axum::serve(listener, service).await {
         error!("Server error: {e}");
     }
 }
 
+fn cors_origins(origins: &str) -> Result<AllowOrigin, String> {
+    if origins.trim() == "*" {
+        return Ok(AllowOrigin::any());
+    }
+    let origins = origins
+        .split(',')
+        .map(str::trim)
+        .filter(|origin| !origin.is_empty())
+        .map(|origin| HeaderValue::from_str(origin).map_err(|_| format!("`{origin}`")))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(AllowOrigin::list(origins))
+}
+
 async fn health_check() -> StatusCode {
     StatusCode::OK
 }
 
+/// How `/api/profiles` lays out the profiles of a page.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Shape {
+    /// An object keyed by id, as the dashboard reads it. JSON parsers need not keep the order
+    /// of its keys.
+    #[default]
+    Object,
+    /// An array in sort order.
+    Array,
+}
+
+/// `GET /api/profiles` parameters, on top of those of [`ProfileQuery`].
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ProfilesOptions {
+    shape: Shape,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ProfileList {
+    #[serde(serialize_with = "query::serialize_by_id")]
+    Object(Vec<Profile>),
+    Array(Vec<Profile>),
+}
+
 #[derive(Serialize)]
 struct ProfilesResponse {
-    profiles: HashMap<String, Profile>,
+    profiles: ProfileList,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
 }
 
-async fn get_profiles(State(state): State<AppState>) -> Json<ProfilesResponse> {
-    info!("Fetching all profiles");
-    let profiles = state
-        .iter()
-        .map(|entry| (entry.key().clone(), entry.value().clone()))
-        .collect();
-    Json(ProfilesResponse { profiles })
+async fn get_profiles(
+    State(state): State<AppState>,
+    Query(query): Query<ProfileQuery>,
+    Query(options): Query<ProfilesOptions>,
+) -> Result<Json<ProfilesResponse>, StatusCode> {
+    info!("Fetching profiles");
+    let store = state.store.clone();
+    let page = store::blocking(move || query.select(store::profiles(&*store)))
+        .await
+        .map_err(|e| select_error("/api/profiles", e))?;
+    let profiles = match options.shape {
+        Shape::Object => ProfileList::Object(page.profiles),
+        Shape::Array => ProfileList::Array(page.profiles),
+    };
+    Ok(Json(ProfilesResponse {
+        profiles,
+        next_cursor: page.next_cursor,
+    }))
 }
 
 async fn get_my_profile(
     State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
     headers: HeaderMap,
 ) -> Result<Json<Profile>, StatusCode> {
-    let client_ip = headers
-        .get("X-Real-Ip")
-        .or_else(|| headers.get("X-Forwarded-For"))
-        .and_then(|h| h.to_str().ok())
-        .and_then(|s| s.split(',').next())
-        .map(|s| s.trim().to_string());
-
-    if let Some(ip) = client_ip {
-        info!("Fetching profile for client IP from headers: {}", ip);
-
-        // Map Docker gateway IPs to real client IPs for local development
-        let target_ip = if is_docker_gateway_ip(&ip) {
-            let real_ip = map_gateway_to_real_ip(&state, &ip);
-            if real_ip != ip {
-                info!(
-                    "Gateway IP {} detected, mapping to real client IP: {}",
-                    ip, real_ip
-                );
-            }
-            real_ip
-        } else {
-            ip.clone()
-        };
+    let raw_ip = state
+        .trusted_proxies
+        .client(peer.ip(), &headers)
+        .to_string();
+    store::blocking(move || my_profile(&state, &raw_ip))
+        .await
+        .map(Json)
+        .map_err(storage_error)
+}
 
-        if let Some(profile) = state.get(&target_ip) {
-            Ok(Json(profile.value().clone()))
-        } else {
-            // If not found, try to find IPv4/IPv6 variant of the same IP
-            // This handles cases where browser uses IPv6 but collectors captured IPv4 (or vice versa)
-            let is_ipv6 = target_ip.contains(':');
-            let variant_profile = if is_ipv6 {
-                // For IPv6, try to find corresponding IPv4 profile (same client, different protocol)
-                // Look for IPv4 profiles that might correspond to the same client
-                state
-                    .iter()
-                    .filter(|entry| {
-                        let key = entry.key();
-                        !key.contains(':') && !is_docker_gateway_ip(key)
-                    })
-                    .max_by_key(|entry| entry.value().last_seen.clone())
-            } else {
-                // For IPv4, try to find corresponding IPv6 profile
-                state
-                    .iter()
-                    .filter(|entry| {
-                        let key = entry.key();
-                        key.contains(':') && !is_docker_gateway_ip(key)
-                    })
-                    .max_by_key(|entry| entry.value().last_seen.clone())
-            };
+/// The profile of the client `raw_ip`, or an empty one if there is none.
+fn my_profile(state: &AppState, raw_ip: &str) -> Result<Profile, StoreError> {
+    let ip = state.pseudonyms.ip(raw_ip);
+    info!("Fetching profile for client IP: {}", ip);
 
-            if let Some(entry) = variant_profile {
-                let variant_ip = entry.key();
-                info!(
-                    "Profile not found for {}, using IPv4/IPv6 variant: {}",
-                    target_ip, variant_ip
-                );
-                Ok(Json(entry.value().clone()))
-            } else {
-                warn!(
-                    "No profile found for client IP: {} (target: {})",
-                    ip, target_ip
-                );
-                Ok(Json(Profile::default()))
-            }
+    // Map Docker gateway IPs to real client IPs for local development
+    let target_ip = if is_docker_gateway_ip(raw_ip) {
+        let real_ip = map_gateway_to_real_ip(state, &ip);
+        if real_ip != ip {
+            info!(
+                "Gateway IP {} detected, mapping to real client IP: {}",
+                ip, real_ip
+            );
         }
+        real_ip
     } else {
-        warn!("X-Real-Ip or X-Forwarded-For header not found or invalid.");
-        Ok(Json(Profile::default()))
+        ip.clone()
+    };
+
+    if let Some(profile) = state.store.get(&target_ip)? {
+        return Ok(profile);
     }
+    warn!(
+        "No profile found for client IP: {} (target: {})",
+        ip, target_ip
+    );
+    Ok(Profile::default())
 }
 
 async fn get_profile_by_id(
     State(state): State<AppState>,
     Path(id): Path<String>,
 ) -> Result<Json<Profile>, StatusCode> {
+    let id = state.pseudonyms.ip(&id);
     info!("Fetching profile for ID: {}", id);
-    if let Some(profile) = state.get(&id) {
-        Ok(Json(profile.value().clone()))
-    } else {
-        Err(StatusCode::NOT_FOUND)
+    let store = state.store.clone();
+    match store::blocking(move || store.get(&id))
+        .await
+        .map_err(storage_error)?
+    {
+        Some(profile) => Ok(Json(profile)),
+        None => Err(StatusCode::NOT_FOUND),
     }
 }
 
-async fn ingest_syn(State(state): State<AppState>, Json(ingest): Json<SynIngest>) {
-    let ip = ingest.source.ip.clone();
-    info!("Received SYN data for {}", ip);
-    let mut profile = state.entry(ip.clone()).or_default();
-    profile.id = ip;
-    profile.syn = Some(ingest);
-    profile.last_seen = now_rfc3339();
-    drop(profile); // Release the lock before cleanup
-    enforce_profile_limit(&state);
-}
-
-async fn ingest_syn_ack(State(state): State<AppState>, Json(ingest): Json<SynAckIngest>) {
-    let client_ip = ingest.destination.ip.clone();
-    info!("Received SYN-ACK data for client {}", client_ip);
-    let mut profile = state.entry(client_ip.clone()).or_default();
-    profile.id = client_ip;
-    profile.syn_ack = Some(ingest);
-    profile.last_seen = now_rfc3339();
-    drop(profile);
-    enforce_profile_limit(&state);
-}
-
-async fn ingest_mtu(State(state): State<AppState>, Json(ingest): Json<MtuIngest>) {
-    let ip = ingest.source.ip.clone();
-    info!("Received MTU data for {}", ip);
-    let mut profile = state.entry(ip.clone()).or_default();
-    profile.id = ip;
-    profile.mtu = Some(ingest);
-    profile.last_seen = now_rfc3339();
-    drop(profile);
-    enforce_profile_limit(&state);
+#[derive(Serialize)]
+struct HistoryResponse {
+    id: String,
+    last_seen: String,
+    history: ProfileHistory,
 }
 
-async fn ingest_uptime(State(state): State<AppState>, Json(ingest): Json<UptimeIngest>) {
-    let ip = ingest.destination.ip.clone();
-    info!("Received uptime data for {}", ip);
-    let mut profile = state.entry(ip.clone()).or_default();
-    profile.id = ip;
-    profile.uptime = Some(ingest);
-    profile.last_seen = now_rfc3339();
-    drop(profile);
-    enforce_profile_limit(&state);
+async fn get_profile_history(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<HistoryResponse>, StatusCode> {
+    let id = state.pseudonyms.ip(&id);
+    info!("Fetching history for ID: {}", id);
+    let store = state.store.clone();
+    match store::blocking(move || store.get(&id))
+        .await
+        .map_err(storage_error)?
+    {
+        Some(profile) => Ok(Json(HistoryResponse {
+            id: profile.id,
+            last_seen: profile.last_seen,
+            history: profile.history,
+        })),
+        None => Err(StatusCode::NOT_FOUND),
+    }
 }
 
-async fn ingest_http_request(State(state): State<AppState>, Json(ingest): Json<HttpRequestIngest>) {
-    let ip = ingest.source.ip.clone();
-    info!("Received HTTP request data for {}", ip);
-
-    // Map Docker gateway IPs to real client IPs for local development
-    let target_ip = if is_docker_gateway_ip(&ip) {
-        let real_ip = map_gateway_to_real_ip(&state, &ip);
-        if real_ip != ip {
-            info!(
-                "Mapping Docker gateway IP {} to real client IP {}",
-                ip, real_ip
-            );
-        }
-        real_ip
-    } else {
-        ip
-    };
-
-    let mut profile = state.entry(target_ip.clone()).or_default();
-    profile.id = target_ip;
-    profile.http_request = Some(ingest);
-    profile.last_seen = now_rfc3339();
-    drop(profile);
-    enforce_profile_limit(&state);
+#[derive(Serialize)]
+struct ConnectionsResponse {
+    id: String,
+    connections: Vec<ConnectionRecord>,
 }
 
-async fn ingest_http_response(
+/// The connection records behind a profile, oldest first.
+async fn get_profile_connections(
     State(state): State<AppState>,
-    Json(ingest): Json<HttpResponseIngest>,
-) {
-    let client_ip = ingest.destination.ip.clone();
-    info!("Received HTTP response data for client {}", client_ip);
-
-    // Map Docker gateway IPs to real client IPs for local development
-    let target_ip = if is_docker_gateway_ip(&client_ip) {
-        let real_ip = map_gateway_to_real_ip(&state, &client_ip);
-        if real_ip != client_ip {
-            info!(
-                "Mapping Docker gateway IP {} to real client IP {} for HTTP response",
-                client_ip, real_ip
-            );
+    Path(id): Path<String>,
+) -> Result<Json<ConnectionsResponse>, StatusCode> {
+    let id = state.pseudonyms.ip(&id);
+    info!("Fetching connections for ID: {}", id);
+    let store = state.store.clone();
+    let found = store::blocking(move || {
+        let Some(profile) = store.get(&id)? else {
+            return Ok(None);
+        };
+        let mut connections = Vec::with_capacity(profile.connections.len());
+        for connection_id in &profile.connections {
+            if let Some(record) = store.connection(connection_id)? {
+                connections.push(record);
+            }
         }
-        real_ip
-    } else {
-        client_ip
-    };
+        Ok::<_, StoreError>(Some(ConnectionsResponse {
+            id: profile.id,
+            connections,
+        }))
+    })
+    .await
+    .map_err(storage_error)?;
+    found.map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+/// One connection record, by its `client->server` id.
+async fn get_connection(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<ConnectionRecord>, StatusCode> {
+    // Connections are stored, and logged, under their client's pseudonym.
+    let id = state.pseudonyms.connection_id(&id);
+    info!("Fetching connection {}", id);
+    let store = state.store.clone();
+    match store::blocking(move || store.connection(&id))
+        .await
+        .map_err(storage_error)?
+    {
+        Some(record) => Ok(Json(record)),
+        None => Err(StatusCode::NOT_FOUND),
+    }
+}
 
-    let mut profile = state.entry(target_ip.clone()).or_default();
-    profile.id = target_ip;
-    profile.http_response = Some(ingest);
-    profile.last_seen = now_rfc3339();
-    drop(profile);
-    enforce_profile_limit(&state);
+fn storage_error(e: StoreError) -> StatusCode {
+    error!("Storage error: {e}");
+    StatusCode::INTERNAL_SERVER_ERROR
 }
 
-async fn ingest_tls(State(state): State<AppState>, Json(ingest): Json<TlsIngest>) {
-    let ip = ingest.source.ip.clone();
-    info!("Received TLS data for {}", ip);
-    let mut profile = state.entry(ip.clone()).or_default();
-    profile.id = ip;
-    profile.tls_client = Some(ingest);
-    profile.last_seen = now_rfc3339();
-    drop(profile);
-    enforce_profile_limit(&state);
+/// The status of a request to `route` whose profiles could not be selected.
+fn select_error(route: &str, e: SelectError) -> StatusCode {
+    match e {
+        SelectError::InvalidCursor => {
+            warn!("Rejected {route} request with an invalid cursor");
+            StatusCode::BAD_REQUEST
+        }
+        SelectError::Store(e) => storage_error(e),
+    }
 }
 
 fn now_rfc3339() -> String {
     Utc::now().to_rfc3339()
 }
 
-/// Maps Docker gateway IPs to real client IPs for local development.
-/// In Docker Compose, HTTP traffic is captured with gateway IP (172.x.x.x),
-/// while TLS/TCP traffic is captured with the real client IP.
-/// This function finds the most recent profile with TLS/TCP data.
+/// Maps Docker gateway IPs to real client IPs for local development: the most recent profile
+/// with TLS/TCP data, as tracked by [`LatestClient`].
 fn map_gateway_to_real_ip(state: &AppState, gateway_ip: &str) -> String {
     state
-        .iter()
-        .filter(|entry| {
-            let profile = entry.value();
-            let key = entry.key();
-
-            // Skip the gateway IP itself and other Docker internal IPs
-            if key == gateway_ip || is_docker_gateway_ip(key) {
-                return false;
-            }
-
-            // Prefer profiles with TLS/TCP data (real client traffic)
-            profile.tls_client.is_some() || profile.syn.is_some() || profile.syn_ack.is_some()
-        })
-        .max_by_key(|entry| entry.value().last_seen.clone())
-        .map(|entry| entry.key().clone())
+        .latest_client
+        .get()
+        .filter(|id| id != gateway_ip)
         .unwrap_or_else(|| gateway_ip.to_string())
 }
 
@@ -480,29 +748,7 @@ fn is_docker_gateway_ip(ip: &str) -> bool {
     ip.starts_with("172.")
 }
 
-fn enforce_profile_limit(state: &AppState) {
-    if state.len() <= MAX_PROFILES {
-        return;
-    }
-
-    let mut profiles: Vec<(String, String)> = state
-        .iter()
-        .map(|entry| (entry.key().clone(), entry.value().last_seen.clone()))
-        .collect();
-
-    profiles.sort_by(|a, b| a.1.cmp(&b.1));
-
-    let to_remove = state.len().saturating_sub(MAX_PROFILES);
-    for (ip, _) in profiles.iter().take(to_remove) {
-        state.remove(ip);
-        debug!(
-            "Removed old profile for {} to maintain limit of {}",
-            ip, MAX_PROFILES
-        );
-    }
-}
-
-#[derive(Serialize)]
+#[derive(Serialize, Default)]
 struct AppStats {
     total_profiles: usize,
     tcp_profiles: usize,
@@ -511,31 +757,32 @@ struct AppStats {
     complete_profiles: usize,
 }
 
-async fn get_stats(State(state): State<AppState>) -> Json<AppStats> {
+impl AppStats {
+    fn count(&mut self, p: &Profile) {
+        let tcp = p.syn.is_some() || p.syn_ack.is_some() || p.mtu.is_some() || p.uptime.is_some();
+        let http = p.http_request.is_some() || p.http_response.is_some();
+        let tls = p.tls_client.is_some();
+        self.total_profiles = self.total_profiles.saturating_add(1);
+        self.tcp_profiles = self.tcp_profiles.saturating_add(usize::from(tcp));
+        self.http_profiles = self.http_profiles.saturating_add(usize::from(http));
+        self.tls_profiles = self.tls_profiles.saturating_add(usize::from(tls));
+        self.complete_profiles = self
+            .complete_profiles
+            .saturating_add(usize::from(http && tls));
+    }
+}
+
+async fn get_stats(State(state): State<AppState>) -> Result<Json<AppStats>, StatusCode> {
     info!("Calculating statistics");
-    let profiles = state
-        .iter()
-        .map(|entry| entry.value().clone())
-        .collect::<Vec<_>>();
-    let stats = AppStats {
-        total_profiles: profiles.len(),
-        tcp_profiles: profiles
-            .iter()
-            .filter(|p| {
-                p.syn.is_some() || p.syn_ack.is_some() || p.mtu.is_some() || p.uptime.is_some()
-            })
-            .count(),
-        http_profiles: profiles
-            .iter()
-            .filter(|p| p.http_request.is_some() || p.http_response.is_some())
-            .count(),
-        tls_profiles: profiles.iter().filter(|p| p.tls_client.is_some()).count(),
-        complete_profiles: profiles
-            .iter()
-            .filter(|p| {
-                (p.http_request.is_some() || p.http_response.is_some()) && p.tls_client.is_some()
-            })
-            .count(),
-    };
-    Json(stats)
+    let store = state.store.clone();
+    let stats = store::blocking(move || {
+        let mut stats = AppStats::default();
+        for profile in store::profiles(&*store) {
+            stats.count(&profile?);
+        }
+        Ok::<_, StoreError>(stats)
+    })
+    .await
+    .map_err(storage_error)?;
+    Ok(Json(stats))
 }
@@ -0,0 +1,96 @@
+use std::time::Instant;
+
+use axum::{
+    extract::{MatchedPath, Request},
+    middleware::Next,
+    response::Response,
+};
+use metrics::{counter, describe_counter, describe_gauge, describe_histogram, histogram, Unit};
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder, PrometheusHandle};
+
+pub const HTTP_REQUESTS: &str = "assembler_http_requests_total";
+pub const HTTP_REQUEST_DURATION: &str = "assembler_http_request_duration_seconds";
+pub const INGESTED_EVENTS: &str = "assembler_ingested_events_total";
+pub const REJECTED_EVENTS: &str = "assembler_rejected_events_total";
+pub const PROFILES: &str = "assembler_profiles";
+pub const PROFILE_EVICTIONS: &str = "assembler_profile_evictions_total";
+pub const JA4_DB_ENTRIES: &str = "assembler_ja4_db_entries";
+pub const JA4_DB_RELOADS: &str = "assembler_ja4_db_reloads_total";
+pub const AUTH_REJECTIONS: &str = "assembler_auth_rejections_total";
+pub const TLS_RELOADS: &str = "assembler_tls_reloads_total";
+pub const TLS_HANDSHAKE_FAILURES: &str = "assembler_tls_handshake_failures_total";
+pub const RATE_LIMITED: &str = "assembler_rate_limited_total";
+
+const REQUEST_DURATION_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+
+/// Installs the global Prometheus recorder whose output is served on `/metrics`.
+pub fn install() -> Result<PrometheusHandle, BuildError> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full(HTTP_REQUEST_DURATION.to_string()),
+            REQUEST_DURATION_BUCKETS,
+        )?
+        .install_recorder()?;
+
+    describe_counter!(HTTP_REQUESTS, "HTTP requests served, by route and status");
+    describe_histogram!(
+        HTTP_REQUEST_DURATION,
+        Unit::Seconds,
+        "Time spent handling HTTP requests, by route"
+    );
+    describe_counter!(INGESTED_EVENTS, "Events merged into profiles, by kind");
+    describe_counter!(
+        REJECTED_EVENTS,
+        "Ingested events refused as malformed or of another schema version"
+    );
+    describe_gauge!(PROFILES, "Profiles currently stored");
+    describe_counter!(
+        PROFILE_EVICTIONS,
+        "Profiles evicted, by reason: capacity or idle"
+    );
+    describe_gauge!(JA4_DB_ENTRIES, "Fingerprints in the loaded JA4 database");
+    describe_counter!(
+        JA4_DB_RELOADS,
+        "JA4 database reloads after the file changed, by result: ok or error"
+    );
+    describe_counter!(
+        AUTH_REJECTIONS,
+        "Requests refused for a missing or invalid credential, by role and reason"
+    );
+    describe_counter!(
+        TLS_RELOADS,
+        "TLS certificate reloads after a file changed, by result: ok or error"
+    );
+    describe_counter!(
+        TLS_HANDSHAKE_FAILURES,
+        "Connections dropped during the TLS handshake, such as clients without a valid certificate"
+    );
+    describe_counter!(
+        RATE_LIMITED,
+        "Requests refused with 429 for exceeding the client's rate limit, by route class"
+    );
+
+    Ok(handle)
+}
+
+/// Records request count and latency, labelled by the matched route rather than the raw
+/// URI so that `/api/profiles/{id}` does not produce one series per client.
+pub async fn track_requests(request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_string(), |path| path.as_str().to_string());
+
+    let started = Instant::now();
+    let response = next.run(request).await;
+    let elapsed = started.elapsed().as_secs_f64();
+
+    let status = response.status().as_u16().to_string();
+    counter!(HTTP_REQUESTS, "method" => method, "path" => path.clone(), "status" => status)
+        .increment(1);
+    histogram!(HTTP_REQUEST_DURATION, "path" => path).record(elapsed);
+    response
+}
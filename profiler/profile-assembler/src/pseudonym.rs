@@ -0,0 +1,304 @@
+//! Client IP pseudonymization: client addresses are replaced before they are stored, served
+//! or logged, so that raw IPs are never kept.
+
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use hmac::{Hmac, KeyInit, Mac};
+use profiler_model::{IngestEvent, NetworkEndpoint, Profile};
+use sha2::Sha256;
+
+/// How client IPs are pseudonymized.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PseudonymMode {
+    /// Raw IPs are kept.
+    Off,
+    /// Only the network prefix is kept, e.g. `203.0.113.0` for `203.0.113.7` with a /24.
+    Truncate,
+    /// A keyed hash, e.g. `v4-5f0c3b1e9d2a7c44`, whose key changes every rotation period.
+    Hash,
+}
+
+enum Mode {
+    Off,
+    Truncate { v4_prefix: u8, v6_prefix: u8 },
+    Hash { secret: Vec<u8>, rotation_secs: u64 },
+}
+
+/// Maps a client IP to the same pseudonym for as long as its key is in use, so that the
+/// layers of one client still meet in one profile.
+pub struct Pseudonymizer {
+    mode: Mode,
+}
+
+impl Pseudonymizer {
+    pub fn off() -> Self {
+        Pseudonymizer { mode: Mode::Off }
+    }
+
+    pub fn truncate(v4_prefix: u8, v6_prefix: u8) -> Self {
+        Pseudonymizer {
+            mode: Mode::Truncate {
+                v4_prefix: v4_prefix.min(32),
+                v6_prefix: v6_prefix.min(128),
+            },
+        }
+    }
+
+    /// Hashes with a key derived from `secret` and the current period of `rotation_secs`.
+    /// Pseudonyms from past periods cannot be linked to current ones.
+    pub fn hash(secret: Vec<u8>, rotation_secs: u64) -> Self {
+        Pseudonymizer {
+            mode: Mode::Hash {
+                secret,
+                rotation_secs: rotation_secs.max(1),
+            },
+        }
+    }
+
+    /// The pseudonym of `ip`. Anything that is not an IP address, such as a pseudonym, is
+    /// returned unchanged, so applying this twice is harmless.
+    pub fn ip(&self, ip: &str) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.ip_at(ip, now)
+    }
+
+    /// The pseudonym of `ip` at `now`, in Unix seconds.
+    fn ip_at(&self, ip: &str, now: u64) -> String {
+        let Ok(addr) = ip.parse::<IpAddr>() else {
+            return ip.to_string();
+        };
+        // An IPv4 client seen through a dual-stack socket is the same client.
+        let addr = addr.to_canonical();
+        match &self.mode {
+            Mode::Off => ip.to_string(),
+            Mode::Truncate {
+                v4_prefix,
+                v6_prefix,
+            } => truncate(addr, *v4_prefix, *v6_prefix).to_string(),
+            Mode::Hash {
+                secret,
+                rotation_secs,
+            } => {
+                let period = now.checked_div(*rotation_secs).unwrap_or_default();
+                let key = hmac(secret, &period.to_be_bytes());
+                let (family, octets) = match addr {
+                    IpAddr::V4(v4) => ("v4", v4.octets().to_vec()),
+                    IpAddr::V6(v6) => ("v6", v6.octets().to_vec()),
+                };
+                let digest: String = hmac(&key, &octets)
+                    .iter()
+                    .take(8)
+                    .map(|byte| format!("{byte:02x}"))
+                    .collect();
+                format!("{family}-{digest}")
+            }
+        }
+    }
+
+    /// Pseudonymizes the client of a connection id, `client->server` as made by
+    /// [`profiler_model::ConnectionRecord::key`], so that an id given with a raw client IP
+    /// finds the connection stored under its pseudonym. Anything else is returned unchanged.
+    pub fn connection_id(&self, id: &str) -> String {
+        let Some((client, server)) = id.split_once("->") else {
+            return id.to_string();
+        };
+        let Some(client) = client.rsplit_once(':').and_then(|(ip, port)| {
+            let ip = ip
+                .strip_prefix('[')
+                .and_then(|ip| ip.strip_suffix(']'))
+                .unwrap_or(ip);
+            Some(NetworkEndpoint {
+                ip: self.ip(ip),
+                port: port.parse().ok()?,
+            })
+        }) else {
+            return id.to_string();
+        };
+        format!("{client}->{server}")
+    }
+
+    /// Pseudonymizes the client of `event`, and any header value that is an IP address,
+    /// such as `X-Forwarded-For`.
+    pub fn event(&self, event: &mut IngestEvent) {
+        if matches!(self.mode, Mode::Off) {
+            return;
+        }
+        let client = event.client_mut();
+        client.ip = self.ip(&client.ip);
+        match event {
+            IngestEvent::HttpRequest(data) => {
+                data.observed.headers = self.headers(&data.observed.headers)
+            }
+            IngestEvent::HttpResponse(data) => {
+                data.observed.headers = self.headers(&data.observed.headers)
+            }
+            _ => {}
+        }
+    }
+
+    /// Pseudonymizes the id of an imported profile and the client of every layer.
+    pub fn profile(&self, profile: &mut Profile) {
+        if matches!(self.mode, Mode::Off) {
+            return;
+        }
+        profile.id = self.ip(&profile.id);
+        let mut layers = profile.layers();
+        for layer in &mut layers {
+            self.event(layer);
+        }
+        for layer in layers {
+            match layer {
+                IngestEvent::Syn(data) => profile.syn = Some(data),
+                IngestEvent::SynAck(data) => profile.syn_ack = Some(data),
+                IngestEvent::Mtu(data) => profile.mtu = Some(data),
+                IngestEvent::Uptime(data) => profile.uptime = Some(data),
+                IngestEvent::HttpRequest(data) => profile.http_request = Some(data),
+                IngestEvent::HttpResponse(data) => profile.http_response = Some(data),
+                IngestEvent::Tls(data) => profile.tls_client = Some(data),
+            }
+        }
+    }
+
+    /// Replaces the header values of a `Name: value, ...` list that are IP addresses.
+    fn headers(&self, joined: &str) -> String {
+        joined
+            .split(", ")
+            .map(|piece| match piece.split_once(": ") {
+                Some((name, value)) if value.parse::<IpAddr>().is_ok() => {
+                    format!("{name}: {}", self.ip(value))
+                }
+                // The further addresses of an X-Forwarded-For list.
+                _ => self.ip(piece),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Reads the secret hash keys are derived from. Surrounding whitespace is ignored.
+pub fn read_secret(path: &Path) -> io::Result<Vec<u8>> {
+    let secret = fs::read_to_string(path)?.trim().as_bytes().to_vec();
+    if secret.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "empty secret"));
+    }
+    Ok(secret)
+}
+
+fn truncate(addr: IpAddr, v4_prefix: u8, v6_prefix: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(v4) => {
+            let mask = u32::MAX
+                .checked_shl(32u32.saturating_sub(u32::from(v4_prefix)))
+                .unwrap_or_default();
+            IpAddr::V4((u32::from(v4) & mask).into())
+        }
+        IpAddr::V6(v6) => {
+            let mask = u128::MAX
+                .checked_shl(128u32.saturating_sub(u32::from(v6_prefix)))
+                .unwrap_or_default();
+            IpAddr::V6((u128::from(v6) & mask).into())
+        }
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    // HMAC accepts keys of any length, so this never fails in practice.
+    let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(key) else {
+        return Vec::new();
+    };
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncation_keeps_the_network_prefix() {
+        let pseudonyms = Pseudonymizer::truncate(24, 48);
+        assert_eq!(pseudonyms.ip("203.0.113.7"), "203.0.113.0");
+        assert_eq!(pseudonyms.ip("2001:db8:1:2::7"), "2001:db8:1::");
+
+        let none = Pseudonymizer::truncate(0, 0);
+        assert_eq!(none.ip("203.0.113.7"), "0.0.0.0");
+        assert_eq!(none.ip("2001:db8::7"), "::");
+
+        let all = Pseudonymizer::truncate(32, 128);
+        assert_eq!(all.ip("203.0.113.7"), "203.0.113.7");
+        assert_eq!(all.ip("2001:db8::7"), "2001:db8::7");
+
+        // Prefixes past the address length are clamped.
+        assert_eq!(
+            Pseudonymizer::truncate(40, 200).ip("203.0.113.7"),
+            "203.0.113.7"
+        );
+    }
+
+    #[test]
+    fn hashes_are_stable_within_a_rotation_period() {
+        let pseudonyms = Pseudonymizer::hash(b"secret".to_vec(), 100);
+        let first = pseudonyms.ip_at("203.0.113.7", 1_000);
+        assert!(first.starts_with("v4-"), "{first}");
+        assert_eq!(first.len(), 19);
+        assert_eq!(pseudonyms.ip_at("203.0.113.7", 1_099), first);
+
+        assert_ne!(pseudonyms.ip_at("203.0.113.7", 1_100), first);
+        assert_ne!(pseudonyms.ip_at("203.0.113.8", 1_000), first);
+        assert_ne!(
+            Pseudonymizer::hash(b"other".to_vec(), 100).ip_at("203.0.113.7", 1_000),
+            first
+        );
+        assert!(pseudonyms.ip_at("2001:db8::7", 1_000).starts_with("v6-"));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_are_the_ipv4_client() {
+        let pseudonyms = Pseudonymizer::hash(b"secret".to_vec(), 100);
+        assert_eq!(
+            pseudonyms.ip_at("::ffff:203.0.113.7", 1_000),
+            pseudonyms.ip_at("203.0.113.7", 1_000)
+        );
+        assert_eq!(
+            Pseudonymizer::truncate(24, 64).ip("::ffff:203.0.113.7"),
+            "203.0.113.0"
+        );
+    }
+
+    #[test]
+    fn pseudonyms_and_other_ids_are_left_alone() {
+        let pseudonyms = Pseudonymizer::hash(b"secret".to_vec(), 100);
+        let pseudonym = pseudonyms.ip("203.0.113.7");
+        assert_eq!(pseudonyms.ip(&pseudonym), pseudonym);
+        assert_eq!(pseudonyms.ip("not an address"), "not an address");
+        assert_eq!(Pseudonymizer::off().ip("203.0.113.7"), "203.0.113.7");
+    }
+
+    #[test]
+    fn connection_ids_are_looked_up_by_the_client_pseudonym() {
+        let pseudonyms = Pseudonymizer::truncate(24, 48);
+        assert_eq!(
+            pseudonyms.connection_id("203.0.113.7:51234->10.0.0.1:443"),
+            "203.0.113.0:51234->10.0.0.1:443"
+        );
+        assert_eq!(
+            pseudonyms.connection_id("[2001:db8:1:2::7]:51234->[2001:db8::1]:443"),
+            "[2001:db8:1::]:51234->[2001:db8::1]:443"
+        );
+        // Already pseudonymized, or not a connection id at all.
+        assert_eq!(
+            pseudonyms.connection_id("203.0.113.0:51234->10.0.0.1:443"),
+            "203.0.113.0:51234->10.0.0.1:443"
+        );
+        assert_eq!(pseudonyms.connection_id("nonsense"), "nonsense");
+        assert_eq!(pseudonyms.connection_id("a:b->c"), "a:b->c");
+    }
+}
@@ -0,0 +1,543 @@
+//! Query parameters of `/api/profiles`: filters, sort order and cursor pagination.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::str::FromStr;
+
+use chrono::{DateTime, FixedOffset};
+use profiler_model::{IngestEvent, Profile};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::store::StoreError;
+
+/// A layer of a profile, named after its field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Layer {
+    Syn,
+    SynAck,
+    Mtu,
+    Uptime,
+    HttpRequest,
+    HttpResponse,
+    TlsClient,
+}
+
+impl Layer {
+    pub const ALL: [Layer; 7] = [
+        Layer::Syn,
+        Layer::SynAck,
+        Layer::Mtu,
+        Layer::Uptime,
+        Layer::HttpRequest,
+        Layer::HttpResponse,
+        Layer::TlsClient,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Layer::Syn => "syn",
+            Layer::SynAck => "syn_ack",
+            Layer::Mtu => "mtu",
+            Layer::Uptime => "uptime",
+            Layer::HttpRequest => "http_request",
+            Layer::HttpResponse => "http_response",
+            Layer::TlsClient => "tls_client",
+        }
+    }
+
+    /// The layer `event` updates.
+    pub fn of(event: &IngestEvent) -> Self {
+        match event {
+            IngestEvent::Syn(_) => Layer::Syn,
+            IngestEvent::SynAck(_) => Layer::SynAck,
+            IngestEvent::Mtu(_) => Layer::Mtu,
+            IngestEvent::Uptime(_) => Layer::Uptime,
+            IngestEvent::HttpRequest(_) => Layer::HttpRequest,
+            IngestEvent::HttpResponse(_) => Layer::HttpResponse,
+            IngestEvent::Tls(_) => Layer::TlsClient,
+        }
+    }
+
+    pub fn is_present(self, profile: &Profile) -> bool {
+        match self {
+            Layer::Syn => profile.syn.is_some(),
+            Layer::SynAck => profile.syn_ack.is_some(),
+            Layer::Mtu => profile.mtu.is_some(),
+            Layer::Uptime => profile.uptime.is_some(),
+            Layer::HttpRequest => profile.http_request.is_some(),
+            Layer::HttpResponse => profile.http_response.is_some(),
+            Layer::TlsClient => profile.tls_client.is_some(),
+        }
+    }
+}
+
+impl FromStr for Layer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Layer::ALL
+            .into_iter()
+            .find(|layer| layer.as_str() == s)
+            .ok_or_else(|| format!("unknown layer `{s}`"))
+    }
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SortKey {
+    Id,
+    LastSeen,
+    Os,
+    Browser,
+    Ja4,
+}
+
+/// Sort order given as `key` or `-key` for descending, e.g. `-last_seen`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Sort {
+    key: SortKey,
+    descending: bool,
+}
+
+impl Default for Sort {
+    fn default() -> Self {
+        Sort {
+            key: SortKey::LastSeen,
+            descending: true,
+        }
+    }
+}
+
+impl TryFrom<String> for Sort {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (descending, name) = match value.strip_prefix('-') {
+            Some(name) => (true, name),
+            None => (false, value.as_str()),
+        };
+        let key = match name {
+            "id" => SortKey::Id,
+            "last_seen" => SortKey::LastSeen,
+            "os" => SortKey::Os,
+            "browser" => SortKey::Browser,
+            "ja4" => SortKey::Ja4,
+            _ => {
+                return Err(format!(
+                    "unknown sort key `{name}`, expected id, last_seen, os, browser or ja4"
+                ))
+            }
+        };
+        Ok(Sort { key, descending })
+    }
+}
+
+impl Sort {
+    /// The value profiles are ordered by, comparable as a plain string.
+    fn value(&self, profile: &Profile) -> String {
+        match self.key {
+            SortKey::Id => profile.id.clone(),
+            // Zero-padded so that timestamps with different precision still compare correctly.
+            SortKey::LastSeen => format!("{:020}", last_seen_nanos(profile).unwrap_or(0)),
+            SortKey::Os => os(profile).unwrap_or_default().to_string(),
+            SortKey::Browser => browser(profile).unwrap_or_default().to_string(),
+            SortKey::Ja4 => ja4(profile).unwrap_or_default().to_string(),
+        }
+    }
+
+    fn compare(&self, a: (&str, &str), b: (&str, &str)) -> Ordering {
+        let ordering = a.cmp(&b);
+        if self.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+
+    /// Sorts `(value, id, item)` entries.
+    fn sort<T>(&self, keyed: &mut [(String, String, T)]) {
+        keyed.sort_by(|(a_value, a_id, _), (b_value, b_id, _)| {
+            self.compare((a_value, a_id), (b_value, b_id))
+        });
+    }
+}
+
+/// `GET /api/profiles` parameters. All filters are optional and combine with AND.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ProfileQuery {
+    /// Case-insensitive substring of the OS detected from the SYN.
+    os: Option<String>,
+    /// Case-insensitive substring of the browser detected from the HTTP request.
+    browser: Option<String>,
+    /// Exact JA4 fingerprint.
+    ja4: Option<String>,
+    /// Case-insensitive substring of the TLS server name.
+    sni: Option<String>,
+    /// Case-insensitive substring of the web server detected from the HTTP response.
+    web_server: Option<String>,
+    /// Comma-separated layers that must be present, e.g. `syn,tls_client`.
+    #[serde(deserialize_with = "layers")]
+    has: Vec<Layer>,
+    /// Comma-separated layers that must be absent.
+    #[serde(deserialize_with = "layers")]
+    lacks: Vec<Layer>,
+    /// Only profiles whose consistency score is at most this, e.g. `80` for those with at
+    /// least one medium finding.
+    max_score: Option<u8>,
+    /// Only profiles last seen at or after this RFC 3339 time.
+    seen_after: Option<DateTime<FixedOffset>>,
+    /// Only profiles last seen before this RFC 3339 time.
+    seen_before: Option<DateTime<FixedOffset>>,
+    sort: Sort,
+    /// Page size; everything that matches when unset. Zero is rejected.
+    limit: Option<NonZeroUsize>,
+    /// `next_cursor` of the previous page.
+    cursor: Option<String>,
+}
+
+/// One page of matching profiles, in sort order.
+pub struct Page<T = Profile> {
+    /// The profiles, or what was kept of them.
+    pub profiles: Vec<T>,
+    /// Pass as `cursor` to fetch the next page; `None` on the last one.
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum SelectError {
+    InvalidCursor,
+    Store(StoreError),
+}
+
+impl From<StoreError> for SelectError {
+    fn from(e: StoreError) -> Self {
+        SelectError::Store(e)
+    }
+}
+
+impl ProfileQuery {
+    pub fn matches(&self, profile: &Profile) -> bool {
+        contains(os(profile), self.os.as_deref())
+            && contains(browser(profile), self.browser.as_deref())
+            && contains(web_server(profile), self.web_server.as_deref())
+            && contains(sni(profile), self.sni.as_deref())
+            && self
+                .ja4
+                .as_deref()
+                .is_none_or(|ja4_filter| ja4(profile) == Some(ja4_filter))
+            && self
+                .max_score
+                .is_none_or(|max_score| profile.consistency.score <= max_score)
+            && self.has.iter().all(|layer| layer.is_present(profile))
+            && !self.lacks.iter().any(|layer| layer.is_present(profile))
+            && self.in_time_range(profile)
+    }
+
+    fn in_time_range(&self, profile: &Profile) -> bool {
+        if self.seen_after.is_none() && self.seen_before.is_none() {
+            return true;
+        }
+        let Ok(last_seen) = DateTime::parse_from_rfc3339(&profile.last_seen) else {
+            return false;
+        };
+        self.seen_after.is_none_or(|after| last_seen >= after)
+            && self.seen_before.is_none_or(|before| last_seen < before)
+    }
+
+    /// Filters, sorts and paginates `profiles`. With a `limit`, only about twice the page is
+    /// held at once however many profiles match.
+    pub fn select(
+        &self,
+        profiles: impl IntoIterator<Item = Result<Profile, StoreError>>,
+    ) -> Result<Page, SelectError> {
+        self.rank(profiles, |profile| profile)
+    }
+
+    /// Like [`select`](Self::select), but keeps only the ids of the page, for callers that
+    /// load each profile when they get to it.
+    pub fn select_ids(
+        &self,
+        profiles: impl IntoIterator<Item = Result<Profile, StoreError>>,
+    ) -> Result<Page<String>, SelectError> {
+        self.rank(profiles, |profile| profile.id)
+    }
+
+    fn rank<T>(
+        &self,
+        profiles: impl IntoIterator<Item = Result<Profile, StoreError>>,
+        keep_of: impl Fn(Profile) -> T,
+    ) -> Result<Page<T>, SelectError> {
+        let after = self.cursor.as_deref().map(decode_cursor).transpose()?;
+        // The page, plus one profile to tell whether another page follows.
+        let keep = self.limit.map(|limit| limit.get().saturating_add(1));
+
+        let mut keyed: Vec<(String, String, T)> = Vec::new();
+        for profile in profiles {
+            let profile = profile?;
+            if !self.matches(&profile) {
+                continue;
+            }
+            let value = self.sort.value(&profile);
+            if let Some((after_value, after_id)) = &after {
+                if self
+                    .sort
+                    .compare((&value, &profile.id), (after_value, after_id))
+                    != Ordering::Greater
+                {
+                    continue;
+                }
+            }
+            keyed.push((value, profile.id.clone(), keep_of(profile)));
+            if let Some(keep) = keep {
+                if keyed.len() >= keep.saturating_mul(2) {
+                    self.sort.sort(&mut keyed);
+                    keyed.truncate(keep);
+                }
+            }
+        }
+        self.sort.sort(&mut keyed);
+
+        let mut next_cursor = None;
+        if let Some(limit) = self.limit.map(NonZeroUsize::get) {
+            if keyed.len() > limit {
+                keyed.truncate(limit);
+                next_cursor = keyed.last().map(|(value, id, _)| encode_cursor(value, id));
+            }
+        }
+
+        Ok(Page {
+            profiles: keyed.into_iter().map(|(_, _, item)| item).collect(),
+            next_cursor,
+        })
+    }
+}
+
+/// Serializes profiles as an object keyed by id, keeping their order.
+pub fn serialize_by_id<S: Serializer>(
+    profiles: &[Profile],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(profiles.iter().map(|profile| (&profile.id, profile)))
+}
+
+/// Parses a comma-separated list of layers, e.g. `syn,tls_client`.
+pub fn layers<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Layer>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| name.parse().map_err(serde::de::Error::custom))
+        .collect()
+}
+
+fn contains(value: Option<&str>, needle: Option<&str>) -> bool {
+    match needle {
+        None => true,
+        Some(needle) => {
+            value.is_some_and(|value| value.to_lowercase().contains(&needle.to_lowercase()))
+        }
+    }
+}
+
+fn last_seen_nanos(profile: &Profile) -> Option<i64> {
+    DateTime::parse_from_rfc3339(&profile.last_seen)
+        .ok()
+        .and_then(|t| t.timestamp_nanos_opt())
+}
+
+fn os(profile: &Profile) -> Option<&str> {
+    profile.syn.as_ref().map(|syn| syn.os_detected.os.as_str())
+}
+
+fn browser(profile: &Profile) -> Option<&str> {
+    profile
+        .http_request
+        .as_ref()
+        .map(|request| request.browser.browser.as_str())
+}
+
+fn web_server(profile: &Profile) -> Option<&str> {
+    profile
+        .http_response
+        .as_ref()
+        .map(|response| response.web_server.web_server.as_str())
+}
+
+fn ja4(profile: &Profile) -> Option<&str> {
+    profile.tls_client.as_ref().map(|tls| tls.ja4.as_str())
+}
+
+fn sni(profile: &Profile) -> Option<&str> {
+    profile
+        .tls_client
+        .as_ref()
+        .and_then(|tls| tls.observed.sni.as_deref())
+}
+
+/// Cursors are the sort value and id of the last profile returned, hex-encoded so they can be
+/// passed back in a query string as is.
+fn encode_cursor(value: &str, id: &str) -> String {
+    format!("{value}\n{id}")
+        .bytes()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn decode_cursor(cursor: &str) -> Result<(String, String), SelectError> {
+    let bytes = cursor
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .filter(|pair| pair.len() == 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or(SelectError::InvalidCursor)
+        })
+        .collect::<Result<Vec<u8>, _>>()?;
+    let decoded = String::from_utf8(bytes).map_err(|_| SelectError::InvalidCursor)?;
+    let (value, id) = decoded.split_once('\n').ok_or(SelectError::InvalidCursor)?;
+    Ok((value.to_string(), id.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+    fn query(sort: &str, limit: usize) -> Result<ProfileQuery, String> {
+        Ok(ProfileQuery {
+            sort: Sort::try_from(sort.to_string())?,
+            limit: NonZeroUsize::new(limit),
+            ..ProfileQuery::default()
+        })
+    }
+
+    /// Profiles `p00` to `p19`, seen a second apart in reverse id order, with two ties.
+    fn profiles() -> Vec<Profile> {
+        (0..20u32)
+            .map(|n| {
+                let second = 59u32.saturating_sub(n).min(55);
+                fixtures::profile(
+                    &format!("p{n:02}"),
+                    &format!("2024-03-01T12:00:{second:02}+00:00"),
+                )
+            })
+            .collect()
+    }
+
+    /// Every page of `query`, following the cursors.
+    fn pages(mut query: ProfileQuery) -> Result<Vec<Vec<String>>, Box<dyn std::error::Error>> {
+        let mut pages = Vec::new();
+        loop {
+            let page = query
+                .select(profiles().into_iter().map(Ok))
+                .map_err(|e| format!("{e:?}"))?;
+            pages.push(
+                page.profiles
+                    .into_iter()
+                    .map(|profile| profile.id)
+                    .collect(),
+            );
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => return Ok(pages),
+            }
+        }
+    }
+
+    #[test]
+    fn cursor_walks_every_page_once() -> TestResult {
+        let pages = pages(query("id", 3)?)?;
+        assert_eq!(pages.len(), 7);
+        assert!(pages.iter().take(6).all(|page| page.len() == 3));
+        let ids: Vec<String> = pages.concat();
+        let expected: Vec<String> = profiles().into_iter().map(|profile| profile.id).collect();
+        assert_eq!(ids, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn descending_order_breaks_ties_by_descending_id() -> TestResult {
+        let ids = pages(query("-last_seen", 4)?)?.concat();
+        // `p00` to `p04` share the latest second.
+        let mut expected: Vec<String> = ["p04", "p03", "p02", "p01", "p00"]
+            .map(String::from)
+            .to_vec();
+        expected.extend((5..20u32).map(|n| format!("p{n:02}")));
+        assert_eq!(ids, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn without_a_limit_everything_is_one_page() -> TestResult {
+        let pages = pages(query("-id", 0)?)?;
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages.concat().first().map(String::as_str), Some("p19"));
+        Ok(())
+    }
+
+    #[test]
+    fn ids_come_in_the_order_of_the_profiles() -> TestResult {
+        let query = query("-last_seen", 7)?;
+        let page = query
+            .select(profiles().into_iter().map(Ok))
+            .map_err(|e| format!("{e:?}"))?;
+        let ids = query
+            .select_ids(profiles().into_iter().map(Ok))
+            .map_err(|e| format!("{e:?}"))?;
+        let expected: Vec<String> = page.profiles.into_iter().map(|p| p.id).collect();
+        assert_eq!(ids.profiles, expected);
+        assert_eq!(ids.next_cursor, page.next_cursor);
+        Ok(())
+    }
+
+    #[test]
+    fn cursor_round_trips_any_value() -> TestResult {
+        let cursor = encode_cursor("Linux / unix", "2001:db8::1");
+        let (value, id) = decode_cursor(&cursor).map_err(|e| format!("{e:?}"))?;
+        assert_eq!(
+            (value.as_str(), id.as_str()),
+            ("Linux / unix", "2001:db8::1")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn tampered_cursor_is_rejected() -> TestResult {
+        let valid = encode_cursor("a", "b");
+        let truncated = valid
+            .get(..valid.len().saturating_sub(1))
+            .unwrap_or_default();
+        for cursor in ["zz", "616", "6162", "ff0a61", truncated] {
+            let mut query = query("id", 2)?;
+            query.cursor = Some(cursor.to_string());
+            assert!(
+                matches!(
+                    query.select(profiles().into_iter().map(Ok)),
+                    Err(SelectError::InvalidCursor)
+                ),
+                "{cursor}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn zero_limit_is_rejected() {
+        let query = serde_json::from_value::<ProfileQuery>(serde_json::json!({ "limit": 0 }));
+        assert!(query.is_err());
+    }
+}
@@ -0,0 +1,463 @@
+//! Per-client token buckets, one per route class, so that one client cannot monopolize the
+//! assembler. A client is its peer address, or the address a trusted proxy forwarded for;
+//! IPv6 clients are counted by their /64, since a single host is usually handed a whole one.
+
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use dashmap::DashMap;
+use metrics::counter;
+use tokio::time::{self, MissedTickBehavior};
+use tracing::debug;
+
+use crate::metrics::RATE_LIMITED;
+use crate::pseudonym::Pseudonymizer;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RouteClass {
+    Ingest,
+    Read,
+    Admin,
+}
+
+impl RouteClass {
+    fn as_str(self) -> &'static str {
+        match self {
+            RouteClass::Ingest => "ingest",
+            RouteClass::Read => "read",
+            RouteClass::Admin => "admin",
+        }
+    }
+}
+
+/// Requests a client may make: `per_minute` on average, up to `burst` at once. A rate of
+/// zero turns the limit off.
+#[derive(Clone, Copy, Debug)]
+pub struct Limit {
+    pub per_minute: u32,
+    pub burst: u32,
+}
+
+impl Limit {
+    fn per_second(self) -> f64 {
+        f64::from(self.per_minute) / 60.0
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Addresses and networks, such as `10.0.0.0/8,::1`, whose forwarding headers are believed.
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies(Vec<(IpAddr, u8)>);
+
+impl FromStr for TrustedProxies {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (addr, prefix) = entry.split_once('/').unwrap_or((entry, ""));
+                let addr: IpAddr = addr.parse().map_err(|_| invalid(entry))?;
+                let max = if addr.is_ipv4() { 32 } else { 128 };
+                let prefix = match prefix {
+                    "" => max,
+                    prefix => prefix
+                        .parse::<u8>()
+                        .ok()
+                        .filter(|prefix| *prefix <= max)
+                        .ok_or_else(|| invalid(entry))?,
+                };
+                Ok((addr, prefix))
+            })
+            .collect::<Result<_, _>>()
+            .map(TrustedProxies)
+    }
+}
+
+fn invalid(entry: &str) -> String {
+    format!("`{entry}` is not an address or a network")
+}
+
+impl TrustedProxies {
+    fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.0.iter().any(|(network, prefix)| match (network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32u32.saturating_sub(u32::from(*prefix)))
+                    .unwrap_or_default();
+                u32::from(*network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128u32.saturating_sub(u32::from(*prefix)))
+                    .unwrap_or_default();
+                u128::from(*network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        })
+    }
+
+    /// The client behind `peer`: the peer itself, or the client it forwarded for if it is a
+    /// trusted proxy. Headers from anyone else are ignored, since the caller sets them.
+    pub fn client(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let client = if self.contains(peer) {
+            forwarded_for(headers).unwrap_or(peer)
+        } else {
+            peer
+        };
+        client.to_canonical()
+    }
+}
+
+pub struct RateLimiter {
+    ingest: Limit,
+    read: Limit,
+    admin: Limit,
+    trusted_proxies: TrustedProxies,
+    /// Clients are logged by pseudonym, like everywhere else.
+    pseudonyms: Arc<Pseudonymizer>,
+    buckets: DashMap<(RouteClass, IpAddr), Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(
+        ingest: Limit,
+        read: Limit,
+        admin: Limit,
+        trusted_proxies: TrustedProxies,
+        pseudonyms: Arc<Pseudonymizer>,
+    ) -> Self {
+        RateLimiter {
+            ingest,
+            read,
+            admin,
+            trusted_proxies,
+            pseudonyms,
+            buckets: DashMap::new(),
+        }
+    }
+
+    fn limit(&self, class: RouteClass) -> Option<Limit> {
+        let limit = match class {
+            RouteClass::Ingest => self.ingest,
+            RouteClass::Read => self.read,
+            RouteClass::Admin => self.admin,
+        };
+        (limit.per_minute > 0).then_some(limit)
+    }
+
+    /// Takes a token from the client's bucket at `now`, or tells how long until one is
+    /// available.
+    fn take(
+        &self,
+        class: RouteClass,
+        client: IpAddr,
+        limit: Limit,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let burst = f64::from(limit.burst.max(1));
+        let mut bucket = self.buckets.entry((class, client)).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * limit.per_second();
+        bucket.tokens = (bucket.tokens + refilled).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / limit.per_second(),
+            ))
+        }
+    }
+
+    /// Forgets the buckets that have refilled by `now`, which behave as new ones would.
+    fn sweep(&self, now: Instant) {
+        self.buckets.retain(|(class, _), bucket| {
+            self.limit(*class).is_some_and(|limit| {
+                let refilled =
+                    now.duration_since(bucket.updated).as_secs_f64() * limit.per_second();
+                bucket.tokens + refilled < f64::from(limit.burst.max(1))
+            })
+        });
+    }
+
+    /// The client a request is counted against.
+    fn client(&self, request: &Request) -> Option<IpAddr> {
+        let ConnectInfo(peer) = request.extensions().get::<ConnectInfo<SocketAddr>>()?;
+        Some(network(
+            self.trusted_proxies.client(peer.ip(), request.headers()),
+        ))
+    }
+}
+
+/// The /64 an IPv6 client is in; IPv4 clients are their own address.
+fn network(client: IpAddr) -> IpAddr {
+    match client {
+        IpAddr::V4(_) => client,
+        IpAddr::V6(ip) => IpAddr::V6((u128::from(ip) & (u128::MAX << 64)).into()),
+    }
+}
+
+/// `X-Real-Ip`, or else the last `X-Forwarded-For` entry, the one the proxy added.
+fn forwarded_for(headers: &HeaderMap) -> Option<IpAddr> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    header("x-real-ip")
+        .and_then(|ip| ip.trim().parse().ok())
+        .or_else(|| {
+            header("x-forwarded-for")
+                .and_then(|list| list.rsplit(',').next())
+                .and_then(|ip| ip.trim().parse().ok())
+        })
+}
+
+/// Periodically forgets idle clients.
+pub async fn sweep(limiter: Arc<RateLimiter>, interval: Duration) {
+    let mut ticker = time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        limiter.sweep(Instant::now());
+    }
+}
+
+async fn limit(limiter: &RateLimiter, class: RouteClass, request: Request, next: Next) -> Response {
+    let (Some(limit), Some(client)) = (limiter.limit(class), limiter.client(&request)) else {
+        return next.run(request).await;
+    };
+    match limiter.take(class, client, limit, Instant::now()) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            debug!(
+                "Rate limited {} request to {} from {}",
+                class.as_str(),
+                request.uri().path(),
+                limiter.pseudonyms.ip(&client.to_string())
+            );
+            counter!(RATE_LIMITED, "class" => class.as_str()).increment(1);
+            // Whole seconds, rounded up so that a retry on time is let through.
+            let retry_after = wait
+                .as_secs()
+                .saturating_add(u64::from(wait.subsec_nanos() > 0));
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after.max(1).to_string())],
+            )
+                .into_response()
+        }
+    }
+}
+
+pub async fn limit_ingest(
+    State(limiter): State<Arc<RateLimiter>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    limit(&limiter, RouteClass::Ingest, request, next).await
+}
+
+pub async fn limit_read(
+    State(limiter): State<Arc<RateLimiter>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    limit(&limiter, RouteClass::Read, request, next).await
+}
+
+pub async fn limit_admin(
+    State(limiter): State<Arc<RateLimiter>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    limit(&limiter, RouteClass::Admin, request, next).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: Limit = Limit {
+        per_minute: 60,
+        burst: 2,
+    };
+
+    fn limiter(trusted_proxies: &str) -> Result<RateLimiter, String> {
+        Ok(RateLimiter::new(
+            LIMIT,
+            LIMIT,
+            Limit {
+                per_minute: 0,
+                burst: 1,
+            },
+            trusted_proxies.parse()?,
+            Arc::new(Pseudonymizer::off()),
+        ))
+    }
+
+    fn ip(ip: &str) -> Result<IpAddr, std::net::AddrParseError> {
+        ip.parse()
+    }
+
+    #[test]
+    fn bucket_allows_the_burst_then_refills_at_the_rate() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let limiter = limiter("")?;
+        let client = ip("203.0.113.7")?;
+        let start = Instant::now();
+        let take = |after_ms| {
+            limiter.take(
+                RouteClass::Read,
+                client,
+                LIMIT,
+                start + Duration::from_millis(after_ms),
+            )
+        };
+
+        assert_eq!(take(0), Ok(()));
+        assert_eq!(take(0), Ok(()));
+        assert_eq!(take(0), Err(Duration::from_secs(1)));
+        // Half a token has come back; the wait is for the other half.
+        assert_eq!(take(500), Err(Duration::from_millis(500)));
+        assert_eq!(take(1_000), Ok(()));
+        assert!(take(1_000).is_err());
+
+        // Other clients and classes have buckets of their own.
+        let at = start + Duration::from_secs(1);
+        assert_eq!(
+            limiter.take(RouteClass::Read, ip("203.0.113.8")?, LIMIT, at),
+            Ok(())
+        );
+        assert_eq!(limiter.take(RouteClass::Ingest, client, LIMIT, at), Ok(()));
+
+        // A long pause refills no more than the burst.
+        assert_eq!(take(60_000), Ok(()));
+        assert_eq!(take(60_000), Ok(()));
+        assert!(take(60_000).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn sweep_forgets_only_refilled_buckets() -> Result<(), Box<dyn std::error::Error>> {
+        let limiter = limiter("")?;
+        let start = Instant::now();
+        let (busy, idle) = (ip("203.0.113.7")?, ip("203.0.113.8")?);
+        for _ in 0..2 {
+            limiter.take(RouteClass::Read, busy, LIMIT, start).ok();
+        }
+        limiter.take(RouteClass::Read, idle, LIMIT, start).ok();
+        // Buckets of a class whose limit was turned off go too.
+        limiter.take(RouteClass::Admin, busy, LIMIT, start).ok();
+
+        limiter.sweep(start + Duration::from_millis(1_500));
+        assert!(limiter.buckets.contains_key(&(RouteClass::Read, busy)));
+        assert!(!limiter.buckets.contains_key(&(RouteClass::Read, idle)));
+        assert!(!limiter.buckets.contains_key(&(RouteClass::Admin, busy)));
+
+        limiter.sweep(start + Duration::from_secs(2));
+        assert!(limiter.buckets.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn trusted_proxies_match_addresses_and_networks() -> Result<(), Box<dyn std::error::Error>> {
+        let proxies: TrustedProxies = "172.28.0.0/16, 10.0.0.1, 2001:db8::/32".parse()?;
+        assert!(proxies.contains(ip("172.28.3.4")?));
+        assert!(!proxies.contains(ip("172.29.0.1")?));
+        assert!(proxies.contains(ip("10.0.0.1")?));
+        assert!(!proxies.contains(ip("10.0.0.2")?));
+        assert!(proxies.contains(ip("2001:db8:ffff::1")?));
+        assert!(!proxies.contains(ip("2001:db9::1")?));
+        // An IPv4 peer seen through a dual-stack socket is still the IPv4 proxy.
+        assert!(proxies.contains(ip("::ffff:10.0.0.1")?));
+
+        let everyone: TrustedProxies = "0.0.0.0/0,::/0".parse()?;
+        assert!(everyone.contains(ip("203.0.113.7")?));
+        assert!(everyone.contains(ip("2001:db8::1")?));
+        assert!(!TrustedProxies::default().contains(ip("127.0.0.1")?));
+
+        for invalid in ["10.0.0.0/33", "2001:db8::/129", "proxy", "10.0.0.0/x"] {
+            assert!(invalid.parse::<TrustedProxies>().is_err(), "{invalid}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn only_trusted_proxies_choose_the_client() -> Result<(), Box<dyn std::error::Error>> {
+        let proxies: TrustedProxies = "172.28.0.0/16".parse()?;
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "198.51.100.1, 203.0.113.7".parse()?);
+
+        assert_eq!(
+            proxies.client(ip("172.28.0.2")?, &headers),
+            ip("203.0.113.7")?
+        );
+        assert_eq!(proxies.client(ip("192.0.2.1")?, &headers), ip("192.0.2.1")?);
+
+        headers.insert("x-real-ip", "203.0.113.9".parse()?);
+        assert_eq!(
+            proxies.client(ip("172.28.0.2")?, &headers),
+            ip("203.0.113.9")?
+        );
+        // A proxy that forwarded for nobody is the client itself.
+        assert_eq!(
+            proxies.client(ip("172.28.0.2")?, &HeaderMap::new()),
+            ip("172.28.0.2")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn ipv6_clients_share_the_bucket_of_their_64() -> Result<(), Box<dyn std::error::Error>> {
+        let limiter = limiter("172.28.0.0/16")?;
+        let client = |peer: &str, forwarded: Option<&str>| {
+            let mut request =
+                Request::builder().extension(ConnectInfo(SocketAddr::new(ip(peer)?, 443)));
+            if let Some(forwarded) = forwarded {
+                request = request.header("x-forwarded-for", forwarded);
+            }
+            let request = request.body(axum::body::Body::empty())?;
+            Ok::<_, Box<dyn std::error::Error>>(limiter.client(&request))
+        };
+
+        let network = Some(ip("2001:db8:1:2::")?);
+        assert_eq!(client("2001:db8:1:2::7", None)?, network);
+        assert_eq!(client("2001:db8:1:2:aaaa:bbbb:cccc:dddd", None)?, network);
+        assert_eq!(
+            client("2001:db8:1:3::7", None)?,
+            Some(ip("2001:db8:1:3::")?)
+        );
+        // Forwarded clients are bucketed the same way.
+        assert_eq!(client("172.28.0.2", Some("2001:db8:1:2::9"))?, network);
+        // IPv4 clients, also when seen through a dual-stack socket, keep their address.
+        assert_eq!(client("203.0.113.7", None)?, Some(ip("203.0.113.7")?));
+        assert_eq!(
+            client("::ffff:203.0.113.7", None)?,
+            Some(ip("203.0.113.7")?)
+        );
+
+        // Hopping addresses within the /64 does not get a fresh burst.
+        let start = Instant::now();
+        for host in ["2001:db8:1:2::1", "2001:db8:1:2::2", "2001:db8:1:2::3"] {
+            let client = client(host, None)?.ok_or("no client")?;
+            let taken = limiter.take(RouteClass::Read, client, LIMIT, start);
+            assert_eq!(taken.is_ok(), host != "2001:db8:1:2::3", "{host}");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::BTreeSet;
+use std::ops::Bound;
+use std::sync::{PoisonError, RwLock};
+
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use profiler_model::{ConnectionRecord, Profile};
+
+use super::{ProfileStore, StoreError};
+
+/// Profiles and connection records in concurrent maps. Fast, but nothing survives a restart.
+#[derive(Default)]
+pub struct MemoryStore {
+    profiles: DashMap<String, Profile>,
+    /// The profile ids in order, for paging. Only changed while the profile's map entry is
+    /// locked, so that it never misses a stored profile.
+    ids: RwLock<BTreeSet<String>>,
+    connections: DashMap<String, ConnectionRecord>,
+}
+
+impl ProfileStore for MemoryStore {
+    fn get(&self, id: &str) -> Result<Option<Profile>, StoreError> {
+        Ok(self.profiles.get(id).map(|entry| entry.value().clone()))
+    }
+
+    fn update(
+        &self,
+        id: &str,
+        update: Box<dyn FnOnce(&mut Profile) + '_>,
+    ) -> Result<(), StoreError> {
+        let mut profile = match self.profiles.entry(id.to_string()) {
+            Entry::Occupied(entry) => entry.into_ref(),
+            Entry::Vacant(entry) => {
+                self.ids
+                    .write()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .insert(id.to_string());
+                entry.insert(Profile::default())
+            }
+        };
+        update(&mut profile);
+        Ok(())
+    }
+
+    fn page(
+        &self,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, Profile)>, StoreError> {
+        let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+        // Collected first: the map is not locked while the ids are.
+        let ids: Vec<String> = self
+            .ids
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .range::<str, _>((start, Bound::Unbounded))
+            .take(limit)
+            .cloned()
+            .collect();
+        Ok(ids
+            .into_iter()
+            .filter_map(|id| {
+                let profile = self.profiles.get(&id)?.value().clone();
+                Some((id, profile))
+            })
+            .collect())
+    }
+
+    fn remove(&self, id: &str) -> Result<(), StoreError> {
+        let removed = self.profiles.remove_if(id, |id, _| {
+            self.ids
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .remove(id);
+            true
+        });
+        if let Some((_, profile)) = removed {
+            for connection in &profile.connections {
+                self.connections.remove(connection);
+            }
+        }
+        Ok(())
+    }
+
+    fn recency(&self) -> Result<Vec<(String, String)>, StoreError> {
+        let mut profiles: Vec<(String, String)> = self
+            .profiles
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().last_seen.clone()))
+            .collect();
+        profiles.sort_by(|a, b| a.1.cmp(&b.1));
+        Ok(profiles)
+    }
+
+    fn connection(&self, id: &str) -> Result<Option<ConnectionRecord>, StoreError> {
+        Ok(self.connections.get(id).map(|entry| entry.value().clone()))
+    }
+
+    fn update_connection(
+        &self,
+        id: &str,
+        update: Box<dyn FnOnce(&mut ConnectionRecord) + '_>,
+    ) -> Result<(), StoreError> {
+        let mut record = self.connections.entry(id.to_string()).or_default();
+        update(&mut record);
+        Ok(())
+    }
+
+    fn remove_connection(&self, id: &str) -> Result<(), StoreError> {
+        self.connections.remove(id);
+        Ok(())
+    }
+}
@@ -0,0 +1,244 @@
+//! Storage backends for assembled profiles.
+
+mod memory;
+mod sqlite;
+
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use clap::ValueEnum;
+use profiler_model::{ConnectionRecord, Profile};
+use tokio::task::{self, JoinError};
+
+pub use memory::MemoryStore;
+pub use sqlite::SqliteStore;
+
+/// Profiles decoded at once when walking the whole store with [`profiles`].
+const PAGE_SIZE: usize = 256;
+
+/// Where the assembler keeps profiles. Handlers only go through this trait.
+///
+/// Implementations are synchronous and may block on disk or on a lock, so async code calls
+/// them through [`blocking`].
+pub trait ProfileStore: Send + Sync {
+    fn get(&self, id: &str) -> Result<Option<Profile>, StoreError>;
+
+    /// Applies `update` to the profile `id`, starting from an empty profile if there is none.
+    fn update(
+        &self,
+        id: &str,
+        update: Box<dyn FnOnce(&mut Profile) + '_>,
+    ) -> Result<(), StoreError>;
+
+    /// Up to `limit` profiles with their ids, in id order, starting after the id `after`.
+    fn page(&self, after: Option<&str>, limit: usize)
+        -> Result<Vec<(String, Profile)>, StoreError>;
+
+    /// Removes the profile `id` together with the connection records linked to it.
+    fn remove(&self, id: &str) -> Result<(), StoreError>;
+
+    /// `(id, last_seen)` of every profile, least recently seen first.
+    fn recency(&self) -> Result<Vec<(String, String)>, StoreError>;
+
+    fn connection(&self, id: &str) -> Result<Option<ConnectionRecord>, StoreError>;
+
+    /// Applies `update` to the connection record `id`, starting from an empty one if there
+    /// is none.
+    fn update_connection(
+        &self,
+        id: &str,
+        update: Box<dyn FnOnce(&mut ConnectionRecord) + '_>,
+    ) -> Result<(), StoreError>;
+
+    fn remove_connection(&self, id: &str) -> Result<(), StoreError>;
+}
+
+/// Every profile in `store`, read a page at a time so that only one page is held in memory.
+pub fn profiles(store: &dyn ProfileStore) -> Profiles<'_> {
+    Profiles {
+        store,
+        page: Vec::new().into_iter(),
+        after: None,
+        done: false,
+    }
+}
+
+pub struct Profiles<'a> {
+    store: &'a dyn ProfileStore,
+    page: std::vec::IntoIter<(String, Profile)>,
+    after: Option<String>,
+    done: bool,
+}
+
+impl Iterator for Profiles<'_> {
+    type Item = Result<Profile, StoreError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((id, profile)) = self.page.next() {
+                self.after = Some(id);
+                return Some(Ok(profile));
+            }
+            if self.done {
+                return None;
+            }
+            match self.store.page(self.after.as_deref(), PAGE_SIZE) {
+                Ok(page) => {
+                    // Profiles removed meanwhile can leave a page short, so only an empty
+                    // one marks the end.
+                    self.done = page.is_empty();
+                    self.page = page.into_iter();
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Runs `f`, which calls the store, on the blocking thread pool rather than on the async
+/// workers that serve other requests.
+pub async fn blocking<T, E, F>(f: F) -> Result<T, E>
+where
+    F: FnOnce() -> Result<T, E> + Send + 'static,
+    T: Send + 'static,
+    E: From<StoreError> + Send + 'static,
+{
+    task::spawn_blocking(f)
+        .await
+        .map_err(|e| E::from(StoreError::Task(e)))?
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+    /// Keep profiles in memory; they are lost on restart.
+    Memory,
+    /// Keep profiles in an SQLite database file.
+    Sqlite,
+}
+
+impl fmt::Display for StorageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageKind::Memory => f.write_str("memory"),
+            StorageKind::Sqlite => f.write_str("sqlite"),
+        }
+    }
+}
+
+/// Opens the configured backend. `sqlite_path` is only used by [`StorageKind::Sqlite`].
+pub fn open(kind: StorageKind, sqlite_path: &Path) -> Result<Arc<dyn ProfileStore>, StoreError> {
+    Ok(match kind {
+        StorageKind::Memory => Arc::new(MemoryStore::default()),
+        StorageKind::Sqlite => Arc::new(SqliteStore::open(sqlite_path)?),
+    })
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Sqlite(rusqlite::Error),
+    Encoding(serde_json::Error),
+    Task(JoinError),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Sqlite(e) => write!(f, "sqlite: {e}"),
+            StoreError::Encoding(e) => write!(f, "stored profile is not valid JSON: {e}"),
+            StoreError::Task(e) => write!(f, "storage task failed: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        StoreError::Sqlite(e)
+    }
+}
+
+impl From<serde_json::Error> for StoreError {
+    fn from(e: serde_json::Error) -> Self {
+        StoreError::Encoding(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+    /// More profiles than fit in one page, inserted out of id order.
+    fn fill(store: &dyn ProfileStore) -> Result<Vec<String>, StoreError> {
+        let mut ids: Vec<String> = (0..PAGE_SIZE.saturating_mul(2).saturating_add(3))
+            .map(|n| format!("10.0.{}.{}", n / 256, n % 256))
+            .collect();
+        for id in ids.iter().rev() {
+            store.update(
+                id,
+                Box::new(|profile| {
+                    profile.id = id.clone();
+                    profile.last_seen = "2024-03-01T12:00:00+00:00".to_string();
+                }),
+            )?;
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    fn walk(store: &dyn ProfileStore) -> Result<Vec<String>, StoreError> {
+        profiles(store)
+            .map(|profile| profile.map(|profile| profile.id))
+            .collect()
+    }
+
+    #[test]
+    fn memory_store_is_walked_in_id_order_across_pages() -> TestResult {
+        let store = MemoryStore::default();
+        let ids = fill(&store)?;
+        assert_eq!(walk(&store)?, ids);
+
+        let after = ids.get(PAGE_SIZE).ok_or("too few profiles")?;
+        let page = store.page(Some(after), 2)?;
+        let page: Vec<&String> = page.iter().map(|(id, _)| id).collect();
+        assert_eq!(
+            page,
+            ids.iter()
+                .skip(PAGE_SIZE.saturating_add(1))
+                .take(2)
+                .collect::<Vec<_>>()
+        );
+
+        // Removed profiles leave the index, and updating one that exists does not add it twice.
+        store.remove(ids.first().ok_or("no profiles")?)?;
+        store.update(after, Box::new(|profile| profile.timestamp = 1))?;
+        assert_eq!(walk(&store)?, ids.get(1..).ok_or("no profiles")?);
+        Ok(())
+    }
+
+    #[test]
+    fn sqlite_store_is_walked_in_id_order_across_pages() -> TestResult {
+        let path = std::env::temp_dir().join(format!("assembler-pages-{}.db", std::process::id()));
+        let store = SqliteStore::open(&path)?;
+        let ids = fill(&store)?;
+        assert_eq!(walk(&store)?, ids);
+
+        store.remove(ids.first().ok_or("no profiles")?)?;
+        assert_eq!(walk(&store)?, ids.get(1..).ok_or("no profiles")?);
+        drop(store);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn empty_store_has_no_profiles() -> TestResult {
+        assert!(walk(&MemoryStore::default())?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use profiler_model::{ConnectionRecord, Profile};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::{ProfileStore, StoreError};
+
+/// Profiles in an SQLite file, one JSON document per client, and connection records likewise
+/// per connection.
+///
+/// Profiles survive restarts and are not bound by RAM. `last_seen` is kept in its own
+/// indexed column so that eviction can be seeded on startup without decoding every profile,
+/// and each connection's profile id so that evicting a profile drops its connections.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> Result<Self, StoreError> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS profiles (
+                 id        TEXT PRIMARY KEY,
+                 last_seen TEXT NOT NULL,
+                 data      TEXT NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS profiles_last_seen ON profiles (last_seen);
+             CREATE TABLE IF NOT EXISTS connections (
+                 id         TEXT PRIMARY KEY,
+                 profile_id TEXT NOT NULL,
+                 data       TEXT NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS connections_profile_id ON connections (profile_id);",
+        )?;
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl ProfileStore for SqliteStore {
+    fn get(&self, id: &str) -> Result<Option<Profile>, StoreError> {
+        let data: Option<String> = self
+            .conn()
+            .query_row("SELECT data FROM profiles WHERE id = ?1", [id], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        Ok(data.map(|data| serde_json::from_str(&data)).transpose()?)
+    }
+
+    fn update(
+        &self,
+        id: &str,
+        update: Box<dyn FnOnce(&mut Profile) + '_>,
+    ) -> Result<(), StoreError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let data: Option<String> = tx
+            .query_row("SELECT data FROM profiles WHERE id = ?1", [id], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        let mut profile: Profile = match data {
+            Some(data) => serde_json::from_str(&data)?,
+            None => Profile::default(),
+        };
+        update(&mut profile);
+        tx.execute(
+            "INSERT INTO profiles (id, last_seen, data) VALUES (?1, ?2, ?3)
+             ON CONFLICT (id) DO UPDATE SET last_seen = excluded.last_seen, data = excluded.data",
+            params![id, profile.last_seen, serde_json::to_string(&profile)?],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn page(
+        &self,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(String, Profile)>, StoreError> {
+        let conn = self.conn();
+        let mut stmt = conn
+            .prepare_cached("SELECT id, data FROM profiles WHERE id > ?1 ORDER BY id LIMIT ?2")?;
+        let rows = stmt.query_map(
+            params![
+                after.unwrap_or_default(),
+                i64::try_from(limit).unwrap_or(i64::MAX)
+            ],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)),
+        )?;
+        let mut profiles = Vec::with_capacity(limit.min(1024));
+        for row in rows {
+            let (id, data) = row?;
+            profiles.push((id, serde_json::from_str(&data)?));
+        }
+        Ok(profiles)
+    }
+
+    fn remove(&self, id: &str) -> Result<(), StoreError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM profiles WHERE id = ?1", [id])?;
+        tx.execute("DELETE FROM connections WHERE profile_id = ?1", [id])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn recency(&self) -> Result<Vec<(String, String)>, StoreError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT id, last_seen FROM profiles ORDER BY last_seen")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }
+
+    fn connection(&self, id: &str) -> Result<Option<ConnectionRecord>, StoreError> {
+        let data: Option<String> = self
+            .conn()
+            .query_row("SELECT data FROM connections WHERE id = ?1", [id], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        Ok(data.map(|data| serde_json::from_str(&data)).transpose()?)
+    }
+
+    fn update_connection(
+        &self,
+        id: &str,
+        update: Box<dyn FnOnce(&mut ConnectionRecord) + '_>,
+    ) -> Result<(), StoreError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let data: Option<String> = tx
+            .query_row("SELECT data FROM connections WHERE id = ?1", [id], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        let mut record: ConnectionRecord = match data {
+            Some(data) => serde_json::from_str(&data)?,
+            None => ConnectionRecord::default(),
+        };
+        update(&mut record);
+        tx.execute(
+            "INSERT INTO connections (id, profile_id, data) VALUES (?1, ?2, ?3)
+             ON CONFLICT (id) DO UPDATE SET profile_id = excluded.profile_id, data = excluded.data",
+            params![id, record.profile, serde_json::to_string(&record)?],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn remove_connection(&self, id: &str) -> Result<(), StoreError> {
+        self.conn()
+            .execute("DELETE FROM connections WHERE id = ?1", [id])?;
+        Ok(())
+    }
+}
@@ -0,0 +1,229 @@
+//! `/api/stream`: profile changes pushed to clients as Server-Sent Events.
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Query, State},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures_util::stream::{self, Stream};
+use profiler_model::Profile;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, info};
+
+use crate::query::{self, Layer};
+use crate::AppState;
+
+/// A profile after one ingested event, as pushed to subscribers.
+#[derive(Debug, Serialize)]
+pub struct ProfileUpdate {
+    pub id: String,
+    /// Layer the event changed.
+    pub layer: Layer,
+    /// Whether the event created the profile.
+    pub created: bool,
+    pub profile: Profile,
+}
+
+/// Fans profile updates out to every `/api/stream` client.
+///
+/// Each subscriber has a buffer of `capacity` updates; one that falls further behind skips
+/// the oldest and is sent a `lagged` event with the number it missed.
+pub struct Updates {
+    sender: broadcast::Sender<Arc<ProfileUpdate>>,
+}
+
+impl Updates {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity.max(1));
+        Updates { sender }
+    }
+
+    /// Whether anyone is listening, so that publishers can skip building updates otherwise.
+    pub fn has_subscribers(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    pub fn publish(&self, update: ProfileUpdate) {
+        // Fails only when nobody is subscribed, which is fine.
+        let _ = self.sender.send(Arc::new(update));
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Arc<ProfileUpdate>> {
+        self.sender.subscribe()
+    }
+}
+
+/// `GET /api/stream` parameters.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct StreamFilter {
+    /// Only updates of this profile.
+    ip: Option<String>,
+    /// Comma-separated layers whose updates are wanted; all when empty.
+    #[serde(deserialize_with = "query::layers")]
+    layer: Vec<Layer>,
+}
+
+impl StreamFilter {
+    fn matches(&self, update: &ProfileUpdate) -> bool {
+        self.ip.as_deref().is_none_or(|ip| ip == update.id)
+            && (self.layer.is_empty() || self.layer.contains(&update.layer))
+    }
+}
+
+/// Streams an event per profile change: `created` or `updated`, with a [`ProfileUpdate`] as
+/// JSON data.
+pub async fn stream_updates(
+    State(state): State<AppState>,
+    Query(mut filter): Query<StreamFilter>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    filter.ip = filter.ip.map(|ip| state.pseudonyms.ip(&ip));
+    info!("Stream subscriber connected ({filter:?})");
+    let receiver = state.updates.subscribe();
+    let events = stream::unfold((receiver, filter), |(mut receiver, filter)| async move {
+        loop {
+            let event = match receiver.recv().await {
+                Ok(update) if filter.matches(&update) => {
+                    let name = if update.created { "created" } else { "updated" };
+                    Event::default().event(name).json_data(&*update)
+                }
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    debug!("Stream subscriber lagged, skipped {skipped} updates");
+                    Ok(Event::default()
+                        .event("lagged")
+                        .data(format!("{{\"skipped\":{skipped}}}")))
+                }
+                Err(RecvError::Closed) => return None,
+            };
+            return Some((event, (receiver, filter)));
+        }
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::BodyDataStream;
+    use axum::response::IntoResponse;
+    use futures_util::StreamExt;
+
+    use crate::fixtures;
+
+    type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+    fn update(id: &str, layer: Layer, created: bool) -> ProfileUpdate {
+        ProfileUpdate {
+            id: id.to_string(),
+            layer,
+            created,
+            profile: fixtures::profile(id, "2024-03-01T12:00:00+00:00"),
+        }
+    }
+
+    fn filter(ip: Option<&str>, layer: &[Layer]) -> StreamFilter {
+        StreamFilter {
+            ip: ip.map(str::to_string),
+            layer: layer.to_vec(),
+        }
+    }
+
+    /// The next event sent down the stream, as `(name, data)`.
+    async fn next_event(
+        body: &mut BodyDataStream,
+    ) -> Result<(String, serde_json::Value), Box<dyn std::error::Error>> {
+        let chunk = body.next().await.ok_or("stream ended")??;
+        let text = String::from_utf8(chunk.to_vec())?;
+        let field = |name: &str| {
+            text.lines()
+                .find_map(|line| line.strip_prefix(name))
+                .map(str::to_string)
+                .ok_or(format!("no {name} in {text:?}"))
+        };
+        Ok((field("event: ")?, serde_json::from_str(&field("data: ")?)?))
+    }
+
+    #[test]
+    fn filters_match_on_ip_and_layer() {
+        let syn = update("1.1.1.1", Layer::Syn, false);
+        let tls = update("1.1.1.1", Layer::TlsClient, false);
+        let other = update("2.2.2.2", Layer::Syn, false);
+
+        let everything = filter(None, &[]);
+        assert!(everything.matches(&syn) && everything.matches(&tls) && everything.matches(&other));
+
+        let one_ip = filter(Some("1.1.1.1"), &[]);
+        assert!(one_ip.matches(&syn) && one_ip.matches(&tls));
+        assert!(!one_ip.matches(&other));
+
+        let layers = filter(None, &[Layer::TlsClient, Layer::HttpRequest]);
+        assert!(layers.matches(&tls));
+        assert!(!layers.matches(&syn) && !layers.matches(&other));
+
+        let both = filter(Some("1.1.1.1"), &[Layer::Syn]);
+        assert!(both.matches(&syn));
+        assert!(!both.matches(&tls) && !both.matches(&other));
+    }
+
+    #[tokio::test]
+    async fn subscribers_are_told_whether_a_profile_was_created() -> TestResult {
+        let state = fixtures::state()?;
+        assert!(!state.updates.has_subscribers());
+        let response = stream_updates(State(state.clone()), Query(filter(Some("1.1.1.1"), &[])))
+            .await
+            .into_response();
+        assert!(state.updates.has_subscribers());
+
+        state.updates.publish(update("1.1.1.1", Layer::Syn, true));
+        state.updates.publish(update("2.2.2.2", Layer::Syn, true));
+        state
+            .updates
+            .publish(update("1.1.1.1", Layer::TlsClient, false));
+
+        let mut body = response.into_body().into_data_stream();
+        let (name, data) = next_event(&mut body).await?;
+        assert_eq!(name, "created");
+        assert_eq!(data["id"], "1.1.1.1");
+        assert_eq!(data["layer"], "syn");
+        assert_eq!(data["created"], true);
+
+        // The other client's update was filtered out.
+        let (name, data) = next_event(&mut body).await?;
+        assert_eq!(name, "updated");
+        assert_eq!(data["layer"], "tls_client");
+        assert_eq!(data["profile"]["id"], "1.1.1.1");
+
+        drop(body);
+        assert!(!state.updates.has_subscribers());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscribers_that_fall_behind_are_told_how_much_they_missed() -> TestResult {
+        let mut state = fixtures::state()?;
+        state.updates = Arc::new(Updates::new(2));
+        let response = stream_updates(State(state.clone()), Query(filter(None, &[])))
+            .await
+            .into_response();
+        for ip in ["1.1.1.1", "2.2.2.2", "3.3.3.3", "4.4.4.4", "5.5.5.5"] {
+            state.updates.publish(update(ip, Layer::Syn, true));
+        }
+
+        let mut body = response.into_body().into_data_stream();
+        let (name, data) = next_event(&mut body).await?;
+        assert_eq!(name, "lagged");
+        assert_eq!(data, serde_json::json!({ "skipped": 3 }));
+        // The stream carries on with the updates still buffered.
+        for ip in ["4.4.4.4", "5.5.5.5"] {
+            let (name, data) = next_event(&mut body).await?;
+            assert_eq!(
+                (name.as_str(), &data["id"]),
+                ("created", &serde_json::json!(ip))
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,348 @@
+//! HTTPS serving, optionally requiring client certificates, with certificates reloaded when
+//! their files change.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, SystemTime};
+
+use axum::{extract::ConnectInfo, Router};
+use hyper::server::conn::http1;
+use hyper_util::{rt::TokioIo, service::TowerToHyperService};
+use metrics::counter;
+use tokio::net::TcpListener;
+use tokio::time::{self, MissedTickBehavior};
+use tokio_rustls::rustls::{
+    self,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+use tokio_rustls::TlsAcceptor;
+use tower_http::add_extension::AddExtension;
+use tracing::{debug, error, info, warn};
+
+use crate::metrics::{TLS_HANDSHAKE_FAILURES, TLS_RELOADS};
+use crate::pseudonym::Pseudonymizer;
+
+/// How long a client may take to complete the handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Certificate files of the server, and of the CAs its clients must be signed by.
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsFiles {
+    fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+        [Some(&self.cert), Some(&self.key), self.client_ca.as_ref()]
+            .into_iter()
+            .flatten()
+    }
+
+    fn load(&self) -> Result<ServerConfig, TlsError> {
+        let chain = CertificateDer::pem_file_iter(&self.cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| TlsError::Pem(self.cert.clone(), e))?;
+        if chain.is_empty() {
+            return Err(TlsError::Empty(self.cert.clone()));
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key)
+            .map_err(|e| TlsError::Pem(self.key.clone(), e))?;
+
+        let builder = match &self.client_ca {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in CertificateDer::pem_file_iter(path)
+                    .map_err(|e| TlsError::Pem(path.clone(), e))?
+                {
+                    let cert = cert.map_err(|e| TlsError::Pem(path.clone(), e))?;
+                    roots.add(cert).map_err(TlsError::Rustls)?;
+                }
+                if roots.is_empty() {
+                    return Err(TlsError::Empty(path.clone()));
+                }
+                let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                    .build()
+                    .map_err(|e| TlsError::ClientCa(path.clone(), e))?;
+                ServerConfig::builder().with_client_cert_verifier(verifier)
+            }
+            None => ServerConfig::builder().with_no_client_auth(),
+        };
+        let mut config = builder
+            .with_single_cert(chain, key)
+            .map_err(TlsError::Rustls)?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(config)
+    }
+}
+
+/// The loaded server configuration, swapped out whole when a file changes. Established
+/// connections keep the configuration they were accepted with.
+pub struct Tls {
+    files: TlsFiles,
+    config: RwLock<Arc<ServerConfig>>,
+    modified: RwLock<Vec<Option<SystemTime>>>,
+}
+
+impl Tls {
+    pub fn open(files: TlsFiles) -> Result<Self, TlsError> {
+        let modified = files.paths().map(|path| modified(path)).collect();
+        let config = files.load()?;
+        match &files.client_ca {
+            Some(path) => info!(
+                "Serving HTTPS with {}, requiring client certificates signed by {}",
+                files.cert.display(),
+                path.display()
+            ),
+            None => info!("Serving HTTPS with {}", files.cert.display()),
+        }
+        Ok(Tls {
+            files,
+            config: RwLock::new(Arc::new(config)),
+            modified: RwLock::new(modified),
+        })
+    }
+
+    fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(
+            self.config
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone(),
+        )
+    }
+
+    /// Reloads the files if any changed since they were last read. Files that fail to load
+    /// leave the current configuration in place.
+    fn reload_if_changed(&self) {
+        let current: Vec<_> = self.files.paths().map(|path| modified(path)).collect();
+        {
+            let mut known = self
+                .modified
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
+            if current == *known {
+                return;
+            }
+            *known = current;
+        }
+
+        match self.files.load() {
+            Ok(config) => {
+                info!("Reloaded TLS certificates");
+                counter!(TLS_RELOADS, "result" => "ok").increment(1);
+                *self.config.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(config);
+            }
+            Err(e) => {
+                error!("Keeping the previous TLS certificates: {e}");
+                counter!(TLS_RELOADS, "result" => "error").increment(1);
+            }
+        }
+    }
+}
+
+/// Periodically picks up changes to the certificate files.
+pub async fn watch(tls: Arc<Tls>, interval: Duration) {
+    let mut ticker = time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        tls.reload_if_changed();
+    }
+}
+
+/// Serves `app` over TLS on `listener`. Handshakes run on the connection's own task, so a
+/// slow client does not hold up the others. Peers are logged by pseudonym.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    tls: Arc<Tls>,
+    pseudonyms: Arc<Pseudonymizer>,
+) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // Usually out of file descriptors; give connections time to close.
+                error!("Failed to accept a connection: {e}");
+                time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let acceptor = tls.acceptor();
+        let app = app.clone();
+        let client = pseudonyms.ip(&peer.ip().to_string());
+        tokio::spawn(async move {
+            let stream = match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    warn!("TLS handshake with {client} failed: {e}");
+                    counter!(TLS_HANDSHAKE_FAILURES).increment(1);
+                    return;
+                }
+                Err(_) => {
+                    warn!("TLS handshake with {client} timed out");
+                    counter!(TLS_HANDSHAKE_FAILURES).increment(1);
+                    return;
+                }
+            };
+            let service = TowerToHyperService::new(AddExtension::new(app, ConnectInfo(peer)));
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                debug!("Connection with {client} ended: {e}");
+            }
+        });
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+#[derive(Debug)]
+pub enum TlsError {
+    Pem(PathBuf, rustls::pki_types::pem::Error),
+    Empty(PathBuf),
+    ClientCa(PathBuf, rustls::server::VerifierBuilderError),
+    Rustls(rustls::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::Pem(path, e) => write!(f, "cannot read {}: {e}", path.display()),
+            TlsError::Empty(path) => write!(f, "no certificate in {}", path.display()),
+            TlsError::ClientCa(path, e) => {
+                write!(f, "unusable client CA {}: {e}", path.display())
+            }
+            TlsError::Rustls(e) => write!(f, "invalid certificate or key: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+
+    type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+    /// An empty directory of its own for each test.
+    fn scratch(name: &str) -> Result<PathBuf, std::io::Error> {
+        let dir = std::env::temp_dir().join(format!("assembler-tls-{name}-{}", std::process::id()));
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+
+    fn ca() -> Result<CertifiedIssuer<'static, KeyPair>, rcgen::Error> {
+        let mut params = CertificateParams::new(Vec::new())?;
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        CertifiedIssuer::self_signed(params, KeyPair::generate()?)
+    }
+
+    /// A certificate for 127.0.0.1 signed by `ca`, and its key, in PEM.
+    fn issue(ca: &CertifiedIssuer<'_, KeyPair>) -> Result<(String, String), rcgen::Error> {
+        let key = KeyPair::generate()?;
+        let cert = CertificateParams::new(vec!["127.0.0.1".to_string()])?.signed_by(&key, ca)?;
+        Ok((cert.pem(), key.serialize_pem()))
+    }
+
+    /// Writes the server's certificate and key into `dir`.
+    fn install(dir: &Path, (cert, key): &(String, String)) -> Result<TlsFiles, std::io::Error> {
+        let files = TlsFiles {
+            cert: dir.join("cert.pem"),
+            key: dir.join("key.pem"),
+            client_ca: None,
+        };
+        fs::write(&files.cert, cert)?;
+        fs::write(&files.key, key)?;
+        Ok(files)
+    }
+
+    fn config(tls: &Tls) -> Arc<ServerConfig> {
+        tls.config
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    #[test]
+    fn broken_files_leave_the_previous_certificates_in_place() -> TestResult {
+        let dir = scratch("reload")?;
+        let ca = ca()?;
+        let (first, second) = (issue(&ca)?, issue(&ca)?);
+        let tls = Tls::open(install(&dir, &first)?)?;
+        let loaded = config(&tls);
+
+        // A certificate with the key of another, then a key that is not PEM at all.
+        install(&dir, &(second.0.clone(), first.1))?;
+        tls.reload_if_changed();
+        assert!(Arc::ptr_eq(&config(&tls), &loaded));
+        install(&dir, &(second.0.clone(), "not a key".to_string()))?;
+        tls.reload_if_changed();
+        assert!(Arc::ptr_eq(&config(&tls), &loaded));
+
+        // Once the pair is whole again, it is picked up.
+        install(&dir, &second)?;
+        tls.reload_if_changed();
+        assert!(!Arc::ptr_eq(&config(&tls), &loaded));
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn clients_without_a_certificate_are_refused_when_one_is_required() -> TestResult {
+        let dir = scratch("client-ca")?;
+        let ca = ca()?;
+        fs::write(dir.join("ca.pem"), ca.pem())?;
+        let files = TlsFiles {
+            client_ca: Some(dir.join("ca.pem")),
+            ..install(&dir, &issue(&ca)?)?
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("https://{}/", listener.local_addr()?);
+        tokio::spawn(serve(
+            listener,
+            Router::new().route("/", get(|| async { "ok" })),
+            Arc::new(Tls::open(files)?),
+            Arc::new(Pseudonymizer::off()),
+        ));
+
+        let client = |identity: Option<reqwest::Identity>| {
+            let mut builder = reqwest::Client::builder()
+                .add_root_certificate(reqwest::Certificate::from_pem(ca.pem().as_bytes())?);
+            if let Some(identity) = identity {
+                builder = builder.identity(identity);
+            }
+            builder.build()
+        };
+
+        assert!(client(None)?.get(&url).send().await.is_err());
+
+        let (cert, key) = issue(&ca)?;
+        let identity = reqwest::Identity::from_pem(format!("{cert}\n{key}").as_bytes())?;
+        let response = client(Some(identity))?.get(&url).send().await?;
+        assert_eq!(response.text().await?, "ok");
+
+        // A certificate from another CA is no better than none.
+        let (cert, key) = issue(&self::ca()?)?;
+        let stranger = reqwest::Identity::from_pem(format!("{cert}\n{key}").as_bytes())?;
+        assert!(client(Some(stranger))?.get(&url).send().await.is_err());
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
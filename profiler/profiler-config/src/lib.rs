@@ -0,0 +1,206 @@
+//! Configuration shared by the profiler binaries.
+//!
+//! Every command-line option of a binary can also be set in a TOML file or through the
+//! environment. Values are resolved in this order, first match wins:
+//!
+//! 1. the command line, e.g. `--batch-size 50`;
+//! 2. an environment variable named after the option, e.g. `PROFILER_BATCH_SIZE=50`;
+//! 3. the config file given by `--config` or `PROFILER_CONFIG`;
+//! 4. the built-in default.
+//!
+//! A single file serves all binaries. Top-level keys apply to every binary, `[collector]`
+//! to the three collectors, and `[tcp-collector]`, `[tls-collector]`, `[http-collector]`
+//! and `[assembler]` to one binary each. Keys are option names, with `-` or `_`:
+//!
+//! ```toml
+//! log_level = "info"
+//!
+//! [collector]
+//! interface = "eth0"
+//! batch_size = 200
+//!
+//! [http-collector]
+//! max_connections = 500
+//!
+//! [assembler]
+//! listen = "0.0.0.0:8000"
+//! max_profiles = 1000
+//! ```
+//!
+//! Unknown sections and keys are rejected so that typos do not go unnoticed, and every value
+//! goes through the same validation as its command-line counterpart.
+
+use clap::{Args, Parser};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+/// Sections a config file may contain.
+pub const SECTIONS: &[&str] = &[
+    "collector",
+    "tcp-collector",
+    "tls-collector",
+    "http-collector",
+    "assembler",
+];
+
+/// Prefix of the environment variables overriding config values.
+pub const ENV_PREFIX: &str = "PROFILER_";
+
+const CONFIG_ENV: &str = "PROFILER_CONFIG";
+
+/// The `--config` option. Flatten it into a binary's arguments so it shows up in `--help`.
+#[derive(Args, Debug, Clone)]
+#[command(about = None, long_about = None)]
+pub struct ConfigArgs {
+    /// TOML file with settings for this binary. Command-line options and `PROFILER_*`
+    /// environment variables take precedence over it.
+    #[clap(long, env = CONFIG_ENV, value_parser)]
+    pub config: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    UnknownSection(String),
+    UnknownKey { section: String, key: String },
+    UnsupportedValue { section: String, key: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "cannot read {}: {e}", path.display()),
+            ConfigError::Parse(path, e) => write!(f, "invalid TOML in {}: {e}", path.display()),
+            ConfigError::UnknownSection(section) => write!(f, "unknown section [{section}]"),
+            ConfigError::UnknownKey { section, key } => {
+                write!(f, "unknown setting `{key}` in {section}")
+            }
+            ConfigError::UnsupportedValue { section, key } => write!(
+                f,
+                "setting `{key}` in {section} must be a string, number or boolean"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Parses the arguments of a binary, layering the config file and environment below the
+/// command line.
+///
+/// `sections` lists the file sections that apply to this binary, most general first, e.g.
+/// `&["collector", "tcp-collector"]`. Invalid command-line, environment or file values
+/// print a usage error and exit, like [`Parser::parse`].
+pub fn load<A: Parser>(sections: &[&str]) -> Result<A, ConfigError> {
+    load_from(std::env::args_os(), sections)
+}
+
+/// [`load`] with the given command line instead of the process's, the binary name first,
+/// like [`Parser::parse_from`].
+pub fn load_from<A, I, T>(args: I, sections: &[&str]) -> Result<A, ConfigError>
+where
+    A: Parser,
+    I: IntoIterator<Item = T>,
+    T: Into<OsString>,
+{
+    let args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+    let mut command = A::command();
+
+    let ids: Vec<String> = command
+        .get_arguments()
+        .filter(|arg| !arg.is_positional())
+        .map(|arg| arg.get_id().to_string())
+        .filter(|id| !matches!(id.as_str(), "help" | "version" | "config"))
+        .collect();
+
+    for id in &ids {
+        let var = format!("{ENV_PREFIX}{}", id.to_uppercase());
+        command = command.mut_arg(id, |arg| arg.env(var));
+    }
+
+    if let Some(path) = config_path(&args) {
+        for (section, key, value) in read_settings(&path, sections)? {
+            let id = key.replace('-', "_");
+            if !ids.contains(&id) {
+                return Err(ConfigError::UnknownKey { section, key });
+            }
+            command = command.mut_arg(&id, |arg| arg.default_value(value));
+        }
+    }
+
+    let matches = command.get_matches_from(args);
+    Ok(A::from_arg_matches(&matches).unwrap_or_else(|e| e.exit()))
+}
+
+/// Finds `--config` before clap runs, since the file feeds the command clap parses.
+fn config_path(args: &[OsString]) -> Option<PathBuf> {
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|a| a.strip_prefix("--config=")) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    std::env::var_os(CONFIG_ENV)
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Reads the settings of `sections` from the file, as `(section, key, value)` in the order
+/// they apply: top-level keys, then each section in turn.
+fn read_settings(
+    path: &Path,
+    sections: &[&str],
+) -> Result<Vec<(String, String, OsString)>, ConfigError> {
+    let content = fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+    let table: Table = content
+        .parse()
+        .map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+
+    let mut settings = Vec::new();
+    for (key, value) in &table {
+        if let Value::Table(_) = value {
+            if !SECTIONS.contains(&key.as_str()) {
+                return Err(ConfigError::UnknownSection(key.clone()));
+            }
+            continue;
+        }
+        settings.push(setting("top level", key, value)?);
+    }
+    for section in sections {
+        let Some(Value::Table(entries)) = table.get(*section) else {
+            continue;
+        };
+        let name = format!("[{section}]");
+        for (key, value) in entries {
+            settings.push(setting(&name, key, value)?);
+        }
+    }
+    Ok(settings)
+}
+
+fn setting(
+    section: &str,
+    key: &str,
+    value: &Value,
+) -> Result<(String, String, OsString), ConfigError> {
+    let value = match value {
+        Value::String(s) => s.clone(),
+        Value::Integer(i) => i.to_string(),
+        Value::Float(f) => f.to_string(),
+        Value::Boolean(b) => b.to_string(),
+        _ => {
+            return Err(ConfigError::UnsupportedValue {
+                section: section.to_string(),
+                key: key.to_string(),
+            })
+        }
+    };
+    Ok((section.to_string(), key.to_string(), OsString::from(value)))
+}
@@ -0,0 +1,125 @@
+use clap::Parser;
+use profiler_config::{load_from, ConfigArgs, ConfigError};
+use std::fs;
+use std::path::PathBuf;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+/// Options named after where each is expected to come from, so that tests running in
+/// parallel do not share environment variables.
+#[derive(Parser, Debug)]
+struct Args {
+    #[clap(flatten)]
+    config: ConfigArgs,
+    #[clap(long, default_value = "default")]
+    from_cli: String,
+    #[clap(long, default_value = "default")]
+    from_env: String,
+    #[clap(long, default_value = "default")]
+    from_file: String,
+    #[clap(long, default_value = "default")]
+    from_section: String,
+    #[clap(long, default_value = "default")]
+    from_default: String,
+    #[clap(long, default_value_t = 1)]
+    batch_size: u32,
+}
+
+/// Writes `content` to a config file of the test's own.
+fn config(name: &str, content: &str) -> Result<PathBuf, std::io::Error> {
+    let path = std::env::temp_dir().join(format!(
+        "profiler-config-{name}-{}.toml",
+        std::process::id()
+    ));
+    fs::write(&path, content)?;
+    Ok(path)
+}
+
+#[test]
+fn values_come_from_the_cli_then_the_environment_then_the_file() -> TestResult {
+    let path = config(
+        "order",
+        r#"
+        from_cli = "file"
+        from-env = "file"
+        from_file = "file"
+        from_section = "top level"
+        batch_size = 50
+
+        [assembler]
+        from_section = "section"
+
+        [tcp-collector]
+        from_file = "another binary's section"
+        "#,
+    )?;
+    std::env::set_var("PROFILER_FROM_CLI", "env");
+    std::env::set_var("PROFILER_FROM_ENV", "env");
+
+    let args: Args = load_from(
+        [
+            "profile-assembler".into(),
+            "--from-cli".into(),
+            "cli".into(),
+            "--config".into(),
+            path.clone().into_os_string(),
+        ],
+        &["assembler"],
+    )?;
+    assert_eq!(args.from_cli, "cli");
+    assert_eq!(args.from_env, "env");
+    assert_eq!(args.from_file, "file");
+    // The binary's own section wins over the top level.
+    assert_eq!(args.from_section, "section");
+    assert_eq!(args.from_default, "default");
+    assert_eq!(args.batch_size, 50);
+    assert_eq!(args.config.config.as_ref(), Some(&path));
+
+    // `--config=` is understood too.
+    let args: Args = load_from(
+        [
+            "profile-assembler".to_string(),
+            format!("--config={}", path.display()),
+        ],
+        &["assembler"],
+    )?;
+    assert_eq!(args.from_file, "file");
+
+    fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[test]
+fn unknown_keys_and_sections_are_refused() -> TestResult {
+    let load = |name: &str, content: &str| -> Result<Result<Args, ConfigError>, std::io::Error> {
+        let path = config(name, content)?;
+        let loaded = load_from(
+            [
+                "profile-assembler".into(),
+                "--config".into(),
+                path.clone().into_os_string(),
+            ],
+            &["assembler"],
+        );
+        fs::remove_file(&path)?;
+        Ok(loaded)
+    };
+
+    assert!(matches!(
+        load("unknown-key", "[assembler]\nbatch_sise = 50\n")?,
+        Err(ConfigError::UnknownKey { section, key }) if section == "[assembler]" && key == "batch_sise"
+    ));
+    assert!(matches!(
+        load("unknown-top-level-key", "batch_sise = 50\n")?,
+        Err(ConfigError::UnknownKey { section, .. }) if section == "top level"
+    ));
+    assert!(matches!(
+        load("unknown-section", "[asembler]\nbatch_size = 50\n")?,
+        Err(ConfigError::UnknownSection(section)) if section == "asembler"
+    ));
+    assert!(matches!(
+        load("unsupported-value", "[assembler]\nbatch_size = [50]\n")?,
+        Err(ConfigError::UnsupportedValue { .. })
+    ));
+    Ok(())
+}
@@ -0,0 +1,59 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    HttpRequestData, HttpResponseData, IngestEvent, MtuData, NetworkEndpoint, SynAckPacketData,
+    SynPacketData, TlsClient, UptimeData,
+};
+
+/// Everything observed on one connection, keyed by its client and server endpoints.
+///
+/// A [`Profile`](crate::Profile) merges every connection from one IP, so devices behind the
+/// same NAT end up mixed together; a connection record only holds what a single socket sent.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ConnectionRecord {
+    /// `client->server`, as built by [`ConnectionRecord::key`].
+    pub id: String,
+    /// Id of the profile this connection was merged into.
+    pub profile: String,
+    pub client: NetworkEndpoint,
+    pub server: NetworkEndpoint,
+    /// Collector timestamps of the first and last event, in Unix seconds.
+    pub first_seen: u64,
+    pub last_seen: u64,
+    pub syn: Option<SynPacketData>,
+    pub syn_ack: Option<SynAckPacketData>,
+    pub mtu: Option<MtuData>,
+    pub uptime: Option<UptimeData>,
+    pub http_request: Option<HttpRequestData>,
+    pub http_response: Option<HttpResponseData>,
+    pub tls_client: Option<TlsClient>,
+}
+
+impl ConnectionRecord {
+    /// Id of the connection between `client` and `server`, e.g. `10.0.0.2:51234->10.0.0.1:443`.
+    pub fn key(client: &NetworkEndpoint, server: &NetworkEndpoint) -> String {
+        format!("{client}->{server}")
+    }
+
+    /// Stores `event` as this connection's observation for its layer.
+    pub fn apply(&mut self, event: &IngestEvent) {
+        let timestamp = event.timestamp();
+        if self.id.is_empty() {
+            self.id = Self::key(event.client(), event.server());
+            self.client = event.client().clone();
+            self.server = event.server().clone();
+            self.first_seen = timestamp;
+        }
+        self.first_seen = self.first_seen.min(timestamp);
+        self.last_seen = self.last_seen.max(timestamp);
+        match event {
+            IngestEvent::Syn(data) => self.syn = Some(data.clone()),
+            IngestEvent::SynAck(data) => self.syn_ack = Some(data.clone()),
+            IngestEvent::Mtu(data) => self.mtu = Some(data.clone()),
+            IngestEvent::Uptime(data) => self.uptime = Some(data.clone()),
+            IngestEvent::HttpRequest(data) => self.http_request = Some(data.clone()),
+            IngestEvent::HttpResponse(data) => self.http_response = Some(data.clone()),
+            IngestEvent::Tls(data) => self.tls_client = Some(data.clone()),
+        }
+    }
+}
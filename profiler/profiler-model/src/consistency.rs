@@ -0,0 +1,39 @@
+use serde::{Deserialize, Serialize};
+
+/// How strongly a finding suggests the client is not what it claims to be.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Low,
+    Medium,
+    High,
+}
+
+/// One contradiction between the layers of a profile.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Finding {
+    /// Stable name of the check that fired, e.g. `user_agent_os_vs_tcp`.
+    pub check: String,
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// Result of comparing what the layers of a profile say about the client.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Consistency {
+    /// 100 when every layer agrees, lower with each finding, down to 0.
+    pub score: u8,
+    /// Checks that had the data they need, whether or not they found anything.
+    pub checked: Vec<String>,
+    pub findings: Vec<Finding>,
+}
+
+impl Default for Consistency {
+    fn default() -> Self {
+        Consistency {
+            score: 100,
+            checked: Vec::new(),
+            findings: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    HttpRequestIngest, HttpResponseIngest, MtuIngest, NetworkEndpoint, SynAckIngest, SynIngest,
+    TlsIngest, UptimeIngest,
+};
+
+/// One ingest payload tagged with its kind, as carried by `/api/ingest/batch`.
+///
+/// Serialized as `{"type": "syn", "data": {...}}`; the tag matches the path of the
+/// single-event endpoint (`/api/ingest/syn`, `/api/ingest/http_request`, ...).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum IngestEvent {
+    Syn(SynIngest),
+    SynAck(SynAckIngest),
+    Mtu(MtuIngest),
+    Uptime(UptimeIngest),
+    HttpRequest(HttpRequestIngest),
+    HttpResponse(HttpResponseIngest),
+    Tls(TlsIngest),
+}
+
+impl IngestEvent {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            IngestEvent::Syn(_) => "syn",
+            IngestEvent::SynAck(_) => "syn_ack",
+            IngestEvent::Mtu(_) => "mtu",
+            IngestEvent::Uptime(_) => "uptime",
+            IngestEvent::HttpRequest(_) => "http_request",
+            IngestEvent::HttpResponse(_) => "http_response",
+            IngestEvent::Tls(_) => "tls",
+        }
+    }
+
+    pub fn schema_version(&self) -> u32 {
+        match self {
+            IngestEvent::Syn(data) => data.schema_version,
+            IngestEvent::SynAck(data) => data.schema_version,
+            IngestEvent::Mtu(data) => data.schema_version,
+            IngestEvent::Uptime(data) => data.schema_version,
+            IngestEvent::HttpRequest(data) => data.schema_version,
+            IngestEvent::HttpResponse(data) => data.schema_version,
+            IngestEvent::Tls(data) => data.schema_version,
+        }
+    }
+
+    /// Collector timestamp of the observation, in Unix seconds.
+    pub fn timestamp(&self) -> u64 {
+        match self {
+            IngestEvent::Syn(data) => data.timestamp,
+            IngestEvent::SynAck(data) => data.timestamp,
+            IngestEvent::Mtu(data) => data.timestamp,
+            IngestEvent::Uptime(data) => data.timestamp,
+            IngestEvent::HttpRequest(data) => data.timestamp,
+            IngestEvent::HttpResponse(data) => data.timestamp,
+            IngestEvent::Tls(data) => data.timestamp,
+        }
+    }
+
+    /// The endpoint that opened the connection, whichever direction the packet travelled.
+    pub fn client(&self) -> &NetworkEndpoint {
+        match self {
+            IngestEvent::Syn(data) => &data.source,
+            IngestEvent::SynAck(data) => &data.destination,
+            IngestEvent::Mtu(data) => &data.source,
+            IngestEvent::Uptime(data) => &data.destination,
+            IngestEvent::HttpRequest(data) => &data.source,
+            IngestEvent::HttpResponse(data) => &data.destination,
+            IngestEvent::Tls(data) => &data.source,
+        }
+    }
+
+    /// Mutable access to [`IngestEvent::client`].
+    pub fn client_mut(&mut self) -> &mut NetworkEndpoint {
+        match self {
+            IngestEvent::Syn(data) => &mut data.source,
+            IngestEvent::SynAck(data) => &mut data.destination,
+            IngestEvent::Mtu(data) => &mut data.source,
+            IngestEvent::Uptime(data) => &mut data.destination,
+            IngestEvent::HttpRequest(data) => &mut data.source,
+            IngestEvent::HttpResponse(data) => &mut data.destination,
+            IngestEvent::Tls(data) => &mut data.source,
+        }
+    }
+
+    /// The endpoint that accepted the connection.
+    pub fn server(&self) -> &NetworkEndpoint {
+        match self {
+            IngestEvent::Syn(data) => &data.destination,
+            IngestEvent::SynAck(data) => &data.source,
+            IngestEvent::Mtu(data) => &data.destination,
+            IngestEvent::Uptime(data) => &data.source,
+            IngestEvent::HttpRequest(data) => &data.destination,
+            IngestEvent::HttpResponse(data) => &data.source,
+            IngestEvent::Tls(data) => &data.destination,
+        }
+    }
+
+    /// What identifies the observation in its layer: the p0f-style signature for TCP and HTTP,
+    /// the JA4 for TLS, the MTU value and the clock frequency for uptime.
+    pub fn signature(&self) -> String {
+        match self {
+            IngestEvent::Syn(data) => data.signature.clone(),
+            IngestEvent::SynAck(data) => data.signature.clone(),
+            IngestEvent::Mtu(data) => data.mtu_value.to_string(),
+            IngestEvent::Uptime(data) => format!("{:.0} Hz", data.freq),
+            IngestEvent::HttpRequest(data) => data.signature.clone(),
+            IngestEvent::HttpResponse(data) => data.signature.clone(),
+            IngestEvent::Tls(data) => data.ja4.clone(),
+        }
+    }
+
+    /// What the signature was matched to: an OS, a link type, a browser, a web server or an
+    /// application.
+    pub fn label(&self) -> Option<&str> {
+        match self {
+            IngestEvent::Syn(data) => Some(&data.os_detected.os),
+            IngestEvent::SynAck(data) => Some(&data.os_detected.os),
+            IngestEvent::Mtu(data) => Some(&data.link),
+            IngestEvent::Uptime(_) => None,
+            IngestEvent::HttpRequest(data) => Some(&data.browser.browser),
+            IngestEvent::HttpResponse(data) => Some(&data.web_server.web_server),
+            IngestEvent::Tls(data) => data
+                .application_detected
+                .as_ref()
+                .and_then(|detection| detection.application.as_deref()),
+        }
+    }
+}
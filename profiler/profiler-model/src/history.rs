@@ -0,0 +1,98 @@
+use serde::{Deserialize, Serialize};
+
+use crate::IngestEvent;
+
+/// One distinct signature seen from a client, with when and how often.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SignatureSeen {
+    pub signature: String,
+    /// What the signature was matched to, e.g. an OS, browser, web server or application, if
+    /// anything.
+    pub label: Option<String>,
+    /// Collector timestamps, in Unix seconds.
+    pub first_seen: u64,
+    pub last_seen: u64,
+    pub count: u64,
+}
+
+/// Distinct signatures observed for a client, per layer.
+///
+/// Each layer keeps at most a fixed number of entries; when a new signature does not fit,
+/// the one seen least recently is dropped.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ProfileHistory {
+    pub syn: Vec<SignatureSeen>,
+    pub syn_ack: Vec<SignatureSeen>,
+    pub mtu: Vec<SignatureSeen>,
+    pub uptime: Vec<SignatureSeen>,
+    pub http_request: Vec<SignatureSeen>,
+    pub http_response: Vec<SignatureSeen>,
+    pub tls_client: Vec<SignatureSeen>,
+}
+
+impl ProfileHistory {
+    /// Counts `event` under its layer, keeping at most `limit` distinct signatures there.
+    pub fn record(&mut self, event: &IngestEvent, limit: usize) {
+        let layer = match event {
+            IngestEvent::Syn(_) => &mut self.syn,
+            IngestEvent::SynAck(_) => &mut self.syn_ack,
+            IngestEvent::Mtu(_) => &mut self.mtu,
+            IngestEvent::Uptime(_) => &mut self.uptime,
+            IngestEvent::HttpRequest(_) => &mut self.http_request,
+            IngestEvent::HttpResponse(_) => &mut self.http_response,
+            IngestEvent::Tls(_) => &mut self.tls_client,
+        };
+        record_signature(
+            layer,
+            event.signature(),
+            event.label().map(str::to_string),
+            event.timestamp(),
+            limit,
+        );
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.syn.is_empty()
+            && self.syn_ack.is_empty()
+            && self.mtu.is_empty()
+            && self.uptime.is_empty()
+            && self.http_request.is_empty()
+            && self.http_response.is_empty()
+            && self.tls_client.is_empty()
+    }
+}
+
+fn record_signature(
+    layer: &mut Vec<SignatureSeen>,
+    signature: String,
+    label: Option<String>,
+    timestamp: u64,
+    limit: usize,
+) {
+    if let Some(seen) = layer.iter_mut().find(|seen| seen.signature == signature) {
+        seen.first_seen = seen.first_seen.min(timestamp);
+        seen.last_seen = seen.last_seen.max(timestamp);
+        seen.count = seen.count.saturating_add(1);
+        seen.label = label;
+        return;
+    }
+
+    layer.push(SignatureSeen {
+        signature,
+        label,
+        first_seen: timestamp,
+        last_seen: timestamp,
+        count: 1,
+    });
+    while layer.len() > limit.max(1) {
+        let Some(stalest) = layer
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, seen)| seen.last_seen)
+            .map(|(index, _)| index)
+        else {
+            break;
+        };
+        layer.remove(stalest);
+    }
+}
@@ -0,0 +1,63 @@
+use serde::{Deserialize, Serialize};
+
+use crate::NetworkEndpoint;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BrowserDetection {
+    pub browser: String,
+    pub quality: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HttpRequestObserved {
+    pub lang: Option<String>,
+    pub user_agent: Option<String>,
+    pub diagnostic: String,
+    pub method: Option<String>,
+    pub version: String,
+    pub headers: String,
+    pub cookies: String,
+    pub referer: Option<String>,
+    pub uri: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HttpRequestData {
+    #[serde(default)]
+    pub schema_version: u32,
+    pub source: NetworkEndpoint,
+    pub destination: NetworkEndpoint,
+    pub observed: HttpRequestObserved,
+    pub signature: String,
+    pub browser: BrowserDetection,
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WebServerDetection {
+    pub web_server: String,
+    pub quality: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HttpResponseObserved {
+    pub server: Option<String>,
+    pub version: String,
+    pub headers: String,
+    pub status_code: Option<u16>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HttpResponseData {
+    #[serde(default)]
+    pub schema_version: u32,
+    pub source: NetworkEndpoint,
+    pub destination: NetworkEndpoint,
+    pub observed: HttpResponseObserved,
+    pub signature: String,
+    pub web_server: WebServerDetection,
+    pub timestamp: u64,
+}
+
+pub type HttpRequestIngest = HttpRequestData;
+pub type HttpResponseIngest = HttpResponseData;
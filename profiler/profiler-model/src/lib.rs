@@ -0,0 +1,96 @@
+//! Wire schema shared by the collectors and the profile assembler.
+//!
+//! Every payload posted to `/api/ingest/*` and every profile served by the
+//! assembler is defined here, so a collector and the assembler can no longer
+//! drift apart silently: a changed field breaks the build, and a collector
+//! built against another schema revision is rejected at ingest time.
+
+mod connection;
+mod consistency;
+mod event;
+mod history;
+mod http;
+mod profile;
+mod redaction;
+mod signature;
+mod tcp;
+mod tls;
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+pub use connection::ConnectionRecord;
+pub use consistency::{Consistency, Finding, Severity};
+pub use event::IngestEvent;
+pub use history::{ProfileHistory, SignatureSeen};
+pub use http::{
+    BrowserDetection, HttpRequestData, HttpRequestIngest, HttpRequestObserved, HttpResponseData,
+    HttpResponseIngest, HttpResponseObserved, WebServerDetection,
+};
+pub use profile::Profile;
+pub use redaction::{
+    InvalidRedaction, Redaction, RedactionPolicy, RedactionRules, DEFAULT_COOKIE_REDACTION,
+    DEFAULT_HEADER_REDACTION, HASH_PREFIX, REDACTED,
+};
+pub use signature::{
+    sign_request, verify_request, KEY_ID_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER,
+};
+pub use tcp::{
+    MtuData, MtuIngest, OsDetection, SynAckIngest, SynAckPacketData, SynIngest, SynPacketData,
+    TcpObserved, UptimeData, UptimeIngest,
+};
+pub use tls::{ApplicationDetection, TlsClient, TlsClientObserved, TlsIngest};
+
+/// Revision of the ingest schema produced by this crate.
+///
+/// Bump it whenever a payload changes in a way an older peer cannot read.
+/// Payloads that do not carry the field at all deserialize as version `0`.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct NetworkEndpoint {
+    pub ip: String,
+    pub port: u16,
+}
+
+/// Formats as `ip:port`, with IPv6 addresses in brackets.
+impl fmt::Display for NetworkEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.ip.contains(':') {
+            write!(f, "[{}]:{}", self.ip, self.port)
+        } else {
+            write!(f, "{}:{}", self.ip, self.port)
+        }
+    }
+}
+
+/// Returned when an ingest payload was produced with a different schema revision.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SchemaMismatch {
+    pub expected: u32,
+    pub found: u32,
+}
+
+impl fmt::Display for SchemaMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "schema version mismatch: expected {}, found {}",
+            self.expected, self.found
+        )
+    }
+}
+
+impl std::error::Error for SchemaMismatch {}
+
+/// Checks a received `schema_version` against [`SCHEMA_VERSION`].
+pub fn check_schema_version(found: u32) -> Result<(), SchemaMismatch> {
+    if found == SCHEMA_VERSION {
+        Ok(())
+    } else {
+        Err(SchemaMismatch {
+            expected: SCHEMA_VERSION,
+            found,
+        })
+    }
+}
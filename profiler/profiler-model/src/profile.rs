@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Consistency, HttpRequestData, HttpResponseData, IngestEvent, MtuData, ProfileHistory,
+    SynAckPacketData, SynPacketData, TlsClient, UptimeData,
+};
+
+/// Everything the assembler knows about one client, as served by `/api/profiles`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Profile {
+    pub id: String,
+    pub timestamp: u64,
+    pub syn: Option<SynPacketData>,
+    pub syn_ack: Option<SynAckPacketData>,
+    pub mtu: Option<MtuData>,
+    pub uptime: Option<UptimeData>,
+    pub http_request: Option<HttpRequestData>,
+    pub http_response: Option<HttpResponseData>,
+    pub tls_client: Option<TlsClient>,
+    pub last_seen: String,
+    /// Every distinct signature seen per layer; the fields above only hold the latest.
+    #[serde(default)]
+    pub history: ProfileHistory,
+    /// Ids of this client's most recent [`ConnectionRecord`](crate::ConnectionRecord)s,
+    /// oldest first.
+    #[serde(default)]
+    pub connections: Vec<String>,
+    /// Contradictions between the layers above, e.g. a user agent claiming another OS than
+    /// the TCP stack.
+    #[serde(default)]
+    pub consistency: Consistency,
+}
+
+impl Profile {
+    /// Makes `event` the latest observation of its layer and records it in the history,
+    /// which keeps at most `history_limit` signatures per layer.
+    pub fn apply(&mut self, event: IngestEvent, history_limit: usize) {
+        self.history.record(&event, history_limit);
+        match event {
+            IngestEvent::Syn(data) => self.syn = Some(data),
+            IngestEvent::SynAck(data) => self.syn_ack = Some(data),
+            IngestEvent::Mtu(data) => self.mtu = Some(data),
+            IngestEvent::Uptime(data) => self.uptime = Some(data),
+            IngestEvent::HttpRequest(data) => self.http_request = Some(data),
+            IngestEvent::HttpResponse(data) => self.http_response = Some(data),
+            IngestEvent::Tls(data) => self.tls_client = Some(data),
+        }
+    }
+
+    /// The latest observation of every layer present, as the events that set them.
+    pub fn layers(&self) -> Vec<IngestEvent> {
+        [
+            self.syn.clone().map(IngestEvent::Syn),
+            self.syn_ack.clone().map(IngestEvent::SynAck),
+            self.mtu.clone().map(IngestEvent::Mtu),
+            self.uptime.clone().map(IngestEvent::Uptime),
+            self.http_request.clone().map(IngestEvent::HttpRequest),
+            self.http_response.clone().map(IngestEvent::HttpResponse),
+            self.tls_client.clone().map(IngestEvent::Tls),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// Links the connection `id` to this profile, keeping the `limit` most recent ones, and
+    /// returns the ids unlinked to make room.
+    pub fn track_connection(&mut self, id: &str, limit: usize) -> Vec<String> {
+        self.connections.retain(|known| known != id);
+        self.connections.push(id.to_string());
+        let excess = self.connections.len().saturating_sub(limit.max(1));
+        self.connections.drain(..excess).collect()
+    }
+}
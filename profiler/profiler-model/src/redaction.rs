@@ -0,0 +1,190 @@
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::{HttpRequestObserved, HttpResponseObserved};
+
+/// Headers whose values are credentials in common use.
+pub const DEFAULT_HEADER_REDACTION: &str =
+    "authorization=name,proxy-authorization=name,cookie=name,set-cookie=name,x-api-key=name,x-auth-token=name";
+/// Cookie values are session identifiers more often than not.
+pub const DEFAULT_COOKIE_REDACTION: &str = "*=name";
+
+/// Value put in place of a value removed by [`Redaction::Name`].
+pub const REDACTED: &str = "[redacted]";
+/// Prefix of a value replaced by [`Redaction::Hash`].
+pub const HASH_PREFIX: &str = "sha256:";
+
+/// What happens to one header or cookie.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Redaction {
+    Keep,
+    /// Keeps the name, replacing the value with [`REDACTED`].
+    Name,
+    /// Replaces the value with a truncated SHA-256, so equal values can still be matched.
+    Hash,
+    /// Leaves the header or cookie out entirely.
+    Drop,
+}
+
+impl FromStr for Redaction {
+    type Err = InvalidRedaction;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(Redaction::Keep),
+            "name" => Ok(Redaction::Name),
+            "hash" => Ok(Redaction::Hash),
+            "drop" => Ok(Redaction::Drop),
+            _ => Err(InvalidRedaction(format!(
+                "unknown action `{s}`, expected keep, name, hash or drop"
+            ))),
+        }
+    }
+}
+
+/// Per-name rules given as `name=action` pairs separated by commas, with `*=action` for the
+/// names not listed (`keep` if absent), e.g. `authorization=drop,x-session=hash,*=keep`.
+/// Names match case-insensitively.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedactionRules {
+    default: Redaction,
+    rules: Vec<(String, Redaction)>,
+}
+
+impl FromStr for RedactionRules {
+    type Err = InvalidRedaction;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rules = RedactionRules {
+            default: Redaction::Keep,
+            rules: Vec::new(),
+        };
+        for rule in s.split(',').map(str::trim).filter(|rule| !rule.is_empty()) {
+            let (name, action) = rule
+                .split_once('=')
+                .ok_or_else(|| InvalidRedaction(format!("`{rule}` is not name=action")))?;
+            let action = action.trim().parse()?;
+            match name.trim() {
+                "*" => rules.default = action,
+                "" => return Err(InvalidRedaction(format!("`{rule}` has no name"))),
+                name => rules.rules.push((name.to_lowercase(), action)),
+            }
+        }
+        Ok(rules)
+    }
+}
+
+impl RedactionRules {
+    fn action(&self, name: &str) -> Redaction {
+        self.rules
+            .iter()
+            .find(|(rule, _)| rule.eq_ignore_ascii_case(name))
+            .map_or(self.default, |(_, action)| *action)
+    }
+
+    /// Joins `name: value` pairs with `, `, as carried in the observed HTTP fields, after
+    /// applying the rules. A missing value is rendered empty.
+    pub fn join<'a>(&self, pairs: impl IntoIterator<Item = (&'a str, Option<&'a str>)>) -> String {
+        pairs
+            .into_iter()
+            .filter_map(|(name, value)| {
+                let value = value.unwrap_or_default();
+                let value = match self.action(name) {
+                    Redaction::Keep => value.to_string(),
+                    Redaction::Drop => return None,
+                    Redaction::Name if value.is_empty() => String::new(),
+                    Redaction::Name => REDACTED.to_string(),
+                    Redaction::Hash => hash(value),
+                };
+                Some(format!("{name}: {value}"))
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Applies the rules to a list already joined by [`RedactionRules::join`] or by a
+    /// collector that does not redact.
+    pub fn redact(&self, joined: &str) -> String {
+        let pairs = split_pairs(joined);
+        self.join(
+            pairs
+                .iter()
+                .map(|(name, value)| (*name, Some(value.as_str()))),
+        )
+    }
+}
+
+/// Rules for the headers and cookies of observed HTTP traffic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedactionPolicy {
+    pub headers: RedactionRules,
+    pub cookies: RedactionRules,
+}
+
+impl RedactionPolicy {
+    pub fn apply_request(&self, observed: &mut HttpRequestObserved) {
+        observed.headers = self.headers.redact(&observed.headers);
+        observed.cookies = self.cookies.redact(&observed.cookies);
+    }
+
+    pub fn apply_response(&self, observed: &mut HttpResponseObserved) {
+        observed.headers = self.headers.redact(&observed.headers);
+    }
+}
+
+/// Hashes a value unless it was already hashed or redacted upstream.
+fn hash(value: &str) -> String {
+    if value.starts_with(HASH_PREFIX) || value == REDACTED || value.is_empty() {
+        return value.to_string();
+    }
+    let digest = Sha256::digest(value.as_bytes());
+    let hex: String = digest
+        .iter()
+        .take(8)
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    format!("{HASH_PREFIX}{hex}")
+}
+
+/// Splits `Name: value, Name: value` back into pairs. A piece not starting with a token and
+/// a colon, or that is an IPv6 address, belongs to the previous value, which itself
+/// contained `, `.
+fn split_pairs(joined: &str) -> Vec<(&str, String)> {
+    let mut pairs: Vec<(&str, String)> = Vec::new();
+    for piece in joined.split(", ").filter(|piece| !piece.is_empty()) {
+        let header = piece
+            .split_once(':')
+            .filter(|(name, _)| is_token(name) && piece.parse::<IpAddr>().is_err())
+            .map(|(name, value)| (name, value.strip_prefix(' ').unwrap_or(value)));
+        match (header, pairs.last_mut()) {
+            (Some((name, value)), _) => pairs.push((name, value.to_string())),
+            (None, Some((_, value))) => {
+                value.push_str(", ");
+                value.push_str(piece);
+            }
+            (None, None) => pairs.push((piece, String::new())),
+        }
+    }
+    pairs
+}
+
+fn is_token(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+}
+
+/// Returned for a malformed rule list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidRedaction(String);
+
+impl fmt::Display for InvalidRedaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidRedaction {}
@@ -0,0 +1,52 @@
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
+
+/// Header naming the key a request was signed with.
+pub const KEY_ID_HEADER: &str = "x-profiler-key-id";
+/// Header carrying the Unix time, in seconds, at which a request was signed.
+pub const TIMESTAMP_HEADER: &str = "x-profiler-timestamp";
+/// Header carrying the hex-encoded signature of a request.
+pub const SIGNATURE_HEADER: &str = "x-profiler-signature";
+
+fn mac(secret: &[u8], timestamp: u64, body: &[u8]) -> Option<Hmac<Sha256>> {
+    // HMAC accepts keys of any length, so this never fails in practice.
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).ok()?;
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b"\n");
+    mac.update(body);
+    Some(mac)
+}
+
+/// HMAC-SHA256 of `timestamp`, a newline and `body`, hex-encoded, as sent in
+/// [`SIGNATURE_HEADER`].
+pub fn sign_request(secret: &[u8], timestamp: u64, body: &[u8]) -> String {
+    mac(secret, timestamp, body)
+        .map(|mac| {
+            mac.finalize()
+                .into_bytes()
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Checks a hex-encoded signature made by [`sign_request`], in constant time.
+pub fn verify_request(secret: &[u8], timestamp: u64, body: &[u8], signature: &str) -> bool {
+    let Some(tag) = decode_hex(signature) else {
+        return false;
+    };
+    mac(secret, timestamp, body).is_some_and(|mac| mac.verify_slice(&tag).is_ok())
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .filter(|pair| pair.len() == 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+        })
+        .collect()
+}
@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+
+use crate::NetworkEndpoint;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OsDetection {
+    pub os: String,
+    pub quality: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TcpObserved {
+    pub version: String,
+    pub initial_ttl: String,
+    pub options_length: u8,
+    pub mss: Option<u16>,
+    pub window_size: String,
+    pub window_scale: Option<u8>,
+    pub options_layout: String,
+    pub quirks: String,
+    pub payload_class: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SynPacketData {
+    #[serde(default)]
+    pub schema_version: u32,
+    pub source: NetworkEndpoint,
+    pub destination: NetworkEndpoint,
+    pub os_detected: OsDetection,
+    pub signature: String,
+    pub observed: TcpObserved,
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SynAckPacketData {
+    #[serde(default)]
+    pub schema_version: u32,
+    pub source: NetworkEndpoint,
+    pub destination: NetworkEndpoint,
+    pub os_detected: OsDetection,
+    pub signature: String,
+    pub observed: TcpObserved,
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MtuData {
+    #[serde(default)]
+    pub schema_version: u32,
+    pub source: NetworkEndpoint,
+    pub destination: NetworkEndpoint,
+    pub link: String,
+    pub mtu_value: u16,
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UptimeData {
+    #[serde(default)]
+    pub schema_version: u32,
+    pub source: NetworkEndpoint,
+    pub destination: NetworkEndpoint,
+    pub uptime_seconds: u64,
+    pub up_mod_days: u32,
+    pub freq: f64,
+    pub timestamp: u64,
+}
+
+pub type SynIngest = SynPacketData;
+pub type SynAckIngest = SynAckPacketData;
+pub type MtuIngest = MtuData;
+pub type UptimeIngest = UptimeData;
@@ -0,0 +1,43 @@
+use serde::{Deserialize, Serialize};
+
+use crate::NetworkEndpoint;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TlsClient {
+    #[serde(default)]
+    pub schema_version: u32,
+    pub timestamp: u64,
+    pub source: NetworkEndpoint,
+    pub destination: NetworkEndpoint,
+    pub ja4: String,
+    pub ja4_raw: String,
+    pub ja4_original: String,
+    pub ja4_original_raw: String,
+    pub observed: TlsClientObserved,
+    /// Client matched from `ja4` in the assembler's JA4 database. Collectors leave it empty.
+    #[serde(default)]
+    pub application_detected: Option<ApplicationDetection>,
+}
+
+/// What a JA4 fingerprint is known to belong to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ApplicationDetection {
+    pub application: Option<String>,
+    pub library: Option<String>,
+    pub os: Option<String>,
+    /// Share of the database records for this fingerprint that agree on the match, 0 to 1.
+    pub quality: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TlsClientObserved {
+    pub version: String,
+    pub sni: Option<String>,
+    pub alpn: Option<String>,
+    pub cipher_suites: Vec<u16>,
+    pub extensions: Vec<u16>,
+    pub signature_algorithms: Vec<u16>,
+    pub elliptic_curves: Vec<u16>,
+}
+
+pub type TlsIngest = TlsClient;
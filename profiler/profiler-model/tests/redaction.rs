@@ -0,0 +1,79 @@
+use profiler_model::{RedactionRules, HASH_PREFIX, REDACTED};
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+#[test]
+fn values_containing_separators_are_redacted_whole() -> TestResult {
+    let rules: RedactionRules = "accept-language=hash,date=name".parse()?;
+    let joined = "Accept-Language: en-US, en;q=0.9, Date: Tue, 15 Nov 1994 08:12:31 GMT, \
+                  Accept: text/html, */*";
+    assert_eq!(
+        rules.redact(joined),
+        rules.join([
+            ("Accept-Language", Some("en-US, en;q=0.9")),
+            ("Date", Some("Tue, 15 Nov 1994 08:12:31 GMT")),
+            ("Accept", Some("text/html, */*")),
+        ])
+    );
+    assert_eq!(
+        rules.redact("Date: Tue, 15 Nov 1994 08:12:31 GMT, Host: a"),
+        format!("Date: {REDACTED}, Host: a")
+    );
+    Ok(())
+}
+
+#[test]
+fn ipv6_addresses_are_not_taken_for_header_names() -> TestResult {
+    let rules: RedactionRules = "x-forwarded-for=hash".parse()?;
+    // `fe80` and the empty name before `::1` would otherwise pass for header names.
+    let joined = "X-Forwarded-For: 2001:db8::1, fe80::1, ::1, X-Real-Ip: 2001:db8::2";
+    assert_eq!(
+        rules.redact(joined),
+        rules.join([
+            ("X-Forwarded-For", Some("2001:db8::1, fe80::1, ::1")),
+            ("X-Real-Ip", Some("2001:db8::2")),
+        ])
+    );
+    assert!(rules.redact(joined).ends_with(", X-Real-Ip: 2001:db8::2"));
+    Ok(())
+}
+
+#[test]
+fn unlisted_names_follow_the_default() -> TestResult {
+    let rules: RedactionRules = "*=drop,host=keep".parse()?;
+    assert_eq!(rules.redact("Host: a, Accept: */*, X-Trace: 1"), "Host: a");
+
+    let rules: RedactionRules = "host=keep,*=name".parse()?;
+    assert_eq!(
+        rules.redact("Host: a, Accept: */*"),
+        format!("Host: a, Accept: {REDACTED}")
+    );
+
+    // Without `*`, what is not listed is kept.
+    let rules: RedactionRules = "authorization=drop".parse()?;
+    assert_eq!(rules.redact("Host: a, Accept: */*"), "Host: a, Accept: */*");
+    Ok(())
+}
+
+#[test]
+fn names_match_case_insensitively() -> TestResult {
+    let rules: RedactionRules = "Authorization=name,X-API-KEY=drop".parse()?;
+    assert_eq!(
+        rules.redact("authorization: a, AUTHORIZATION: b, x-api-key: c, X-Api-Key: d"),
+        format!("authorization: {REDACTED}, AUTHORIZATION: {REDACTED}")
+    );
+    Ok(())
+}
+
+#[test]
+fn hashed_and_redacted_values_are_not_hashed_again() -> TestResult {
+    let rules: RedactionRules = "x-session=hash".parse()?;
+    let hashed = rules.redact("X-Session: s1");
+    assert!(hashed.starts_with(&format!("X-Session: {HASH_PREFIX}")));
+    assert_eq!(rules.redact(&hashed), hashed);
+
+    let redacted = format!("X-Session: {REDACTED}");
+    assert_eq!(rules.redact(&redacted), redacted);
+    assert_eq!(rules.redact("X-Session: "), "X-Session: ");
+    Ok(())
+}
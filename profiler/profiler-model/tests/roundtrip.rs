@@ -0,0 +1,345 @@
+use profiler_model::{
+    check_schema_version, sign_request, verify_request, ApplicationDetection, BrowserDetection,
+    ConnectionRecord, HttpRequestData, HttpRequestObserved, HttpResponseData, HttpResponseObserved,
+    IngestEvent, MtuData, NetworkEndpoint, OsDetection, Profile, ProfileHistory, RedactionRules,
+    SchemaMismatch, SynAckPacketData, SynPacketData, TcpObserved, TlsClient, TlsClientObserved,
+    UptimeData, WebServerDetection, SCHEMA_VERSION,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt::Debug;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+fn roundtrip<T>(value: &T) -> TestResult
+where
+    T: Serialize + DeserializeOwned + PartialEq + Debug,
+{
+    let json = serde_json::to_string(value)?;
+    let decoded: T = serde_json::from_str(&json)?;
+    assert_eq!(&decoded, value);
+    Ok(())
+}
+
+fn client() -> NetworkEndpoint {
+    NetworkEndpoint {
+        ip: "192.168.1.10".to_string(),
+        port: 51234,
+    }
+}
+
+fn server() -> NetworkEndpoint {
+    NetworkEndpoint {
+        ip: "10.0.0.1".to_string(),
+        port: 443,
+    }
+}
+
+fn tcp_observed() -> TcpObserved {
+    TcpObserved {
+        version: "4".to_string(),
+        initial_ttl: "64".to_string(),
+        options_length: 0,
+        mss: Some(1460),
+        window_size: "mss*44".to_string(),
+        window_scale: Some(7),
+        options_layout: "Mss,Sok,Ts,Nop,Ws".to_string(),
+        quirks: "Df,NonZeroID".to_string(),
+        payload_class: "0".to_string(),
+    }
+}
+
+fn syn() -> SynPacketData {
+    SynPacketData {
+        schema_version: SCHEMA_VERSION,
+        source: client(),
+        destination: server(),
+        os_detected: OsDetection {
+            os: "Linux / unix / 3.11 and newer".to_string(),
+            quality: 1.0,
+        },
+        signature: "4:64+0:0:1460:mss*44,7:mss,sok,ts,nop,ws:df,id+:0".to_string(),
+        observed: tcp_observed(),
+        timestamp: 1_700_000_000,
+    }
+}
+
+fn http_request() -> HttpRequestData {
+    HttpRequestData {
+        schema_version: SCHEMA_VERSION,
+        source: client(),
+        destination: server(),
+        observed: HttpRequestObserved {
+            lang: Some("English".to_string()),
+            user_agent: Some("Mozilla/5.0".to_string()),
+            diagnostic: "none".to_string(),
+            method: Some("GET".to_string()),
+            version: "1.1".to_string(),
+            headers: "Host: localhost, Accept: */*".to_string(),
+            cookies: String::new(),
+            referer: None,
+            uri: Some("/".to_string()),
+        },
+        signature: "1:Host,Accept:Accept-Encoding:Mozilla/5.0".to_string(),
+        browser: BrowserDetection {
+            browser: "Firefox/???/???".to_string(),
+            quality: 0.5,
+        },
+        timestamp: 1_700_000_001,
+    }
+}
+
+fn tls_client() -> TlsClient {
+    TlsClient {
+        schema_version: SCHEMA_VERSION,
+        timestamp: 1_700_000_002,
+        source: client(),
+        destination: server(),
+        ja4: "t13d1516h2_8daaf6152771_02713d6af862".to_string(),
+        ja4_raw: "t13d1516h2_002f,0035_0005,000a".to_string(),
+        ja4_original: "t13d1516h2_8daaf6152771_e5627efa2ab1".to_string(),
+        ja4_original_raw: "t13d1516h2_002f,0035_0000,0005".to_string(),
+        observed: TlsClientObserved {
+            version: "1.3".to_string(),
+            sni: Some("localhost".to_string()),
+            alpn: Some("h2".to_string()),
+            cipher_suites: vec![0x1301, 0x1302],
+            extensions: vec![0x0000, 0x000a],
+            signature_algorithms: vec![0x0403],
+            elliptic_curves: vec![0x001d],
+        },
+        application_detected: Some(ApplicationDetection {
+            application: Some("Chrome".to_string()),
+            library: None,
+            os: None,
+            quality: 1.0,
+        }),
+    }
+}
+
+#[test]
+fn tcp_payloads_roundtrip() -> TestResult {
+    roundtrip(&syn())?;
+    roundtrip(&SynAckPacketData {
+        schema_version: SCHEMA_VERSION,
+        source: server(),
+        destination: client(),
+        os_detected: OsDetection {
+            os: "unknown".to_string(),
+            quality: 0.0,
+        },
+        signature: "4:64+0:0:1460:65535,7:mss,sok,ts,nop,ws:df:0".to_string(),
+        observed: tcp_observed(),
+        timestamp: 1_700_000_000,
+    })?;
+    roundtrip(&MtuData {
+        schema_version: SCHEMA_VERSION,
+        source: client(),
+        destination: server(),
+        link: "Ethernet".to_string(),
+        mtu_value: 1500,
+        timestamp: 1_700_000_000,
+    })?;
+    roundtrip(&UptimeData {
+        schema_version: SCHEMA_VERSION,
+        source: server(),
+        destination: client(),
+        uptime_seconds: 86_400,
+        up_mod_days: 49,
+        freq: 1000.0,
+        timestamp: 1_700_000_000,
+    })
+}
+
+#[test]
+fn http_payloads_roundtrip() -> TestResult {
+    roundtrip(&http_request())?;
+    roundtrip(&HttpResponseData {
+        schema_version: SCHEMA_VERSION,
+        source: server(),
+        destination: client(),
+        observed: HttpResponseObserved {
+            server: Some("nginx".to_string()),
+            version: "1.1".to_string(),
+            headers: "Server: nginx".to_string(),
+            status_code: Some(200),
+        },
+        signature: "1:Server:Content-Type:nginx".to_string(),
+        web_server: WebServerDetection {
+            web_server: "nginx/???/???".to_string(),
+            quality: 1.0,
+        },
+        timestamp: 1_700_000_000,
+    })
+}
+
+#[test]
+fn tls_payload_roundtrip() -> TestResult {
+    roundtrip(&tls_client())
+}
+
+#[test]
+fn profile_roundtrip() -> TestResult {
+    roundtrip(&Profile::default())?;
+    roundtrip(&Profile {
+        id: client().ip,
+        timestamp: 1_700_000_000,
+        syn: Some(syn()),
+        http_request: Some(http_request()),
+        tls_client: Some(tls_client()),
+        last_seen: "2024-01-01T00:00:00+00:00".to_string(),
+        ..Profile::default()
+    })
+}
+
+#[test]
+fn payload_without_schema_version_is_rejected() -> TestResult {
+    let mut value = serde_json::to_value(syn())?;
+    if let Some(object) = value.as_object_mut() {
+        object.remove("schema_version");
+    }
+    let decoded: SynPacketData = serde_json::from_value(value)?;
+    assert_eq!(decoded.schema_version, 0);
+    assert_eq!(
+        check_schema_version(decoded.schema_version),
+        Err(SchemaMismatch {
+            expected: SCHEMA_VERSION,
+            found: 0,
+        })
+    );
+    Ok(())
+}
+
+#[test]
+fn current_schema_version_is_accepted() {
+    assert_eq!(check_schema_version(SCHEMA_VERSION), Ok(()));
+}
+
+#[test]
+fn ingest_event_is_tagged_by_endpoint_name() -> TestResult {
+    let event = IngestEvent::HttpRequest(http_request());
+    let value = serde_json::to_value(&event)?;
+    assert_eq!(value["type"], "http_request");
+    assert_eq!(value["data"]["schema_version"], SCHEMA_VERSION);
+    assert_eq!(event.kind(), "http_request");
+    roundtrip(&event)?;
+    roundtrip(&IngestEvent::Syn(syn()))?;
+    roundtrip(&IngestEvent::Tls(tls_client()))
+}
+
+#[test]
+fn profile_history_counts_distinct_signatures() -> TestResult {
+    let mut profile = Profile::default();
+    profile.apply(IngestEvent::Tls(tls_client()), 2);
+    let mut later = tls_client();
+    later.timestamp = 1_700_000_100;
+    profile.apply(IngestEvent::Tls(later.clone()), 2);
+
+    assert_eq!(profile.tls_client, Some(later));
+    let [seen] = profile.history.tls_client.as_slice() else {
+        panic!("expected one JA4 in {:?}", profile.history.tls_client);
+    };
+    assert_eq!(seen.count, 2);
+    assert_eq!(seen.first_seen, 1_700_000_002);
+    assert_eq!(seen.last_seen, 1_700_000_100);
+    roundtrip(&profile)
+}
+
+#[test]
+fn profile_history_forgets_least_recently_seen() {
+    let mut history = ProfileHistory::default();
+    for (ja4, timestamp) in [("a", 30), ("b", 10), ("c", 20), ("a", 40)] {
+        let mut tls = tls_client();
+        tls.ja4 = ja4.to_string();
+        tls.timestamp = timestamp;
+        history.record(&IngestEvent::Tls(tls), 2);
+    }
+    let kept: Vec<&str> = history
+        .tls_client
+        .iter()
+        .map(|seen| seen.signature.as_str())
+        .collect();
+    assert_eq!(kept, ["a", "c"]);
+}
+
+#[test]
+fn connection_record_is_keyed_by_client_and_server() -> TestResult {
+    let mut record = ConnectionRecord::default();
+    record.apply(&IngestEvent::Syn(syn()));
+    record.apply(&IngestEvent::Tls(tls_client()));
+
+    assert_eq!(record.id, "192.168.1.10:51234->10.0.0.1:443");
+    assert_eq!(record.client, client());
+    assert_eq!(record.server, server());
+    assert_eq!(record.first_seen, 1_700_000_000);
+    assert_eq!(record.last_seen, 1_700_000_002);
+    assert!(record.syn.is_some() && record.tls_client.is_some());
+
+    let v6 = NetworkEndpoint {
+        ip: "2001:db8::1".to_string(),
+        port: 443,
+    };
+    assert_eq!(
+        ConnectionRecord::key(&client(), &v6),
+        "192.168.1.10:51234->[2001:db8::1]:443"
+    );
+    roundtrip(&record)
+}
+
+#[test]
+fn profile_keeps_most_recent_connections() {
+    let mut profile = Profile::default();
+    assert!(profile.track_connection("a", 2).is_empty());
+    assert!(profile.track_connection("b", 2).is_empty());
+    assert!(profile.track_connection("a", 2).is_empty());
+    assert_eq!(profile.track_connection("c", 2), ["b"]);
+    assert_eq!(profile.connections, ["a", "c"]);
+}
+
+#[test]
+fn request_signature_covers_timestamp_and_body() {
+    let body = b"{\"a\":1}\n";
+    let signature = sign_request(b"secret", 1_700_000_000, body);
+    assert_eq!(
+        signature,
+        "67a36286693bb047e65ded8401c48912a6983fbd5c20392fbc2a292ec5251ae9"
+    );
+    assert!(verify_request(b"secret", 1_700_000_000, body, &signature));
+    assert!(!verify_request(b"secret", 1_700_000_001, body, &signature));
+    assert!(!verify_request(
+        b"secret",
+        1_700_000_000,
+        b"{}\n",
+        &signature
+    ));
+    assert!(!verify_request(b"other", 1_700_000_000, body, &signature));
+    assert!(!verify_request(b"secret", 1_700_000_000, body, "zz"));
+}
+
+#[test]
+fn redaction_keeps_names_and_order() -> TestResult {
+    let rules: RedactionRules = "authorization=name,x-session=hash,x-debug=drop".parse()?;
+    let joined = "Host: example.com, Accept: text/html, */*, Authorization: Bearer abc, \
+                  X-Debug: 1, X-Session: s1";
+    let redacted = rules.redact(joined);
+    assert_eq!(
+        redacted,
+        "Host: example.com, Accept: text/html, */*, Authorization: [redacted], \
+         X-Session: sha256:e8bc163c82eee187"
+    );
+    assert_eq!(rules.redact(&redacted), redacted);
+    let forwarded = "X-Forwarded-For: 2001:db8::1, 2001:db8::2, Host: a";
+    assert_eq!(rules.redact(forwarded), forwarded);
+
+    let cookies: RedactionRules = "*=name,lang=keep".parse()?;
+    assert_eq!(
+        cookies.join([
+            ("sid", Some("secret")),
+            ("lang", Some("en")),
+            ("empty", None)
+        ]),
+        "sid: [redacted], lang: en, empty: "
+    );
+    assert!("authorization=erase".parse::<RedactionRules>().is_err());
+    assert!("authorization".parse::<RedactionRules>().is_err());
+    Ok(())
+}
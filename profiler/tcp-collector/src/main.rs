@@ -1,24 +1,39 @@
+use clap::builder::RangedU64ValueParser;
 use clap::Parser;
+use collector_common::auth::Credential;
+use collector_common::batch::{BatchArgs, BatchSender};
+use collector_common::capture::{CaptureSource, ReplayClock};
+use collector_common::health::{AnalyzerState, Health, HealthArgs};
+use collector_common::queue::{self, QueueArgs};
+use collector_common::tls::AssemblerClient;
 use huginn_net_db::MatchQualityType;
 use huginn_net_tcp::OperativeSystem;
 use huginn_net_tcp::{HuginnNetTcp, TcpAnalysisResult};
-use serde::Serialize;
-use std::env;
+use profiler_config::ConfigArgs;
+use profiler_model::{
+    IngestEvent, MtuIngest, NetworkEndpoint, OsDetection, SynAckIngest, SynIngest, TcpObserved,
+    UptimeIngest, SCHEMA_VERSION,
+};
+use std::net::SocketAddr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc as std_mpsc;
 use std::sync::Arc;
 use std::thread;
-use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::runtime::Runtime;
-use tokio::sync::mpsc as tokio_mpsc;
 use tracing::{error, info, Level};
 use tracing_subscriber::FmtSubscriber;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[clap(short, long, value_parser)]
-    interface: Option<String>,
+    /// Network interface to capture on.
+    #[clap(short, long, value_parser, default_value = "wlp0s20f3")]
+    interface: String,
+    /// Read packets from a pcap file, a directory of pcap files or a glob pattern instead of
+    /// capturing live; takes precedence over `--interface`. The collector exits once every
+    /// file has been processed.
+    #[clap(long, value_parser)]
+    pcap: Option<String>,
     #[clap(
         short,
         long,
@@ -26,77 +41,25 @@ struct Args {
         default_value = "http://localhost:8000/api/ingest"
     )]
     assembler_endpoint: String,
+    /// Flows tracked by the huginn-net analyzer.
+    #[clap(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..), default_value_t = 1000)]
+    analyzer_cache_size: usize,
+    /// Address serving `/live`, `/ready` and `/metrics`.
+    #[clap(long, value_parser, default_value = "0.0.0.0:9002")]
+    health_listen: SocketAddr,
+    /// Log verbosity: error, warn, info, debug or trace.
+    #[clap(long, value_parser, default_value = "info")]
+    log_level: Level,
+    #[command(flatten)]
+    batch: BatchArgs,
+    #[command(flatten)]
+    queue: QueueArgs,
+    #[command(flatten)]
+    health: HealthArgs,
+    #[command(flatten)]
+    config: ConfigArgs,
 }
 
-#[derive(Serialize, Debug)]
-pub struct NetworkEndpoint {
-    pub ip: String,
-    pub port: u16,
-}
-
-#[derive(Serialize, Debug)]
-pub struct OsDetection {
-    pub os: String,
-    pub quality: f32,
-}
-
-#[derive(Serialize, Debug)]
-pub struct TcpObserved {
-    pub version: String,
-    pub initial_ttl: String,
-    pub options_length: u8,
-    pub mss: Option<u16>,
-    pub window_size: String,
-    pub window_scale: Option<u8>,
-    pub options_layout: String,
-    pub quirks: String,
-    pub payload_class: String,
-}
-
-#[derive(Serialize, Debug)]
-pub struct SynPacketData {
-    pub source: NetworkEndpoint,
-    pub destination: NetworkEndpoint,
-    pub os_detected: OsDetection,
-    pub signature: String,
-    pub observed: TcpObserved,
-    pub timestamp: u64,
-}
-
-#[derive(Serialize, Debug)]
-pub struct SynAckPacketData {
-    pub source: NetworkEndpoint,
-    pub destination: NetworkEndpoint,
-    pub os_detected: OsDetection,
-    pub signature: String,
-    pub observed: TcpObserved,
-    pub timestamp: u64,
-}
-
-#[derive(Serialize, Debug)]
-pub struct MtuData {
-    pub source: NetworkEndpoint,
-    pub destination: NetworkEndpoint,
-    pub link: String,
-    pub mtu_value: u16,
-    pub timestamp: u64,
-}
-
-#[derive(Serialize, Debug)]
-pub struct UptimeData {
-    pub source: NetworkEndpoint,
-    pub destination: NetworkEndpoint,
-    pub uptime_seconds: u64,
-    pub up_mod_days: u32,
-    pub freq: f64,
-    pub timestamp: u64,
-}
-
-type SynIngest = SynPacketData;
-type SynAckIngest = SynAckPacketData;
-type MtuIngest = MtuData;
-type UptimeIngest = UptimeData;
-
 fn format_os(os: &OperativeSystem) -> String {
     let mut parts = vec![os.name.as_str()];
 
@@ -112,8 +75,16 @@ fn format_os(os: &OperativeSystem) -> String {
 }
 
 fn main() {
+    let args: Args = match profiler_config::load(&["collector", "tcp-collector"]) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e}");
+            return;
+        }
+    };
+
     let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::INFO)
+        .with_max_level(args.log_level)
         .finish();
 
     if let Err(e) = tracing::subscriber::set_global_default(subscriber) {
@@ -121,13 +92,43 @@ fn main() {
         return;
     }
 
-    let args = Args::parse();
-    let interface = args
-        .interface
-        .unwrap_or_else(|| env::var("PROFILER_INTERFACE").unwrap_or("wlp0s20f3".to_string()));
+    let metrics_handle = match collector_common::metrics::install("tcp") {
+        Ok(handle) => handle,
+        Err(e) => {
+            error!("Failed to install metrics recorder: {e}");
+            return;
+        }
+    };
     let assembler_endpoint = args.assembler_endpoint;
+    let credential = match Credential::load(&args.batch.auth) {
+        Ok(credential) => credential,
+        Err(e) => {
+            error!("Failed to read the assembler key: {e}");
+            return;
+        }
+    };
+    let client = match AssemblerClient::open(&args.batch.tls) {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Failed to load the assembler TLS settings: {e}");
+            return;
+        }
+    };
+    let source = match CaptureSource::resolve(args.interface, args.pcap.as_deref()) {
+        Ok(source) => source,
+        Err(e) => {
+            error!("Invalid capture input: {e}");
+            return;
+        }
+    };
 
-    info!("Booting tcp-collector on interface {interface} pointed to {assembler_endpoint}");
+    info!("Booting tcp-collector on {source} pointed to {assembler_endpoint}");
+    let queue_policy = args.queue.policy_for(&source);
+    info!(
+        "Queueing up to {} results with policy {queue_policy}",
+        args.queue.queue_capacity
+    );
+    let health = Health::new(&source, &args.health);
 
     // Setup graceful shutdown
     let cancel_signal = Arc::new(AtomicBool::new(false));
@@ -143,58 +144,87 @@ fn main() {
     }
 
     let (sync_tx, sync_rx) = std_mpsc::channel::<TcpAnalysisResult>();
-    let (async_tx, mut async_rx) = tokio_mpsc::channel(1000);
+    let (queue_tx, mut queue_rx) = queue::bounded(
+        args.queue.queue_capacity,
+        queue_policy,
+        args.queue.queue_sample_rate,
+    );
 
+    let bridge_health = health.clone();
     thread::spawn(move || {
         while let Ok(item) = sync_rx.recv() {
+            bridge_health.record_packet();
             if processing_cancel_signal.load(Ordering::Relaxed) {
                 info!("Shutdown signal received, stopping sync-to-async bridge");
                 break;
             }
-            if async_tx.blocking_send(item).is_err() {
+            if queue_tx.push(item).is_err() {
                 error!("async channel closed");
                 break;
             }
         }
     });
 
+    let clock = Arc::new(ReplayClock::default());
+    let analysis_clock = clock.clone();
     let analysis_cancel_signal = cancel_signal.clone();
+    let analysis_health = health.clone();
+    let analyzer_cache_size = args.analyzer_cache_size;
 
     thread::spawn(move || {
-        info!("Starting TCP analysis on interface {interface}...");
-        let mut tcp_analyzer = match HuginnNetTcp::new(None, 1000) {
+        info!("Starting TCP analysis on {source}...");
+        let mut tcp_analyzer = match HuginnNetTcp::new(None, analyzer_cache_size) {
             Ok(analyzer) => analyzer,
             Err(e) => {
                 error!("Failed to create HuginnNetTcp analyzer: {}", e);
+                analysis_health.set_analyzer(AnalyzerState::Failed);
                 return;
             }
         };
 
-        if let Err(e) =
-            tcp_analyzer.analyze_network(&interface, sync_tx, Some(analysis_cancel_signal))
-        {
-            error!("Huginn-net-tcp analysis failed: {e}");
-        } else {
-            info!("TCP analysis finished cleanly.");
-        }
-    });
-
-    thread::spawn(|| {
-        use std::io::Write;
-        use std::net::{TcpListener, TcpStream};
-
-        fn handle_health_request(mut stream: TcpStream) {
-            let response = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK";
-            let _ = stream.write_all(response.as_bytes());
-        }
-
-        if let Ok(listener) = TcpListener::bind("0.0.0.0:9002") {
-            for stream in listener.incoming().flatten() {
-                handle_health_request(stream);
+        match source {
+            CaptureSource::Interface(interface) => {
+                analysis_health.set_analyzer(AnalyzerState::Running);
+                if let Err(e) =
+                    tcp_analyzer.analyze_network(&interface, sync_tx, Some(analysis_cancel_signal))
+                {
+                    error!("Huginn-net-tcp analysis failed: {e}");
+                    analysis_health.set_analyzer(AnalyzerState::Failed);
+                } else {
+                    info!("TCP analysis finished cleanly.");
+                    analysis_health.set_analyzer(AnalyzerState::Stopped);
+                }
+            }
+            CaptureSource::Pcap(files) => {
+                analysis_health.set_analyzer(AnalyzerState::Running);
+                for file in files {
+                    if analysis_cancel_signal.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    info!("Reading pcap file {}", file.display());
+                    let replay = match analysis_clock.open(&file) {
+                        Ok(replay) => replay,
+                        Err(e) => {
+                            error!("Skipping {}: {e}", file.display());
+                            continue;
+                        }
+                    };
+                    if let Err(e) = tcp_analyzer.analyze_pcap(
+                        &replay.path().to_string_lossy(),
+                        sync_tx.clone(),
+                        Some(analysis_cancel_signal.clone()),
+                    ) {
+                        error!("Huginn-net-tcp analysis of {} failed: {e}", file.display());
+                    }
+                }
+                info!("TCP analysis of pcap input finished.");
+                analysis_health.set_analyzer(AnalyzerState::Stopped);
             }
         }
     });
 
+    collector_common::health::spawn(args.health_listen, health.clone(), metrics_handle);
+
     let rt = match Runtime::new() {
         Ok(rt) => rt,
         Err(e) => {
@@ -203,21 +233,19 @@ fn main() {
         }
     };
     rt.block_on(async move {
-        let client = reqwest::Client::new();
+        let batch_sender =
+            BatchSender::spawn(client, &assembler_endpoint, &args.batch, credential, health);
         info!("Starting TCP result processor...");
 
-        while let Some(tcp_result) = async_rx.recv().await {
+        while let Some(tcp_result) = queue_rx.recv().await {
             if cancel_signal.load(Ordering::Relaxed) {
                 info!("Shutdown signal received, stopping result processing");
                 break;
             }
-            let now = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs();
 
             if let Some(syn) = tcp_result.syn {
                 let ingest = SynIngest {
+                    schema_version: SCHEMA_VERSION,
                     source: NetworkEndpoint {
                         ip: syn.source.ip.to_string(),
                         port: syn.source.port,
@@ -240,12 +268,13 @@ fn main() {
                     },
                     signature: syn.sig.to_string(),
                     observed: to_details(&syn.sig),
-                    timestamp: now,
+                    timestamp: captured_at(&clock, &syn.source, &syn.destination),
                 };
-                send_syn_to_assembler(ingest, &client, &assembler_endpoint).await;
+                batch_sender.send(IngestEvent::Syn(ingest)).await;
             }
             if let Some(syn_ack) = tcp_result.syn_ack {
                 let ingest = SynAckIngest {
+                    schema_version: SCHEMA_VERSION,
                     source: NetworkEndpoint {
                         ip: syn_ack.source.ip.to_string(),
                         port: syn_ack.source.port,
@@ -268,12 +297,13 @@ fn main() {
                     },
                     signature: syn_ack.sig.to_string(),
                     observed: to_details(&syn_ack.sig),
-                    timestamp: now,
+                    timestamp: captured_at(&clock, &syn_ack.source, &syn_ack.destination),
                 };
-                send_syn_ack_to_assembler(ingest, &client, &assembler_endpoint).await;
+                batch_sender.send(IngestEvent::SynAck(ingest)).await;
             }
             if let Some(mtu) = tcp_result.mtu {
                 let ingest = MtuIngest {
+                    schema_version: SCHEMA_VERSION,
                     source: NetworkEndpoint {
                         ip: mtu.source.ip.to_string(),
                         port: mtu.source.port,
@@ -284,9 +314,9 @@ fn main() {
                     },
                     link: format!("{:?}", mtu.link.link),
                     mtu_value: mtu.mtu,
-                    timestamp: now,
+                    timestamp: captured_at(&clock, &mtu.source, &mtu.destination),
                 };
-                send_mtu_to_assembler(ingest, &client, &assembler_endpoint).await;
+                batch_sender.send(IngestEvent::Mtu(ingest)).await;
             }
             if let Some(client_uptime) = tcp_result.client_uptime {
                 let total_seconds = (client_uptime.days as u64)
@@ -295,6 +325,7 @@ fn main() {
                     .saturating_add((client_uptime.hours as u64).saturating_mul(3600))
                     .saturating_add((client_uptime.min as u64).saturating_mul(60));
                 let ingest = UptimeIngest {
+                    schema_version: SCHEMA_VERSION,
                     source: NetworkEndpoint {
                         ip: client_uptime.source.ip.to_string(),
                         port: client_uptime.source.port,
@@ -306,9 +337,13 @@ fn main() {
                     uptime_seconds: total_seconds,
                     up_mod_days: client_uptime.up_mod_days,
                     freq: client_uptime.freq,
-                    timestamp: now,
+                    timestamp: captured_at(
+                        &clock,
+                        &client_uptime.source,
+                        &client_uptime.destination,
+                    ),
                 };
-                send_uptime_to_assembler(ingest, &client, &assembler_endpoint).await;
+                batch_sender.send(IngestEvent::Uptime(ingest)).await;
             }
             if let Some(server_uptime) = tcp_result.server_uptime {
                 let total_seconds = (server_uptime.days as u64)
@@ -317,6 +352,7 @@ fn main() {
                     .saturating_add((server_uptime.hours as u64).saturating_mul(3600))
                     .saturating_add((server_uptime.min as u64).saturating_mul(60));
                 let ingest = UptimeIngest {
+                    schema_version: SCHEMA_VERSION,
                     source: NetworkEndpoint {
                         ip: server_uptime.source.ip.to_string(),
                         port: server_uptime.source.port,
@@ -328,12 +364,17 @@ fn main() {
                     uptime_seconds: total_seconds,
                     up_mod_days: server_uptime.up_mod_days,
                     freq: server_uptime.freq,
-                    timestamp: now,
+                    timestamp: captured_at(
+                        &clock,
+                        &server_uptime.source,
+                        &server_uptime.destination,
+                    ),
                 };
-                send_uptime_to_assembler(ingest, &client, &assembler_endpoint).await;
+                batch_sender.send(IngestEvent::Uptime(ingest)).await;
             }
         }
 
+        batch_sender.close().await;
         info!("TCP collector shutdown completed");
     });
 }
@@ -364,74 +405,14 @@ fn to_details(sig: &huginn_net_tcp::ObservableTcp) -> TcpObserved {
     }
 }
 
-async fn send_syn_to_assembler(data: SynIngest, client: &reqwest::Client, endpoint: &str) {
-    info!(
-        "Sending SYN data for {}:{}",
-        data.source.ip, data.source.port
-    );
-    let url = format!("{endpoint}/syn");
-    match client.post(&url).json(&data).send().await {
-        Ok(response) => {
-            if !response.status().is_success() {
-                let status = response.status();
-                let body = response.text().await.unwrap_or_default();
-                error!("Failed to send SYN data, status: {status} body: {body}");
-            }
-        }
-        Err(e) => error!("Failed to send SYN data: {e}"),
-    }
-}
-
-async fn send_syn_ack_to_assembler(data: SynAckIngest, client: &reqwest::Client, endpoint: &str) {
-    info!(
-        "Sending SYN-ACK data for {}:{} -> {}:{}",
-        data.source.ip, data.source.port, data.destination.ip, data.destination.port
-    );
-    let url = format!("{endpoint}/syn_ack");
-    match client.post(&url).json(&data).send().await {
-        Ok(response) => {
-            if !response.status().is_success() {
-                let status = response.status();
-                let body = response.text().await.unwrap_or_default();
-                error!("Failed to send SYN-ACK data, status: {status} body: {body}");
-            }
-        }
-        Err(e) => error!("Failed to send SYN-ACK data: {e}"),
-    }
-}
-
-async fn send_mtu_to_assembler(data: MtuIngest, client: &reqwest::Client, endpoint: &str) {
-    info!(
-        "Sending MTU data for {}:{}",
-        data.source.ip, data.source.port
-    );
-    let url = format!("{endpoint}/mtu");
-    match client.post(&url).json(&data).send().await {
-        Ok(response) => {
-            if !response.status().is_success() {
-                let status = response.status();
-                let body = response.text().await.unwrap_or_default();
-                error!("Failed to send MTU data, status: {status} body: {body}");
-            }
-        }
-        Err(e) => error!("Failed to send MTU data: {e}"),
-    }
-}
-
-async fn send_uptime_to_assembler(data: UptimeIngest, client: &reqwest::Client, endpoint: &str) {
-    info!(
-        "Sending uptime data for {}:{}",
-        data.source.ip, data.source.port
-    );
-    let url = format!("{endpoint}/uptime");
-    match client.post(&url).json(&data).send().await {
-        Ok(response) => {
-            if !response.status().is_success() {
-                let status = response.status();
-                let body = response.text().await.unwrap_or_default();
-                error!("Failed to send uptime data, status: {status} body: {body}");
-            }
-        }
-        Err(e) => error!("Failed to send uptime data: {e}"),
-    }
+/// When the traffic of a result was captured.
+fn captured_at(
+    clock: &ReplayClock,
+    source: &huginn_net_tcp::IpPort,
+    destination: &huginn_net_tcp::IpPort,
+) -> u64 {
+    clock.timestamp(
+        SocketAddr::new(source.ip, source.port),
+        SocketAddr::new(destination.ip, destination.port),
+    )
 }
@@ -1,63 +1,71 @@
+use clap::builder::RangedU64ValueParser;
 use clap::Parser;
+use collector_common::auth::Credential;
+use collector_common::batch::{BatchArgs, BatchSender};
+use collector_common::capture::{CaptureSource, ReplayClock};
+use collector_common::health::{AnalyzerState, Health, HealthArgs};
+use collector_common::queue::{self, QueueArgs};
+use collector_common::tls::AssemblerClient;
 use huginn_net_tls::{HuginnNetTls, TlsClientOutput};
-use serde::Serialize;
-use std::env;
+use profiler_config::ConfigArgs;
+use profiler_model::{IngestEvent, NetworkEndpoint, TlsClient, TlsClientObserved, SCHEMA_VERSION};
+use std::net::SocketAddr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc as std_mpsc;
 use std::sync::Arc;
 use std::thread;
-use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::runtime::Runtime;
-use tokio::sync::mpsc as tokio_mpsc;
 use tracing::{error, info, Level};
 use tracing_subscriber::FmtSubscriber;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    #[clap(short, long, value_parser)]
-    interface: Option<String>,
+    /// Network interface to capture on.
+    #[clap(short, long, value_parser, default_value = "wlp0s20f3")]
+    interface: String,
+    /// Read packets from a pcap file, a directory of pcap files or a glob pattern instead of
+    /// capturing live; takes precedence over `--interface`. The collector exits once every
+    /// file has been processed.
+    #[clap(long, value_parser)]
+    pcap: Option<String>,
     #[clap(
         short,
         long,
         value_parser,
-        default_value = "http://localhost:8000/api/ingest/tls"
+        default_value = "http://localhost:8000/api/ingest"
     )]
     assembler_endpoint: String,
-}
-
-#[derive(Serialize, Clone, Debug)]
-pub struct TlsClient {
-    pub timestamp: u64,
-    pub source: NetworkEndpoint,
-    pub destination: NetworkEndpoint,
-    pub ja4: String,
-    pub ja4_raw: String,
-    pub ja4_original: String,
-    pub ja4_original_raw: String,
-    pub observed: TlsClientObserved,
-}
-
-#[derive(Serialize, Clone, Debug)]
-pub struct TlsClientObserved {
-    pub version: String,
-    pub sni: Option<String>,
-    pub alpn: Option<String>,
-    pub cipher_suites: Vec<u16>,
-    pub extensions: Vec<u16>,
-    pub signature_algorithms: Vec<u16>,
-    pub elliptic_curves: Vec<u16>,
-}
-
-#[derive(Serialize, Clone, Debug)]
-pub struct NetworkEndpoint {
-    pub ip: String,
-    pub port: u16,
+    /// Flows tracked by the huginn-net analyzer.
+    #[clap(long, value_parser = RangedU64ValueParser::<usize>::new().range(1..), default_value_t = 1000)]
+    analyzer_cache_size: usize,
+    /// Address serving `/live`, `/ready` and `/metrics`.
+    #[clap(long, value_parser, default_value = "0.0.0.0:9003")]
+    health_listen: SocketAddr,
+    /// Log verbosity: error, warn, info, debug or trace.
+    #[clap(long, value_parser, default_value = "info")]
+    log_level: Level,
+    #[command(flatten)]
+    batch: BatchArgs,
+    #[command(flatten)]
+    queue: QueueArgs,
+    #[command(flatten)]
+    health: HealthArgs,
+    #[command(flatten)]
+    config: ConfigArgs,
 }
 
 fn main() {
+    let args: Args = match profiler_config::load(&["collector", "tls-collector"]) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("Invalid configuration: {e}");
+            return;
+        }
+    };
+
     let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::INFO)
+        .with_max_level(args.log_level)
         .finish();
 
     if let Err(e) = tracing::subscriber::set_global_default(subscriber) {
@@ -65,13 +73,43 @@ fn main() {
         return;
     }
 
-    let args = Args::parse();
-    let interface = args
-        .interface
-        .unwrap_or_else(|| env::var("PROFILER_INTERFACE").unwrap_or("wlp0s20f3".to_string()));
+    let metrics_handle = match collector_common::metrics::install("tls") {
+        Ok(handle) => handle,
+        Err(e) => {
+            error!("Failed to install metrics recorder: {e}");
+            return;
+        }
+    };
     let assembler_endpoint = args.assembler_endpoint;
+    let credential = match Credential::load(&args.batch.auth) {
+        Ok(credential) => credential,
+        Err(e) => {
+            error!("Failed to read the assembler key: {e}");
+            return;
+        }
+    };
+    let client = match AssemblerClient::open(&args.batch.tls) {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Failed to load the assembler TLS settings: {e}");
+            return;
+        }
+    };
+    let source = match CaptureSource::resolve(args.interface, args.pcap.as_deref()) {
+        Ok(source) => source,
+        Err(e) => {
+            error!("Invalid capture input: {e}");
+            return;
+        }
+    };
 
-    info!("Booting tls-collector on interface {interface} pointed to {assembler_endpoint}");
+    info!("Booting tls-collector on {source} pointed to {assembler_endpoint}");
+    let queue_policy = args.queue.policy_for(&source);
+    info!(
+        "Queueing up to {} results with policy {queue_policy}",
+        args.queue.queue_capacity
+    );
+    let health = Health::new(&source, &args.health);
 
     let cancel_signal = Arc::new(AtomicBool::new(false));
     let ctrl_c_signal = cancel_signal.clone();
@@ -86,52 +124,80 @@ fn main() {
     }
 
     let (sync_tx, sync_rx) = std_mpsc::channel::<TlsClientOutput>();
-    let (async_tx, mut async_rx) = tokio_mpsc::channel(1000);
+    let (queue_tx, mut queue_rx) = queue::bounded(
+        args.queue.queue_capacity,
+        queue_policy,
+        args.queue.queue_sample_rate,
+    );
 
+    let bridge_health = health.clone();
     thread::spawn(move || {
         while let Ok(item) = sync_rx.recv() {
+            bridge_health.record_packet();
             if processing_cancel_signal.load(Ordering::Relaxed) {
                 info!("Shutdown signal received, stopping sync-to-async bridge");
                 break;
             }
-            if async_tx.blocking_send(item).is_err() {
+            if queue_tx.push(item).is_err() {
                 error!("Failed to send fingerprint to async processor. Channel closed.");
                 break;
             }
         }
     });
 
+    let clock = Arc::new(ReplayClock::default());
+    let analysis_clock = clock.clone();
     let analysis_cancel_signal = cancel_signal.clone();
+    let analysis_health = health.clone();
+    let analyzer_cache_size = args.analyzer_cache_size;
 
     thread::spawn(move || {
-        info!("Starting TLS analysis on interface {interface}...");
-        let mut tls_analyzer = HuginnNetTls::new(1000);
-
-        if let Err(e) =
-            tls_analyzer.analyze_network(&interface, sync_tx, Some(analysis_cancel_signal))
-        {
-            error!("Huginn-net-tls analysis failed: {e}");
-        } else {
-            info!("TLS analysis finished cleanly.");
-        }
-    });
-
-    thread::spawn(|| {
-        use std::io::Write;
-        use std::net::{TcpListener, TcpStream};
+        info!("Starting TLS analysis on {source}...");
+        let mut tls_analyzer = HuginnNetTls::new(analyzer_cache_size);
 
-        fn handle_health_request(mut stream: TcpStream) {
-            let response = "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK";
-            let _ = stream.write_all(response.as_bytes());
-        }
-
-        if let Ok(listener) = TcpListener::bind("0.0.0.0:9003") {
-            for stream in listener.incoming().flatten() {
-                handle_health_request(stream);
+        match source {
+            CaptureSource::Interface(interface) => {
+                analysis_health.set_analyzer(AnalyzerState::Running);
+                if let Err(e) =
+                    tls_analyzer.analyze_network(&interface, sync_tx, Some(analysis_cancel_signal))
+                {
+                    error!("Huginn-net-tls analysis failed: {e}");
+                    analysis_health.set_analyzer(AnalyzerState::Failed);
+                } else {
+                    info!("TLS analysis finished cleanly.");
+                    analysis_health.set_analyzer(AnalyzerState::Stopped);
+                }
+            }
+            CaptureSource::Pcap(files) => {
+                analysis_health.set_analyzer(AnalyzerState::Running);
+                for file in files {
+                    if analysis_cancel_signal.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    info!("Reading pcap file {}", file.display());
+                    let replay = match analysis_clock.open(&file) {
+                        Ok(replay) => replay,
+                        Err(e) => {
+                            error!("Skipping {}: {e}", file.display());
+                            continue;
+                        }
+                    };
+                    if let Err(e) = tls_analyzer.analyze_pcap(
+                        &replay.path().to_string_lossy(),
+                        sync_tx.clone(),
+                        Some(analysis_cancel_signal.clone()),
+                    ) {
+                        error!("Huginn-net-tls analysis of {} failed: {e}", file.display());
+                    }
+                }
+                info!("TLS analysis of pcap input finished.");
+                analysis_health.set_analyzer(AnalyzerState::Stopped);
             }
         }
     });
 
+    collector_common::health::spawn(args.health_listen, health.clone(), metrics_handle);
+
     let rt = match Runtime::new() {
         Ok(rt) => rt,
         Err(e) => {
@@ -140,22 +206,19 @@ fn main() {
         }
     };
     rt.block_on(async {
-        let client = reqwest::Client::new();
+        let batch_sender =
+            BatchSender::spawn(client, &assembler_endpoint, &args.batch, credential, health);
         info!("Starting TLS result processor...");
 
-        while let Some(tls_data) = async_rx.recv().await {
+        while let Some(tls_data) = queue_rx.recv().await {
             if cancel_signal.load(Ordering::Relaxed) {
                 info!("Shutdown signal received, stopping result processing");
                 break;
             }
 
-            let now = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs();
-
             let ingest: TlsClient = TlsClient {
-                timestamp: now,
+                schema_version: SCHEMA_VERSION,
+                timestamp: captured_at(&clock, &tls_data.source, &tls_data.destination),
                 source: NetworkEndpoint {
                     ip: tls_data.source.ip.to_string(),
                     port: tls_data.source.port,
@@ -177,29 +240,24 @@ fn main() {
                     signature_algorithms: tls_data.sig.signature_algorithms.clone(),
                     elliptic_curves: tls_data.sig.elliptic_curves.clone(),
                 },
+                application_detected: None,
             };
-            send_tls_to_assembler(ingest, &client, &assembler_endpoint).await;
+            batch_sender.send(IngestEvent::Tls(ingest)).await;
         }
 
+        batch_sender.close().await;
         info!("TLS collector shutdown completed");
     });
 }
 
-async fn send_tls_to_assembler(data: TlsClient, client: &reqwest::Client, endpoint: &str) {
-    info!("Sending TLS data for {}", data.source.ip);
-    match client.post(endpoint).json(&data).send().await {
-        Ok(response) => {
-            if !response.status().is_success() {
-                error!(
-                    "Failed to send TLS data for {}. Status: {}, Body: {:?}",
-                    data.source.ip,
-                    response.status(),
-                    response.text().await
-                );
-            }
-        }
-        Err(e) => {
-            error!("Error sending TLS data for {}: {:?}", data.source.ip, e);
-        }
-    }
+/// When the traffic of a result was captured.
+fn captured_at(
+    clock: &ReplayClock,
+    source: &huginn_net_tls::IpPort,
+    destination: &huginn_net_tls::IpPort,
+) -> u64 {
+    clock.timestamp(
+        SocketAddr::new(source.ip, source.port),
+        SocketAddr::new(destination.ip, destination.port),
+    )
 }